@@ -0,0 +1,68 @@
+//! Times applying 100k appends made by three clients in turn, each update delivered to a replica
+//! which has all the others.
+//!
+//! Run with `cargo bench --bench append`.
+
+use std::time::{Duration, Instant};
+use yata_impl::{Document, Update};
+
+const APPENDS: usize = 100_000;
+const CLIENTS: u64 = 3;
+const RUNS: usize = 5;
+
+/// Each append as the update its client sends, in the order they were made.
+fn round_robin_updates() -> Vec<Update<u64>> {
+    let mut clients: Vec<Document<u64>> = (1..=CLIENTS).map(Document::with_client_id).collect();
+    let mut updates = Vec::with_capacity(APPENDS);
+
+    for value in 0..APPENDS {
+        let sender = value % clients.len();
+        let since = clients[sender].state_vector();
+
+        clients[sender].push(value as u64);
+        let update = clients[sender].take_local_update(since.as_ref());
+
+        for (receiver, client) in clients.iter_mut().enumerate() {
+            if receiver != sender {
+                update.clone().apply(client).unwrap();
+            }
+        }
+
+        updates.push(update);
+    }
+
+    updates
+}
+
+/// The fastest of `RUNS` runs applying every update to a fresh document.
+fn fastest(updates: &[Update<u64>]) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let mut document = Document::with_client_id(0);
+            let updates = updates.to_vec();
+            let start = Instant::now();
+
+            for update in updates {
+                update.apply(&mut document).unwrap();
+            }
+
+            let elapsed = start.elapsed();
+            assert_eq!(document.len(), APPENDS);
+
+            elapsed
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let updates = round_robin_updates();
+    let elapsed = fastest(&updates);
+
+    println!("{} appends from {} clients", APPENDS, CLIENTS);
+    println!(
+        "  applied:          {:>8.1?} ({:.0} ns each)",
+        elapsed,
+        elapsed.as_nanos() as f64 / APPENDS as f64
+    );
+}
@@ -0,0 +1,57 @@
+//! Times applying a 100k-element update by decoding it into an `Update` first, and by streaming
+//! it straight into the document with `Document::apply_encoded`.
+//!
+//! Run with `cargo bench --bench apply`.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::{Duration, Instant};
+use yata_impl::{Document, Update};
+
+const ELEMENTS: usize = 100_000;
+const RUNS: usize = 5;
+
+/// An update with `ELEMENTS` elements, inserted at random positions so most are separate blocks.
+fn encoded_update() -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut document = Document::with_client_id(1);
+
+    for value in 0..ELEMENTS {
+        let index = rng.gen_range(0, document.len() + 1);
+        document.insert(index, value.to_string());
+    }
+
+    Update::from_document(&document).encode().unwrap()
+}
+
+/// The fastest of `RUNS` runs of `apply` on a fresh document.
+fn fastest(apply: impl Fn(&mut Document<String>)) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let mut document = Document::with_client_id(2);
+            let start = Instant::now();
+
+            apply(&mut document);
+
+            let elapsed = start.elapsed();
+            assert_eq!(document.len(), ELEMENTS);
+
+            elapsed
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let bytes = encoded_update();
+
+    let decoded = fastest(|document| {
+        let update = document.decode_update(&bytes).unwrap();
+        update.apply(document).unwrap();
+    });
+    let streamed = fastest(|document| document.apply_encoded(&bytes).unwrap());
+
+    println!("{} elements, {} bytes", ELEMENTS, bytes.len());
+    println!("  decode, then apply: {:>8.1?}", decoded);
+    println!("  apply_encoded:      {:>8.1?}", streamed);
+}
@@ -0,0 +1,45 @@
+//! Times building documents of increasing size by inserting at random positions.
+//!
+//! Run with `cargo bench`. With positional lookups in O(log n), doubling the number of inserts
+//! should roughly double the time taken rather than quadruple it.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::{Duration, Instant};
+use yata_impl::Document;
+
+fn random_inserts(count: usize) -> Duration {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut document = Document::new();
+    let start = Instant::now();
+
+    for value in 0..count {
+        let index = rng.gen_range(0, document.len() + 1);
+        document.insert(index, value);
+    }
+
+    let elapsed = start.elapsed();
+    assert_eq!(document.len(), count);
+
+    elapsed
+}
+
+fn main() {
+    let mut previous: Option<Duration> = None;
+
+    for count in [25_000, 50_000, 100_000] {
+        let elapsed = random_inserts(count);
+
+        match previous {
+            Some(previous) => println!(
+                "{:>7} random inserts: {:>8.1?} ({:.1}x the previous size's time)",
+                count,
+                elapsed,
+                elapsed.as_secs_f64() / previous.as_secs_f64()
+            ),
+            None => println!("{:>7} random inserts: {:>8.1?}", count, elapsed),
+        }
+
+        previous = Some(elapsed);
+    }
+}
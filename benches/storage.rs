@@ -0,0 +1,70 @@
+//! Times integrating a 200k-element document from several clients into a fresh replica, with the
+//! default `VecStorage` and with a `PagedArena`.
+//!
+//! Run with `cargo bench --bench storage`.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::{Duration, Instant};
+use yata_impl::{BlockStorage, Document, PagedArena, Update, VecStorage};
+
+const ELEMENTS: usize = 200_000;
+const CLIENTS: u64 = 4;
+const RUNS: usize = 3;
+
+/// An update with `ELEMENTS` elements, inserted at random positions by `CLIENTS` clients taking
+/// turns, so most are separate blocks with origins in other clients' blocks.
+fn update() -> Update<u32> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut replicas: Vec<Document<u32>> = (1..=CLIENTS).map(Document::with_client_id).collect();
+
+    for (turn, chunk) in (0..ELEMENTS as u32)
+        .collect::<Vec<_>>()
+        .chunks(ELEMENTS / 20)
+        .enumerate()
+    {
+        let (before, after) = replicas.split_at_mut(turn % CLIENTS as usize);
+        let (replica, after) = after.split_first_mut().unwrap();
+
+        for value in chunk {
+            let index = rng.gen_range(0, replica.len() + 1);
+            replica.insert(index, *value);
+        }
+
+        for other in before.iter_mut().chain(after) {
+            replica.sync_with(other).unwrap();
+        }
+    }
+
+    Update::from_document(&replicas[0])
+}
+
+/// The fastest of `RUNS` runs applying `update` to a fresh document kept in `S`.
+fn fastest<S: BlockStorage<u32>>(update: &Update<u32>) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let mut document = Document::with_storage(CLIENTS + 1, S::default());
+            let update = update.clone();
+            let start = Instant::now();
+
+            update.apply(&mut document).unwrap();
+
+            let elapsed = start.elapsed();
+            assert_eq!(document.len(), ELEMENTS);
+
+            elapsed
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let update = update();
+
+    let vec = fastest::<VecStorage<u32>>(&update);
+    let arena = fastest::<PagedArena<u32>>(&update);
+
+    println!("{} elements from {} clients:", ELEMENTS, CLIENTS);
+    println!("  VecStorage: {:>8.1?}", vec);
+    println!("  PagedArena: {:>8.1?}", arena);
+}
@@ -0,0 +1,50 @@
+//! Two replicas of a document kept in sync over a TCP connection on localhost.
+//!
+//! Run with `cargo run --example tcp_sync --features net`.
+
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+use yata_impl::net::Peer;
+use yata_impl::{Document, SharedDocument};
+
+fn text(document: &SharedDocument<String>) -> String {
+    document.read(|document| document.iter().cloned().collect())
+}
+
+fn main() -> std::io::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?;
+
+    let alice = SharedDocument::new(Document::with_client_id(1));
+    let bob = SharedDocument::new(Document::with_client_id(2));
+
+    for value in ["H", "e", "l", "l", "o"] {
+        alice.push(value.to_owned());
+    }
+
+    let server = {
+        let alice = alice.clone();
+
+        thread::spawn(move || Peer::serve(&listener, alice))
+    };
+    let client = Peer::connect(address, bob.clone())?;
+    let server = server.join().expect("server thread panicked")?;
+
+    // Edits on either side are pushed to the other as they are made
+    bob.push("!".to_owned());
+    alice.insert(5, ",".to_owned());
+    alice.insert(6, " world".to_owned());
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+
+    while text(&alice) != text(&bob) && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    println!("alice: {}", text(&alice));
+    println!("bob:   {}", text(&bob));
+
+    client.close()?;
+    server.close()
+}
@@ -0,0 +1,268 @@
+//! Ephemeral per-client state, such as cursor positions or user names, which is shared alongside
+//! a document but isn't part of its history.
+
+use crate::clock::Clock;
+use crate::document::ClientId;
+use crate::encoding::{self, DecodeError, EncodeError};
+use bincode::{Decode, Encode};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// A single client's state on the wire. A `None` payload means the client has gone away.
+#[derive(Encode, Decode)]
+struct AwarenessEntry<A> {
+    client_id: ClientId,
+    clock: Clock,
+    payload: Option<A>,
+}
+
+#[derive(Debug)]
+struct ClientState<A> {
+    /// Bumped by the client on every change, so older states never replace newer ones.
+    clock: Clock,
+    payload: Option<A>,
+    /// When the state was last received, for pruning clients which have gone quiet.
+    last_seen: Instant,
+}
+
+/// The latest awareness state of every client, including this one.
+///
+/// Each client only changes its own state, so states are merged by keeping whichever has the
+/// larger clock. Clients are expected to re-send their state more often than the timeout, as
+/// remote clients which haven't been heard from within it are dropped by [`Awareness::prune`].
+#[derive(Debug)]
+pub struct Awareness<A> {
+    client_id: ClientId,
+    states: HashMap<ClientId, ClientState<A>>,
+    timeout: Duration,
+}
+
+impl<A: Clone + Encode + Decode> Awareness<A> {
+    /// Creates an awareness instance for `client_id`, which should match the client id of the
+    /// document it accompanies.
+    pub fn new(client_id: ClientId) -> Awareness<A> {
+        Awareness {
+            client_id,
+            states: HashMap::new(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Sets how long a remote client may go unheard before it is pruned. Defaults to 30 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Awareness<A> {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Replaces this client's state.
+    pub fn local_update(&mut self, payload: A) {
+        self.set_local(Some(payload));
+    }
+
+    /// Removes this client's state, e.g. when it goes offline.
+    pub fn remove_local(&mut self) {
+        self.set_local(None);
+    }
+
+    fn set_local(&mut self, payload: Option<A>) {
+        let clock = self.clock(self.client_id).advance(1);
+
+        self.states.insert(
+            self.client_id,
+            ClientState {
+                clock,
+                payload,
+                last_seen: Instant::now(),
+            },
+        );
+    }
+
+    /// The state of `client_id`, if it has one.
+    pub fn get(&self, client_id: ClientId) -> Option<&A> {
+        self.states.get(&client_id)?.payload.as_ref()
+    }
+
+    /// Every client with a state, ordered by client id.
+    pub fn states(&self) -> Vec<(ClientId, &A)> {
+        let mut states: Vec<(ClientId, &A)> = self
+            .states
+            .iter()
+            .filter_map(|(client_id, state)| Some((*client_id, state.payload.as_ref()?)))
+            .collect();
+        states.sort_by_key(|(client_id, _)| *client_id);
+
+        states
+    }
+
+    /// The latest awareness clock seen from `client_id`.
+    pub fn clock(&self, client_id: ClientId) -> Clock {
+        self.states
+            .get(&client_id)
+            .map_or(Clock::ZERO, |state| state.clock)
+    }
+
+    /// The latest awareness clock seen from every client, for [`Awareness::encode_update`].
+    pub fn clocks(&self) -> HashMap<ClientId, Clock> {
+        self.states
+            .iter()
+            .map(|(client_id, state)| (*client_id, state.clock))
+            .collect()
+    }
+
+    /// Encodes every state, removals included, which is newer than the clocks in `since`. Pass
+    /// an empty map to encode everything.
+    pub fn encode_update(&self, since: &HashMap<ClientId, Clock>) -> Result<Vec<u8>, EncodeError> {
+        let mut entries: Vec<AwarenessEntry<A>> = self
+            .states
+            .iter()
+            .filter(|(client_id, state)| {
+                state.clock > *since.get(client_id).unwrap_or(&Clock::ZERO)
+            })
+            .map(|(client_id, state)| AwarenessEntry {
+                client_id: *client_id,
+                clock: state.clock,
+                payload: state.payload.clone(),
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.client_id);
+
+        encoding::encode(&entries)
+    }
+
+    /// Merges states encoded by [`Awareness::encode_update`], received at `now`.
+    pub fn apply_update(&mut self, bytes: &[u8], now: Instant) -> Result<(), DecodeError> {
+        let entries: Vec<AwarenessEntry<A>> = encoding::decode(bytes)?;
+
+        for entry in entries {
+            if entry.clock <= self.clock(entry.client_id) {
+                continue;
+            }
+
+            if entry.client_id == self.client_id {
+                // Someone has a newer state for us, e.g. from before this instance restarted, so
+                // ours moves past it to replace it everywhere
+                if let Some(state) = self.states.get_mut(&self.client_id) {
+                    state.clock = entry.clock.saturating_advance(1);
+                }
+
+                continue;
+            }
+
+            self.states.insert(
+                entry.client_id,
+                ClientState {
+                    clock: entry.clock,
+                    payload: entry.payload,
+                    last_seen: now,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Removes the state of every remote client not heard from within the timeout before `now`,
+    /// returning their ids. Their clocks are kept so stale copies of their state are ignored.
+    pub fn prune(&mut self, now: Instant) -> Vec<ClientId> {
+        let mut removed: Vec<ClientId> = self
+            .states
+            .iter_mut()
+            .filter(|(client_id, state)| {
+                **client_id != self.client_id
+                    && state.payload.is_some()
+                    && now.saturating_duration_since(state.last_seen) >= self.timeout
+            })
+            .map(|(client_id, state)| {
+                state.payload = None;
+
+                *client_id
+            })
+            .collect();
+        removed.sort();
+
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::awareness::Awareness;
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    fn sync(from: &Awareness<String>, to: &mut Awareness<String>, now: Instant) {
+        let update = from.encode_update(&to.clocks()).unwrap();
+        to.apply_update(&update, now).unwrap();
+    }
+
+    #[test]
+    fn instances_converge() {
+        let now = Instant::now();
+        let mut instances: Vec<Awareness<String>> = (1..=3).map(Awareness::new).collect();
+
+        instances[0].local_update("alice".to_owned());
+        instances[1].local_update("bob".to_owned());
+        instances[2].local_update("carol".to_owned());
+        instances[1].local_update("bob at 12".to_owned());
+
+        // Everyone hears from the next instance along, twice round the ring
+        for _ in 0..2 {
+            for index in 0..3 {
+                let (from, to) = (index, (index + 1) % 3);
+                let update = instances[from]
+                    .encode_update(&instances[to].clocks())
+                    .unwrap();
+                instances[to].apply_update(&update, now).unwrap();
+            }
+        }
+
+        instances[0].remove_local();
+        for index in 1..3 {
+            let update = instances[0].encode_update(&HashMap::new()).unwrap();
+            instances[index].apply_update(&update, now).unwrap();
+        }
+
+        for instance in &instances {
+            assert_eq!(
+                instance.states(),
+                vec![(2, &"bob at 12".to_owned()), (3, &"carol".to_owned())]
+            );
+        }
+    }
+
+    #[test]
+    fn stale_clients_are_pruned() {
+        let start = Instant::now();
+        let mut local: Awareness<String> = Awareness::new(1).with_timeout(Duration::from_secs(10));
+        let mut quiet = Awareness::new(2);
+        let mut chatty = Awareness::new(3);
+
+        local.local_update("local".to_owned());
+        quiet.local_update("quiet".to_owned());
+        chatty.local_update("chatty".to_owned());
+
+        sync(&quiet, &mut local, start);
+        sync(&chatty, &mut local, start);
+
+        chatty.local_update("still here".to_owned());
+        sync(&chatty, &mut local, start + Duration::from_secs(8));
+
+        assert!(local.prune(start + Duration::from_secs(9)).is_empty());
+        assert_eq!(local.prune(start + Duration::from_secs(12)), vec![2]);
+
+        assert_eq!(local.get(1), Some(&"local".to_owned()));
+        assert_eq!(local.get(2), None);
+        assert_eq!(local.get(3), Some(&"still here".to_owned()));
+
+        // A late copy of the pruned state doesn't bring it back, but a newer one does
+        let stale = quiet.encode_update(&HashMap::new()).unwrap();
+        local
+            .apply_update(&stale, start + Duration::from_secs(13))
+            .unwrap();
+        assert_eq!(local.get(2), None);
+
+        quiet.local_update("back".to_owned());
+        sync(&quiet, &mut local, start + Duration::from_secs(14));
+        assert_eq!(local.get(2), Some(&"back".to_owned()));
+    }
+}
@@ -0,0 +1,163 @@
+use crate::encoding::EncodeError;
+use crate::text::TextUpdateError;
+use crate::{Document, StateVector, Update};
+use std::ops::Range;
+
+/// A collaboratively edited byte buffer, such as an append-mostly binary log.
+///
+/// Each edit stores its bytes contiguously in a single block, and blocks of bytes encode as one
+/// byte per byte plus a small header, so updates stay close to the size of the data they carry.
+#[derive(Debug, Default)]
+pub struct BinaryDocument {
+    document: Document<u8>,
+}
+
+impl BinaryDocument {
+    pub fn new() -> BinaryDocument {
+        BinaryDocument {
+            document: Document::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_client_id(client_id: u64) -> BinaryDocument {
+        BinaryDocument {
+            document: Document::with_client_id(client_id),
+        }
+    }
+
+    /// Inserts `bytes` so the first of them ends up at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index > len`.
+    pub fn insert(&mut self, index: usize, bytes: &[u8]) -> Update<u8> {
+        self.document.transact(|transaction| {
+            transaction.insert_values(index, bytes.iter().copied());
+        })
+    }
+
+    /// Appends `bytes` to the end of the buffer.
+    pub fn push(&mut self, bytes: &[u8]) -> Update<u8> {
+        let len = self.len();
+
+        self.insert(len, bytes)
+    }
+
+    /// Deletes the bytes in `range`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is out of bounds.
+    pub fn delete(&mut self, range: Range<usize>) -> Update<u8> {
+        assert!(range.start <= range.end, "range {:?} is reversed", range);
+
+        self.document
+            .transact(|transaction| transaction.remove_range(range.start, range.len()))
+    }
+
+    /// A copy of the bytes in `range`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is out of bounds.
+    pub fn read(&self, range: Range<usize>) -> Vec<u8> {
+        self.document.slice(range).copied().collect()
+    }
+
+    /// The number of bytes in the buffer.
+    pub fn len(&self) -> usize {
+        self.document.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.document.is_empty()
+    }
+
+    pub fn state_vector(&self) -> StateVector {
+        self.document.state_vector()
+    }
+
+    /// Encodes everything a replica at state `since` is missing, for
+    /// [`BinaryDocument::apply_update`]. Pass an empty [`StateVector`] to encode the whole buffer.
+    pub fn encode_update(&self, since: &StateVector) -> Result<Vec<u8>, EncodeError> {
+        Update::from_document_since(&self.document, since.as_ref()).encode()
+    }
+
+    /// Applies an update encoded by [`BinaryDocument::encode_update`] or [`Update::encode`].
+    pub fn apply_update(&mut self, bytes: &[u8]) -> Result<(), TextUpdateError> {
+        Update::decode(bytes)
+            .map_err(TextUpdateError::Decode)?
+            .apply(&mut self.document)
+            .map_err(TextUpdateError::Apply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::binary::BinaryDocument;
+    use crate::StateVector;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn edits_by_byte_index() {
+        let mut buffer = BinaryDocument::with_client_id(1);
+        buffer.push(b"hello");
+        buffer.push(b" world");
+        buffer.insert(5, b",");
+        buffer.delete(0..1);
+
+        assert_eq!(buffer.read(0..buffer.len()), b"ello, world");
+        assert_eq!(buffer.read(4..6), b", ");
+        assert_eq!(buffer.len(), 11);
+    }
+
+    #[test]
+    fn large_payloads_encode_close_to_their_size() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let payload: Vec<u8> = (0..1 << 20).map(|_| rng.gen()).collect();
+
+        let mut buffer = BinaryDocument::with_client_id(1);
+        let (head, tail) = payload.split_at(payload.len() / 2);
+        buffer.push(tail);
+        // Inserting in the middle of existing data is stored as one block too
+        buffer.insert(0, &head[..1000]);
+        buffer.insert(1000, &head[1000..]);
+
+        let encoded = buffer.encode_update(&StateVector::new()).unwrap();
+        assert!(
+            encoded.len() <= payload.len() * 105 / 100,
+            "encoded {} bytes to {}",
+            payload.len(),
+            encoded.len()
+        );
+
+        let mut other = BinaryDocument::with_client_id(2);
+        other.apply_update(&encoded).unwrap();
+
+        assert_eq!(other.len(), payload.len());
+        assert_eq!(other.read(0..other.len()), payload);
+    }
+
+    #[test]
+    fn concurrent_appends_do_not_interleave() {
+        let mut buffer1 = BinaryDocument::with_client_id(1);
+        buffer1.push(b"log:");
+
+        let mut buffer2 = BinaryDocument::with_client_id(2);
+        buffer2
+            .apply_update(&buffer1.encode_update(&StateVector::new()).unwrap())
+            .unwrap();
+
+        let update1 = buffer1.push(b"abc").encode().unwrap();
+        let update2 = buffer2.push(b"xyz").encode().unwrap();
+
+        buffer1.apply_update(&update2).unwrap();
+        buffer2.apply_update(&update1).unwrap();
+
+        let merged = buffer1.read(0..10);
+        assert_eq!(merged, buffer2.read(0..10));
+        assert!(merged == b"log:abcxyz" || merged == b"log:xyzabc");
+    }
+}
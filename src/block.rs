@@ -1,7 +1,9 @@
-use crate::document::{BlockId, ClientId, Clock};
-use std::ops::Add;
+use crate::clock::{Clock, ClockRange};
+use crate::document::{BlockId, ClientId};
+use bincode::{Decode, Encode};
 
-#[derive(Debug, Eq, PartialEq, Clone)]
+#[derive(Debug, Eq, PartialEq, Clone, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Block<T: Clone> {
     // The clock index this block was inserted as
     pub(crate) id: Clock,
@@ -14,8 +16,25 @@ pub struct Block<T: Clone> {
     pub(crate) value: Vec<T>,
     pub(crate) length: usize,
     pub(crate) deleted: bool,
+    /// Passed to the [`ConflictResolver`](crate::ConflictResolver) when ordering concurrent inserts.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) priority: u8,
 }
 
+/// A value which can be stored in a [`Document`](crate::Document).
+///
+/// `Item` has no required methods, so any `Clone` type can opt in with a one-line impl:
+///
+/// ```
+/// # use yata_impl::{Document, Item};
+/// #[derive(Clone)]
+/// struct Cell(u32);
+///
+/// impl Item for Cell {}
+///
+/// let mut document = Document::new();
+/// document.push(Cell(1));
+/// ```
 pub trait Item: Clone {}
 
 impl<T: Item> Item for Option<T> {
@@ -45,37 +64,47 @@ impl<T: Clone> Block<T> {
             self.value = vec![];
         }
     }
+
+    /// The clocks of the block's elements.
+    pub(crate) fn clocks(&self) -> ClockRange {
+        ClockRange::starting_at(self.id, self.length as u64)
+    }
+
+    /// The number of live elements in the block.
+    pub(crate) fn live_length(&self) -> usize {
+        if self.deleted {
+            0
+        } else {
+            self.length
+        }
+    }
 }
 
 impl<T: Item> Block<T> {
-    pub fn with_value(id: Clock, left: Option<BlockId>, value: T) -> Block<T> {
-        Block {
-            id,
-            origin_left: left,
-            left,
-            origin_right: None,
-            right: None,
-            value: vec![value],
-            length: 1,
-            deleted: false,
-        }
+    /// A one-element block created after the element `origin_left`. It has no neighbours until
+    /// it's linked into a store.
+    pub fn with_value(id: impl Into<Clock>, origin_left: Option<BlockId>, value: T) -> Block<T> {
+        Block::with_value_and_right(id, origin_left, None, value)
     }
 
+    /// A one-element block created between the elements `origin_left` and `origin_right`. It has
+    /// no neighbours until it's linked into a store.
     pub fn with_value_and_right(
-        id: Clock,
-        left: Option<BlockId>,
-        right: Option<BlockId>,
+        id: impl Into<Clock>,
+        origin_left: Option<BlockId>,
+        origin_right: Option<BlockId>,
         value: T,
     ) -> Block<T> {
         Block {
-            id,
-            origin_left: left,
-            origin_right: right,
-            left,
-            right,
+            id: id.into(),
+            origin_left,
+            origin_right,
+            left: None,
+            right: None,
             value: vec![value],
             length: 1,
             deleted: false,
+            priority: 0,
         }
     }
 
@@ -89,24 +118,43 @@ impl<T: Item> Block<T> {
             origin_left: self.origin_left,
             origin_right: self.origin_right,
             left: self.left,
-            right: self.right,
+            right: next.right,
             value: self.value,
             length: self.length + next.length,
             deleted: self.deleted,
+            priority: self.priority,
         }
     }
 
-    pub fn split_at(self, client_id: ClientId, index: Clock) -> (Block<T>, Block<T>) {
-        let (left_value, right_value) = if self.deleted {
-            (vec![], vec![])
+    /// Splits the block so its first `index` elements stay in the left half. Each element has
+    /// its own clock, so the right half starts at clock `id + index`, and was inserted directly
+    /// after the left half's last element.
+    ///
+    /// # Panics
+    ///
+    /// Panics unless `0 < index < length`, as either half would otherwise be empty.
+    pub fn split_at(mut self, client_id: ClientId, index: u64) -> (Block<T>, Block<T>) {
+        let offset = usize::try_from(index).expect("split index exceeds addressable memory");
+
+        assert!(
+            offset > 0 && offset < self.length,
+            "split index (is {}) should be within 1..{}",
+            offset,
+            self.length
+        );
+
+        let right_value = if self.deleted {
+            vec![]
         } else {
-            let (left, right) = self.value.split_at(index as usize);
+            let right_value = self.value.split_off(offset);
+            // Otherwise the left half keeps the whole block's allocation
+            self.value.shrink_to_fit();
 
-            (left.to_vec(), right.to_vec())
+            right_value
         };
 
         let left_block_id = Some(BlockId::new(client_id, self.id));
-        let right_block_id = Some(BlockId::new(client_id, self.id + index));
+        let right_block_id = Some(BlockId::new(client_id, self.id.advance(index)));
 
         (
             Block {
@@ -115,22 +163,180 @@ impl<T: Item> Block<T> {
                 origin_right: self.origin_right,
                 left: self.left,
                 right: right_block_id,
-                value: left_value,
-                length: index as usize,
+                value: self.value,
+                length: offset,
                 deleted: self.deleted,
+                priority: self.priority,
             },
             Block {
-                id: self.id + index,
-                origin_left: left_block_id,
+                id: self.id.advance(index),
+                origin_left: Some(BlockId::new(client_id, self.id.advance(index - 1))),
                 origin_right: self.origin_right,
                 left: left_block_id,
                 right: self.right,
                 value: right_value,
-                length: self.length - index as usize,
+                length: self.length - offset,
                 deleted: self.deleted,
+                priority: self.priority,
             },
         )
     }
 }
 
 impl Item for String {}
+
+impl<T: Item> Item for Vec<T> {}
+
+macro_rules! impl_item {
+    ($($t:ty),*) => {
+        $(impl Item for $t {})*
+    };
+}
+
+impl_item!(char, bool, f32, f64, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+macro_rules! impl_item_for_tuple {
+    ($($t:ident),+) => {
+        impl<$($t: Item),+> Item for ($($t,)+) {}
+    };
+}
+
+impl_item_for_tuple!(A);
+impl_item_for_tuple!(A, B);
+impl_item_for_tuple!(A, B, C);
+impl_item_for_tuple!(A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+    use crate::block::Block;
+    use crate::clock::Clock;
+    use crate::document::BlockId;
+    use crate::storage::BlockStorage;
+    use crate::{Document, Update};
+
+    /// A block of `values` with clocks from 10, between blocks 1@0 and 1@20.
+    fn block(values: &[char], deleted: bool) -> Block<char> {
+        Block {
+            id: Clock::new(10),
+            origin_left: Some(BlockId::new(1, 0)),
+            left: Some(BlockId::new(1, 0)),
+            origin_right: Some(BlockId::new(1, 20)),
+            right: Some(BlockId::new(1, 20)),
+            value: if deleted { vec![] } else { values.to_vec() },
+            length: values.len(),
+            deleted,
+            priority: 0,
+        }
+    }
+
+    #[test]
+    fn split_in_the_middle() {
+        let (left, right) = block(&['a', 'b', 'c', 'd'], false).split_at(2, 3);
+
+        assert_eq!(
+            (left.id.get(), left.length, left.value),
+            (10, 3, vec!['a', 'b', 'c'])
+        );
+        assert_eq!(left.origin_left, Some(BlockId::new(1, 0)));
+        assert_eq!(left.right, Some(BlockId::new(2, 13)));
+
+        assert_eq!(
+            (right.id.get(), right.length, right.value),
+            (13, 1, vec!['d'])
+        );
+        // The right half was inserted after the left half's last element, not its first
+        assert_eq!(right.origin_left, Some(BlockId::new(2, 12)));
+        assert_eq!(right.origin_right, Some(BlockId::new(1, 20)));
+        assert_eq!(right.left, Some(BlockId::new(2, 10)));
+        assert_eq!(right.right, Some(BlockId::new(1, 20)));
+    }
+
+    #[test]
+    fn split_in_the_middle_of_a_deleted_block() {
+        let (left, right) = block(&['a', 'b', 'c', 'd'], true).split_at(2, 1);
+
+        assert!(left.deleted && right.deleted);
+        assert_eq!((left.id.get(), left.length), (10, 1));
+        assert_eq!((right.id.get(), right.length), (11, 3));
+        assert_eq!(right.origin_left, Some(BlockId::new(2, 10)));
+        assert!(left.value.is_empty() && right.value.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "split index (is 0) should be within 1..4")]
+    fn split_at_the_start() {
+        block(&['a', 'b', 'c', 'd'], false).split_at(2, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "split index (is 4) should be within 1..4")]
+    fn split_at_the_end() {
+        block(&['a', 'b', 'c', 'd'], false).split_at(2, 4);
+    }
+
+    #[test]
+    fn partial_deletes_only_delete_the_middle_of_a_block() {
+        let mut doc = Document::with_client_id(1);
+        for c in "abcdef".chars() {
+            doc.push(c);
+        }
+        doc.remove_range(2, 2);
+
+        assert_eq!(doc.to_vec(), vec!['a', 'b', 'e', 'f']);
+        assert_eq!(
+            doc.store
+                .data
+                .iter(1)
+                .map(|block| (block.id.get(), block.length, block.deleted))
+                .collect::<Vec<_>>(),
+            vec![(0, 2, false), (2, 2, true), (4, 2, false)]
+        );
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc).apply(&mut doc2).unwrap();
+
+        assert_eq!(doc2.to_vec(), doc.to_vec());
+    }
+
+    #[test]
+    fn char_document_round_trip() {
+        let mut doc = Document::with_client_id(1);
+        for (index, c) in "hllo".chars().enumerate() {
+            doc.insert(index, c);
+        }
+        doc.insert(1, 'e');
+        doc.push('!');
+        doc.remove(5);
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc).apply(&mut doc2).unwrap();
+
+        assert_eq!(doc2.store.iter_values().collect::<String>(), "hello");
+    }
+
+    #[test]
+    fn byte_document_round_trip() {
+        let mut doc = Document::with_client_id(1);
+        for byte in [1u8, 2, 3, 4] {
+            doc.push(byte);
+        }
+        doc.remove_range(1, 2);
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc).apply(&mut doc2).unwrap();
+
+        assert_eq!(
+            doc2.store.iter_values().copied().collect::<Vec<u8>>(),
+            vec![1, 4]
+        );
+    }
+
+    #[test]
+    fn tuple_document() {
+        let mut doc = Document::with_client_id(1);
+        doc.push(('a', 1u32));
+        doc.push(('b', 2u32));
+
+        assert_eq!(doc.get(1), Some(&('b', 2)));
+    }
+}
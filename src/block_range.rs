@@ -0,0 +1,153 @@
+use crate::clock::ClockRange;
+use crate::document::{BlockId, ClientId};
+use bincode::{Decode, Encode};
+
+/// A run of consecutive clocks from one client, e.g. the elements a deletion covers or the part
+/// of a client's history one replica has and another hasn't.
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlockRange {
+    pub client_id: ClientId,
+    pub clocks: ClockRange,
+}
+
+impl BlockRange {
+    pub fn new(client_id: ClientId, clocks: impl Into<ClockRange>) -> BlockRange {
+        BlockRange {
+            client_id,
+            clocks: clocks.into(),
+        }
+    }
+
+    /// The id of the range's first element.
+    pub fn start(&self) -> BlockId {
+        BlockId::new(self.client_id, self.clocks.start)
+    }
+
+    /// The number of clocks the range covers.
+    pub fn len(&self) -> u64 {
+        self.clocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clocks.is_empty()
+    }
+
+    /// The ids of every element in the range, in clock order.
+    pub fn ids(&self) -> impl Iterator<Item = BlockId> {
+        let client_id = self.client_id;
+
+        self.clocks
+            .iter()
+            .map(move |clock| BlockId::new(client_id, clock))
+    }
+
+    /// Whether the element `id` is in the range.
+    pub fn contains(&self, id: BlockId) -> bool {
+        id.client_id == self.client_id && self.clocks.contains(id.clock)
+    }
+
+    /// The clocks both ranges cover, or `None` if they don't overlap.
+    pub fn intersect(&self, other: &BlockRange) -> Option<BlockRange> {
+        if self.client_id != other.client_id {
+            return None;
+        }
+
+        self.clocks
+            .intersect(other.clocks)
+            .map(|clocks| BlockRange::new(self.client_id, clocks))
+    }
+
+    /// The single range covering both, or `None` if there would be a gap between them. Ranges
+    /// which only touch end to end are joined.
+    pub fn union_adjacent(&self, other: &BlockRange) -> Option<BlockRange> {
+        if other.is_empty() {
+            return Some(self.clone());
+        }
+
+        if self.is_empty() {
+            return Some(other.clone());
+        }
+
+        let touches =
+            self.clocks.start <= other.clocks.end && other.clocks.start <= self.clocks.end;
+
+        (self.client_id == other.client_id && touches).then(|| {
+            BlockRange::new(
+                self.client_id,
+                self.clocks.start.min(other.clocks.start)..self.clocks.end.max(other.clocks.end),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block_range::BlockRange;
+    use crate::document::BlockId;
+    use bincode::{config, decode_from_slice, encode_to_vec};
+
+    #[test]
+    fn contains_only_its_own_clients_clocks() {
+        let range = BlockRange::new(1, 2..4);
+
+        assert!(range.contains(BlockId::new(1, 2)));
+        assert!(range.contains(BlockId::new(1, 3)));
+        assert!(!range.contains(BlockId::new(1, 4)));
+        assert!(!range.contains(BlockId::new(2, 3)));
+        assert_eq!(
+            range.ids().collect::<Vec<_>>(),
+            vec![BlockId::new(1, 2), BlockId::new(1, 3)]
+        );
+        assert_eq!(
+            range.ids().collect::<Vec<_>>(),
+            BlockId::new(1, 2).range(2).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn intersects_overlapping_ranges() {
+        let range = BlockRange::new(1, 2..6);
+
+        assert_eq!(
+            range.intersect(&BlockRange::new(1, 4..9)),
+            Some(BlockRange::new(1, 4..6))
+        );
+        assert_eq!(
+            range.intersect(&BlockRange::new(1, 3..4)),
+            Some(BlockRange::new(1, 3..4))
+        );
+        assert_eq!(range.intersect(&BlockRange::new(1, 6..8)), None);
+        assert_eq!(range.intersect(&BlockRange::new(2, 2..6)), None);
+    }
+
+    #[test]
+    fn unions_only_ranges_without_a_gap() {
+        let range = BlockRange::new(1, 2..4);
+
+        assert_eq!(
+            range.union_adjacent(&BlockRange::new(1, 4..7)),
+            Some(BlockRange::new(1, 2..7))
+        );
+        assert_eq!(
+            range.union_adjacent(&BlockRange::new(1, 0..3)),
+            Some(BlockRange::new(1, 0..4))
+        );
+        assert_eq!(range.union_adjacent(&BlockRange::new(1, 5..7)), None);
+        assert_eq!(range.union_adjacent(&BlockRange::new(2, 4..7)), None);
+        assert_eq!(
+            range.union_adjacent(&BlockRange::new(2, 9..9)),
+            Some(range.clone())
+        );
+    }
+
+    #[test]
+    fn round_trips_through_bincode() {
+        let range = BlockRange::new(7, 3..12);
+        let configuration = config::standard();
+        let encoded = encode_to_vec(&range, configuration).unwrap();
+        let (decoded, _): (BlockRange, usize) = decode_from_slice(&encoded, configuration).unwrap();
+
+        assert_eq!(decoded, range);
+    }
+}
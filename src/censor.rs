@@ -0,0 +1,133 @@
+//! Removing content from a document for good, e.g. content which should never have been shared.
+
+use crate::block::Item;
+use crate::clock::ClockRange;
+use crate::delete_set::DeleteSet;
+use crate::document::{ClientId, Document};
+use crate::storage::BlockStorage;
+
+impl<T: Item, S: BlockStorage<T>> Document<T, S> {
+    /// Removes the elements `client_id` created within `clocks` for good. They are tombstoned
+    /// like any other deletion, but their values are also dropped from the document's retained
+    /// deletions, so neither [`Document::checkout`] nor undo can bring them back.
+    ///
+    /// The censored clocks are kept in snapshots and sent with every update, and each replica
+    /// receiving them does the same. Blocks in them which arrive later, e.g. in an update taken
+    /// before the content was censored, are integrated as tombstones without their content.
+    /// Clocks the document hasn't seen yet can be censored ahead of time.
+    ///
+    /// Copies of the values held outside the document, e.g. by an
+    /// [`UndoManager`](crate::UndoManager), aren't reached.
+    pub fn censor(&mut self, client_id: ClientId, clocks: impl Into<ClockRange>) {
+        let mut censored = DeleteSet::empty();
+        censored.insert(client_id, clocks);
+
+        self.observed(true, |document| {
+            document.censor_ranges(&censored);
+            document.debug_assert_consistent();
+        });
+    }
+
+    /// The elements censored here or by any replica this document has heard from.
+    pub fn censored(&self) -> &DeleteSet {
+        &self.censored
+    }
+
+    /// Tombstones everything in `censored` which isn't already, dropping the values of it kept
+    /// for [`Document::checkout`].
+    pub(crate) fn censor_ranges(&mut self, censored: &DeleteSet) {
+        for range in censored.diff(&self.censored) {
+            for store in self.stores_mut() {
+                store.delete_clocks(range.client_id, range.clocks);
+
+                if let Some(kept) = &mut store.deleted_values {
+                    kept.remove(range.client_id, range.clocks);
+                }
+            }
+
+            self.censored.insert(range.client_id, range.clocks);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{BlockId, Document, Snapshot, Update};
+
+    fn edited() -> Document<String> {
+        let mut document = Document::with_client_id(1).with_retained_deletions();
+
+        for value in ["a", "secret", "password", "b"] {
+            document.push(value.to_owned());
+        }
+
+        document
+    }
+
+    #[test]
+    fn censored_content_doesnt_come_back_from_an_old_update() {
+        let mut document = edited();
+        let original = Update::from_document(&document);
+        let before = document.state_vector();
+
+        document.push("c".to_owned());
+        document.remove_range(1, 2);
+        assert_eq!(document.checkout(before.as_ref()).len(), 4);
+
+        document.censor(1, 1..3);
+        original.clone().apply(&mut document).unwrap();
+        document.apply_encoded(&original.encode().unwrap()).unwrap();
+
+        assert_eq!(document.to_vec(), vec!["a", "b", "c"]);
+        assert_eq!(document.checkout(before.as_ref()).to_vec(), vec!["a", "b"]);
+        assert!(document.censored().contains(BlockId::new(1, 2)));
+        assert_eq!(document.check_integrity(), vec![]);
+    }
+
+    #[test]
+    fn replicas_converge_whether_or_not_they_saw_the_content() {
+        let mut document = edited();
+        let original = Update::from_document(&document);
+
+        let mut saw: Document<String> = Document::with_client_id(2);
+        original.clone().apply(&mut saw).unwrap();
+
+        document.censor(1, 1..3);
+        document.push("c".to_owned());
+
+        // One replica hears of the censoring before the content, the other after
+        let mut never_saw: Document<String> = Document::with_client_id(3);
+        Update::from_document(&document)
+            .apply(&mut never_saw)
+            .unwrap();
+        original.clone().apply(&mut never_saw).unwrap();
+        document.sync_with(&mut saw).unwrap();
+
+        for replica in [&saw, &never_saw] {
+            assert_eq!(replica.to_vec(), vec!["a", "b", "c"]);
+            assert_eq!(replica.censored(), document.censored());
+            assert_eq!(replica.state_vector(), document.state_vector());
+        }
+
+        // Anything the censored elements could have been kept in stays free of them
+        let restored: Document<String> =
+            Document::restore(Snapshot::decode(&never_saw.snapshot().encode().unwrap()).unwrap());
+        let mut fresh: Document<String> = Document::with_client_id(4);
+        Update::from_document(&saw).apply(&mut fresh).unwrap();
+        original.apply(&mut fresh).unwrap();
+
+        assert_eq!(restored.censored(), document.censored());
+        assert_eq!(fresh.to_vec(), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn censoring_ahead_of_time_tombstones_blocks_as_they_arrive() {
+        let mut document: Document<String> = Document::with_client_id(2);
+        document.censor(1, 2..3);
+
+        edited().sync_with(&mut document).unwrap();
+
+        assert_eq!(document.to_vec(), vec!["a", "secret", "b"]);
+        assert_eq!(document.check_integrity(), vec![]);
+    }
+}
@@ -0,0 +1,232 @@
+//! Pruning a document's history once no replica can edit concurrently with it any more.
+
+use crate::block::Item;
+use crate::clock::Clock;
+use crate::document::{BlockId, ClientId, ClockVector, Document, StateVector};
+use crate::storage::BlockStorage;
+use crate::version::DeleteLog;
+
+/// Where a document's history was pruned by [`Document::checkpoint`]: the clock it had reached
+/// from each client. History before it can no longer be sent to or accepted from other replicas.
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct Checkpoint {
+    state: StateVector,
+}
+
+impl Checkpoint {
+    /// The clock of each client the checkpoint was taken at.
+    pub fn state(&self) -> &StateVector {
+        &self.state
+    }
+
+    /// The checkpoint's clock for `client_id`, or 0 if it has nothing from it.
+    pub fn clock(&self, client_id: ClientId) -> Clock {
+        self.state.clock(client_id)
+    }
+
+    /// Whether nothing has been pruned, e.g. because the document was never checkpointed.
+    pub fn is_empty(&self) -> bool {
+        self.state.as_ref().is_empty()
+    }
+
+    pub(crate) fn clients(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.state.as_ref().keys().copied()
+    }
+
+    /// Each client's clock, ordered by client so equal checkpoints encode the same way.
+    pub(crate) fn clocks(&self) -> Vec<(ClientId, Clock)> {
+        let mut clocks: Vec<(ClientId, Clock)> = self
+            .state
+            .as_ref()
+            .iter()
+            .map(|(client_id, clock)| (*client_id, *clock))
+            .collect();
+        clocks.sort_unstable();
+
+        clocks
+    }
+
+    pub(crate) fn from_clocks(clocks: Vec<(ClientId, Clock)>) -> Checkpoint {
+        Checkpoint {
+            state: StateVector::from(clocks.into_iter().collect::<ClockVector>()),
+        }
+    }
+}
+
+impl<T: Item, S: BlockStorage<T>> Document<T, S> {
+    /// Prunes the document's history up to its current state. Only call this once no replica
+    /// can make an edit concurrent with anything before that state, e.g. once every peer has
+    /// acknowledged it.
+    ///
+    /// Unlike [`Document::gc`], tombstones before the checkpoint are dropped entirely, apart from
+    /// those marks and moves are attached to, and each client's runs of live elements are merged
+    /// into single blocks. Updates building on history before the checkpoint are rejected with
+    /// [`ApplyError::PrunedHistory`](crate::ApplyError::PrunedHistory) rather than integrated
+    /// wrongly, and so are this document's updates at replicas which haven't reached it. Those
+    /// replicas catch up with [`Document::resync`].
+    ///
+    /// Who made each deletion and the values of deleted elements go too, so neither
+    /// [`Document::checkout`] nor undo can reach back past the checkpoint.
+    pub fn checkpoint(&mut self) -> Checkpoint {
+        let state = self.state_vector();
+        let anchors: Vec<BlockId> = self
+            .marks
+            .marks()
+            .iter()
+            .flat_map(|mark| [mark.start, mark.end])
+            .collect();
+
+        for store in self.stores_mut() {
+            store.prune(state.as_ref(), &anchors);
+        }
+
+        self.deletions = DeleteLog::new();
+        self.checkpoint = Checkpoint { state };
+        self.advance_local_clock();
+        self.debug_assert_consistent();
+
+        self.checkpoint.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ApplyError, BlockId, Clock, Document, Update};
+
+    /// A document which has been edited and deleted from by two clients over many rounds,
+    /// leaving behind far more blocks than live elements.
+    fn churned() -> (Document<u32>, Document<u32>) {
+        let mut first = Document::with_client_id(1);
+        let mut second = Document::with_client_id(2);
+
+        for round in 0..50 {
+            first.insert(first.len() / 2, round);
+            first.push(round + 100);
+            second.insert(0, round + 200);
+
+            if second.len() > 4 {
+                second.remove_range(1, 2);
+            }
+
+            if first.len() > 6 {
+                first.remove(first.len() / 3);
+            }
+
+            first.sync_with(&mut second).unwrap();
+        }
+
+        (first, second)
+    }
+
+    #[test]
+    fn collapses_blocks_without_changing_content() {
+        let (mut document, _) = churned();
+        let before = document.to_vec();
+        let blocks_before = document.blocks().count();
+
+        let checkpoint = document.checkpoint();
+
+        assert_eq!(document.to_vec(), before);
+        assert_eq!(checkpoint.state(), &document.state_vector());
+        assert!(document.blocks().all(|view| !view.is_deleted()));
+        assert!(document.blocks().count() * 4 < blocks_before);
+        assert!(document.blocks().count() <= document.len());
+        assert_eq!(document.check_integrity(), vec![]);
+    }
+
+    #[test]
+    fn keeps_editing_and_syncing_after_a_checkpoint() {
+        let (mut first, mut second) = churned();
+
+        first.checkpoint();
+        second.checkpoint();
+
+        first.insert(3, 1000);
+        first.remove(0);
+        second.push(2000);
+        first.sync_with(&mut second).unwrap();
+
+        assert_eq!(first.to_vec(), second.to_vec());
+        assert_eq!(
+            first
+                .to_vec()
+                .iter()
+                .filter(|value| **value >= 1000)
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn rejects_stale_updates_with_pruned_history() {
+        let (mut document, peer) = churned();
+        let stale = Update::from_document(&peer);
+
+        document.checkpoint();
+        let before = document.snapshot();
+
+        assert!(matches!(
+            stale.apply(&mut document),
+            Err(ApplyError::PrunedHistory { .. })
+        ));
+        assert_eq!(document.snapshot(), before);
+    }
+
+    #[test]
+    fn applies_updates_from_clients_pruned_past_zero() {
+        let mut first = Document::with_client_id(1);
+        let mut second: Document<u32> = Document::with_client_id(2);
+        let mut third: Document<u32> = Document::with_client_id(3);
+
+        for value in 0..500 {
+            first.push(value);
+        }
+        first.remove_range(0, 500);
+        first.push(500);
+        first.sync_with(&mut second).unwrap();
+        first.sync_with(&mut third).unwrap();
+
+        for document in [&mut first, &mut second, &mut third] {
+            document.checkpoint();
+        }
+        assert_eq!(first.blocks().next().unwrap().id(), BlockId::new(1, 500));
+
+        first.insert(0, 501);
+        first.push(502);
+
+        let since = second.state_vector();
+        let update = Update::from_document_since(&first, since.as_ref());
+
+        third.apply_encoded(&update.encode().unwrap()).unwrap();
+        update.apply(&mut second).unwrap();
+
+        assert_eq!(second.to_vec(), vec![501, 500, 502]);
+        assert_eq!(third.to_vec(), second.to_vec());
+        assert_eq!(second.state_vector().clock(1), Clock::new(503));
+        assert_eq!(second.check_integrity(), vec![]);
+    }
+
+    #[test]
+    fn peers_behind_a_checkpoint_resync_from_a_snapshot() {
+        let (mut document, _) = churned();
+        let mut behind: Document<u32> = Document::with_client_id(3);
+
+        document.checkpoint();
+        document.push(4000);
+
+        let update = Update::from_document(&document);
+
+        assert!(matches!(
+            update.apply(&mut behind),
+            Err(ApplyError::PrunedHistory { client_id: 1, .. })
+        ));
+
+        behind.resync(document.snapshot());
+        behind.push(5000);
+        Update::from_document(&behind).apply(&mut document).unwrap();
+
+        assert_eq!(behind.client_id(), 3);
+        assert_eq!(behind.to_vec(), document.to_vec());
+        assert_eq!(document.to_vec().last(), Some(&5000));
+    }
+}
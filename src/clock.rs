@@ -0,0 +1,251 @@
+//! Clocks, which number the elements each client inserts.
+
+use bincode::{Decode, Encode};
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
+/// A position in one client's history: the number of elements the client had inserted before an
+/// element, which with the client's id identifies the element. A client's history may start
+/// after zero, e.g. once it has been pruned by [`Document::checkpoint`](crate::Document::checkpoint).
+///
+/// Clocks also order writes to a document's map, registers, marks and moves as Lamport clocks,
+/// through [`Clock::tick`] and [`Clock::observe`].
+///
+/// Arithmetic is explicit and never wraps: [`Clock::advance`] panics on overflow, so clocks from
+/// other replicas go through [`Clock::checked_advance`] until they have been checked.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, Default, Encode, Decode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct Clock(u64);
+
+impl Clock {
+    pub const ZERO: Clock = Clock(0);
+    pub const MAX: Clock = Clock(u64::MAX);
+
+    pub const fn new(clock: u64) -> Clock {
+        Clock(clock)
+    }
+
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+
+    /// The clock `length` elements later.
+    ///
+    /// # Panics
+    ///
+    /// Panics if that would be past [`Clock::MAX`].
+    pub fn advance(self, length: u64) -> Clock {
+        self.checked_advance(length)
+            .unwrap_or_else(|| panic!("clock {} can't advance by {}", self, length))
+    }
+
+    /// The clock `length` elements later, or `None` if that would be past [`Clock::MAX`].
+    pub fn checked_advance(self, length: u64) -> Option<Clock> {
+        self.0.checked_add(length).map(Clock)
+    }
+
+    /// The clock `length` elements later, or [`Clock::MAX`] if that would be past it.
+    pub fn saturating_advance(self, length: u64) -> Clock {
+        Clock(self.0.saturating_add(length))
+    }
+
+    /// The clock `length` elements earlier.
+    ///
+    /// # Panics
+    ///
+    /// Panics if that would be before zero.
+    pub fn rewind(self, length: u64) -> Clock {
+        self.checked_rewind(length)
+            .unwrap_or_else(|| panic!("clock {} can't rewind by {}", self, length))
+    }
+
+    /// The clock `length` elements earlier, or `None` if that would be before zero.
+    pub fn checked_rewind(self, length: u64) -> Option<Clock> {
+        self.0.checked_sub(length).map(Clock)
+    }
+
+    /// The number of elements from `start` to this clock.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `start` is later than this clock.
+    pub fn offset_from(self, start: Clock) -> u64 {
+        self.0
+            .checked_sub(start.0)
+            .unwrap_or_else(|| panic!("clock {} is before {}", self, start))
+    }
+
+    /// How far into `range` this clock is, or `None` if it's outside it.
+    pub fn offset_in(self, range: ClockRange) -> Option<u64> {
+        range.contains(self).then(|| self.0 - range.start.0)
+    }
+
+    /// Moves a Lamport clock on for a new local event, returning the event's clock, which is
+    /// later than every clock this one has seen.
+    pub fn tick(&mut self) -> Clock {
+        *self = self.advance(1);
+        *self
+    }
+
+    /// Takes in the clock of an event from another replica, so this clock's later ticks are
+    /// ordered after it.
+    pub fn observe(&mut self, seen: Clock) {
+        *self = (*self).max(seen);
+    }
+}
+
+impl From<u64> for Clock {
+    fn from(clock: u64) -> Self {
+        Clock(clock)
+    }
+}
+
+impl From<Clock> for u64 {
+    fn from(clock: Clock) -> Self {
+        clock.0
+    }
+}
+
+impl Display for Clock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The clocks from `start` up to but not including `end`, of a single client.
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy, Default, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClockRange {
+    pub start: Clock,
+    pub end: Clock,
+}
+
+impl ClockRange {
+    pub fn new(start: Clock, end: Clock) -> ClockRange {
+        ClockRange { start, end }
+    }
+
+    /// The `length` clocks from `start`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if they would run past [`Clock::MAX`].
+    pub fn starting_at(start: Clock, length: u64) -> ClockRange {
+        ClockRange::new(start, start.advance(length))
+    }
+
+    /// The number of clocks in the range.
+    pub fn len(&self) -> u64 {
+        self.end.0.saturating_sub(self.start.0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+
+    pub fn contains(&self, clock: Clock) -> bool {
+        self.start <= clock && clock < self.end
+    }
+
+    /// The clocks in both ranges, or `None` if they don't overlap.
+    pub fn intersect(&self, other: ClockRange) -> Option<ClockRange> {
+        let range = ClockRange::new(self.start.max(other.start), self.end.min(other.end));
+
+        (!range.is_empty()).then_some(range)
+    }
+
+    /// Every clock in the range, in order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = Clock> {
+        (self.start.0..self.end.0).map(Clock)
+    }
+}
+
+impl From<Range<u64>> for ClockRange {
+    fn from(range: Range<u64>) -> Self {
+        ClockRange::new(Clock(range.start), Clock(range.end))
+    }
+}
+
+impl From<Range<Clock>> for ClockRange {
+    fn from(range: Range<Clock>) -> Self {
+        ClockRange::new(range.start, range.end)
+    }
+}
+
+impl Display for ClockRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{Clock, ClockRange};
+    use bincode::{config, encode_to_vec};
+
+    #[test]
+    fn advances_without_wrapping() {
+        let clock = Clock::new(u64::MAX - 2);
+
+        assert_eq!(clock.advance(2), Clock::MAX);
+        assert_eq!(clock.checked_advance(3), None);
+        assert_eq!(clock.saturating_advance(3), Clock::MAX);
+        assert_eq!(Clock::new(1).checked_rewind(2), None);
+        assert_eq!(Clock::new(3).rewind(1), Clock::new(2));
+        assert_eq!(Clock::new(5).offset_from(Clock::new(2)), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't advance")]
+    fn advancing_past_the_largest_clock_panics() {
+        Clock::MAX.advance(1);
+    }
+
+    #[test]
+    fn lamport_clocks_tick_past_everything_observed() {
+        let mut clock = Clock::ZERO;
+
+        assert_eq!(clock.tick(), Clock::new(1));
+        clock.observe(Clock::new(7));
+        clock.observe(Clock::new(3));
+        assert_eq!(clock.tick(), Clock::new(8));
+    }
+
+    #[test]
+    fn ranges_locate_clocks() {
+        let range = ClockRange::starting_at(Clock::new(500), 3);
+
+        assert_eq!(range, ClockRange::from(500..503));
+        assert_eq!(Clock::new(502).offset_in(range), Some(2));
+        assert_eq!(Clock::new(503).offset_in(range), None);
+        assert_eq!(Clock::new(499).offset_in(range), None);
+        assert_eq!(
+            range.intersect(ClockRange::from(502..600)),
+            Some(ClockRange::from(502..503))
+        );
+        assert_eq!(range.intersect(ClockRange::from(503..600)), None);
+        assert_eq!(
+            range.iter().collect::<Vec<_>>(),
+            vec![Clock::new(500), Clock::new(501), Clock::new(502)]
+        );
+    }
+
+    #[test]
+    fn encodes_like_the_integers_it_wraps() {
+        let configuration = config::standard();
+
+        assert_eq!(
+            encode_to_vec(Clock::new(300), configuration).unwrap(),
+            encode_to_vec(300u64, configuration).unwrap()
+        );
+        assert_eq!(
+            encode_to_vec(ClockRange::from(5..300), configuration).unwrap(),
+            encode_to_vec(5u64..300, configuration).unwrap()
+        );
+    }
+}
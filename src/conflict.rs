@@ -0,0 +1,164 @@
+use crate::document::BlockId;
+use std::cmp::Ordering;
+use std::fmt;
+
+/// One of two blocks inserted concurrently at the same position, as seen by a
+/// [`ConflictResolver`].
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct InsertCandidate {
+    /// The id of the block's first element.
+    pub id: BlockId,
+    /// The priority of the document which inserted the block, set with
+    /// [`Document::with_priority`](crate::Document::with_priority).
+    pub priority: u8,
+}
+
+/// Decides the order of blocks inserted concurrently at the same position.
+///
+/// Every replica of a document must use the same resolver, and `cmp` must be a total order which
+/// only depends on the candidates, or replicas will order concurrent inserts differently.
+pub trait ConflictResolver: fmt::Debug + Send + Sync {
+    /// Returns [`Ordering::Less`] if `a` belongs before `b`.
+    fn cmp(&self, a: &InsertCandidate, b: &InsertCandidate) -> Ordering;
+}
+
+/// Orders concurrent inserts by client id, smallest first. This is the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ClientIdOrder;
+
+impl ConflictResolver for ClientIdOrder {
+    fn cmp(&self, a: &InsertCandidate, b: &InsertCandidate) -> Ordering {
+        a.id.client_id.cmp(&b.id.client_id)
+    }
+}
+
+/// Orders concurrent inserts by priority, highest first, falling back to [`ClientIdOrder`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PriorityOrder;
+
+impl ConflictResolver for PriorityOrder {
+    fn cmp(&self, a: &InsertCandidate, b: &InsertCandidate) -> Ordering {
+        b.priority
+            .cmp(&a.priority)
+            .then_with(|| ClientIdOrder.cmp(a, b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conflict::{ClientIdOrder, ConflictResolver, InsertCandidate, PriorityOrder};
+    use crate::document::BlockId;
+    use crate::{Document, Update};
+    use std::cmp::Ordering;
+
+    fn candidate(client_id: u64, priority: u8) -> InsertCandidate {
+        InsertCandidate {
+            id: BlockId::new(client_id, 0),
+            priority,
+        }
+    }
+
+    /// Two replicas of "ad" which concurrently insert "b" and "c" between the two elements, then
+    /// exchange encoded updates.
+    fn concurrent_inserts(mut low: Document<char>, mut high: Document<char>) -> (String, String) {
+        low.extend(['a', 'd']);
+        Update::from_document(&low).apply(&mut high).unwrap();
+
+        low.insert(1, 'b');
+        high.insert(1, 'c');
+
+        let to_high = Update::from_document_since(&low, high.state_vector().as_ref());
+        let to_low = high.encode_full_update().unwrap();
+
+        Update::decode(&to_high.encode().unwrap())
+            .unwrap()
+            .apply(&mut high)
+            .unwrap();
+        Update::decode(&to_low).unwrap().apply(&mut low).unwrap();
+
+        (low.iter().collect(), high.iter().collect())
+    }
+
+    #[test]
+    fn higher_priority_inserts_come_first_whatever_the_client_ids() {
+        let (low, high) = concurrent_inserts(
+            Document::with_client_id(1).with_conflict_resolver(PriorityOrder),
+            Document::with_client_id(9)
+                .with_conflict_resolver(PriorityOrder)
+                .with_priority(5),
+        );
+
+        assert_eq!(low, "acbd");
+        assert_eq!(high, "acbd");
+
+        let (low, high) = concurrent_inserts(
+            Document::with_client_id(1),
+            Document::with_client_id(9).with_priority(5),
+        );
+
+        assert_eq!(low, "abcd");
+        assert_eq!(high, "abcd");
+    }
+
+    #[test]
+    fn priority_order_falls_back_to_client_ids() {
+        assert_eq!(
+            PriorityOrder.cmp(&candidate(9, 1), &candidate(1, 0)),
+            Ordering::Less
+        );
+        assert_eq!(
+            PriorityOrder.cmp(&candidate(9, 1), &candidate(1, 1)),
+            Ordering::Greater
+        );
+        assert_eq!(
+            ClientIdOrder.cmp(&candidate(9, 1), &candidate(1, 0)),
+            Ordering::Greater
+        );
+    }
+
+    #[test]
+    fn concurrent_prepends_converge_whatever_the_delivery_order() {
+        let mut base = Document::with_client_id(9);
+        base.push('x');
+
+        // Each client prepends twice, so the second insert's right origin is its first
+        let updates: Vec<Update<char>> = [(1, ['a', 'b']), (2, ['c', 'd']), (3, ['e', 'f'])]
+            .into_iter()
+            .map(|(client_id, values)| {
+                let mut document = Document::with_client_id(client_id);
+                Update::from_document(&base).apply(&mut document).unwrap();
+
+                for value in values {
+                    document.insert(0, value);
+                }
+
+                Update::from_document_since(&document, base.state_vector().as_ref())
+            })
+            .collect();
+
+        let orders = [
+            [0, 1, 2],
+            [0, 2, 1],
+            [1, 0, 2],
+            [1, 2, 0],
+            [2, 0, 1],
+            [2, 1, 0],
+        ];
+
+        for order in orders {
+            let mut document = Document::with_client_id(10);
+            Update::from_document(&base).apply(&mut document).unwrap();
+
+            for index in order {
+                updates[index].clone().apply(&mut document).unwrap();
+            }
+
+            assert_eq!(
+                document.iter().collect::<String>(),
+                "badcfex",
+                "delivered in order {:?}",
+                order
+            );
+        }
+    }
+}
@@ -1,45 +1,637 @@
 use crate::block::{Block, Item};
-use crate::document::{BlockId, ClientId, Clock};
+use crate::block_range::BlockRange;
+use crate::clock::{Clock, ClockRange};
+use crate::document::{BlockId, ClientId};
+use crate::observer::DeleteEvent;
+use crate::storage::BlockStorage;
 use crate::Document;
+use bincode::de::Decoder;
+use bincode::enc::Encoder;
+use bincode::error::{DecodeError, EncodeError};
 use bincode::{Decode, Encode};
 
-#[derive(Eq, PartialEq, Clone, Encode, Decode, Debug)]
+/// A set of deleted elements, kept as coalesced ranges ordered by client and then clock.
+#[derive(Eq, PartialEq, Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "DeleteSetParts", into = "DeleteSetParts")
+)]
 pub struct DeleteSet {
+    ranges: Vec<BlockRange>,
+}
+
+/// The layout delete sets are encoded in: each client's ranges as `(clock, length)` pairs.
+#[derive(Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct DeleteSetParts {
     deletes: Vec<(ClientId, Vec<(Clock, usize)>)>,
 }
 
+impl From<&DeleteSet> for DeleteSetParts {
+    fn from(delete_set: &DeleteSet) -> Self {
+        let mut deletes: Vec<(ClientId, Vec<(Clock, usize)>)> = vec![];
+
+        for range in &delete_set.ranges {
+            let run = (range.clocks.start, range.len() as usize);
+
+            match deletes.last_mut() {
+                Some((client_id, runs)) if *client_id == range.client_id => runs.push(run),
+                _ => deletes.push((range.client_id, vec![run])),
+            }
+        }
+
+        DeleteSetParts { deletes }
+    }
+}
+
+impl From<DeleteSet> for DeleteSetParts {
+    fn from(delete_set: DeleteSet) -> Self {
+        DeleteSetParts::from(&delete_set)
+    }
+}
+
+impl From<DeleteSetParts> for DeleteSet {
+    fn from(parts: DeleteSetParts) -> Self {
+        // Ranges off the wire may be in any order, overlap, or run past the largest clock
+        let mut ranges: Vec<BlockRange> = parts
+            .deletes
+            .into_iter()
+            .flat_map(|(client_id, runs)| {
+                runs.into_iter().map(move |(clock, length)| {
+                    BlockRange::new(client_id, clock..clock.saturating_advance(length as u64))
+                })
+            })
+            .filter(|range| !range.is_empty())
+            .collect();
+        ranges.sort_by_key(|range| range.start());
+
+        DeleteSet {
+            ranges: coalesce(ranges),
+        }
+    }
+}
+
+impl Encode for DeleteSet {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        DeleteSetParts::from(self).encode(encoder)
+    }
+}
+
+impl Decode for DeleteSet {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Ok(DeleteSetParts::decode(decoder)?.into())
+    }
+}
+
 impl DeleteSet {
-    pub fn apply<T: Item>(&self, document: &mut Document<T>) {
-        for (client, clocks) in &self.deletes {
-            for (clock, length) in clocks {
-                for i in *clock..(*clock + (*length as Clock)) {
-                    document.store[BlockId::new(*client, i)].delete();
+    /// Tombstones every element covered by this delete set. Clocks the document hasn't seen yet
+    /// are skipped.
+    pub fn apply<T: Item, S: BlockStorage<T>>(&self, document: &mut Document<T, S>) {
+        document.observed(false, |document| self.apply_unobserved(document));
+    }
+
+    pub(crate) fn apply_unobserved<T: Item, S: BlockStorage<T>>(
+        &self,
+        document: &mut Document<T, S>,
+    ) {
+        // Indices are taken before anything is deleted, so they all refer to the same document
+        let mut tombstoned = vec![];
+
+        if document.observers.observes_deletes() {
+            for range in self.iter() {
+                tombstoned.extend(
+                    document
+                        .store
+                        .live_elements_in(range.client_id, range.clocks),
+                );
+            }
+
+            tombstoned.sort_unstable_by_key(|(_, index)| *index);
+        }
+
+        // Each root only has some of a client's clocks, and skips the rest
+        for range in self.iter() {
+            document.store.delete_clocks(range.client_id, range.clocks);
+
+            for store in document.roots.values_mut() {
+                store.delete_clocks(range.client_id, range.clocks);
+            }
+        }
+
+        document.observers.deleted(DeleteEvent {
+            elements: tombstoned,
+        });
+    }
+
+    /// Every deleted range, ordered by client and then clock.
+    pub fn iter(&self) -> impl Iterator<Item = &BlockRange> + '_ {
+        self.ranges.iter()
+    }
+
+    /// Whether the element `id` is deleted.
+    pub fn contains(&self, id: BlockId) -> bool {
+        let ranges = self.client_ranges(id.client_id);
+        let after = ranges.partition_point(|range| range.clocks.start <= id.clock);
+
+        after > 0 && ranges[after - 1].contains(id)
+    }
+
+    /// Whether any of `clocks` from `client_id` are deleted.
+    pub(crate) fn overlaps(&self, client_id: ClientId, clocks: ClockRange) -> bool {
+        let ranges = self.client_ranges(client_id);
+        let after = ranges.partition_point(|range| range.clocks.start < clocks.end);
+
+        after > 0 && ranges[after - 1].clocks.end > clocks.start
+    }
+
+    /// The parts of `clocks` from `client_id` which are in the delete set, ordered by clock.
+    pub(crate) fn within(
+        &self,
+        client_id: ClientId,
+        clocks: ClockRange,
+    ) -> impl Iterator<Item = ClockRange> + '_ {
+        self.client_ranges(client_id)
+            .iter()
+            .filter_map(move |range| range.clocks.intersect(clocks))
+    }
+
+    /// Whether the delete set has no deletions at all.
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// The deletions in this delete set which aren't in `other`, e.g. to send a replica only the
+    /// deletions it hasn't seen.
+    pub fn diff(&self, other: &DeleteSet) -> DeleteSet {
+        let mut diff = DeleteSet::empty();
+
+        for range in self.iter() {
+            let mut start = range.clocks.start;
+
+            for seen in other.client_ranges(range.client_id) {
+                if range.intersect(seen).is_none() {
+                    continue;
                 }
+
+                diff.insert(range.client_id, start..seen.clocks.start.max(start));
+                start = seen.clocks.end;
             }
+
+            diff.insert(range.client_id, start..range.clocks.end.max(start));
         }
+
+        diff
     }
 
-    pub fn from<T: Item>(document: &Document<T>) -> DeleteSet {
-        DeleteSet {
-            deletes: document
-                .store
-                .data
-                .iter()
-                .map(|(client_id, block)| {
-                    (
-                        *client_id,
-                        block
-                            .iter()
-                            .filter(|Block { deleted, .. }| *deleted)
-                            .map(|Block { deleted, id, .. }| (*id, 1))
-                            .collect(),
-                    )
-                })
-                .collect(),
+    /// The deletions in exactly one of the two delete sets, e.g. to find where two replicas
+    /// disagree about what has been deleted.
+    pub fn symmetric_difference(&self, other: &DeleteSet) -> DeleteSet {
+        self.diff(other).merge(other.diff(self))
+    }
+
+    /// Splits the delete set into the deletions of clocks below `known(client_id)` and the rest.
+    pub(crate) fn split_known(&self, known: impl Fn(ClientId) -> Clock) -> (DeleteSet, DeleteSet) {
+        let mut below = DeleteSet::empty();
+        let mut above = DeleteSet::empty();
+
+        for range in self.iter() {
+            let split = known(range.client_id).clamp(range.clocks.start, range.clocks.end);
+
+            below.insert(range.client_id, range.clocks.start..split);
+            above.insert(range.client_id, split..range.clocks.end);
         }
+
+        (below, above)
+    }
+
+    /// The ranges deleted from `client_id`, ordered by clock.
+    fn client_ranges(&self, client_id: ClientId) -> &[BlockRange] {
+        let start = self
+            .ranges
+            .partition_point(|range| range.client_id < client_id);
+        let end = self
+            .ranges
+            .partition_point(|range| range.client_id <= client_id);
+
+        &self.ranges[start..end]
+    }
+
+    /// Every deletion in `document`, ordered by client so the same deletions always encode to the
+    /// same bytes. Clients with nothing deleted are left out.
+    pub fn from<T: Item, S: BlockStorage<T>>(document: &Document<T, S>) -> DeleteSet {
+        let mut clients = document.store.data.clients();
+        clients.sort_unstable();
+
+        let ranges = clients
+            .into_iter()
+            .flat_map(|client_id| {
+                coalesce(
+                    document
+                        .store
+                        .data
+                        .iter(client_id)
+                        .filter(|Block { deleted, .. }| *deleted)
+                        .map(|Block { id, length, .. }| {
+                            BlockRange::new(client_id, ClockRange::starting_at(*id, *length as u64))
+                        }),
+                )
+            })
+            .collect();
+
+        let mut delete_set = DeleteSet { ranges };
+
+        for store in document.roots.values() {
+            for client_id in store.data.clients() {
+                for block in store.data.iter(client_id).filter(|block| block.deleted) {
+                    delete_set.insert(
+                        client_id,
+                        ClockRange::starting_at(block.id, block.length as u64),
+                    );
+                }
+            }
+        }
+
+        delete_set
     }
 
     pub fn empty() -> DeleteSet {
-        DeleteSet { deletes: vec![] }
+        DeleteSet { ranges: vec![] }
+    }
+
+    /// Marks `clocks` from `client_id` as deleted, merging with any overlapping or adjacent ranges.
+    pub fn insert(&mut self, client_id: ClientId, clocks: impl Into<ClockRange>) {
+        let clocks = clocks.into();
+
+        if clocks.is_empty() {
+            return;
+        }
+
+        let mut inserted = BlockRange::new(client_id, clocks);
+
+        // A client's ranges are ordered and apart, so those the new one touches are consecutive
+        let first = self
+            .ranges
+            .partition_point(|range| range.start() < inserted.start());
+        let first = match first.checked_sub(1) {
+            Some(previous) if self.ranges[previous].union_adjacent(&inserted).is_some() => previous,
+            _ => first,
+        };
+        let mut last = first;
+
+        while let Some(union) = self
+            .ranges
+            .get(last)
+            .and_then(|range| range.union_adjacent(&inserted))
+        {
+            inserted = union;
+            last += 1;
+        }
+
+        self.ranges.splice(first..last, [inserted]);
+    }
+
+    /// Combines two delete sets into one covering the deletions of both.
+    pub fn merge(mut self, other: DeleteSet) -> DeleteSet {
+        for range in other.ranges {
+            self.insert(range.client_id, range.clocks);
+        }
+
+        self
+    }
+}
+
+impl IntoIterator for DeleteSet {
+    type Item = BlockRange;
+    type IntoIter = std::vec::IntoIter<BlockRange>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ranges.into_iter()
+    }
+}
+
+/// Merges overlapping and adjacent ranges from a sequence ordered by client and then clock.
+fn coalesce(ranges: impl IntoIterator<Item = BlockRange>) -> Vec<BlockRange> {
+    let mut coalesced: Vec<BlockRange> = vec![];
+
+    for range in ranges {
+        match coalesced.last_mut() {
+            Some(last) => match last.union_adjacent(&range) {
+                Some(union) => *last = union,
+                None => coalesced.push(range),
+            },
+            None => coalesced.push(range),
+        }
+    }
+
+    coalesced
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block_range::BlockRange;
+    use crate::clock::{Clock, ClockRange};
+    use crate::delete_set::{DeleteSet, DeleteSetParts};
+    use crate::document::{BlockId, ClientId};
+    use crate::{Document, Update};
+    use bincode::{config, decode_from_slice, encode_to_vec};
+
+    /// Delete sets in the layout they're encoded in, with each run's clock as a plain integer.
+    fn parts(deletes: Vec<(ClientId, Vec<(u64, usize)>)>) -> DeleteSetParts {
+        let deletes = deletes
+            .into_iter()
+            .map(|(client_id, runs)| {
+                let runs = runs
+                    .into_iter()
+                    .map(|(clock, length)| (Clock::new(clock), length))
+                    .collect();
+
+                (client_id, runs)
+            })
+            .collect();
+
+        DeleteSetParts { deletes }
+    }
+
+    #[test]
+    fn coalesces_consecutive_deletes() {
+        let mut doc = Document::with_client_id(1);
+        for value in ["a", "b", "c", "d"] {
+            doc.push(value.to_owned());
+        }
+
+        doc.remove(0);
+        doc.remove(0);
+        doc.remove(0);
+
+        let delete_set = DeleteSet::from(&doc);
+        assert_eq!(delete_set.ranges, vec![BlockRange::new(1, 0..3)]);
+
+        let uncoalesced = parts(vec![(1, vec![(0, 1), (1, 1), (2, 1)])]);
+
+        let configuration = config::standard();
+        let coalesced_size = encode_to_vec(&delete_set, configuration).unwrap().len();
+        let uncoalesced_size = encode_to_vec(&uncoalesced, configuration).unwrap().len();
+
+        assert!(coalesced_size < uncoalesced_size);
+    }
+
+    #[test]
+    fn keeps_separate_ranges_apart() {
+        let mut doc = Document::with_client_id(1);
+        for value in ["a", "b", "c", "d"] {
+            doc.push(value.to_owned());
+        }
+
+        doc.remove(2);
+        doc.remove(0);
+
+        assert_eq!(
+            DeleteSet::from(&doc).ranges,
+            vec![BlockRange::new(1, 0..1), BlockRange::new(1, 2..3)]
+        );
+    }
+
+    #[test]
+    fn insert_merges_overlapping_and_adjacent_ranges() {
+        let mut delete_set = DeleteSet::empty();
+        delete_set.insert(1, 4..6);
+        delete_set.insert(1, 0..2);
+        delete_set.insert(1, 2..3);
+        delete_set.insert(1, 5..8);
+        delete_set.insert(2, 0..1);
+        delete_set.insert(2, 3..3);
+
+        assert_eq!(
+            delete_set.ranges,
+            vec![
+                BlockRange::new(1, 0..3),
+                BlockRange::new(1, 4..8),
+                BlockRange::new(2, 0..1)
+            ]
+        );
+    }
+
+    #[test]
+    fn lists_only_clients_with_deletions_in_client_order() {
+        let mut doc = Document::with_client_id(1);
+        for client_id in [9, 4, 7, 2] {
+            let mut other = Document::with_client_id(client_id);
+            other.push(client_id);
+            Update::from_document(&other).apply(&mut doc).unwrap();
+        }
+
+        for value in [9, 2, 7] {
+            let index = doc.iter().position(|other| *other == value).unwrap();
+            doc.remove(index);
+        }
+
+        assert_eq!(
+            DeleteSet::from(&doc).ranges,
+            vec![
+                BlockRange::new(2, 0..1),
+                BlockRange::new(7, 0..1),
+                BlockRange::new(9, 0..1)
+            ]
+        );
+
+        let mut inserted = DeleteSet::empty();
+        inserted.insert(9, 0..1);
+        inserted.insert(2, 0..1);
+        inserted.insert(7, 0..1);
+
+        assert_eq!(inserted, DeleteSet::from(&doc));
+    }
+
+    #[test]
+    fn merge_combines_delete_sets() {
+        let mut left = DeleteSet::empty();
+        left.insert(1, 0..2);
+        left.insert(2, 5..6);
+
+        let mut right = DeleteSet::empty();
+        right.insert(1, 2..4);
+        right.insert(3, 0..1);
+
+        assert_eq!(
+            left.merge(right).ranges,
+            vec![
+                BlockRange::new(1, 0..4),
+                BlockRange::new(2, 5..6),
+                BlockRange::new(3, 0..1)
+            ]
+        );
+    }
+
+    #[test]
+    fn contains_deleted_elements() {
+        let mut delete_set = DeleteSet::empty();
+        assert!(delete_set.is_empty());
+
+        delete_set.insert(1, 2..4);
+        delete_set.insert(1, 6..7);
+
+        assert!(!delete_set.is_empty());
+        assert_eq!(
+            (0..8)
+                .filter(|clock| delete_set.contains(BlockId::new(1, *clock)))
+                .collect::<Vec<_>>(),
+            vec![2, 3, 6]
+        );
+        assert!(!delete_set.contains(BlockId::new(2, 2)));
+    }
+
+    #[test]
+    fn diff_keeps_only_unseen_ranges() {
+        let mut ours = DeleteSet::empty();
+        ours.insert(1, 0..10);
+        ours.insert(2, 0..2);
+        ours.insert(3, 5..6);
+
+        let mut theirs = DeleteSet::empty();
+        theirs.insert(1, 2..4);
+        theirs.insert(1, 6..7);
+        theirs.insert(1, 9..12);
+        theirs.insert(2, 0..2);
+
+        assert_eq!(
+            ours.diff(&theirs).ranges,
+            vec![
+                BlockRange::new(1, 0..2),
+                BlockRange::new(1, 4..6),
+                BlockRange::new(1, 7..9),
+                BlockRange::new(3, 5..6)
+            ]
+        );
+        assert_eq!(theirs.diff(&ours).ranges, vec![BlockRange::new(1, 10..12)]);
+        assert!(ours.diff(&ours).is_empty());
+    }
+
+    #[test]
+    fn symmetric_difference_finds_where_replicas_disagree() {
+        let mut first = Document::with_client_id(1);
+        for value in 0..10 {
+            first.push(value);
+        }
+
+        let mut second = Document::with_client_id(2);
+        first.sync_with(&mut second).unwrap();
+        second.push(10);
+        first.sync_with(&mut second).unwrap();
+
+        // Both delete some of the same elements, but neither hears of the other's deletions
+        first.remove_range(2, 4);
+        second.remove_range(3, 2);
+        second.remove(second.len() - 1);
+
+        let ours = DeleteSet::from(&first);
+        let theirs = DeleteSet::from(&second);
+
+        assert_eq!(
+            ours.symmetric_difference(&theirs).ranges,
+            vec![
+                BlockRange::new(1, 2..3),
+                BlockRange::new(1, 5..6),
+                BlockRange::new(2, 0..1)
+            ]
+        );
+        assert_eq!(
+            theirs.symmetric_difference(&ours),
+            ours.symmetric_difference(&theirs)
+        );
+        assert_eq!(
+            first.deleted_ranges().collect::<Vec<_>>(),
+            vec![(1, ClockRange::from(2..6))]
+        );
+        assert_eq!(first.is_deleted(BlockId::new(1, 5)), Some(true));
+        assert_eq!(second.is_deleted(BlockId::new(1, 5)), Some(false));
+        assert_eq!(first.is_deleted(BlockId::new(3, 0)), None);
+
+        first.sync_with(&mut second).unwrap();
+
+        assert!(DeleteSet::from(&first)
+            .symmetric_difference(&DeleteSet::from(&second))
+            .is_empty());
+    }
+
+    #[test]
+    fn insert_bridges_the_ranges_it_touches() {
+        let mut delete_set = DeleteSet::empty();
+        delete_set.insert(1, 0..2);
+        delete_set.insert(1, 4..5);
+        delete_set.insert(1, 7..9);
+        delete_set.insert(2, 0..1);
+        delete_set.insert(1, 1..8);
+
+        assert_eq!(
+            delete_set.ranges,
+            vec![BlockRange::new(1, 0..9), BlockRange::new(2, 0..1)]
+        );
+    }
+
+    #[test]
+    fn decodes_the_original_layout_into_coalesced_ranges() {
+        let configuration = config::standard();
+        let layout = parts(vec![
+            (2, vec![(3, 1), (0, 2)]),
+            (1, vec![(4, 2), (0, 0), (5, 3)]),
+            (2, vec![(2, 1)]),
+            (3, vec![(u64::MAX - 1, 5)]),
+        ]);
+        let encoded = encode_to_vec(&layout, configuration).unwrap();
+        let (decoded, _): (DeleteSet, usize) = decode_from_slice(&encoded, configuration).unwrap();
+
+        assert_eq!(
+            decoded.ranges,
+            vec![
+                BlockRange::new(1, 4..8),
+                BlockRange::new(2, 0..4),
+                BlockRange::new(3, u64::MAX - 1..u64::MAX)
+            ]
+        );
+
+        let mut delete_set = DeleteSet::empty();
+        delete_set.insert(1, 0..2);
+        delete_set.insert(1, 5..6);
+        delete_set.insert(4, 1..3);
+
+        assert_eq!(
+            encode_to_vec(&delete_set, configuration).unwrap(),
+            encode_to_vec(
+                parts(vec![(1, vec![(0, 2), (5, 1)]), (4, vec![(1, 2)])]),
+                configuration
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn round_trips_through_json() {
+        let mut delete_set = DeleteSet::empty();
+        delete_set.insert(1, 0..2);
+        delete_set.insert(2, 5..6);
+
+        let encoded = serde_json::to_string(&delete_set).unwrap();
+
+        assert_eq!(encoded, r#"{"deletes":[[1,[[0,2]]],[2,[[5,1]]]]}"#);
+        assert_eq!(
+            serde_json::from_str::<DeleteSet>(&encoded).unwrap(),
+            delete_set
+        );
+    }
+
+    #[test]
+    fn applies_ranges_inside_merged_blocks() {
+        let mut doc = Document::with_client_id(1);
+        for value in ["a", "b", "c", "d"] {
+            doc.push(value.to_owned());
+        }
+
+        let mut delete_set = DeleteSet::empty();
+        delete_set.insert(1, 1..3);
+        delete_set.apply(&mut doc);
+
+        assert_eq!(doc.store.iter_values().collect::<Vec<_>>(), vec!["a", "d"]);
     }
 }
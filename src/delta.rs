@@ -0,0 +1,219 @@
+use crate::block::Item;
+use crate::document::BlockId;
+use crate::moves::moved_elements;
+use crate::storage::BlockStorage;
+use crate::store::Store;
+use bincode::{Decode, Encode};
+use std::collections::HashSet;
+
+/// A single step of a [`Delta`].
+#[derive(Eq, PartialEq, Clone, Debug, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DeltaOp<T> {
+    /// Skips over this many unchanged elements.
+    Retain(usize),
+    /// Inserts these values at the current position.
+    Insert(Vec<T>),
+    /// Removes this many elements from the current position.
+    Delete(usize),
+}
+
+/// What a change did to a document, as a walk over the document from the start which retains,
+/// inserts and deletes elements in turn.
+///
+/// Positions are those of the document the change was applied to, so a plain copy of the
+/// document's values can be kept in sync with [`Delta::apply_to_vec`]. Trailing retains are
+/// omitted.
+#[derive(Eq, PartialEq, Clone, Debug, Default, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Delta<T> {
+    ops: Vec<DeltaOp<T>>,
+}
+
+impl<T: Item> Delta<T> {
+    /// Compares the live elements `before` a change with those in `store` afterwards.
+    ///
+    /// Elements never move, so the elements in both appear in the same order, and everything else
+    /// was either inserted or deleted in between them.
+    pub(crate) fn between<S: BlockStorage<T>>(before: &[BlockId], store: &Store<T, S>) -> Delta<T> {
+        let after: Vec<(BlockId, &T)> = store.iter_live_elements().collect();
+        let after_order: Vec<BlockId> = after.iter().map(|(id, _)| *id).collect();
+
+        // Moved elements are reported as removed from where they were and inserted where they are
+        let moved = moved_elements(before, &after_order);
+        let before_ids: HashSet<BlockId> = before
+            .iter()
+            .filter(|id| !moved.contains(id))
+            .copied()
+            .collect();
+        let after_ids: HashSet<BlockId> = after_order
+            .into_iter()
+            .filter(|id| !moved.contains(id))
+            .collect();
+
+        let mut delta = Delta { ops: vec![] };
+        let mut before = before.iter().peekable();
+        let mut after = after.into_iter().peekable();
+
+        loop {
+            if before.next_if(|id| !after_ids.contains(id)).is_some() {
+                delta.delete();
+            } else if let Some((_, value)) = after.next_if(|(id, _)| !before_ids.contains(id)) {
+                delta.insert(value.clone());
+            } else if before.next().is_some() && after.next().is_some() {
+                delta.retain();
+            } else {
+                break;
+            }
+        }
+
+        if let Some(DeltaOp::Retain(_)) = delta.ops.last() {
+            delta.ops.pop();
+        }
+
+        delta
+    }
+
+    fn retain(&mut self) {
+        match self.ops.last_mut() {
+            Some(DeltaOp::Retain(count)) => *count += 1,
+            _ => self.ops.push(DeltaOp::Retain(1)),
+        }
+    }
+
+    fn insert(&mut self, value: T) {
+        match self.ops.last_mut() {
+            Some(DeltaOp::Insert(values)) => values.push(value),
+            _ => self.ops.push(DeltaOp::Insert(vec![value])),
+        }
+    }
+
+    fn delete(&mut self) {
+        match self.ops.last_mut() {
+            Some(DeltaOp::Delete(count)) => *count += 1,
+            _ => self.ops.push(DeltaOp::Delete(1)),
+        }
+    }
+
+    pub fn ops(&self) -> &[DeltaOp<T>] {
+        &self.ops
+    }
+
+    /// Whether the change left the document as it was.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Applies the change to `values`, which should hold the document's values from before it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `values` is too short for the delta.
+    pub fn apply_to_vec(&self, values: &mut Vec<T>) {
+        let mut index = 0;
+
+        for op in &self.ops {
+            match op {
+                DeltaOp::Retain(count) => index += count,
+                DeltaOp::Insert(inserted) => {
+                    values.splice(index..index, inserted.iter().cloned());
+                    index += inserted.len();
+                }
+                DeltaOp::Delete(count) => {
+                    values.drain(index..index + count);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::delta::{Delta, DeltaOp};
+    use crate::{Document, Update};
+    use bincode::{config, decode_from_slice, encode_to_vec};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn describes_an_update_in_the_receivers_positions() {
+        let mut doc1 = Document::with_client_id(1);
+        for value in "abcd".chars() {
+            doc1.push(value);
+        }
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+
+        // doc2 has inserted before doc1's edits, which shifts where they land
+        doc2.insert(0, 'x');
+        doc1.remove(1);
+        doc1.insert(2, 'y');
+
+        let delta = Update::from_document_since(&doc1, doc2.state_vector().as_ref())
+            .apply_with_delta(&mut doc2)
+            .unwrap();
+
+        assert_eq!(
+            delta.ops(),
+            [
+                DeltaOp::Retain(2),
+                DeltaOp::Delete(1),
+                DeltaOp::Retain(1),
+                DeltaOp::Insert(vec!['y']),
+            ]
+        );
+
+        let mut values = vec!['x', 'a', 'b', 'c', 'd'];
+        delta.apply_to_vec(&mut values);
+        assert_eq!(values, doc2.to_vec());
+
+        let configuration = config::standard();
+        let encoded = encode_to_vec(&delta, configuration).unwrap();
+        let (decoded, _): (Delta<char>, usize) =
+            decode_from_slice(&encoded, configuration).unwrap();
+        assert_eq!(decoded, delta);
+    }
+
+    #[test]
+    fn deltas_keep_a_shadow_vec_in_sync() {
+        for seed in 0..10 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut docs: Vec<Document<usize>> = (1..=3).map(Document::with_client_id).collect();
+            let mut shadows: Vec<Vec<usize>> = vec![vec![]; docs.len()];
+
+            for step in 0..100 {
+                let doc = rng.gen_range(0, docs.len());
+                let len = docs[doc].len();
+
+                match rng.gen_range(0, 3) {
+                    0 if len > 0 => {
+                        let index = rng.gen_range(0, len);
+                        let count = rng.gen_range(1, (len - index).min(3) + 1);
+                        docs[doc].remove_range(index, count);
+                        shadows[doc].drain(index..index + count);
+                    }
+                    1 => {
+                        let from = rng.gen_range(0, docs.len());
+                        let update = Update::from_document_since(
+                            &docs[from],
+                            docs[doc].state_vector().as_ref(),
+                        );
+
+                        update
+                            .apply_with_delta(&mut docs[doc])
+                            .unwrap()
+                            .apply_to_vec(&mut shadows[doc]);
+                    }
+                    _ => {
+                        let index = rng.gen_range(0, len + 1);
+                        docs[doc].insert(index, step);
+                        shadows[doc].insert(index, step);
+                    }
+                }
+
+                assert_eq!(shadows[doc], docs[doc].to_vec(), "seed {}", seed);
+            }
+        }
+    }
+}
@@ -1,45 +1,2786 @@
-use crate::store::Store;
-use std::collections::HashMap;
+use crate::store::{BlockView, Store};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Range;
 
-use crate::block::Item;
-use bincode::{Decode, Encode};
+use crate::block::{Block, Item};
+use crate::block_range::BlockRange;
+use crate::checkpoint::Checkpoint;
+use crate::clock::{Clock, ClockRange};
+use crate::conflict::ConflictResolver;
+use crate::delete_set::DeleteSet;
+use crate::encoding::{self, DecodeError, EncodeError};
+use crate::integrity::{self, IntegrityIssue, RepairReport};
+use crate::limits::Limits;
+use crate::map::MapStore;
+use crate::marks::{Mark, MarkStore};
+#[cfg(feature = "metrics")]
+use crate::metrics::{MetricEvent, MetricsSink};
+use crate::moves::Move;
+use crate::observer::{ChangeEvent, DeleteEvent, Observers, SubscriptionId};
+use crate::register::RegisterStore;
+use crate::root::RootHandle;
+use crate::snapshot::{RootSnapshot, Snapshot};
+use crate::stats::DocumentStats;
+use crate::storage::{BlockStorage, VecStorage};
+use crate::transaction::Transaction;
+use crate::undo::LocalChange;
+use crate::update::{self, ApplyEncodedError, ApplyError, Update, UpdateRef};
+use crate::version::{DeleteLog, DeletedValues, VersionView};
+use bincode::{config, encode_to_vec, Decode, Encode};
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::mem;
+use std::num::ParseIntError;
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
 
-pub type Clock = u64;
 pub type ClientId = u64;
 
 pub type ClockVector = HashMap<ClientId, Clock>;
 
-#[derive(Debug, Eq, PartialEq, Clone, Copy, Encode, Decode)]
+/// How many clocks a replica has seen from each client. Exchanging these is the first step of
+/// syncing two replicas: each side then sends the other whatever its vector is missing.
+#[derive(Debug, Eq, PartialEq, Clone, Default, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StateVector(ClockVector);
+
+impl StateVector {
+    pub fn new() -> StateVector {
+        StateVector(HashMap::new())
+    }
+
+    /// The number of clocks seen from `client_id`.
+    pub fn clock(&self, client_id: ClientId) -> Clock {
+        self.0.get(&client_id).copied().unwrap_or_default()
+    }
+
+    /// Whether this vector has seen everything `other` has.
+    pub fn dominates(&self, other: &StateVector) -> bool {
+        other
+            .0
+            .iter()
+            .all(|(client_id, clock)| self.clock(*client_id) >= *clock)
+    }
+
+    /// The clock ranges seen by this vector but not by `other`, ordered by client.
+    pub fn diff(&self, other: &StateVector) -> Vec<BlockRange> {
+        let mut missing: Vec<BlockRange> = self
+            .0
+            .iter()
+            .filter(|(client_id, clock)| other.clock(**client_id) < **clock)
+            .map(|(client_id, clock)| BlockRange::new(*client_id, other.clock(*client_id)..*clock))
+            .collect();
+
+        missing.sort_by_key(|range| range.client_id);
+        missing
+    }
+}
+
+impl From<ClockVector> for StateVector {
+    fn from(clocks: ClockVector) -> Self {
+        StateVector(clocks)
+    }
+}
+
+impl AsRef<ClockVector> for StateVector {
+    fn as_ref(&self) -> &ClockVector {
+        &self.0
+    }
+}
+
+/// The id of a single element: the client which inserted it and that client's clock at the
+/// time. Ids are ordered by client and then clock, and display as `client@clock`.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BlockId {
     pub client_id: ClientId,
     pub clock: Clock,
 }
 
 impl BlockId {
-    pub fn new(client_id: ClientId, clock: Clock) -> BlockId {
-        BlockId { client_id, clock }
+    pub fn new(client_id: ClientId, clock: impl Into<Clock>) -> BlockId {
+        BlockId {
+            client_id,
+            clock: clock.into(),
+        }
+    }
+
+    /// The ids of the `length` elements from the same client starting at this one.
+    pub fn range(self, length: usize) -> impl Iterator<Item = BlockId> {
+        BlockRange::new(
+            self.client_id,
+            ClockRange::starting_at(self.clock, length as u64),
+        )
+        .ids()
+    }
+}
+
+impl Display for BlockId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.client_id, self.clock)
+    }
+}
+
+impl FromStr for BlockId {
+    type Err = ParseBlockIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (client_id, clock) = s
+            .split_once('@')
+            .ok_or(ParseBlockIdError::MissingSeparator)?;
+
+        Ok(BlockId::new(
+            client_id
+                .parse()
+                .map_err(ParseBlockIdError::InvalidClientId)?,
+            clock
+                .parse::<u64>()
+                .map_err(ParseBlockIdError::InvalidClock)?,
+        ))
+    }
+}
+
+/// Why a string couldn't be parsed as a [`BlockId`].
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum ParseBlockIdError {
+    /// There was no `@` between the client id and the clock.
+    MissingSeparator,
+    InvalidClientId(ParseIntError),
+    InvalidClock(ParseIntError),
+}
+
+impl Display for ParseBlockIdError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseBlockIdError::MissingSeparator => write!(f, "expected `client@clock`"),
+            ParseBlockIdError::InvalidClientId(error) => write!(f, "invalid client id: {}", error),
+            ParseBlockIdError::InvalidClock(error) => write!(f, "invalid clock: {}", error),
+        }
+    }
+}
+
+impl Error for ParseBlockIdError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseBlockIdError::MissingSeparator => None,
+            ParseBlockIdError::InvalidClientId(error) => Some(error),
+            ParseBlockIdError::InvalidClock(error) => Some(error),
+        }
     }
 }
 
+/// What a [`Document`] does when an update contains blocks under its own client id which it
+/// didn't generate, i.e. when another replica has been given the same id.
+///
+/// Either way the update is rejected with [`ApplyError::ClientIdConflict`], as its blocks can't be
+/// told apart from the document's own. Conflicts are found by comparing clocks and origins, so a
+/// replica which made exactly the same shape of edits with different values goes unnoticed.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub enum ClientIdPolicy {
+    /// Only reject the update.
+    #[default]
+    Reject,
+    /// Also switch to a fresh random client id, so the document's later edits can't collide.
+    /// Blocks made before the switch stay under the old id.
+    Reassign,
+}
+
 #[derive(Debug)]
-pub struct Document<T: Item> {
+pub struct Document<T: Item, S: BlockStorage<T> = VecStorage<T>> {
     clock: Clock,
     pub(crate) client_id: ClientId,
+    client_id_policy: ClientIdPolicy,
+    pub(crate) limits: Limits,
     pub(crate) clients: ClockVector,
-    pub(crate) store: Store<T>,
+    pub(crate) store: Store<T, S>,
+    /// The document's named roots, each a sequence of its own.
+    pub(crate) roots: BTreeMap<String, Store<T, S>>,
+    pub(crate) map: MapStore<T>,
+    pub(crate) marks: MarkStore,
+    /// Who made each deletion, for [`Document::checkout`].
+    pub(crate) deletions: DeleteLog,
+    /// Where the document's history was last pruned by [`Document::checkpoint`], if ever.
+    pub(crate) checkpoint: Checkpoint,
+    /// Elements removed with [`Document::censor`], here or by another replica.
+    pub(crate) censored: DeleteSet,
+    unsent: UnsentChanges,
+    pending: Vec<Update<T>>,
+    pub(crate) observers: Observers<T>,
+    /// Local changes, recorded only while an undo manager is tracking the document.
+    history: Option<Vec<(Instant, LocalChange<T>)>>,
+}
+
+/// Local changes which no update from [`Document::take_local_update`] or [`Document::transact`]
+/// has carried yet.
+#[derive(Debug)]
+pub(crate) struct UnsentChanges {
+    pub(crate) deletes: DeleteSet,
+    /// Elements whose values were replaced.
+    pub(crate) written: DeleteSet,
+    /// Map keys which were set or removed.
+    pub(crate) map_keys: BTreeSet<String>,
+    pub(crate) marks: Vec<Mark>,
+    pub(crate) moves: Vec<Move>,
+}
+
+impl UnsentChanges {
+    pub(crate) fn new() -> UnsentChanges {
+        UnsentChanges {
+            deletes: DeleteSet::empty(),
+            written: DeleteSet::empty(),
+            map_keys: BTreeSet::new(),
+            marks: vec![],
+            moves: vec![],
+        }
+    }
+}
+
+/// The result of [`Document::apply_or_queue`].
+#[derive(Eq, PartialEq, Debug)]
+pub enum QueueOutcome {
+    /// The update was applied, along with `drained` previously queued updates it unblocked.
+    Applied { drained: usize },
+    /// The update is missing dependencies and has been queued until they arrive.
+    Queued,
 }
 
 impl<T: Item> Document<T> {
-    pub(crate) fn with_client_id(client_id: u64) -> Document<T> {
+    /// Reopens a document from a [`Snapshot`], continuing as the same client.
+    ///
+    /// The snapshot isn't trusted to be consistent: if it's corrupt, the document can't be read
+    /// until it's been [repaired](Document::repair), so check snapshots from untrusted storage
+    /// with [`Document::check_integrity`].
+    pub fn restore(snapshot: Snapshot<T>) -> Document<T> {
+        let Snapshot {
+            client_id,
+            clock,
+            clients,
+            start,
+            end,
+            blocks,
+            map,
+            marks,
+            moves,
+            deletions,
+            deleted_values,
+            roots,
+            registers,
+            checkpoint,
+            censored,
+        } = snapshot;
+
+        let mut store =
+            Store::from_parts(client_id, start, end, blocks.into_iter().collect(), moves);
+        store.deleted_values = deleted_values.map(DeletedValues::from_parts);
+        store.registers = RegisterStore::from_writes(registers);
+        store.min_local_clock = clock;
+
+        let roots = roots
+            .into_iter()
+            .map(|root| {
+                let mut store = Store::from_parts(
+                    client_id,
+                    root.start,
+                    root.end,
+                    root.blocks.into_iter().collect(),
+                    vec![],
+                );
+                store.min_local_clock = clock;
+
+                (root.name, store)
+            })
+            .collect();
+
         Document {
-            clock: 0,
+            clock,
             client_id,
-            clients: HashMap::new(),
-            store: Store::new(client_id),
+            client_id_policy: ClientIdPolicy::default(),
+            limits: Limits::default(),
+            clients: clients.into_iter().collect(),
+            store,
+            roots,
+            map: MapStore::from_entries(map),
+            marks: MarkStore::from_marks(marks),
+            deletions: DeleteLog::from_deletions(deletions),
+            checkpoint: Checkpoint::from_clocks(checkpoint),
+            censored,
+            unsent: UnsentChanges::new(),
+            pending: vec![],
+            observers: Observers::new(),
+            history: None,
         }
     }
 
+    /// Replaces the document's contents with `snapshot`'s, taken by another replica, while
+    /// going on editing as this document's own client. This is how a replica which has fallen
+    /// behind another's [checkpoint](Document::checkpoint) catches up, as the history it's
+    /// missing can no longer be sent as an update.
+    ///
+    /// Anything the document has which the snapshot doesn't is lost, its own edits included, and
+    /// so are queued updates. Observers, limits and other settings are kept.
+    pub fn resync(&mut self, snapshot: Snapshot<T>) {
+        let mut restored = Document::restore(snapshot);
+
+        for store in restored.stores_mut() {
+            store.client_id = self.client_id;
+            store.priority = self.store.priority;
+            store.resolver = self.store.resolver.clone();
+            #[cfg(feature = "metrics")]
+            {
+                store.metrics = self.store.metrics.clone();
+            }
+        }
+
+        if self.store.deleted_values.is_some() && restored.store.deleted_values.is_none() {
+            restored.store.deleted_values = Some(DeletedValues::new());
+        }
+
+        restored.client_id = self.client_id;
+        restored.client_id_policy = self.client_id_policy;
+        restored.limits = self.limits.clone();
+        restored.observers = mem::replace(&mut self.observers, Observers::new());
+        restored.history = self.history.take().map(|_| vec![]);
+        restored.advance_local_clock();
+
+        *self = restored;
+    }
+
+    /// Creates an empty document which edits as `client_id`, e.g. one allocated by a server.
+    ///
+    /// Every replica of a document must have a different client id. See [`ClientIdPolicy`] for
+    /// what happens when they don't.
+    pub fn with_client_id(client_id: ClientId) -> Document<T> {
+        Document::with_storage(client_id, VecStorage::new())
+    }
+
+    /// Creates an empty document with a random client id.
     pub fn new() -> Document<T> {
         Document::with_client_id(rand::random())
     }
 }
+
+impl<T: Item, S: BlockStorage<T>> Document<T, S> {
+    /// Captures the document's full state. Clients are ordered by id, so equal documents produce
+    /// equal snapshots.
+    pub fn snapshot(&self) -> Snapshot<T> {
+        let mut clients: Vec<(ClientId, Clock)> = self
+            .clients
+            .iter()
+            .map(|(client_id, clock)| (*client_id, *clock))
+            .collect();
+        clients.sort_by_key(|(client_id, _)| *client_id);
+
+        let (start, end, mut blocks) = self.store.to_parts();
+        blocks.sort_by_key(|(client_id, _)| *client_id);
+
+        Snapshot {
+            client_id: self.client_id,
+            clock: self.clock,
+            clients,
+            start,
+            end,
+            blocks,
+            map: self.map.entries(),
+            marks: self.marks.marks(),
+            moves: self.store.moves.moves(),
+            deletions: self.deletions.deletions(),
+            deleted_values: self
+                .store
+                .deleted_values
+                .as_ref()
+                .map(DeletedValues::to_parts),
+            roots: self
+                .roots
+                .iter()
+                .map(|(name, store)| {
+                    let (start, end, mut blocks) = store.to_parts();
+                    blocks.sort_by_key(|(client_id, _)| *client_id);
+
+                    RootSnapshot {
+                        name: name.clone(),
+                        start,
+                        end,
+                        blocks,
+                    }
+                })
+                .collect(),
+            registers: self.store.registers.writes(),
+            checkpoint: self.checkpoint.clocks(),
+            censored: self.censored.clone(),
+        }
+    }
+
+    /// Creates an empty document which edits as `client_id` and keeps its blocks in `storage`,
+    /// e.g. a [`PagedArena`](crate::PagedArena) for a very large document. `storage` should be
+    /// empty.
+    pub fn with_storage(client_id: ClientId, storage: S) -> Document<T, S> {
+        let mut store = Store::new(client_id);
+        store.data = storage;
+
+        Document {
+            clock: Clock::ZERO,
+            client_id,
+            client_id_policy: ClientIdPolicy::default(),
+            limits: Limits::default(),
+            clients: HashMap::new(),
+            store,
+            roots: BTreeMap::new(),
+            map: MapStore::new(),
+            marks: MarkStore::new(),
+            deletions: DeleteLog::new(),
+            checkpoint: Checkpoint::default(),
+            censored: DeleteSet::empty(),
+            unsent: UnsentChanges::new(),
+            pending: vec![],
+            observers: Observers::new(),
+            history: None,
+        }
+    }
+
+    /// Sets what happens when another replica turns out to share this document's client id.
+    /// Defaults to [`ClientIdPolicy::Reject`].
+    pub fn with_client_id_policy(mut self, policy: ClientIdPolicy) -> Document<T, S> {
+        self.client_id_policy = policy;
+        self
+    }
+
+    /// Sets the bounds on what remote updates may add to the document. Defaults to
+    /// [`Limits::default`].
+    pub fn with_limits(mut self, limits: Limits) -> Document<T, S> {
+        self.limits = limits;
+        self
+    }
+
+    /// Reserves room for about `blocks` blocks of local edits up front. Bulk loads with
+    /// [`Extend`] or [`Document::insert_values`] only take one block each, so this mostly helps
+    /// documents built from many scattered edits.
+    pub fn with_capacity_hint(mut self, blocks: usize) -> Document<T, S> {
+        self.store.reserve(blocks);
+        self
+    }
+
+    /// Sets how concurrent inserts at the same position are ordered. Every replica of a document
+    /// must use the same resolver. Defaults to [`ClientIdOrder`](crate::ClientIdOrder).
+    pub fn with_conflict_resolver(
+        mut self,
+        resolver: impl ConflictResolver + 'static,
+    ) -> Document<T, S> {
+        let resolver: Arc<dyn ConflictResolver> = Arc::new(resolver);
+
+        for store in self.stores_mut() {
+            store.resolver = resolver.clone();
+        }
+
+        self
+    }
+
+    /// Sets the priority of this document's inserts, which is sent along with them for the
+    /// [`ConflictResolver`] to compare. Defaults to 0.
+    pub fn with_priority(mut self, priority: u8) -> Document<T, S> {
+        for store in self.stores_mut() {
+            store.priority = priority;
+        }
+
+        self
+    }
+
+    /// Sends this document's [`MetricEvent`](crate::MetricEvent)s to `sink`. Defaults to a
+    /// [`NoopSink`](crate::NoopSink).
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics_sink(mut self, sink: Arc<dyn MetricsSink>) -> Document<T, S> {
+        for store in self.stores_mut() {
+            store.metrics = sink.clone();
+        }
+
+        self
+    }
+
+    /// Keeps the values of elements deleted from now on, so [`Document::checkout`] can show
+    /// versions from before they were deleted. They're kept for good, so this costs as much
+    /// memory as never deleting anything.
+    pub fn with_retained_deletions(mut self) -> Document<T, S> {
+        self.store
+            .deleted_values
+            .get_or_insert_with(DeletedValues::new);
+        self
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Decodes an update encoded by [`Update::encode`], rejecting it if it's larger than the
+    /// document's limits allow.
+    pub fn decode_update(&self, bytes: &[u8]) -> Result<Update<T>, DecodeError>
+    where
+        T: Decode,
+    {
+        Update::decode_with_limits(bytes, &self.limits)
+    }
+
+    /// The id this document's edits are made as.
+    pub fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    /// Handles an update which claimed this document's client id, according to its policy.
+    pub(crate) fn client_id_conflict(&mut self) {
+        if self.client_id_policy == ClientIdPolicy::Reassign {
+            let mut client_id = rand::random();
+
+            while self.clients.contains_key(&client_id)
+                || self.stores().any(|store| store.data.contains(client_id))
+            {
+                client_id = rand::random();
+            }
+
+            self.client_id = client_id;
+            self.clock = Clock::ZERO;
+
+            for store in self.stores_mut() {
+                store.client_id = client_id;
+                store.min_local_clock = Clock::ZERO;
+            }
+        }
+    }
+
+    /// Inserts `value` at `index`, shifting all elements after it to the right. Returns the id of
+    /// the new element, which [`Document::index_of_id`] finds wherever later edits move it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index > len`, matching [`Vec::insert`].
+    pub fn insert(&mut self, index: usize, value: T) -> BlockId {
+        let len = self.len();
+
+        assert!(
+            index <= len,
+            "insertion index (is {}) should be <= len (is {})",
+            index,
+            len
+        );
+
+        self.observed(true, |document| {
+            document.record_insert();
+            let id = document.store.insert(index, value);
+            document.advance_local_clock();
+
+            id
+        })
+    }
+
+    /// Inserts `values` at `index` in order, shifting all elements after them to the right. The
+    /// values are stored together as a single block, which is much cheaper than inserting them one
+    /// at a time anywhere but the end of the document.
+    ///
+    /// Returns the id of the first value's element, or `None` if there were no values. The rest
+    /// follow it, so the element of the `n`th value is at offset `n` from it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index > len`.
+    pub fn insert_values(
+        &mut self,
+        index: usize,
+        values: impl IntoIterator<Item = T>,
+    ) -> Option<BlockId> {
+        let len = self.len();
+
+        assert!(
+            index <= len,
+            "insertion index (is {}) should be <= len (is {})",
+            index,
+            len
+        );
+
+        let values: Vec<T> = values.into_iter().collect();
+
+        self.observed(true, |document| {
+            let first = BlockId::new(document.client_id, document.store.next_local_clock());
+
+            if document.history.is_some() && !values.is_empty() {
+                let ids = ClockRange::starting_at(first.clock, values.len() as u64)
+                    .iter()
+                    .map(|clock| BlockId::new(document.client_id, clock))
+                    .collect();
+
+                document.record(LocalChange::Inserted(ids));
+            }
+
+            let inserted = (!values.is_empty()).then_some(first);
+            document.store.insert_values(index, values);
+            document.advance_local_clock();
+
+            inserted
+        })
+    }
+
+    /// Appends `value` to the end of the document, returning the id of its element.
+    pub fn push(&mut self, value: T) -> BlockId {
+        self.observed(true, |document| {
+            document.record_insert();
+            let id = document.store.append(value);
+            document.advance_local_clock();
+
+            id
+        })
+    }
+
+    /// Replaces the value of the element at `index` in place, keeping its position, unlike
+    /// removing it and inserting `value` instead.
+    ///
+    /// Concurrent writes to the same element are resolved like map writes: the latest wins,
+    /// with concurrent writes ordered the same way on every replica. Writes to an element which
+    /// is concurrently removed are dropped. Like the map, replaced values are sent with every
+    /// update built from the whole document, and local updates carry those replaced since the
+    /// last one was taken. Observers are told of the element being removed and inserted again
+    /// with `value`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn set(&mut self, index: usize, value: T) {
+        let len = self.len();
+
+        assert!(
+            index < len,
+            "set index (is {}) should be < len (is {})",
+            index,
+            len
+        );
+
+        self.observed(true, |document| {
+            let element = document.store.element_at(index).unwrap();
+
+            if document.history.is_some() {
+                let previous = document.get(index).unwrap().clone();
+                document.record(LocalChange::Replaced(element, previous));
+            }
+
+            document.store.set(index, value);
+            document
+                .unsent
+                .written
+                .insert(element.client_id, ClockRange::starting_at(element.clock, 1));
+        });
+    }
+
+    /// Removes the element at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds, matching [`Vec::remove`].
+    pub fn remove(&mut self, index: usize) {
+        let len = self.len();
+
+        assert!(
+            index < len,
+            "removal index (is {}) should be < len (is {})",
+            index,
+            len
+        );
+
+        self.delete_range(index, 1);
+    }
+
+    /// Removes `count` elements starting at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range `index..index + count` is out of bounds.
+    pub fn remove_range(&mut self, index: usize, count: usize) {
+        self.delete_range(index, count);
+    }
+
+    /// Moves the elements at `src` so they're shown before the element which was at
+    /// `dest_index`, or at the end if `dest_index` is the length. Moving a range to either of its
+    /// own ends does nothing.
+    ///
+    /// The elements keep their identity, so concurrent edits to them are kept and shown at the
+    /// new position, as are elements later inserted inside the range. Where moves overlap,
+    /// each element is shown where the last move to take it in sends it: later moves win, and
+    /// concurrent ones are ordered by client id, the larger winning, so every replica settles on
+    /// the same place. Like marks, moves are sent with every update built from the whole
+    /// document.
+    ///
+    /// Once a document has moves, every edit works out the order of the whole document again,
+    /// so edits cost time proportional to its size.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `src` or `dest_index` is out of bounds, or `src` ends before it starts.
+    pub fn move_range(&mut self, src: Range<usize>, dest_index: usize) {
+        let len = self.len();
+
+        assert!(
+            src.start <= src.end && src.end <= len,
+            "move range {:?} out of range for document of length {}",
+            src,
+            len
+        );
+        assert!(
+            dest_index <= len,
+            "move destination (is {}) should be <= len (is {})",
+            dest_index,
+            len
+        );
+
+        if src.is_empty() || (src.start..=src.end).contains(&dest_index) {
+            return;
+        }
+
+        self.observed(true, |document| {
+            let moves = document.store.move_range(src, dest_index);
+            document.unsent.moves.extend(moves);
+            document.advance_local_clock();
+        });
+    }
+
+    /// Runs `f` against a [`Transaction`], returning an update containing exactly the blocks and
+    /// deletions it made.
+    ///
+    /// The update depends on the document's state before the transaction, so it can be sent on its
+    /// own to any replica which had seen that state. Transactions borrow the document mutably, so
+    /// they can't be nested or overlap.
+    ///
+    /// Local observers see the whole transaction as a single change.
+    pub fn transact(&mut self, f: impl FnOnce(&mut Transaction<T, S>)) -> Update<T> {
+        let before = self.state_vector();
+        let earlier = mem::replace(&mut self.unsent, UnsentChanges::new());
+
+        self.observed(true, |document| f(&mut Transaction::new(document)));
+
+        // Changes from before the transaction stay unsent, but its own go in its update
+        let changes = mem::replace(&mut self.unsent, earlier);
+
+        Update::from_local_changes(self, before.as_ref(), changes)
+    }
+
+    /// An update of the blocks created since `since` and the deletions, replaced values, map
+    /// writes, marks and moves made locally since the last update was taken, by this or
+    /// [`Document::transact`], which are then no longer pending.
+    ///
+    /// Unlike [`Update::from_document_since`], the update doesn't resend every deletion, map entry,
+    /// mark and move in the document, so taking one after each batch of edits keeps updates small.
+    /// Changes applied from other replicas aren't included.
+    pub fn take_local_update(&mut self, since: &ClockVector) -> Update<T> {
+        let changes = mem::replace(&mut self.unsent, UnsentChanges::new());
+
+        Update::from_local_changes(self, since, changes)
+    }
+
+    /// Registers `callback` to be told about every change applied from a remote [`Update`] or
+    /// [`DeleteSet`], once per apply, after the change has been fully integrated.
+    ///
+    /// Callbacks must be `Send + Sync` so documents can be shared between threads.
+    pub fn observe(
+        &mut self,
+        callback: impl FnMut(&ChangeEvent<T>) + Send + Sync + 'static,
+    ) -> SubscriptionId {
+        self.observers.add(false, callback)
+    }
+
+    /// Like [`Document::observe`], but `callback` is also told about local edits.
+    pub fn observe_local(
+        &mut self,
+        callback: impl FnMut(&ChangeEvent<T>) + Send + Sync + 'static,
+    ) -> SubscriptionId {
+        self.observers.add(true, callback)
+    }
+
+    /// Registers `callback` to be told about elements tombstoned by deletions from other
+    /// replicas, whether applied as a [`DeleteSet`] or as part of an [`Update`], with the
+    /// indices they had just before. Elements which were already deleted aren't reported.
+    pub fn observe_deletes(
+        &mut self,
+        callback: impl FnMut(&DeleteEvent) + Send + Sync + 'static,
+    ) -> SubscriptionId {
+        self.observers.add_deletes(callback)
+    }
+
+    /// Removes a callback registered with [`Document::observe`], [`Document::observe_local`] or
+    /// [`Document::observe_deletes`], returning whether it was still registered.
+    pub fn unobserve(&mut self, id: SubscriptionId) -> bool {
+        self.observers.remove(id)
+    }
+
+    /// Runs `f`, reporting whatever it changes to observers as a single event. Changes nested
+    /// inside `f` are folded into the same event.
+    pub(crate) fn observed<R>(&mut self, local: bool, f: impl FnOnce(&mut Self) -> R) -> R {
+        let before = self.observers.begin(&self.store, local);
+
+        match panic::catch_unwind(AssertUnwindSafe(|| f(self))) {
+            Ok(result) => {
+                self.observers.end(before, &self.store, local);
+
+                result
+            }
+            Err(payload) => {
+                self.observers.abort(before);
+
+                panic::resume_unwind(payload)
+            }
+        }
+    }
+
+    pub(crate) fn delete_range(&mut self, index: usize, count: usize) -> DeleteSet {
+        let len = self.len();
+
+        assert!(
+            index.checked_add(count).is_some_and(|end| end <= len),
+            "removing {} values from {} is out of range for document of length {}",
+            count,
+            index,
+            len
+        );
+
+        self.observed(true, |document| {
+            if document.history.is_some() {
+                let removed = document
+                    .store
+                    .iter_live_elements()
+                    .skip(index)
+                    .take(count)
+                    .map(|(id, value)| (id, value.clone()))
+                    .collect();
+                document.record(LocalChange::Deleted(removed));
+            }
+
+            let deleted = document.store.delete_range(index, count);
+            document.record_deletion(&deleted);
+
+            deleted
+        })
+    }
+
+    /// Deletes whichever of the elements in `ids` are still live, returning the ranges deleted.
+    pub(crate) fn delete_elements(&mut self, ids: &DeleteSet) -> DeleteSet {
+        self.observed(true, |document| {
+            if document.history.is_some() {
+                let removed = document
+                    .store
+                    .iter_live_elements()
+                    .filter(|(id, _)| ids.contains(*id))
+                    .map(|(id, value)| (id, value.clone()))
+                    .collect();
+                document.record(LocalChange::Deleted(removed));
+            }
+
+            let deleted = ids.iter().fold(DeleteSet::empty(), |deleted, range| {
+                deleted.merge(document.store.delete_clocks(range.client_id, range.clocks))
+            });
+            document.record_deletion(&deleted);
+
+            deleted
+        })
+    }
+
+    /// Records that this replica made the deletions in `deleted`, as of its current clock, and
+    /// leaves them pending for the next update taken.
+    pub(crate) fn record_deletion(&mut self, deleted: &DeleteSet) {
+        let by = BlockId::new(self.client_id, self.clock);
+
+        self.deletions.record(deleted, by);
+        self.unsent.deletes =
+            mem::replace(&mut self.unsent.deletes, DeleteSet::empty()).merge(deleted.clone());
+    }
+
+    /// Merges runs of deleted blocks which every peer has already seen into single tombstones,
+    /// dropping their per-block bookkeeping.
+    ///
+    /// `safe_vector` must be dominated by the state of every peer, e.g. the minimum of their state
+    /// vectors, otherwise peers which haven't seen the deletions yet may be sent a different block
+    /// layout than they expect.
+    pub fn gc(&mut self, safe_vector: &ClockVector) {
+        for store in self.stores_mut() {
+            store.gc(safe_vector);
+        }
+
+        #[cfg(feature = "metrics")]
+        self.store.metrics.record(MetricEvent::Tombstones {
+            count: self
+                .stores()
+                .flat_map(|store| store.iter_blocks())
+                .filter(|view| view.is_deleted())
+                .count(),
+        });
+    }
+
+    /// Checks the document's blocks for corruption, e.g. after restoring a snapshot read from
+    /// damaged storage. Returns nothing if the document is consistent.
+    pub fn check_integrity(&self) -> Vec<IntegrityIssue> {
+        let issues = if self.roots.is_empty() {
+            integrity::check(&self.store)
+        } else {
+            integrity::check_roots(self.stores())
+        };
+
+        // Clocks skipped before the checkpoint were tombstones it pruned
+        issues
+            .into_iter()
+            .filter(|issue| {
+                !matches!(
+                    issue,
+                    IntegrityIssue::ClockGap { client_id, actual, .. }
+                        if *actual <= self.checkpoint.clock(*client_id)
+                )
+            })
+            .collect()
+    }
+
+    /// Rebuilds a corrupt document by integrating its blocks again from their origins. Blocks
+    /// which can't be recovered are dropped, along with the marks and moves anchored to them,
+    /// and reported as lost.
+    ///
+    /// If any of the document's own elements are lost, sync with a peer which has them before
+    /// editing, or new elements will reuse their ids.
+    pub fn repair(&mut self) -> RepairReport {
+        let issues = self.check_integrity();
+
+        if issues.is_empty() {
+            return RepairReport::default();
+        }
+
+        let shared_clocks = !self.roots.is_empty() || !self.checkpoint.is_empty();
+        let mut lost = vec![];
+
+        for store in self.stores_mut() {
+            let (rebuilt, store_lost) = integrity::rebuild(store, shared_clocks);
+            *store = rebuilt;
+            lost.extend(store_lost);
+        }
+
+        lost.sort_by_key(|(client_id, clocks)| (*client_id, clocks.start));
+
+        let marks = self
+            .marks
+            .marks()
+            .into_iter()
+            .filter(|mark| {
+                self.find_block(mark.start).is_some() && self.find_block(mark.end).is_some()
+            })
+            .collect();
+        self.marks = MarkStore::from_marks(marks);
+        self.advance_local_clock();
+
+        RepairReport { issues, lost }
+    }
+
+    /// Starts recording local changes for an [`UndoManager`](crate::UndoManager).
+    pub(crate) fn start_recording(&mut self) {
+        self.history.get_or_insert_with(Vec::new);
+    }
+
+    /// Takes the local changes recorded since the last call.
+    pub(crate) fn take_recorded(&mut self) -> Vec<(Instant, LocalChange<T>)> {
+        self.history.as_mut().map(mem::take).unwrap_or_default()
+    }
+
+    fn record_insert(&mut self) {
+        let id = BlockId::new(self.client_id, self.store.next_local_clock());
+
+        self.record(LocalChange::Inserted(vec![id]));
+    }
+
+    fn record(&mut self, change: LocalChange<T>) {
+        if let Some(history) = &mut self.history {
+            if !matches!(&change, LocalChange::Deleted(removed) if removed.is_empty()) {
+                history.push((Instant::now(), change));
+            }
+        }
+    }
+
+    /// The number of live (non-deleted) elements in the document.
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The element at `index`, or `None` if `index` is out of bounds.
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.store.values_from(index).next()
+    }
+
+    /// Where the live element at `index` is, as the id of the block holding it and its offset in
+    /// that block, or `None` if `index` is out of bounds.
+    ///
+    /// Blocks are split as other elements are inserted among them, but an element keeps its
+    /// clock, so [`Document::index_of_id`] finds it from the pair for as long as it's live.
+    pub fn id_at(&self, index: usize) -> Option<(BlockId, usize)> {
+        self.store.find_live(index)
+    }
+
+    /// The index of the live element `offset` elements after `id`, e.g. one returned by
+    /// [`Document::insert`] with an offset of 0, or a pair from [`Document::id_at`]. Returns `None`
+    /// if the element has been deleted or hasn't reached this document.
+    pub fn index_of_id(&self, id: BlockId, offset: usize) -> Option<usize> {
+        let clock = id.clock.checked_advance(offset as u64)?;
+
+        match self
+            .store
+            .live_elements_before(BlockId::new(id.client_id, clock))?
+        {
+            (index, true) => Some(index),
+            (_, false) => None,
+        }
+    }
+
+    /// The elements in `range`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is out of bounds, matching slice indexing.
+    pub fn slice(&self, range: Range<usize>) -> impl Iterator<Item = &T> {
+        let len = self.len();
+
+        assert!(
+            range.start <= range.end && range.end <= len,
+            "range {:?} out of range for document of length {}",
+            range,
+            len
+        );
+
+        self.store.values_from(range.start).take(range.len())
+    }
+
+    /// Every element in the document, in order. Iterating from the back walks the document from
+    /// its end rather than collecting it first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &T> {
+        self.store.iter_values()
+    }
+
+    /// Every element in the document with the id of the block holding it and its offset within
+    /// that block. The element's own id, which stays the same when blocks are split, is the
+    /// block's id with the offset added to its clock.
+    pub fn iter_with_ids(&self) -> impl DoubleEndedIterator<Item = (BlockId, usize, &T)> {
+        self.store
+            .iter_blocks()
+            .filter(|block| !block.is_deleted())
+            .flat_map(move |block| {
+                let id = block.id();
+
+                block
+                    .values()
+                    .iter()
+                    .enumerate()
+                    .map(move |(offset, value)| {
+                        let element = BlockId::new(id.client_id, id.clock.advance(offset as u64));
+                        let value = self.store.registers.get(element).unwrap_or(value);
+
+                        (id, offset, value)
+                    })
+            })
+    }
+
+    /// Every block in the document, deleted ones included, in document order.
+    pub fn blocks(&self) -> impl DoubleEndedIterator<Item = BlockView<'_, T>> {
+        self.store.iter_blocks()
+    }
+
+    /// Whether the element `id` has been deleted, or `None` if the document doesn't have it,
+    /// e.g. because it hasn't arrived yet or was pruned by [`Document::checkpoint`].
+    pub fn is_deleted(&self, id: BlockId) -> Option<bool> {
+        self.stores()
+            .find_map(|store| store.get_block(id))
+            .map(|(block, _)| block.deleted)
+    }
+
+    /// Every deleted range of clocks still held as tombstones, coalesced and ordered by client
+    /// and then clock, across every root. Comparing them between replicas shows where their
+    /// deletions disagree.
+    pub fn deleted_ranges(&self) -> impl Iterator<Item = (ClientId, ClockRange)> {
+        DeleteSet::from(self)
+            .into_iter()
+            .map(|range| (range.client_id, range.clocks))
+    }
+
+    /// The client which inserted the element at `index`, or `None` if `index` is out of bounds.
+    pub fn author_at(&self, index: usize) -> Option<ClientId> {
+        self.store
+            .element_at(index)
+            .map(|element| element.client_id)
+    }
+
+    /// The live elements split into runs inserted by the same client, in document order. Runs are
+    /// as long as possible, so neighbouring runs always have different authors.
+    pub fn authorship_runs(&self) -> impl Iterator<Item = (ClientId, Range<usize>)> + '_ {
+        let mut blocks = self
+            .store
+            .iter_blocks()
+            .filter(|block| !block.is_deleted())
+            .peekable();
+        let mut start = 0;
+
+        std::iter::from_fn(move || {
+            let first = blocks.next()?;
+            let client_id = first.client_id();
+            let mut end = start + first.len();
+
+            while let Some(block) = blocks.next_if(|block| block.client_id() == client_id) {
+                end += block.len();
+            }
+
+            let run = start..end;
+            start = end;
+
+            Some((client_id, run))
+        })
+    }
+
+    /// A copy of every element in the document.
+    pub fn to_vec(&self) -> Vec<T> {
+        self.store.iter_values().cloned().collect()
+    }
+
+    /// The document as it was at state `at`, e.g. a [`Document::state_vector`] taken earlier:
+    /// elements inserted after `at` are left out, and so are deletions their maker made after
+    /// `at`.
+    ///
+    /// State vectors don't change when something is only deleted, so a deletion counts as part of
+    /// every version which has everything its maker had inserted when making it. Deletions from
+    /// peers which don't record who made them count as part of every version.
+    ///
+    /// Elements which have since been deleted can only be shown if the document was made
+    /// [`with_retained_deletions`](Document::with_retained_deletions) before they were, and are
+    /// left out otherwise. Moves are shown as they are now.
+    pub fn checkout(&self, at: &ClockVector) -> VersionView<'_, T> {
+        VersionView::new(self, at)
+    }
+
+    /// The value of `key` in the document's map.
+    pub fn map_get(&self, key: &str) -> Option<&T> {
+        self.map.get(key)
+    }
+
+    /// Sets `key` in the document's map, returning its previous value.
+    ///
+    /// Concurrent writes to the same key are resolved by last writer wins, so every replica ends
+    /// up with the same value once they've synced.
+    pub fn map_set(&mut self, key: impl Into<String>, value: T) -> Option<T> {
+        let key = key.into();
+        self.unsent.map_keys.insert(key.clone());
+
+        self.map.set(self.client_id, key, value)
+    }
+
+    /// Removes `key` from the document's map, returning its previous value.
+    pub fn map_remove(&mut self, key: &str) -> Option<T> {
+        self.unsent.map_keys.insert(key.to_owned());
+
+        self.map.remove(self.client_id, key)
+    }
+
+    /// The number of keys with a value in the document's map.
+    pub fn map_len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Attaches `key` and `value` to the elements in `range`, e.g. to make them bold.
+    ///
+    /// The mark sticks to those elements, so it also covers elements later inserted between
+    /// them, but not those inserted at either end of the range. Where marks with the same key
+    /// overlap, the one added last wins, with concurrent marks ordered the same way on every
+    /// replica. Like the map, marks are sent with every update built from the whole document.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is empty or out of bounds.
+    pub fn add_mark(&mut self, range: Range<usize>, key: impl Into<String>, value: Vec<u8>) {
+        let len = self.len();
+
+        assert!(
+            range.start < range.end && range.end <= len,
+            "mark range {:?} is empty or out of range for document of length {}",
+            range,
+            len
+        );
+
+        let start = self.store.element_at(range.start).unwrap();
+        let end = self.store.element_at(range.end - 1).unwrap();
+
+        let mark = self
+            .marks
+            .add(self.client_id, start, end, key.into(), value);
+        self.unsent.marks.push(mark.clone());
+    }
+
+    /// The marks on the element at `index`, one per key, ordered by key. Empty if `index` is out
+    /// of bounds.
+    pub fn marks_at(&self, index: usize) -> impl Iterator<Item = (&str, &[u8])> {
+        let mut winners: Vec<&Mark> = vec![];
+
+        if index < self.len() {
+            for mark in self.marks.iter() {
+                // Marks are only placed in the document's own sequence
+                let (Some((start, _)), Some((end, end_live))) = (
+                    self.store.live_elements_before(mark.start),
+                    self.store.live_elements_before(mark.end),
+                ) else {
+                    continue;
+                };
+
+                if index < start || index >= end + usize::from(end_live) {
+                    continue;
+                }
+
+                match winners.iter_mut().find(|winner| winner.key == mark.key) {
+                    Some(winner) if mark.wins_over(winner) => *winner = mark,
+                    Some(_) => {}
+                    None => winners.push(mark),
+                }
+            }
+        }
+
+        winners.sort_by(|a, b| a.key.cmp(&b.key));
+
+        winners
+            .into_iter()
+            .map(|mark| (mark.key.as_str(), mark.value.as_slice()))
+    }
+
+    /// The next clock the document expects from each client it has seen, ordered by client id.
+    pub fn clients(&self) -> impl Iterator<Item = (ClientId, Clock)> {
+        let mut clients: Vec<(ClientId, Clock)> = self
+            .clients
+            .iter()
+            .map(|(client_id, clock)| (*client_id, *clock))
+            .collect();
+        clients.sort_by_key(|(client_id, _)| *client_id);
+
+        clients.into_iter()
+    }
+
+    /// The update which brings a replica at `target_state` up to date with this document: the
+    /// blocks it's missing, from whichever clients, along with every deletion.
+    pub fn diff(&self, target_state: &ClockVector) -> Update<T> {
+        Update::from_document_since(self, target_state)
+    }
+
+    /// Counts of the document's blocks and elements, overall and for each client.
+    pub fn stats(&self) -> DocumentStats {
+        DocumentStats::new(self)
+    }
+
+    /// The number of clocks this document has seen from each client.
+    pub fn state_vector(&self) -> StateVector {
+        StateVector(
+            self.stores()
+                .flat_map(|store| store.data.clients())
+                .chain(self.checkpoint.clients())
+                .map(|client_id| (client_id, self.next_clock(client_id)))
+                .collect(),
+        )
+    }
+
+    /// The sequence named `name`, created empty if the document doesn't have it yet.
+    ///
+    /// Each root orders its own elements, so edits to one never shift the indices of another,
+    /// while sharing the document's clocks, updates and deletes, so every root syncs together.
+    /// Roots another replica created are created here as its updates arrive.
+    pub fn get_or_create_root(&mut self, name: &str) -> RootHandle<'_, T, S> {
+        self.root_store_mut(name);
+
+        RootHandle::new(self, name.to_owned())
+    }
+
+    /// The names of the document's roots, in order.
+    pub fn root_names(&self) -> impl Iterator<Item = &str> {
+        self.roots.keys().map(String::as_str)
+    }
+
+    /// The store of the root `name`, created if it doesn't exist yet.
+    pub(crate) fn root_store_mut(&mut self, name: &str) -> &mut Store<T, S> {
+        if !self.roots.contains_key(name) {
+            let store = self.store.sibling();
+            self.roots.insert(name.to_owned(), store);
+        }
+
+        self.roots.get_mut(name).expect("the root was just created")
+    }
+
+    /// The document's own store followed by the store of each root.
+    pub(crate) fn stores(&self) -> impl Iterator<Item = &Store<T, S>> {
+        std::iter::once(&self.store).chain(self.roots.values())
+    }
+
+    pub(crate) fn stores_mut(&mut self) -> impl Iterator<Item = &mut Store<T, S>> {
+        std::iter::once(&mut self.store).chain(self.roots.values_mut())
+    }
+
+    /// The next clock of `client_id`, which may be in any root, or past tombstones pruned at the
+    /// checkpoint.
+    pub(crate) fn next_clock(&self, client_id: ClientId) -> Clock {
+        self.stores()
+            .map(|store| store.next_clock(client_id))
+            .max()
+            .unwrap_or_default()
+            .max(self.checkpoint.clock(client_id))
+    }
+
+    /// Looks up the block containing the element `id` in whichever root has it.
+    pub(crate) fn find_block(&self, id: BlockId) -> Option<(&Block<T>, usize)> {
+        self.stores().find_map(|store| store.get_block(id))
+    }
+
+    /// Checks every root's list in debug builds, like [`Store::debug_assert_store_consistent`].
+    pub(crate) fn debug_assert_consistent(&self) {
+        for store in self.stores() {
+            store.debug_assert_store_consistent();
+        }
+    }
+
+    /// Applies `update`, or queues it if it depends on updates which haven't arrived yet.
+    ///
+    /// Whenever an update is applied, any queued updates it unblocks are applied too, transitively.
+    /// Updates which are invalid for any other reason are rejected and never queued.
+    pub fn apply_or_queue(&mut self, update: Update<T>) -> Result<QueueOutcome, ApplyError> {
+        update.check_limits(self)?;
+
+        match update.check_dependencies(self) {
+            Err(ApplyError::MissingDependency { .. }) => {
+                self.pending.push(update);
+                self.pending_changed();
+
+                return Ok(QueueOutcome::Queued);
+            }
+            // Applying reports any other error, handling client id conflicts on the way
+            _ => update.apply(self)?,
+        }
+
+        Ok(QueueOutcome::Applied {
+            drained: self.drain_pending(),
+        })
+    }
+
+    /// The clocks this document has to reach before `update` can apply, for each client it is
+    /// behind on, or `None` if `update` is applicable now.
+    ///
+    /// This is what to request from the peer `update` came from.
+    pub fn missing_for(&self, update: &Update<T>) -> Option<ClockVector> {
+        update.missing_for(self)
+    }
+
+    /// The number of updates waiting on missing dependencies.
+    pub fn pending_updates(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Drops queued updates whose contents are entirely covered by `state`, e.g. because the
+    /// same changes arrived through a different update.
+    pub fn prune_pending(&mut self, state: &ClockVector) {
+        self.pending.retain(|update| !update.is_covered_by(state));
+        self.pending_changed();
+    }
+
+    fn drain_pending(&mut self) -> usize {
+        let mut drained = 0;
+
+        while let Some(index) = self.pending.iter().position(|update| {
+            !matches!(
+                update.check_dependencies(self),
+                Err(ApplyError::MissingDependency { .. })
+            )
+        }) {
+            // Anything which fails for reasons other than missing dependencies can never apply,
+            // so it is dropped rather than left to clog the queue
+            if self.pending.remove(index).apply(self).is_ok() {
+                drained += 1;
+            }
+
+            self.pending_changed();
+        }
+
+        drained
+    }
+
+    /// Reports the number of queued updates to the metrics sink, after it may have changed.
+    fn pending_changed(&self) {
+        #[cfg(feature = "metrics")]
+        self.store.metrics.record(MetricEvent::PendingUpdates {
+            depth: self.pending.len(),
+        });
+    }
+
+    pub(crate) fn advance_local_clock(&mut self) {
+        let clock = self.next_clock(self.client_id);
+        self.clock = clock;
+        self.clients.insert(self.client_id, clock);
+
+        for store in self.stores_mut() {
+            store.min_local_clock = clock;
+        }
+    }
+}
+
+impl<T: Item + Encode, S: BlockStorage<T>> Document<T, S> {
+    /// Encodes the whole document as an update, like `Update::from_document(..).encode()` but
+    /// without building the update first, so values are never cloned. Documents with named
+    /// roots or a checkpoint are encoded through an [`Update`].
+    pub fn encode_full_update(&self) -> Result<Vec<u8>, EncodeError> {
+        if !self.roots.is_empty() || !self.checkpoint.is_empty() {
+            return Update::from_document(self).encode();
+        }
+
+        encoding::encode(&UpdateRef::new(self))
+    }
+}
+
+impl<T: Item + Decode, S: BlockStorage<T>> Document<T, S> {
+    /// Applies an update encoded by [`Update::encode`], like [`Document::decode_update`] followed
+    /// by [`Update::apply`], but without building the [`Update`].
+    ///
+    /// The update is read once without its values to check it, then again as its blocks are
+    /// integrated, so its values are never all held at once. Nothing is changed if it is
+    /// malformed or can't be applied.
+    pub fn apply_encoded(&mut self, bytes: &[u8]) -> Result<(), ApplyEncodedError> {
+        update::apply_encoded(self, bytes)
+    }
+}
+
+impl<T: Item + PartialEq, S: BlockStorage<T>> Document<T, S> {
+    /// Whether both documents have the same live values in every root, regardless of how they
+    /// got there. A root one document lacks counts as empty.
+    pub fn content_eq(&self, other: &Document<T, S>) -> bool {
+        fn values<'a, T: Item, S: BlockStorage<T>>(
+            document: &'a Document<T, S>,
+            name: &str,
+        ) -> impl Iterator<Item = &'a T> {
+            document
+                .roots
+                .get(name)
+                .into_iter()
+                .flat_map(|store| store.iter_values())
+        }
+
+        let root_eq = |name: &String| values(self, name).eq(values(other, name));
+
+        self.store.iter_values().eq(other.store.iter_values())
+            && self.roots.keys().chain(other.roots.keys()).all(root_eq)
+    }
+}
+
+impl<T: Item + Encode, S: BlockStorage<T>> Document<T, S> {
+    /// Sends each document whatever it's missing from the other, after which both hold the same
+    /// elements with the same deletions.
+    ///
+    /// Fails with [`ApplyError::Diverged`] if their fingerprints still differ afterwards, or with
+    /// whatever error either document rejected the other's update with.
+    pub fn sync_with(&mut self, other: &mut Document<T, S>) -> Result<(), ApplyError> {
+        let to_other = self.diff(other.state_vector().as_ref());
+        let to_self = other.diff(self.state_vector().as_ref());
+
+        to_other.apply(other)?;
+        to_self.apply(self)?;
+
+        if self.state_fingerprint() != other.state_fingerprint() {
+            return Err(ApplyError::Diverged);
+        }
+
+        Ok(())
+    }
+
+    /// A hash of every element in the document, deleted or not, along with its id.
+    ///
+    /// Two documents have the same fingerprint exactly when they hold the same elements in the
+    /// same order with the same deletions, however their blocks happen to be split or stored. The
+    /// hash is FNV-1a over the elements' bincode encoding, so it's stable across runs and
+    /// platforms and can be exchanged between peers to detect divergence.
+    pub fn state_fingerprint(&self) -> u64 {
+        let mut fingerprint = Fnv1a::new();
+        fingerprint.write_elements(&self.store);
+
+        // Each root's elements follow its name, so they can't be taken for another root's. Empty
+        // roots are left out, as replicas only create them once they're used.
+        for (name, store) in &self.roots {
+            if store.data.block_count() > 0 {
+                fingerprint.write(&encode_to_vec(name, config::standard()).expect("names encode"));
+                fingerprint.write_elements(store);
+            }
+        }
+
+        fingerprint.finish()
+    }
+}
+
+/// The 64 bit FNV-1a hash, which unlike `std`'s hashers is guaranteed not to change.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Fnv1a {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    /// Hashes every element of `store`, deleted or not, along with its id.
+    fn write_elements<T: Item + Encode, S: BlockStorage<T>>(&mut self, store: &Store<T, S>) {
+        for (id, value) in store.iter_elements() {
+            self.write(&encode_to_vec(id, config::standard()).expect("ids always encode"));
+
+            match value {
+                Some(value) => {
+                    self.write(&[1]);
+                    self.write(
+                        &encode_to_vec(value, config::standard()).expect("values always encode"),
+                    );
+                }
+                None => self.write(&[0]),
+            }
+        }
+    }
+}
+
+impl<T: Item, S: BlockStorage<T>> Default for Document<T, S> {
+    fn default() -> Self {
+        Document::with_storage(rand::random(), S::default())
+    }
+}
+
+/// Appends the values as a single block, like [`Document::insert_values`] at the end.
+impl<T: Item, S: BlockStorage<T>> Extend<T> for Document<T, S> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, values: I) {
+        self.insert_values(self.len(), values);
+    }
+}
+
+/// Builds a document with a random client id holding the values in a single block.
+impl<T: Item, S: BlockStorage<T>> FromIterator<T> for Document<T, S> {
+    fn from_iter<I: IntoIterator<Item = T>>(values: I) -> Self {
+        let mut document = Document::default();
+        document.extend(values);
+        document
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::Block;
+    use crate::block_range::BlockRange;
+    use crate::clock::{Clock, ClockRange};
+    use crate::document::{
+        BlockId, ClientId, ClientIdPolicy, ParseBlockIdError, QueueOutcome, StateVector,
+    };
+    use crate::storage::BlockStorage;
+    use crate::{ApplyError, Document, UndoManager, Update};
+    use bincode::{config, decode_from_slice, encode_to_vec};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::HashMap;
+    use std::ops::Range;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn insert_at_start() {
+        let mut doc = Document::with_client_id(1);
+        doc.push("b".to_owned());
+        doc.insert(0, "a".to_owned());
+
+        assert_eq!(doc.get(0), Some(&"a".to_owned()));
+        assert_eq!(doc.get(1), Some(&"b".to_owned()));
+        assert_eq!(doc.len(), 2);
+    }
+
+    #[test]
+    fn insert_at_end() {
+        let mut doc = Document::with_client_id(1);
+        doc.push("a".to_owned());
+        doc.insert(1, "b".to_owned());
+
+        assert_eq!(doc.get(1), Some(&"b".to_owned()));
+        assert_eq!(doc.get(2), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "insertion index (is 2) should be <= len (is 1)")]
+    fn insert_out_of_bounds() {
+        let mut doc = Document::with_client_id(1);
+        doc.push("a".to_owned());
+        doc.insert(2, "b".to_owned());
+    }
+
+    #[test]
+    fn remove_elements() {
+        let mut doc = Document::with_client_id(1);
+        doc.push("a".to_owned());
+        doc.push("b".to_owned());
+        doc.push("c".to_owned());
+        doc.push("d".to_owned());
+
+        doc.remove(0);
+        doc.remove_range(1, 2);
+
+        assert_eq!(doc.len(), 1);
+        assert_eq!(doc.get(0), Some(&"b".to_owned()));
+    }
+
+    #[test]
+    #[should_panic(expected = "removal index (is 1) should be < len (is 1)")]
+    fn remove_out_of_bounds() {
+        let mut doc = Document::with_client_id(1);
+        doc.push("a".to_owned());
+        doc.remove(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "removing 2 values from 2 is out of range for document of length 3")]
+    fn remove_range_out_of_bounds() {
+        let mut doc = Document::with_client_id(1);
+        doc.insert_values(0, ["a", "b", "c"].map(str::to_owned));
+        doc.remove_range(2, 2);
+    }
+
+    #[test]
+    fn inserted_ids_track_elements_through_remote_edits() {
+        let mut doc = Document::with_client_id(1);
+        let a = doc.push("a".to_owned());
+        let b = doc
+            .insert_values(1, ["b".to_owned(), "c".to_owned(), "d".to_owned()])
+            .unwrap();
+        let e = doc.push("e".to_owned());
+
+        assert_eq!(doc.insert_values(0, []), None);
+        assert_eq!(doc.index_of_id(b, 1), Some(2));
+
+        // Appends extend the last block, so c is found from a's block rather than b's
+        assert_eq!(doc.id_at(2), Some((a, 2)));
+
+        // Inserting between c and d splits the block they share
+        let mut remote = Document::with_client_id(2);
+        doc.sync_with(&mut remote).unwrap();
+        remote.insert(3, "x".to_owned());
+        remote.insert(0, "y".to_owned());
+        remote.remove(1);
+        remote.sync_with(&mut doc).unwrap();
+
+        assert_eq!(doc.to_vec(), vec!["y", "b", "c", "x", "d", "e"]);
+        assert_eq!(doc.index_of_id(a, 0), None);
+        assert_eq!(doc.index_of_id(b, 1), Some(2));
+        assert_eq!(doc.index_of_id(b, 2), Some(4));
+        assert_eq!(doc.index_of_id(e, 0), Some(5));
+
+        let (block, offset) = doc.id_at(4).unwrap();
+        assert_eq!(block, BlockId::new(1, Clock::new(3)));
+        assert_eq!(offset, 0);
+        assert_eq!(doc.index_of_id(block, offset), Some(4));
+        assert_eq!(doc.id_at(6), None);
+    }
+
+    #[test]
+    fn local_edits_advance_clock() {
+        let mut doc = Document::with_client_id(1);
+        doc.push("a".to_owned());
+        doc.insert(0, "b".to_owned());
+
+        assert_eq!(doc.clock, Clock::new(2));
+        assert_eq!(doc.clients.get(&1), Some(&Clock::new(2)));
+    }
+
+    #[test]
+    fn drains_queued_updates_transitively() {
+        let update = |clock: Clock, value: &str| {
+            let origin_left = clock.checked_rewind(1).map(|clock| BlockId::new(1, clock));
+
+            Update::from_blocks(
+                1,
+                vec![Block::with_value_and_right(
+                    clock,
+                    origin_left,
+                    None,
+                    value.to_owned(),
+                )],
+                vec![(1, ClockRange::starting_at(clock, 1))],
+            )
+        };
+
+        let mut doc = Document::with_client_id(2);
+
+        assert_eq!(
+            doc.apply_or_queue(update(Clock::new(2), "c")),
+            Ok(QueueOutcome::Queued)
+        );
+        assert_eq!(
+            doc.apply_or_queue(update(Clock::new(1), "b")),
+            Ok(QueueOutcome::Queued)
+        );
+        assert_eq!(doc.pending_updates(), 2);
+
+        assert_eq!(
+            doc.apply_or_queue(update(Clock::ZERO, "a")),
+            Ok(QueueOutcome::Applied { drained: 2 })
+        );
+        assert_eq!(doc.pending_updates(), 0);
+        assert_eq!(
+            doc.store.iter_values().collect::<Vec<&String>>(),
+            vec!["a", "b", "c"]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_updates_without_queueing() {
+        let mut doc: Document<String> = Document::with_client_id(2);
+
+        // The block's origin comes after the block itself, so it can never apply
+        let update = Update::from_blocks(
+            1,
+            vec![Block::with_value_and_right(
+                0,
+                Some(BlockId::new(1, 0)),
+                None,
+                "a".to_owned(),
+            )],
+            vec![(1, 0..1)],
+        );
+
+        assert!(doc.apply_or_queue(update).is_err());
+        assert_eq!(doc.pending_updates(), 0);
+    }
+
+    #[test]
+    fn prunes_pending_updates_covered_by_state() {
+        let mut doc = Document::with_client_id(2);
+        doc.apply_or_queue(Update::from_blocks(
+            1,
+            vec![Block::with_value_and_right(
+                1,
+                Some(BlockId::new(1, 0)),
+                None,
+                "b".to_owned(),
+            )],
+            vec![(1, 1..2)],
+        ))
+        .unwrap();
+
+        doc.prune_pending(&HashMap::from([(1, Clock::new(1))]));
+        assert_eq!(doc.pending_updates(), 1);
+
+        doc.prune_pending(&HashMap::from([(1, Clock::new(2))]));
+        assert_eq!(doc.pending_updates(), 0);
+    }
+
+    #[test]
+    fn state_vector_tracks_local_and_remote_clocks() {
+        let mut doc1 = Document::with_client_id(1);
+        doc1.push("a".to_owned());
+        doc1.push("b".to_owned());
+
+        let mut doc2 = Document::with_client_id(2);
+        doc2.push("c".to_owned());
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+
+        let state = doc2.state_vector();
+        assert_eq!(
+            state,
+            StateVector::from(HashMap::from([(1, Clock::new(2)), (2, Clock::new(1))]))
+        );
+
+        assert!(state.dominates(&doc1.state_vector()));
+        assert!(!doc1.state_vector().dominates(&state));
+    }
+
+    #[test]
+    fn state_vector_diff() {
+        let ours = StateVector::from(HashMap::from([
+            (1, Clock::new(4)),
+            (2, Clock::new(1)),
+            (3, Clock::new(2)),
+        ]));
+        let theirs = StateVector::from(HashMap::from([
+            (1, Clock::new(2)),
+            (2, Clock::new(1)),
+            (4, Clock::new(7)),
+        ]));
+
+        assert_eq!(
+            ours.diff(&theirs),
+            vec![BlockRange::new(1, 2..4), BlockRange::new(3, 0..2)]
+        );
+        assert_eq!(theirs.diff(&ours), vec![BlockRange::new(4, 0..7)]);
+    }
+
+    #[test]
+    fn sync_using_state_vectors() {
+        let mut doc1 = Document::with_client_id(1);
+        doc1.push("a".to_owned());
+
+        let mut doc2 = Document::with_client_id(2);
+        doc2.push("b".to_owned());
+
+        let state1 = doc1.state_vector();
+        let state2 = doc2.state_vector();
+
+        Update::from_document_since(&doc2, state1.as_ref())
+            .apply(&mut doc1)
+            .unwrap();
+        Update::from_document_since(&doc1, state2.as_ref())
+            .apply(&mut doc2)
+            .unwrap();
+
+        assert_eq!(doc1.state_vector(), doc2.state_vector());
+        assert_eq!(doc1.len(), 2);
+        assert_eq!(doc2.len(), 2);
+    }
+
+    #[test]
+    fn block_ids_display_and_parse() {
+        for id in [
+            BlockId::new(0, 0),
+            BlockId::new(7, 42),
+            BlockId::new(u64::MAX, u64::MAX),
+        ] {
+            assert_eq!(id.to_string().parse::<BlockId>(), Ok(id));
+        }
+
+        assert_eq!(BlockId::new(3, 17).to_string(), "3@17");
+        assert_eq!(
+            "317".parse::<BlockId>(),
+            Err(ParseBlockIdError::MissingSeparator)
+        );
+        assert!(matches!(
+            "x@1".parse::<BlockId>(),
+            Err(ParseBlockIdError::InvalidClientId(_))
+        ));
+        assert!(matches!(
+            "1@-1".parse::<BlockId>(),
+            Err(ParseBlockIdError::InvalidClock(_))
+        ));
+    }
+
+    #[test]
+    fn block_ids_order_by_client_then_clock() {
+        let mut ids = vec![BlockId::new(2, 0), BlockId::new(1, 5), BlockId::new(1, 2)];
+        ids.sort();
+
+        assert_eq!(
+            ids,
+            vec![BlockId::new(1, 2), BlockId::new(1, 5), BlockId::new(2, 0)]
+        );
+        assert_eq!(
+            BlockId::new(4, 1).range(3).collect::<Vec<_>>(),
+            vec![BlockId::new(4, 1), BlockId::new(4, 2), BlockId::new(4, 3)]
+        );
+    }
+
+    #[test]
+    fn state_vector_round_trips_through_bincode() {
+        let state = StateVector::from(HashMap::from([
+            (1, Clock::new(4)),
+            (u64::MAX, Clock::new(1)),
+        ]));
+
+        let configuration = config::standard();
+        let encoded = encode_to_vec(&state, configuration).unwrap();
+        let (decoded, _): (StateVector, usize) =
+            decode_from_slice(&encoded, configuration).unwrap();
+
+        assert_eq!(decoded, state);
+    }
+
+    fn churned_document() -> Document<String> {
+        let mut doc = Document::with_client_id(1);
+        for value in ["a", "b", "c", "d", "e", "f"] {
+            doc.push(value.to_owned());
+        }
+
+        // Separate deletes leave a separate tombstone for each of "b", "c" and "d"
+        doc.remove(1);
+        doc.remove(1);
+        doc.remove(1);
+
+        doc
+    }
+
+    #[test]
+    fn gc_merges_tombstones_seen_by_every_peer() {
+        let mut doc = churned_document();
+        assert_eq!(doc.store.data.len(1), 5);
+
+        doc.gc(&HashMap::from([(1, Clock::new(6))]));
+
+        assert_eq!(doc.store.data.len(1), 3);
+        assert_eq!(
+            doc.store.iter_values().collect::<Vec<_>>(),
+            vec!["a", "e", "f"]
+        );
+
+        let mut fresh = Document::with_client_id(2);
+        Update::from_document(&doc).apply(&mut fresh).unwrap();
+
+        assert_eq!(
+            fresh.store.iter_values().collect::<Vec<_>>(),
+            vec!["a", "e", "f"]
+        );
+
+        fresh.insert(1, "x".to_owned());
+        doc.push("y".to_owned());
+        Update::from_document_since(&fresh, doc.state_vector().as_ref())
+            .apply(&mut doc)
+            .unwrap();
+        Update::from_document_since(&doc, fresh.state_vector().as_ref())
+            .apply(&mut fresh)
+            .unwrap();
+
+        assert_eq!(
+            doc.store.iter_values().collect::<Vec<_>>(),
+            vec!["a", "x", "e", "f", "y"]
+        );
+        assert_eq!(
+            fresh.store.iter_values().collect::<Vec<_>>(),
+            doc.store.iter_values().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn gc_keeps_tombstones_not_yet_seen_by_every_peer() {
+        let mut doc = churned_document();
+
+        // A peer which has only seen "a" to "c" can't have seen "d" being deleted
+        doc.gc(&HashMap::from([(1, Clock::new(3))]));
+
+        assert_eq!(doc.store.data.len(1), 4);
+        assert_eq!(doc.store[BlockId::new(1, 1)].length, 2);
+        assert_eq!(doc.store[BlockId::new(1, 3)].length, 1);
+    }
+
+    #[test]
+    fn peer_with_original_tombstones_integrates_against_gc_document() {
+        let mut doc = churned_document();
+        let mut peer = Document::with_client_id(2);
+        Update::from_document(&doc).apply(&mut peer).unwrap();
+
+        doc.gc(&HashMap::from([(1, Clock::new(6)), (2, Clock::new(0))]));
+
+        // The peer's insert has the unmerged tombstone of "b" as its right origin
+        peer.insert(1, "x".to_owned());
+        Update::from_document_since(&peer, doc.state_vector().as_ref())
+            .apply(&mut doc)
+            .unwrap();
+
+        assert_eq!(
+            doc.store.iter_values().collect::<Vec<_>>(),
+            vec!["a", "x", "e", "f"]
+        );
+    }
+
+    #[test]
+    fn synced_replicas_share_a_fingerprint() {
+        let mut doc1 = churned_document();
+        let mut doc2 = Document::with_client_id(2);
+        doc2.push("x".to_owned());
+        doc2.push("y".to_owned());
+        doc2.remove(0);
+
+        Update::from_document_since(&doc1, doc2.state_vector().as_ref())
+            .apply(&mut doc2)
+            .unwrap();
+        Update::from_document_since(&doc2, doc1.state_vector().as_ref())
+            .apply(&mut doc1)
+            .unwrap();
+
+        assert!(doc1.content_eq(&doc2));
+        assert_eq!(doc1.state_fingerprint(), doc2.state_fingerprint());
+
+        // Restoring rebuilds the store's HashMap, which mustn't change the fingerprint
+        assert_eq!(
+            Document::restore(doc1.snapshot()).state_fingerprint(),
+            doc1.state_fingerprint()
+        );
+
+        doc1.push("z".to_owned());
+
+        assert!(!doc1.content_eq(&doc2));
+        assert_ne!(doc1.state_fingerprint(), doc2.state_fingerprint());
+    }
+
+    #[test]
+    fn fingerprint_includes_deletions() {
+        let mut doc1 = Document::with_client_id(1);
+        doc1.push("a".to_owned());
+        doc1.push("b".to_owned());
+
+        let mut doc2 = Document::with_client_id(1);
+        doc2.push("a".to_owned());
+
+        assert_ne!(doc1.state_fingerprint(), doc2.state_fingerprint());
+
+        doc1.remove(1);
+
+        assert!(doc1.content_eq(&doc2));
+        assert_ne!(doc1.state_fingerprint(), doc2.state_fingerprint());
+    }
+
+    #[test]
+    fn fingerprint_is_stable() {
+        // Peers compare fingerprints, so changing this breaks comparison with older versions
+        assert_eq!(
+            churned_document().state_fingerprint(),
+            17_315_543_427_407_629_878
+        );
+    }
+
+    #[test]
+    fn indexed_access_matches_iteration() {
+        let mut doc1 = Document::with_client_id(1);
+        let mut doc2 = Document::with_client_id(2);
+
+        for value in ["a", "b", "c", "d", "e"] {
+            doc1.push(value.to_owned());
+        }
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+
+        doc1.remove_range(1, 2);
+        doc2.insert(2, "x".to_owned());
+        doc2.push("y".to_owned());
+        doc2.remove(0);
+        Update::from_document_since(&doc2, doc1.state_vector().as_ref())
+            .apply(&mut doc1)
+            .unwrap();
+        doc1.insert(1, "z".to_owned());
+
+        let values: Vec<&String> = doc1.store.iter_values().collect();
+        assert_eq!(values, vec!["x", "z", "d", "e", "y"]);
+
+        for index in 0..=doc1.len() {
+            assert_eq!(doc1.get(index), doc1.store.iter_values().nth(index));
+        }
+
+        assert_eq!(doc1.slice(1..4).collect::<Vec<_>>(), &values[1..4]);
+        assert_eq!(doc1.slice(5..5).count(), 0);
+        assert_eq!(doc1.to_vec(), vec!["x", "z", "d", "e", "y"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "range 2..4 out of range for document of length 3")]
+    fn slice_out_of_bounds() {
+        let mut doc = Document::with_client_id(1);
+        for value in ["a", "b", "c"] {
+            doc.push(value.to_owned());
+        }
+
+        doc.slice(2..4).count();
+    }
+
+    #[test]
+    fn inserts_several_values_as_one_block() {
+        let mut doc = Document::with_client_id(1);
+        let mut undo = UndoManager::new(&mut doc).with_capture_timeout(Duration::ZERO);
+        doc.insert_values(0, "ad".chars());
+        doc.insert_values(1, "bc".chars());
+        doc.insert_values(4, std::iter::empty());
+
+        assert_eq!(doc.to_vec(), vec!['a', 'b', 'c', 'd']);
+        assert_eq!(
+            doc.blocks().map(|block| block.len()).collect::<Vec<_>>(),
+            vec![1, 2, 1]
+        );
+
+        undo.undo(&mut doc);
+        assert_eq!(doc.to_vec(), vec!['a', 'd']);
+    }
+
+    #[test]
+    fn reverse_iteration_matches_forward_iteration() {
+        for seed in 0..10 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut docs: Vec<Document<usize>> = (1..=3).map(Document::with_client_id).collect();
+
+            for step in 0..100 {
+                let doc = rng.gen_range(0, docs.len());
+                let len = docs[doc].len();
+
+                match rng.gen_range(0, 3) {
+                    0 if len > 0 => docs[doc].remove(rng.gen_range(0, len)),
+                    1 => {
+                        let from = rng.gen_range(0, docs.len());
+                        Update::from_document_since(&docs[from], docs[doc].state_vector().as_ref())
+                            .apply(&mut docs[doc])
+                            .unwrap();
+                    }
+                    _ => {
+                        docs[doc].insert(rng.gen_range(0, len + 1), step);
+                    }
+                }
+
+                let forward: Vec<&usize> = docs[doc].iter().collect();
+                let mut backward: Vec<&usize> = docs[doc].iter().rev().collect();
+                backward.reverse();
+                assert_eq!(forward, backward, "seed {}", seed);
+
+                // Taking from both ends meets in the middle without skipping or repeating
+                let mut iter = docs[doc].iter();
+                let (mut front, mut back): (Vec<&usize>, Vec<&usize>) = (vec![], vec![]);
+                while let Some(value) = iter.next() {
+                    front.push(value);
+                    back.extend(iter.next_back());
+                }
+                front.extend(back.into_iter().rev());
+                assert_eq!(front, forward, "seed {}", seed);
+            }
+        }
+    }
+
+    #[test]
+    fn iterates_with_ids_and_blocks() {
+        let mut doc1 = Document::with_client_id(1);
+        for value in "abcd".chars() {
+            doc1.push(value);
+        }
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+        doc2.insert(2, 'x');
+        doc2.remove(4);
+
+        assert_eq!(
+            doc2.iter_with_ids().collect::<Vec<_>>(),
+            vec![
+                (BlockId::new(1, 0), 0, &'a'),
+                (BlockId::new(1, 0), 1, &'b'),
+                (BlockId::new(2, 0), 0, &'x'),
+                (BlockId::new(1, 2), 0, &'c'),
+            ]
+        );
+        assert_eq!(
+            doc2.iter_with_ids()
+                .rev()
+                .map(|(_, _, value)| value)
+                .collect::<String>(),
+            "cxba"
+        );
+
+        assert_eq!(
+            doc2.blocks()
+                .map(|block| (
+                    block.id(),
+                    block.client_id(),
+                    block.is_deleted(),
+                    block.len()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (BlockId::new(1, 0), 1, false, 2),
+                (BlockId::new(2, 0), 2, false, 1),
+                (BlockId::new(1, 2), 1, false, 1),
+                (BlockId::new(1, 3), 1, true, 1),
+            ]
+        );
+        assert_eq!(doc2.blocks().next_back().unwrap().values(), &[] as &[char]);
+        assert_eq!(doc2.blocks().next().unwrap().values(), &['a', 'b']);
+    }
+
+    #[test]
+    fn authorship_runs_match_each_elements_author() {
+        for seed in 0..10 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut docs: Vec<Document<usize>> = (1..=3).map(Document::with_client_id).collect();
+
+            for step in 0..150 {
+                let doc = rng.gen_range(0, docs.len());
+                let len = docs[doc].len();
+
+                match rng.gen_range(0, 4) {
+                    0 if len > 0 => docs[doc].remove(rng.gen_range(0, len)),
+                    1 => {
+                        let from = rng.gen_range(0, docs.len());
+                        Update::from_document_since(&docs[from], docs[doc].state_vector().as_ref())
+                            .apply(&mut docs[doc])
+                            .unwrap();
+                    }
+                    _ => {
+                        docs[doc].insert(rng.gen_range(0, len + 1), step);
+                    }
+                }
+            }
+
+            let mut merged = Document::with_client_id(4);
+            for doc in &docs {
+                Update::from_document_since(doc, merged.state_vector().as_ref())
+                    .apply(&mut merged)
+                    .unwrap();
+            }
+
+            let authors: Vec<ClientId> = (0..merged.len())
+                .map(|index| merged.author_at(index).unwrap())
+                .collect();
+            assert_eq!(merged.author_at(merged.len()), None);
+
+            let mut expected: Vec<(ClientId, Range<usize>)> = vec![];
+            for (index, author) in authors.into_iter().enumerate() {
+                match expected.last_mut() {
+                    Some((client_id, run)) if *client_id == author => run.end = index + 1,
+                    _ => expected.push((author, index..index + 1)),
+                }
+            }
+
+            assert_eq!(
+                merged.authorship_runs().collect::<Vec<_>>(),
+                expected,
+                "seed {}",
+                seed
+            );
+        }
+    }
+
+    /// Two documents which were both given client id 1, and have made different edits.
+    fn colliding_documents(policy: ClientIdPolicy) -> (Document<char>, Document<char>) {
+        let mut doc1 = Document::with_client_id(1).with_client_id_policy(policy);
+        doc1.push('a');
+        doc1.push('b');
+
+        let mut doc2 = Document::with_client_id(1).with_client_id_policy(policy);
+        doc2.push('x');
+        doc2.insert(0, 'y');
+
+        (doc1, doc2)
+    }
+
+    #[test]
+    fn rejects_updates_from_another_document_with_the_same_client_id() {
+        let (mut doc1, mut doc2) = colliding_documents(ClientIdPolicy::Reject);
+
+        // doc2's second element has different origins to doc1's
+        assert_eq!(
+            Update::from_document(&doc2).apply(&mut doc1),
+            Err(ApplyError::ClientIdConflict(1))
+        );
+
+        // doc1 has clocks doc2 never made, so the update can't be queued waiting for them either
+        assert_eq!(
+            doc2.apply_or_queue(Update::from_document(&doc1)),
+            Err(ApplyError::ClientIdConflict(1))
+        );
+
+        assert_eq!(doc1.to_vec(), vec!['a', 'b']);
+        assert_eq!(doc2.to_vec(), vec!['y', 'x']);
+        assert_eq!(doc1.client_id(), 1);
+        assert_eq!(doc2.pending_updates(), 0);
+    }
+
+    #[test]
+    fn accepts_its_own_blocks_back() {
+        let mut doc1 = Document::with_client_id(1);
+        doc1.push('a');
+        doc1.push('b');
+        doc1.insert(1, 'c');
+        doc1.remove(0);
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+        doc2.push('d');
+
+        Update::from_document(&doc2).apply(&mut doc1).unwrap();
+
+        assert_eq!(doc1.to_vec(), vec!['c', 'b', 'd']);
+    }
+
+    #[test]
+    fn reassigns_client_id_on_conflict() {
+        let (mut doc1, doc2) = colliding_documents(ClientIdPolicy::Reassign);
+
+        assert_eq!(
+            Update::from_document(&doc2).apply(&mut doc1),
+            Err(ApplyError::ClientIdConflict(1))
+        );
+        assert_ne!(doc1.client_id(), 1);
+
+        // Later edits are made under the new id, and sync alongside the old blocks
+        doc1.push('c');
+
+        let mut doc3 = Document::with_client_id(3);
+        Update::from_document(&doc1).apply(&mut doc3).unwrap();
+
+        assert_eq!(doc3.to_vec(), vec!['a', 'b', 'c']);
+        assert_eq!(doc3.state_vector().clock(1), Clock::new(2));
+        assert_eq!(doc3.state_vector().clock(doc1.client_id()), Clock::new(1));
+    }
+
+    #[test]
+    fn full_update_encodes_like_an_owned_update() {
+        let mut doc = churned_document();
+        doc.map_set("key", "value".to_owned());
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc).apply(&mut doc2).unwrap();
+        doc2.insert(1, "x".to_owned());
+        doc2.insert(2, "y".to_owned());
+        doc2.remove(0);
+
+        let encoded = doc2.encode_full_update().unwrap();
+
+        assert_eq!(encoded, Update::from_document(&doc2).encode().unwrap());
+        assert_eq!(
+            encoded[1..],
+            encode_to_vec(Update::from_document(&doc2), config::standard()).unwrap()
+        );
+        assert_eq!(
+            Document::<String>::new().encode_full_update().unwrap(),
+            Update::from_document(&Document::<String>::new())
+                .encode()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn encodes_large_documents_quickly() {
+        let mut doc = Document::with_client_id(1);
+        for value in 0..100_000u32 {
+            doc.push(value);
+        }
+
+        // Every third element removed, leaving tens of thousands of blocks
+        for index in (0..33_333).map(|i| i * 2) {
+            doc.remove(index);
+        }
+
+        let start = Instant::now();
+        let encoded = doc.encode_full_update().unwrap();
+        let elapsed = start.elapsed();
+
+        assert!(elapsed.as_secs() < 5, "took {:?}", elapsed);
+
+        let mut other: Document<u32> = Document::with_client_id(2);
+        Update::decode(&encoded).unwrap().apply(&mut other).unwrap();
+
+        assert_eq!(other.len(), doc.len());
+        assert_eq!(other.state_fingerprint(), doc.state_fingerprint());
+    }
+
+    fn marks(doc: &Document<String>) -> Vec<Vec<(&str, &[u8])>> {
+        (0..doc.len())
+            .map(|index| doc.marks_at(index).collect())
+            .collect()
+    }
+
+    #[test]
+    fn marks_expand_with_inserts_inside_them_only() {
+        let mut doc = Document::with_client_id(1);
+        for value in ["a", "b", "c", "d"] {
+            doc.push(value.to_owned());
+        }
+
+        doc.add_mark(1..3, "bold", vec![1]);
+        doc.insert(2, "inside".to_owned());
+        doc.insert(1, "before".to_owned());
+        doc.insert(5, "after".to_owned());
+
+        let bold: &[(&str, &[u8])] = &[("bold", &[1])];
+        assert_eq!(marks(&doc), vec![&[][..], &[], bold, bold, bold, &[], &[]]);
+        assert_eq!(doc.marks_at(doc.len()).count(), 0);
+
+        // Deleting an end of the mark keeps the rest of it marked
+        doc.remove(4);
+        assert_eq!(marks(&doc), vec![&[][..], &[], bold, bold, &[], &[]]);
+    }
+
+    #[test]
+    fn overlapping_marks_converge() {
+        let mut doc = Document::with_client_id(1);
+        for value in ["a", "b", "c", "d"] {
+            doc.push(value.to_owned());
+        }
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc).apply(&mut doc2).unwrap();
+
+        doc.add_mark(0..3, "color", b"red".to_vec());
+        doc.add_mark(0..1, "bold", vec![1]);
+        doc2.add_mark(1..4, "color", b"blue".to_vec());
+
+        let update = Update::from_document(&doc);
+        Update::from_document(&doc2).apply(&mut doc).unwrap();
+        update.apply(&mut doc2).unwrap();
+
+        assert_eq!(marks(&doc), marks(&doc2));
+        assert_eq!(
+            marks(&doc),
+            vec![
+                vec![("bold", &[1][..]), ("color", b"red")],
+                vec![("color", b"blue")],
+                vec![("color", b"blue")],
+                vec![("color", b"blue")],
+            ]
+        );
+
+        // A later mark wins over both
+        doc2.add_mark(1..2, "color", b"green".to_vec());
+        Update::from_document(&doc2).apply(&mut doc).unwrap();
+
+        assert_eq!(
+            doc.marks_at(1).collect::<Vec<_>>(),
+            vec![("color", &b"green"[..])]
+        );
+        assert_eq!(marks(&doc), marks(&doc2));
+    }
+
+    #[test]
+    fn marks_reach_peers_through_local_updates() {
+        let mut doc = Document::with_client_id(1);
+        let mut doc2 = Document::with_client_id(2);
+        for value in ["a", "b", "c"] {
+            doc.push(value.to_owned());
+        }
+        doc.take_local_update(doc2.state_vector().as_ref())
+            .apply(&mut doc2)
+            .unwrap();
+
+        doc.add_mark(0..1, "bold", vec![1]);
+        let update = doc.transact(|transaction| transaction.add_mark(1..3, "link", vec![2]));
+        update.apply(&mut doc2).unwrap();
+
+        // Only the transaction's own mark is in its update
+        assert_eq!(doc2.marks_at(0).count(), 0);
+        assert_eq!(
+            doc2.marks_at(2).collect::<Vec<_>>(),
+            vec![("link", &[2][..])]
+        );
+
+        doc.take_local_update(doc2.state_vector().as_ref())
+            .apply(&mut doc2)
+            .unwrap();
+        assert_eq!(marks(&doc2), marks(&doc));
+    }
+
+    #[test]
+    fn marks_round_trip_through_updates_and_snapshots() {
+        let mut doc = Document::with_client_id(1);
+        for value in ["a", "b", "c"] {
+            doc.push(value.to_owned());
+        }
+        doc.add_mark(1..3, "link", b"https://example.com".to_vec());
+
+        let encoded = doc.encode_full_update().unwrap();
+        let mut doc2 = Document::with_client_id(2);
+        Update::decode(&encoded).unwrap().apply(&mut doc2).unwrap();
+
+        assert_eq!(marks(&doc2), marks(&doc));
+
+        let restored = Document::restore(doc.snapshot());
+        assert_eq!(marks(&restored), marks(&doc));
+    }
+
+    #[test]
+    fn bulk_loads_into_a_single_block() {
+        let doc: Document<usize> = (0..10_000).collect();
+
+        assert_eq!(
+            doc.store.iter_values().copied().collect::<Vec<_>>(),
+            (0..10_000).collect::<Vec<_>>()
+        );
+        assert_eq!(doc.store.data.len(doc.client_id), 1);
+
+        let mut peer = Document::with_client_id(doc.client_id.wrapping_add(1));
+        Update::decode(&doc.encode_full_update().unwrap())
+            .unwrap()
+            .apply(&mut peer)
+            .unwrap();
+
+        assert!(peer.content_eq(&doc));
+        assert_eq!(peer.store.data.len(doc.client_id), 1);
+    }
+
+    #[test]
+    fn extend_appends_to_the_last_block() {
+        let mut doc = Document::with_client_id(1).with_capacity_hint(16);
+        doc.push("a".to_owned());
+        doc.extend(["b", "c"].map(String::from));
+        doc.extend(["d"].map(String::from));
+        doc.extend(Vec::new());
+
+        assert_eq!(doc.iter().map(String::as_str).collect::<String>(), "abcd");
+        assert_eq!(doc.store.data.len(1), 1);
+        assert!(doc.store.data.heap_bytes() >= 16 * std::mem::size_of::<Block<String>>());
+    }
+
+    #[test]
+    fn clocks_advance_on_local_edits_and_applied_updates() {
+        let mut doc = Document::with_client_id(1);
+        assert_eq!(doc.clients().count(), 0);
+
+        doc.push("a".to_owned());
+        doc.extend(["b", "c"].map(String::from));
+        assert_eq!(doc.clients().collect::<Vec<_>>(), vec![(1, Clock::new(3))]);
+
+        // Deletes don't use up clocks
+        doc.remove(0);
+        assert_eq!(doc.clients().collect::<Vec<_>>(), vec![(1, Clock::new(3))]);
+
+        let mut doc2 = Document::with_client_id(2);
+        doc2.push("x".to_owned());
+        Update::from_document(&doc).apply(&mut doc2).unwrap();
+        assert_eq!(
+            doc2.clients().collect::<Vec<_>>(),
+            vec![(1, Clock::new(3)), (2, Clock::new(1))]
+        );
+
+        doc2.push("y".to_owned());
+        Update::from_document_since(&doc2, doc.state_vector().as_ref())
+            .apply(&mut doc)
+            .unwrap();
+        assert_eq!(
+            doc.clients().collect::<Vec<_>>(),
+            vec![(1, Clock::new(3)), (2, Clock::new(2))]
+        );
+        assert_eq!(
+            doc.clients().collect::<Vec<_>>(),
+            doc2.clients().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn sync_with_merges_divergent_histories() {
+        let mut doc1 = Document::with_client_id(1);
+        doc1.extend(["h", "e", "l", "l", "o"].map(String::from));
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+
+        // Client 3 is only known to doc2
+        let mut doc3 = Document::with_client_id(3);
+        doc3.push("!".to_owned());
+        doc3.map_set("greeting", "yes".to_owned());
+        Update::from_document(&doc3).apply(&mut doc2).unwrap();
+
+        doc1.insert(2, "y".to_owned());
+        doc1.remove(0);
+        doc2.remove(3);
+        doc2.push("?".to_owned());
+
+        doc1.sync_with(&mut doc2).unwrap();
+
+        assert_eq!(
+            doc1.iter().collect::<Vec<_>>(),
+            doc2.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            doc1.iter().map(String::as_str).collect::<String>(),
+            "eylo!?"
+        );
+        assert_eq!(doc1.state_vector(), doc2.state_vector());
+        assert_eq!(doc1.map_get("greeting").map(String::as_str), Some("yes"));
+
+        // Already in sync, so nothing changes
+        let fingerprint = doc1.state_fingerprint();
+        doc2.sync_with(&mut doc1).unwrap();
+        assert_eq!(doc2.state_fingerprint(), fingerprint);
+    }
+
+    #[test]
+    fn diff_brings_a_replica_up_to_date() {
+        let mut doc1 = Document::with_client_id(1);
+        doc1.extend(["a", "b"].map(String::from));
+
+        let mut doc2 = Document::with_client_id(2);
+        doc1.diff(doc2.state_vector().as_ref())
+            .apply(&mut doc2)
+            .unwrap();
+        doc1.remove(0);
+        doc1.push("c".to_owned());
+
+        let diff = doc1.diff(doc2.state_vector().as_ref());
+        diff.apply(&mut doc2).unwrap();
+
+        assert!(doc2.content_eq(&doc1));
+    }
+
+    #[test]
+    fn local_updates_carry_deletions_spanning_clients_as_ranges() {
+        let mut doc1 = Document::with_client_id(1);
+        doc1.push("a".to_owned());
+        doc1.push("b".to_owned());
+        doc1.push("c".to_owned());
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+        doc2.insert_values(3, ["x".to_owned(), "y".to_owned(), "z".to_owned()]);
+        Update::from_document(&doc2).apply(&mut doc1).unwrap();
+
+        // Part of each client's block, so both have to be split
+        doc1.remove_range(1, 3);
+
+        assert_eq!(
+            doc1.unsent.deletes.iter().collect::<Vec<_>>(),
+            vec![&BlockRange::new(1, 1..3), &BlockRange::new(2, 0..1)]
+        );
+
+        let update = doc1.take_local_update(doc2.state_vector().as_ref());
+        let bytes = update.encode().unwrap();
+        Update::<String>::decode(&bytes)
+            .unwrap()
+            .apply(&mut doc2)
+            .unwrap();
+
+        assert_eq!(doc2.to_vec(), vec!["a", "y", "z"]);
+        assert!(doc2.content_eq(&doc1));
+        assert!(doc1.unsent.deletes.is_empty());
+    }
+
+    #[test]
+    fn local_updates_carry_every_kind_of_change() {
+        let mut doc1 = Document::with_client_id(1);
+        let mut doc2 = Document::with_client_id(2);
+        let send = |doc1: &mut Document<String>, doc2: &mut Document<String>| {
+            doc1.take_local_update(doc2.state_vector().as_ref())
+                .apply(doc2)
+                .unwrap();
+        };
+
+        doc1.insert_values(0, ["a", "b", "c", "d"].map(String::from));
+        send(&mut doc1, &mut doc2);
+        assert_eq!(doc2.to_vec(), vec!["a", "b", "c", "d"]);
+
+        doc1.remove(3);
+        send(&mut doc1, &mut doc2);
+        assert_eq!(doc2.to_vec(), vec!["a", "b", "c"]);
+
+        doc1.set(1, "x".to_owned());
+        send(&mut doc1, &mut doc2);
+        assert_eq!(doc2.to_vec(), vec!["a", "x", "c"]);
+
+        doc1.move_range(0..1, 3);
+        send(&mut doc1, &mut doc2);
+        assert_eq!(doc2.to_vec(), vec!["x", "c", "a"]);
+
+        doc1.add_mark(0..2, "bold", vec![1]);
+        send(&mut doc1, &mut doc2);
+        assert_eq!(marks(&doc2), marks(&doc1));
+        assert_eq!(doc2.marks_at(1).count(), 1);
+
+        doc1.map_set("k", "7".to_owned());
+        send(&mut doc1, &mut doc2);
+        assert_eq!(doc2.map_get("k"), Some(&"7".to_owned()));
+
+        doc1.map_remove("k");
+        send(&mut doc1, &mut doc2);
+        assert_eq!(doc2.map_get("k"), None);
+
+        assert!(doc2.content_eq(&doc1));
+    }
+
+    #[test]
+    fn transactions_leave_earlier_deletions_pending() {
+        let mut doc = Document::with_client_id(1);
+        doc.insert_values(0, ["a".to_owned(), "b".to_owned(), "c".to_owned()]);
+        doc.remove(0);
+
+        doc.transact(|transaction| transaction.remove(0));
+
+        assert_eq!(
+            doc.unsent.deletes.iter().collect::<Vec<_>>(),
+            vec![&BlockRange::new(1, 0..1)]
+        );
+    }
+
+    fn root(doc: &mut Document<String>, name: &str) -> Vec<String> {
+        doc.get_or_create_root(name).to_vec()
+    }
+
+    #[test]
+    fn roots_edit_independently_and_converge() {
+        let mut doc1 = Document::with_client_id(1);
+        let mut doc2 = Document::with_client_id(2);
+
+        doc1.push("main".to_owned());
+        doc1.get_or_create_root("title")
+            .insert_values(0, ["a", "b"].map(String::from));
+        doc1.get_or_create_root("body").push("x".to_owned());
+        doc1.sync_with(&mut doc2).unwrap();
+
+        // Concurrent edits to both roots on both sides
+        doc1.get_or_create_root("title").insert(1, "1".to_owned());
+        doc1.get_or_create_root("body").remove(0);
+        doc2.get_or_create_root("title").push("2".to_owned());
+        doc2.get_or_create_root("body").push("y".to_owned());
+        doc2.insert(0, "first".to_owned());
+
+        // An edit in one root doesn't shift indices in another
+        assert_eq!(
+            doc1.get_or_create_root("title").get(1),
+            Some(&"1".to_owned())
+        );
+        assert_eq!(doc2.get(1), Some(&"main".to_owned()));
+
+        let update = Update::from_document_since(&doc1, doc2.state_vector().as_ref());
+        update.apply(&mut doc2).unwrap();
+        let update = Update::from_document_since(&doc2, doc1.state_vector().as_ref());
+        update.apply(&mut doc1).unwrap();
+
+        assert!(doc1.content_eq(&doc2));
+        assert_eq!(doc1.state_fingerprint(), doc2.state_fingerprint());
+        assert_eq!(doc1.to_vec(), vec!["first", "main"]);
+        assert_eq!(root(&mut doc1, "title"), vec!["a", "1", "b", "2"]);
+        assert_eq!(root(&mut doc2, "title"), root(&mut doc1, "title"));
+        assert_eq!(root(&mut doc1, "body"), vec!["y"]);
+        assert_eq!(root(&mut doc2, "body"), vec!["y"]);
+        assert_eq!(doc1.root_names().collect::<Vec<_>>(), vec!["body", "title"]);
+
+        // Roots share the document's clocks, so the state vector counts every root's blocks
+        assert_eq!(doc1.state_vector().clock(1), Clock::new(5));
+        assert_eq!(doc1.state_vector().clock(2), Clock::new(3));
+        assert!(doc1.check_integrity().is_empty());
+    }
+
+    #[test]
+    fn roots_round_trip_through_encoded_updates() {
+        let mut doc = Document::with_client_id(1);
+        doc.push("a".to_owned());
+        doc.get_or_create_root("notes")
+            .insert_values(0, ["x", "y", "z"].map(String::from));
+        doc.push("b".to_owned());
+        doc.get_or_create_root("notes").remove(1);
+
+        let mut replica = Document::with_client_id(2);
+        replica
+            .apply_encoded(&doc.encode_full_update().unwrap())
+            .unwrap();
+
+        assert_eq!(replica.to_vec(), vec!["a", "b"]);
+        assert_eq!(root(&mut replica, "notes"), vec!["x", "z"]);
+        assert!(replica.content_eq(&doc));
+
+        // Local updates carry root edits too
+        let before = doc.state_vector();
+        doc.get_or_create_root("notes").push("w".to_owned());
+        let update = doc.take_local_update(before.as_ref());
+        replica.apply_encoded(&update.encode().unwrap()).unwrap();
+
+        assert_eq!(root(&mut replica, "notes"), vec!["x", "z", "w"]);
+        assert_eq!(replica.state_fingerprint(), doc.state_fingerprint());
+    }
+
+    #[test]
+    fn roots_restore_from_snapshots() {
+        let mut doc = Document::with_client_id(1);
+        doc.push("a".to_owned());
+        doc.get_or_create_root("notes").push("x".to_owned());
+
+        let mut restored = Document::restore(doc.snapshot());
+        assert!(restored.content_eq(&doc));
+        assert_eq!(restored.state_fingerprint(), doc.state_fingerprint());
+
+        // The restored document's local clock carries on past every root's blocks
+        restored.get_or_create_root("notes").push("y".to_owned());
+        restored.push("b".to_owned());
+        assert_eq!(restored.state_vector().clock(1), Clock::new(4));
+
+        let mut replica = Document::with_client_id(2);
+        Update::from_document(&doc).apply(&mut replica).unwrap();
+        let update = Update::from_document_since(&restored, replica.state_vector().as_ref());
+        update.apply(&mut replica).unwrap();
+
+        assert!(replica.content_eq(&restored));
+        assert_eq!(root(&mut replica, "notes"), vec!["x", "y"]);
+        assert!(replica.check_integrity().is_empty());
+    }
+}
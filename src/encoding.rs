@@ -0,0 +1,186 @@
+use crate::limits::Limit;
+use bincode::config::{self, Config};
+use bincode::de::read::SliceReader;
+use bincode::de::DecoderImpl;
+use bincode::enc::write::Writer;
+use bincode::{Decode, Encode};
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+pub use bincode::error::EncodeError;
+
+/// The version written as a varint at the start of every encoded payload. Bump this whenever the
+/// encoding of any persisted type changes incompatibly; data which older readers can skip goes in
+/// an update's extensions instead.
+pub const FORMAT_VERSION: u32 = 12;
+
+/// The most memory bincode may reserve up front for the collections in a payload. Lengths come
+/// straight off the wire, so without this a forged length could reserve any amount.
+const MAX_PREALLOCATION: usize = 1 << 30;
+
+/// Why a payload couldn't be decoded.
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    /// The payload was empty, so has no version header.
+    MissingVersion,
+    /// The payload was written by a newer, incompatible version of this crate.
+    UnsupportedVersion { found: u32, supported: u32 },
+    /// The payload's body isn't a valid encoding, e.g. because it was truncated.
+    Malformed(bincode::error::DecodeError),
+    /// The payload decoded successfully but was followed by this many unexpected bytes.
+    TrailingBytes(usize),
+    /// The payload breaks one of a document's [`Limits`](crate::Limits).
+    LimitExceeded(Limit),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::MissingVersion => write!(f, "payload is empty"),
+            DecodeError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "payload has format version {} but only versions up to {} are supported",
+                found, supported
+            ),
+            DecodeError::Malformed(error) => write!(f, "payload is malformed: {}", error),
+            DecodeError::TrailingBytes(count) => {
+                write!(f, "payload has {} unexpected trailing bytes", count)
+            }
+            DecodeError::LimitExceeded(limit) => {
+                write!(f, "payload exceeds the limit on {}", limit)
+            }
+        }
+    }
+}
+
+impl Error for DecodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DecodeError::Malformed(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Encodes `value` behind a [`FORMAT_VERSION`] header.
+pub(crate) fn encode<V: Encode>(value: &V) -> Result<Vec<u8>, EncodeError> {
+    let mut bytes = bincode::encode_to_vec(FORMAT_VERSION, config::standard())?;
+    bytes.extend(bincode::encode_to_vec(value, config::standard())?);
+
+    Ok(bytes)
+}
+
+/// Counts the bytes written to it, to measure an encoding without allocating it.
+struct SizeWriter(usize);
+
+impl Writer for SizeWriter {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), EncodeError> {
+        self.0 += bytes.len();
+
+        Ok(())
+    }
+}
+
+/// The number of bytes `value` encodes to, without a header. A value which fails to encode counts
+/// the bytes written before it failed, as encoding it for real reports the error.
+pub(crate) fn encoded_len<V: Encode>(value: &V) -> usize {
+    let mut writer = SizeWriter(0);
+    let _ = bincode::encode_into_writer(value, &mut writer, config::standard());
+
+    writer.0
+}
+
+/// The number of bytes [`encode`] writes for `value`.
+pub(crate) fn encoded_len_with_header<V: Encode>(value: &V) -> usize {
+    encoded_len(&FORMAT_VERSION) + encoded_len(value)
+}
+
+/// Decodes a payload written by [`encode`], which must consist of exactly one value.
+///
+/// Payloads from any version up to [`FORMAT_VERSION`] are decoded with the current layout, so
+/// types whose layout has changed must handle the older versions themselves first.
+pub(crate) fn decode<V: Decode>(bytes: &[u8]) -> Result<V, DecodeError> {
+    version(bytes)?;
+
+    decode_body(bytes)
+}
+
+/// The format version a payload was written with, rejecting versions newer than this crate's.
+pub(crate) fn version(bytes: &[u8]) -> Result<u32, DecodeError> {
+    let (version, _) = header(bytes)?;
+
+    if version > FORMAT_VERSION {
+        return Err(DecodeError::UnsupportedVersion {
+            found: version,
+            supported: FORMAT_VERSION,
+        });
+    }
+
+    Ok(version)
+}
+
+/// The version at the start of a payload, and the number of bytes it takes up.
+fn header(bytes: &[u8]) -> Result<(u32, usize), DecodeError> {
+    if bytes.is_empty() {
+        return Err(DecodeError::MissingVersion);
+    }
+
+    bincode::decode_from_slice(bytes, config::standard()).map_err(DecodeError::Malformed)
+}
+
+/// Decodes the body of a payload, whatever its version. Used to read payloads written by older
+/// versions into their original types.
+pub(crate) fn decode_body<V: Decode>(bytes: &[u8]) -> Result<V, DecodeError> {
+    let body = body(bytes)?;
+    let (value, read) = decode_prefix(body)?;
+
+    if read != body.len() {
+        return Err(DecodeError::TrailingBytes(body.len() - read));
+    }
+
+    Ok(value)
+}
+
+/// The body of a payload, i.e. everything after its version header.
+pub(crate) fn body(bytes: &[u8]) -> Result<&[u8], DecodeError> {
+    let (_, header_length) = header(bytes)?;
+
+    Ok(&bytes[header_length..])
+}
+
+/// Decodes a value from the start of part of a body, returning it and the number of bytes it took
+/// up, so the rest can be decoded separately.
+pub(crate) fn decode_prefix<V: Decode>(bytes: &[u8]) -> Result<(V, usize), DecodeError> {
+    let configuration = config::standard().with_limit::<MAX_PREALLOCATION>();
+
+    bincode::decode_from_slice(bytes, configuration).map_err(DecodeError::Malformed)
+}
+
+/// A decoder over part of a body, for reading values one at a time rather than all at once.
+pub(crate) fn decoder(bytes: &[u8]) -> DecoderImpl<SliceReader<'_>, impl Config> {
+    DecoderImpl::new(
+        SliceReader::new(bytes),
+        config::standard().with_limit::<MAX_PREALLOCATION>(),
+    )
+}
+
+/// Encodes a list of values as one section of a payload, laid out like a `Vec`, so readers which
+/// don't know the section can skip it.
+pub(crate) fn encode_section<V: Encode>(values: &[V]) -> Result<Vec<u8>, EncodeError> {
+    let mut bytes = bincode::encode_to_vec(values.len() as u64, config::standard())?;
+
+    for value in values {
+        bincode::encode_into_std_write(value, &mut bytes, config::standard())?;
+    }
+
+    Ok(bytes)
+}
+
+/// Decodes a section written by [`encode_section`]. Bytes after the value are ignored, leaving
+/// room for later versions to append to the section.
+pub(crate) fn decode_section<V: Decode>(bytes: &[u8]) -> Result<V, bincode::error::DecodeError> {
+    let configuration = config::standard().with_limit::<MAX_PREALLOCATION>();
+
+    bincode::decode_from_slice(bytes, configuration).map(|(value, _)| value)
+}
@@ -0,0 +1,381 @@
+//! A C interface to [`TextDocument`], for calling the crate from other languages, e.g. Swift or
+//! Kotlin. Enabled by the `ffi` feature; build a shared library with
+//! `cargo rustc --release --features ffi --crate-type cdylib`.
+//!
+//! Documents are opaque handles from [`yata_doc_new`], freed with [`yata_doc_free`]. Text is UTF-8
+//! and indices count `char`s, as on [`TextDocument`]. Functions which can fail return one of the
+//! `YATA_*` codes, and never unwind into the caller: a panic is caught and reported as
+//! [`YATA_ERR_PANIC`]. Buffers the library allocates are returned as a [`YataBuffer`], which the
+//! caller must free with [`yata_buffer_free`].
+
+use crate::encoding;
+use crate::{StateVector, TextDocument, TextUpdateError};
+use std::panic::{self, AssertUnwindSafe};
+use std::{ptr, slice, str};
+
+/// The call succeeded.
+pub const YATA_OK: i32 = 0;
+/// A handle or buffer pointer was null.
+pub const YATA_ERR_NULL: i32 = 1;
+/// Text passed in wasn't valid UTF-8.
+pub const YATA_ERR_INVALID_UTF8: i32 = 2;
+/// An index or range was outside the document.
+pub const YATA_ERR_OUT_OF_RANGE: i32 = 3;
+/// An update or state vector couldn't be decoded.
+pub const YATA_ERR_DECODE: i32 = 4;
+/// An update decoded but couldn't be applied, e.g. because it depends on changes the document
+/// doesn't have yet.
+pub const YATA_ERR_APPLY: i32 = 5;
+/// An update couldn't be encoded.
+pub const YATA_ERR_ENCODE: i32 = 6;
+/// The library panicked. The document may have been left part way through the call.
+pub const YATA_ERR_PANIC: i32 = 7;
+
+/// An opaque handle to a [`TextDocument`].
+pub struct YataDocument {
+    text: TextDocument,
+}
+
+/// Bytes allocated by the library, to be freed with [`yata_buffer_free`].
+#[repr(C)]
+#[derive(Debug)]
+pub struct YataBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+impl YataBuffer {
+    fn empty() -> YataBuffer {
+        YataBuffer {
+            data: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    fn from_vec(bytes: Vec<u8>) -> YataBuffer {
+        let bytes = Box::into_raw(bytes.into_boxed_slice());
+
+        YataBuffer {
+            data: bytes as *mut u8,
+            len: bytes.len(),
+        }
+    }
+}
+
+/// Runs `f`, turning a panic into [`YATA_ERR_PANIC`].
+fn guard(f: impl FnOnce() -> i32) -> i32 {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(YATA_ERR_PANIC)
+}
+
+/// The `len` bytes at `data`, which may be null if `len` is 0.
+///
+/// # Safety
+///
+/// `data` must be valid for reads of `len` bytes.
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    match (data.is_null(), len) {
+        (true, 0) => Some(&[]),
+        (true, _) => None,
+        (false, _) => Some(slice::from_raw_parts(data, len)),
+    }
+}
+
+/// Writes `bytes` to `out`, to be freed by the caller.
+///
+/// # Safety
+///
+/// `out` must be null or valid for writes.
+unsafe fn write_buffer(out: *mut YataBuffer, bytes: Vec<u8>) -> i32 {
+    match out.as_mut() {
+        Some(out) => {
+            *out = YataBuffer::from_vec(bytes);
+            YATA_OK
+        }
+        None => YATA_ERR_NULL,
+    }
+}
+
+/// Creates an empty document with a random client id, or returns null if that panics.
+#[no_mangle]
+pub extern "C" fn yata_doc_new() -> *mut YataDocument {
+    panic::catch_unwind(|| {
+        Box::into_raw(Box::new(YataDocument {
+            text: TextDocument::new(),
+        }))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Frees a document. Null is ignored.
+///
+/// # Safety
+///
+/// `doc` must be null or a handle from [`yata_doc_new`] which hasn't been freed.
+#[no_mangle]
+pub unsafe extern "C" fn yata_doc_free(doc: *mut YataDocument) {
+    if !doc.is_null() {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(doc))));
+    }
+}
+
+/// Inserts the `len` bytes of UTF-8 text at `text` so its first `char` ends up at `index`.
+///
+/// # Safety
+///
+/// `doc` must be a live handle and `text` valid for reads of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn yata_doc_insert(
+    doc: *mut YataDocument,
+    index: usize,
+    text: *const u8,
+    len: usize,
+) -> i32 {
+    guard(|| {
+        let (Some(doc), Some(text)) = (doc.as_mut(), bytes(text, len)) else {
+            return YATA_ERR_NULL;
+        };
+
+        let Ok(text) = str::from_utf8(text) else {
+            return YATA_ERR_INVALID_UTF8;
+        };
+
+        if index > doc.text.len_chars() {
+            return YATA_ERR_OUT_OF_RANGE;
+        }
+
+        doc.text.insert_str(index, text);
+        YATA_OK
+    })
+}
+
+/// Deletes `count` `char`s from `index` onwards.
+///
+/// # Safety
+///
+/// `doc` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn yata_doc_delete(
+    doc: *mut YataDocument,
+    index: usize,
+    count: usize,
+) -> i32 {
+    guard(|| {
+        let Some(doc) = doc.as_mut() else {
+            return YATA_ERR_NULL;
+        };
+
+        match index.checked_add(count) {
+            Some(end) if end <= doc.text.len_chars() => {
+                doc.text.delete(index..end);
+                YATA_OK
+            }
+            _ => YATA_ERR_OUT_OF_RANGE,
+        }
+    })
+}
+
+/// Writes the document's text, as UTF-8 without a terminating nul, to `out`.
+///
+/// # Safety
+///
+/// `doc` must be a live handle and `out` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn yata_doc_to_string(doc: *const YataDocument, out: *mut YataBuffer) -> i32 {
+    guard(|| match doc.as_ref() {
+        Some(doc) => write_buffer(out, doc.text.to_string().into_bytes()),
+        None => YATA_ERR_NULL,
+    })
+}
+
+/// Writes the document's encoded state vector to `out`, for a peer to pass to
+/// [`yata_doc_encode_update_since`].
+///
+/// # Safety
+///
+/// `doc` must be a live handle and `out` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn yata_doc_state_vector(
+    doc: *const YataDocument,
+    out: *mut YataBuffer,
+) -> i32 {
+    guard(|| {
+        let Some(doc) = doc.as_ref() else {
+            return YATA_ERR_NULL;
+        };
+
+        match encoding::encode(&doc.text.state_vector()) {
+            Ok(bytes) => write_buffer(out, bytes),
+            Err(_) => YATA_ERR_ENCODE,
+        }
+    })
+}
+
+/// Writes an encoded update of everything a peer with the encoded state vector at `sv` is
+/// missing to `out`. An empty state vector encodes the whole document.
+///
+/// # Safety
+///
+/// `doc` must be a live handle, `sv` valid for reads of `sv_len` bytes and `out` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn yata_doc_encode_update_since(
+    doc: *const YataDocument,
+    sv: *const u8,
+    sv_len: usize,
+    out: *mut YataBuffer,
+) -> i32 {
+    guard(|| {
+        let (Some(doc), Some(sv)) = (doc.as_ref(), bytes(sv, sv_len)) else {
+            return YATA_ERR_NULL;
+        };
+
+        let since = if sv.is_empty() {
+            StateVector::new()
+        } else {
+            match encoding::decode(sv) {
+                Ok(since) => since,
+                Err(_) => return YATA_ERR_DECODE,
+            }
+        };
+
+        match doc.text.encode_update(&since) {
+            Ok(bytes) => write_buffer(out, bytes),
+            Err(_) => YATA_ERR_ENCODE,
+        }
+    })
+}
+
+/// Applies the encoded update of `len` bytes at `update`.
+///
+/// # Safety
+///
+/// `doc` must be a live handle and `update` valid for reads of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn yata_doc_apply_update(
+    doc: *mut YataDocument,
+    update: *const u8,
+    len: usize,
+) -> i32 {
+    guard(|| {
+        let (Some(doc), Some(update)) = (doc.as_mut(), bytes(update, len)) else {
+            return YATA_ERR_NULL;
+        };
+
+        match doc.text.apply_update(update) {
+            Ok(()) => YATA_OK,
+            Err(TextUpdateError::Decode(_)) => YATA_ERR_DECODE,
+            Err(TextUpdateError::Apply(_)) => YATA_ERR_APPLY,
+        }
+    })
+}
+
+/// Frees a buffer the library returned, and empties it. Empty buffers are ignored.
+///
+/// # Safety
+///
+/// `buffer` must be null or point to a buffer written by this library which hasn't been freed.
+#[no_mangle]
+pub unsafe extern "C" fn yata_buffer_free(buffer: *mut YataBuffer) {
+    let Some(buffer) = buffer.as_mut() else {
+        return;
+    };
+
+    if !buffer.data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            buffer.data,
+            buffer.len,
+        )));
+    }
+
+    *buffer = YataBuffer::empty();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ffi::*;
+
+    unsafe fn text(doc: *const YataDocument) -> String {
+        let mut buffer = YataBuffer::empty();
+        assert_eq!(yata_doc_to_string(doc, &mut buffer), YATA_OK);
+
+        let text = String::from_utf8(slice::from_raw_parts(buffer.data, buffer.len).to_vec());
+        yata_buffer_free(&mut buffer);
+
+        text.unwrap()
+    }
+
+    /// Sends `to` everything it's missing from `from`.
+    unsafe fn sync(from: *const YataDocument, to: *mut YataDocument) {
+        let mut sv = YataBuffer::empty();
+        let mut update = YataBuffer::empty();
+
+        assert_eq!(yata_doc_state_vector(to, &mut sv), YATA_OK);
+        assert_eq!(
+            yata_doc_encode_update_since(from, sv.data, sv.len, &mut update),
+            YATA_OK
+        );
+        assert_eq!(yata_doc_apply_update(to, update.data, update.len), YATA_OK);
+
+        yata_buffer_free(&mut sv);
+        yata_buffer_free(&mut update);
+    }
+
+    #[test]
+    fn round_trips_edits_between_handles() {
+        unsafe {
+            let first = yata_doc_new();
+            let second = yata_doc_new();
+
+            let hello = "héllo wörld";
+            assert_eq!(
+                yata_doc_insert(first, 0, hello.as_ptr(), hello.len()),
+                YATA_OK
+            );
+            sync(first, second);
+            assert_eq!(text(second), "héllo wörld");
+
+            assert_eq!(yata_doc_delete(second, 5, 6), YATA_OK);
+            let there = ", there";
+            assert_eq!(
+                yata_doc_insert(second, 5, there.as_ptr(), there.len()),
+                YATA_OK
+            );
+            sync(second, first);
+            assert_eq!(text(first), "héllo, there");
+
+            yata_doc_free(first);
+            yata_doc_free(second);
+        }
+    }
+
+    #[test]
+    fn reports_errors_rather_than_panicking() {
+        unsafe {
+            let doc = yata_doc_new();
+            let invalid = [0xffu8, 0xfe];
+            let mut buffer = YataBuffer::empty();
+
+            assert_eq!(
+                yata_doc_insert(doc, 1, "a".as_ptr(), 1),
+                YATA_ERR_OUT_OF_RANGE
+            );
+            assert_eq!(
+                yata_doc_insert(doc, 0, invalid.as_ptr(), 2),
+                YATA_ERR_INVALID_UTF8
+            );
+            assert_eq!(yata_doc_delete(doc, 0, usize::MAX), YATA_ERR_OUT_OF_RANGE);
+            assert_eq!(
+                yata_doc_apply_update(doc, invalid.as_ptr(), 2),
+                YATA_ERR_DECODE
+            );
+            assert_eq!(
+                yata_doc_encode_update_since(doc, invalid.as_ptr(), 2, &mut buffer),
+                YATA_ERR_DECODE
+            );
+            assert_eq!(yata_doc_to_string(ptr::null(), &mut buffer), YATA_ERR_NULL);
+            assert_eq!(yata_doc_to_string(doc, ptr::null_mut()), YATA_ERR_NULL);
+            assert_eq!(guard(|| panic!("inside the library")), YATA_ERR_PANIC);
+
+            yata_doc_free(doc);
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use crate::document::BlockId;
+use std::collections::HashMap;
+
+/// A node of the [`BlockIndex`] tree, standing for a single block.
+#[derive(Debug, Clone)]
+struct Node {
+    block_id: BlockId,
+    /// The block's length, or 0 if it is deleted.
+    live: usize,
+    priority: u64,
+    parent: Option<usize>,
+    left: Option<usize>,
+    right: Option<usize>,
+    /// The number of nodes in this subtree.
+    count: usize,
+    /// The number of live elements in this subtree.
+    total: usize,
+}
+
+/// The blocks of a store in document order, as an order-statistics tree, so that the block at a
+/// live index and the number of live elements before a block can both be found in O(log n).
+///
+/// The tree is a treap kept balanced by random priorities. Nodes are never removed, as blocks
+/// aren't either; [`BlockIndex::rebuild`] starts over when blocks are merged.
+#[derive(Debug, Clone)]
+pub(crate) struct BlockIndex {
+    nodes: Vec<Node>,
+    root: Option<usize>,
+    by_id: HashMap<BlockId, usize>,
+    /// The state of a xorshift generator for node priorities, seeded the same way every time so
+    /// stores behave deterministically.
+    seed: u64,
+}
+
+impl Default for BlockIndex {
+    fn default() -> Self {
+        BlockIndex::new()
+    }
+}
+
+impl BlockIndex {
+    pub(crate) fn new() -> BlockIndex {
+        BlockIndex {
+            nodes: vec![],
+            root: None,
+            by_id: HashMap::new(),
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    /// Rebuilds the index from every block in document order, with its live length.
+    pub(crate) fn rebuild(&mut self, blocks: impl IntoIterator<Item = (BlockId, usize)>) {
+        self.nodes.clear();
+        self.by_id.clear();
+        self.root = None;
+
+        for (block_id, live) in blocks {
+            self.insert_at(self.count(self.root), block_id, live);
+        }
+    }
+
+    /// The number of live elements in every block.
+    pub(crate) fn len(&self) -> usize {
+        self.total(self.root)
+    }
+
+    /// Inserts `block_id` directly after `previous`, or at the start if `previous` is `None`.
+    pub(crate) fn insert_after(
+        &mut self,
+        previous: Option<BlockId>,
+        block_id: BlockId,
+        live: usize,
+    ) {
+        let position = previous.map_or(0, |previous| self.position(previous) + 1);
+
+        self.insert_at(position, block_id, live);
+    }
+
+    /// Inserts `block_id` directly before `next`, or at the end if `next` is `None`.
+    pub(crate) fn insert_before(&mut self, next: Option<BlockId>, block_id: BlockId, live: usize) {
+        let position = match next {
+            Some(next) => self.position(next),
+            None => self.count(self.root),
+        };
+
+        self.insert_at(position, block_id, live);
+    }
+
+    /// Changes the live length of `block_id`, e.g. because it was deleted or split.
+    pub(crate) fn set_live(&mut self, block_id: BlockId, live: usize) {
+        let node = self.node(block_id);
+        self.nodes[node].live = live;
+
+        let mut current = Some(node);
+
+        while let Some(node) = current {
+            self.update(node);
+            current = self.nodes[node].parent;
+        }
+    }
+
+    /// The block containing the live element at `index`, and the element's offset within it.
+    pub(crate) fn find_live(&self, mut index: usize) -> Option<(BlockId, usize)> {
+        let mut current = self.root;
+
+        while let Some(node) = current {
+            let Node {
+                left, right, live, ..
+            } = self.nodes[node];
+            let before = self.total(left);
+
+            if index < before {
+                current = left;
+            } else if index - before < live {
+                return Some((self.nodes[node].block_id, index - before));
+            } else {
+                index -= before + live;
+                current = right;
+            }
+        }
+
+        None
+    }
+
+    /// The number of live elements in the blocks before `block_id`.
+    pub(crate) fn live_before(&self, block_id: BlockId) -> usize {
+        let node = self.node(block_id);
+        let mut before = self.total(self.nodes[node].left);
+        let mut child = node;
+
+        while let Some(parent) = self.nodes[child].parent {
+            if self.nodes[parent].right == Some(child) {
+                before += self.total(self.nodes[parent].left) + self.nodes[parent].live;
+            }
+
+            child = parent;
+        }
+
+        before
+    }
+
+    /// The number of blocks before `block_id`.
+    fn position(&self, block_id: BlockId) -> usize {
+        let node = self.node(block_id);
+        let mut before = self.count(self.nodes[node].left);
+        let mut child = node;
+
+        while let Some(parent) = self.nodes[child].parent {
+            if self.nodes[parent].right == Some(child) {
+                before += self.count(self.nodes[parent].left) + 1;
+            }
+
+            child = parent;
+        }
+
+        before
+    }
+
+    fn insert_at(&mut self, position: usize, block_id: BlockId, live: usize) {
+        let node = self.nodes.len();
+        let priority = self.next_priority();
+
+        self.nodes.push(Node {
+            block_id,
+            live,
+            priority,
+            parent: None,
+            left: None,
+            right: None,
+            count: 1,
+            total: live,
+        });
+        self.by_id.insert(block_id, node);
+
+        let (before, after) = self.split(self.root, position);
+        let left = self.merge(before, Some(node));
+        self.root = self.merge(left, after);
+
+        if let Some(root) = self.root {
+            self.nodes[root].parent = None;
+        }
+    }
+
+    /// Splits the tree under `node` into its first `count` nodes and the rest.
+    fn split(&mut self, node: Option<usize>, count: usize) -> (Option<usize>, Option<usize>) {
+        let Some(node) = node else {
+            return (None, None);
+        };
+
+        // Appends split off nothing, so needn't walk down and relink the right spine
+        if count >= self.nodes[node].count {
+            return (Some(node), None);
+        }
+
+        let left = self.nodes[node].left;
+        let left_count = self.count(left);
+
+        if count <= left_count {
+            let (before, after) = self.split(left, count);
+            self.set_left(node, after);
+
+            (before, Some(node))
+        } else {
+            let (before, after) = self.split(self.nodes[node].right, count - left_count - 1);
+            self.set_right(node, before);
+
+            (Some(node), after)
+        }
+    }
+
+    /// Joins two trees, with every node of `left` ending up before every node of `right`.
+    fn merge(&mut self, left: Option<usize>, right: Option<usize>) -> Option<usize> {
+        match (left, right) {
+            (None, tree) | (tree, None) => tree,
+            (Some(left), Some(right)) => {
+                if self.nodes[left].priority > self.nodes[right].priority {
+                    let merged = self.merge(self.nodes[left].right, Some(right));
+                    self.set_right(left, merged);
+
+                    Some(left)
+                } else {
+                    let merged = self.merge(Some(left), self.nodes[right].left);
+                    self.set_left(right, merged);
+
+                    Some(right)
+                }
+            }
+        }
+    }
+
+    fn set_left(&mut self, node: usize, child: Option<usize>) {
+        self.nodes[node].left = child;
+
+        if let Some(child) = child {
+            self.nodes[child].parent = Some(node);
+        }
+
+        self.update(node);
+    }
+
+    fn set_right(&mut self, node: usize, child: Option<usize>) {
+        self.nodes[node].right = child;
+
+        if let Some(child) = child {
+            self.nodes[child].parent = Some(node);
+        }
+
+        self.update(node);
+    }
+
+    /// Recomputes `node`'s subtree totals from its children.
+    fn update(&mut self, node: usize) {
+        let Node {
+            left, right, live, ..
+        } = self.nodes[node];
+
+        self.nodes[node].count = 1 + self.count(left) + self.count(right);
+        self.nodes[node].total = live + self.total(left) + self.total(right);
+    }
+
+    fn count(&self, node: Option<usize>) -> usize {
+        node.map_or(0, |node| self.nodes[node].count)
+    }
+
+    fn total(&self, node: Option<usize>) -> usize {
+        node.map_or(0, |node| self.nodes[node].total)
+    }
+
+    fn node(&self, block_id: BlockId) -> usize {
+        match self.by_id.get(&block_id) {
+            Some(node) => *node,
+            None => panic!("{:?} is not in the index", block_id),
+        }
+    }
+
+    fn next_priority(&mut self) -> u64 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+
+        self.seed
+    }
+}
@@ -0,0 +1,573 @@
+//! Checking a store for corruption, e.g. from a damaged snapshot, and rebuilding it from what
+//! can be recovered.
+
+use crate::block::{Block, Item};
+use crate::clock::{Clock, ClockRange};
+use crate::document::{BlockId, ClientId};
+use crate::moves::MoveStore;
+use crate::register::RegisterStore;
+use crate::storage::BlockStorage;
+use crate::store::Store;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Something wrong with a document's blocks, found by
+/// [`Document::check_integrity`](crate::Document::check_integrity).
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum IntegrityIssue {
+    /// The list starts at a block which doesn't exist or has a left neighbour, or doesn't start
+    /// at all although there are blocks.
+    InvalidStart(Option<BlockId>),
+    /// The list ends at a block which doesn't exist, has a right neighbour or isn't where walking
+    /// from the start finishes.
+    InvalidEnd(Option<BlockId>),
+    /// `block`'s left neighbour isn't the start of a block.
+    DanglingLeft { block: BlockId, left: BlockId },
+    /// `block`'s right neighbour isn't the start of a block.
+    DanglingRight { block: BlockId, right: BlockId },
+    /// Only one of `left` and `right` points at the other as its neighbour.
+    MismatchedLinks { left: BlockId, right: BlockId },
+    /// Walking right from the start comes back round to `block`.
+    Cycle(BlockId),
+    /// Walking right from the start never reaches `block`.
+    Unreachable(BlockId),
+    /// `block`'s origin isn't an element of the document.
+    MissingOrigin { block: BlockId, origin: BlockId },
+    /// A client's blocks skip the clocks `expected..actual`.
+    ClockGap {
+        client_id: ClientId,
+        expected: Clock,
+        actual: Clock,
+    },
+    /// A block starting at `block` repeats clocks of the client's earlier blocks.
+    OverlappingClocks(BlockId),
+    /// A live block holds a different number of values than its length, or a deleted one holds
+    /// values.
+    LengthMismatch {
+        block: BlockId,
+        length: usize,
+        values: usize,
+    },
+}
+
+/// What [`Document::repair`](crate::Document::repair) found and had to drop.
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct RepairReport {
+    /// The issues found before repairing.
+    pub issues: Vec<IntegrityIssue>,
+    /// The elements which couldn't be recovered, as ranges of each client's clocks. Peers which
+    /// still have them can send them again.
+    pub lost: Vec<(ClientId, ClockRange)>,
+}
+
+impl RepairReport {
+    /// Whether the document was already consistent, so nothing was changed.
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// The block starting at `id`, if there is one.
+fn block_at<T: Item, S: BlockStorage<T>>(store: &Store<T, S>, id: BlockId) -> Option<&Block<T>> {
+    store
+        .get_block(id)
+        .filter(|(_, offset)| *offset == 0)
+        .map(|(block, _)| block)
+}
+
+/// Every client's blocks, in client order so issues are reported in the same order each time.
+fn clients<T: Item, S: BlockStorage<T>>(store: &Store<T, S>) -> Vec<(ClientId, Vec<&Block<T>>)> {
+    let mut clients: Vec<(ClientId, Vec<&Block<T>>)> = store
+        .data
+        .clients()
+        .into_iter()
+        .map(|client_id| (client_id, store.data.iter(client_id).collect()))
+        .collect();
+    clients.sort_by_key(|(client_id, _)| *client_id);
+
+    clients
+}
+
+/// Finds everything wrong with `store`, without relying on any of it being right.
+pub(crate) fn check<T: Item, S: BlockStorage<T>>(store: &Store<T, S>) -> Vec<IntegrityIssue> {
+    let mut issues = vec![];
+    let clients = clients(store);
+
+    for (client_id, blocks) in &clients {
+        let mut expected = Clock::ZERO;
+
+        for block in blocks.iter() {
+            let block_id = BlockId::new(*client_id, block.id);
+
+            if block.id > expected {
+                issues.push(IntegrityIssue::ClockGap {
+                    client_id: *client_id,
+                    expected,
+                    actual: block.id,
+                });
+            } else if block.id < expected {
+                issues.push(IntegrityIssue::OverlappingClocks(block_id));
+            }
+
+            expected = expected.max(block.id.saturating_advance(block.length as u64));
+
+            let values = if block.deleted { 0 } else { block.length };
+            if block.value.len() != values {
+                issues.push(IntegrityIssue::LengthMismatch {
+                    block: block_id,
+                    length: block.length,
+                    values: block.value.len(),
+                });
+            }
+
+            for origin in [block.origin_left, block.origin_right]
+                .into_iter()
+                .flatten()
+            {
+                if store.get_block(origin).is_none() {
+                    issues.push(IntegrityIssue::MissingOrigin {
+                        block: block_id,
+                        origin,
+                    });
+                }
+            }
+
+            if let Some(left) = block.left {
+                match block_at(store, left) {
+                    None => issues.push(IntegrityIssue::DanglingLeft {
+                        block: block_id,
+                        left,
+                    }),
+                    Some(neighbour) if neighbour.right != Some(block_id) => {
+                        issues.push(IntegrityIssue::MismatchedLinks {
+                            left,
+                            right: block_id,
+                        })
+                    }
+                    Some(_) => {}
+                }
+            }
+
+            if let Some(right) = block.right {
+                match block_at(store, right) {
+                    None => issues.push(IntegrityIssue::DanglingRight {
+                        block: block_id,
+                        right,
+                    }),
+                    Some(neighbour) if neighbour.left != Some(block_id) => {
+                        issues.push(IntegrityIssue::MismatchedLinks {
+                            left: block_id,
+                            right,
+                        })
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+    }
+
+    let (start, end) = store.ends();
+    let has_blocks = clients.iter().any(|(_, blocks)| !blocks.is_empty());
+
+    let start_is_valid = match start {
+        Some(start) => block_at(store, start).is_some_and(|block| block.left.is_none()),
+        None => !has_blocks,
+    };
+    if !start_is_valid {
+        issues.push(IntegrityIssue::InvalidStart(start));
+    }
+
+    let mut walked = HashSet::new();
+    let mut last = None;
+    let mut current = start;
+    let mut finished = true;
+
+    while let Some(block_id) = current {
+        if !walked.insert(block_id) {
+            issues.push(IntegrityIssue::Cycle(block_id));
+            finished = false;
+            break;
+        }
+
+        let Some(block) = block_at(store, block_id) else {
+            // Already reported as a dangling pointer or an invalid start
+            walked.remove(&block_id);
+            finished = false;
+            break;
+        };
+
+        last = Some(block_id);
+        current = block.right;
+    }
+
+    let end_is_valid = match end {
+        Some(end) => {
+            block_at(store, end).is_some_and(|block| block.right.is_none())
+                && (!finished || last == Some(end))
+        }
+        None => !has_blocks,
+    };
+    if !end_is_valid {
+        issues.push(IntegrityIssue::InvalidEnd(end));
+    }
+
+    for (client_id, blocks) in &clients {
+        for block in blocks.iter() {
+            let block_id = BlockId::new(*client_id, block.id);
+
+            if !walked.contains(&block_id) {
+                issues.push(IntegrityIssue::Unreachable(block_id));
+            }
+        }
+    }
+
+    issues
+}
+
+/// Like [`check`], for the stores of a document with named roots. Each holds only some of every
+/// client's clocks, so gaps are only reported where no store has the missing clocks.
+pub(crate) fn check_roots<'a, T: Item + 'a, S: BlockStorage<T> + 'a>(
+    stores: impl Iterator<Item = &'a Store<T, S>>,
+) -> Vec<IntegrityIssue> {
+    let mut issues = vec![];
+    let mut clocks: HashMap<ClientId, Vec<ClockRange>> = HashMap::new();
+
+    for store in stores {
+        issues.extend(
+            check(store)
+                .into_iter()
+                .filter(|issue| !matches!(issue, IntegrityIssue::ClockGap { .. })),
+        );
+
+        for (client_id, blocks) in clients(store) {
+            clocks
+                .entry(client_id)
+                .or_default()
+                .extend(blocks.iter().map(|block| {
+                    ClockRange::new(block.id, block.id.saturating_advance(block.length as u64))
+                }));
+        }
+    }
+
+    let mut clocks: Vec<(ClientId, Vec<ClockRange>)> = clocks.into_iter().collect();
+    clocks.sort_by_key(|(client_id, _)| *client_id);
+
+    for (client_id, mut ranges) in clocks {
+        ranges.sort_by_key(|range| range.start);
+        let mut expected = Clock::ZERO;
+
+        for range in ranges {
+            if range.start > expected {
+                issues.push(IntegrityIssue::ClockGap {
+                    client_id,
+                    expected,
+                    actual: range.start,
+                });
+            }
+
+            expected = expected.max(range.end);
+        }
+    }
+
+    issues
+}
+
+/// Builds a consistent copy of `store` by integrating its blocks again from their origins, the
+/// way a replica receiving them would, so linkage is recovered whatever state it was in.
+///
+/// Each client's blocks are kept up to the first one which can't be: one after a gap in its
+/// clocks, one missing values, or one whose origins were never recovered. Duplicated elements
+/// are kept once. With `shared_clocks`, the store is one of a document's roots, whose clocks
+/// skip those of the other roots, so gaps are allowed. Returns the new store along with the
+/// elements which were lost.
+#[allow(clippy::type_complexity)]
+pub(crate) fn rebuild<T: Item, S: BlockStorage<T>>(
+    store: &mut Store<T, S>,
+    shared_clocks: bool,
+) -> (Store<T, S>, Vec<(ClientId, ClockRange)>) {
+    let mut rebuilt = Store::from_parts(store.client_id, None, None, HashMap::new(), vec![]);
+    rebuilt.min_local_clock = store.min_local_clock;
+    rebuilt.priority = store.priority;
+    rebuilt.resolver = store.resolver.clone();
+    rebuilt.deleted_values = store.deleted_values.take();
+    #[cfg(feature = "metrics")]
+    {
+        rebuilt.metrics = store.metrics.clone();
+    }
+
+    let clients = clients(store);
+    let mut queues: Vec<(ClientId, VecDeque<Block<T>>)> = vec![];
+
+    for (client_id, blocks) in &clients {
+        let mut blocks: Vec<Block<T>> = blocks.iter().map(|block| (*block).clone()).collect();
+        blocks.sort_by_key(|block| block.id);
+
+        let mut queue = VecDeque::new();
+        let mut expected = Clock::ZERO;
+
+        for mut block in blocks {
+            let end = block.id.saturating_advance(block.length as u64);
+
+            if end <= expected {
+                // Every element is already queued
+                continue;
+            }
+
+            let skips_clocks = block.id > expected && !shared_clocks;
+
+            if skips_clocks
+                || block.id < expected
+                || (!block.deleted && block.value.len() < block.length)
+            {
+                break;
+            }
+
+            if block.deleted {
+                block.value.clear();
+            } else {
+                block.value.truncate(block.length);
+            }
+
+            block.left = None;
+            block.right = None;
+            expected = end;
+            queue.push_back(block);
+        }
+
+        queues.push((*client_id, queue));
+    }
+
+    // Integrate whichever blocks have their origins, until none of those left do
+    let mut progress = true;
+    while progress {
+        progress = false;
+
+        for (client_id, queue) in &mut queues {
+            while let Some(block) = queue.front() {
+                let ready = [block.origin_left, block.origin_right]
+                    .into_iter()
+                    .flatten()
+                    .all(|origin| rebuilt.get_block(origin).is_some());
+
+                if !ready {
+                    break;
+                }
+
+                let block = queue.pop_front().expect("front was just checked");
+                let expected = match shared_clocks {
+                    true => block.id,
+                    false => rebuilt.next_clock(*client_id),
+                };
+
+                if rebuilt
+                    .integrate(*client_id, expected, vec![block])
+                    .is_err()
+                {
+                    queue.clear();
+                    break;
+                }
+
+                progress = true;
+            }
+        }
+    }
+
+    let moves = store
+        .moves
+        .moves()
+        .into_iter()
+        .filter(|mv| {
+            [mv.start, mv.end, mv.marker]
+                .into_iter()
+                .all(|id| rebuilt.get_block(id).is_some())
+        })
+        .collect();
+    rebuilt.moves = MoveStore::from_moves(moves);
+    rebuilt.registers = RegisterStore::from_writes(
+        store
+            .registers
+            .writes()
+            .into_iter()
+            .filter(|write| {
+                rebuilt
+                    .get_block(write.element)
+                    .is_some_and(|(block, _)| !block.deleted)
+            })
+            .collect(),
+    );
+    rebuilt.reorder();
+
+    let mut lost: Vec<(ClientId, ClockRange)> = vec![];
+    for (client_id, blocks) in &clients {
+        let recovered = rebuilt.next_clock(*client_id);
+        let mut ranges: Vec<ClockRange> = blocks
+            .iter()
+            .map(|block| {
+                let end = block.id.saturating_advance(block.length as u64);
+
+                ClockRange::new(block.id.max(recovered), end)
+            })
+            .filter(|range| !range.is_empty())
+            .collect();
+        ranges.sort_by_key(|range| range.start);
+
+        for range in ranges {
+            if let Some((last_client, last)) = lost.last_mut() {
+                if last_client == client_id && range.start <= last.end {
+                    last.end = last.end.max(range.end);
+                    continue;
+                }
+            }
+
+            lost.push((*client_id, range));
+        }
+    }
+
+    (rebuilt, lost)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::Block;
+    use crate::clock::{Clock, ClockRange};
+    use crate::integrity::IntegrityIssue;
+    use crate::{BlockId, ClientId, Document, Update};
+
+    /// A document restored from a snapshot of `document` after `corrupt` has edited its blocks.
+    fn corrupted(
+        document: &Document<char>,
+        corrupt: impl FnOnce(&mut Vec<(ClientId, Vec<Block<char>>)>),
+    ) -> Document<char> {
+        let mut snapshot = document.snapshot();
+        corrupt(&mut snapshot.blocks);
+
+        Document::restore(snapshot)
+    }
+
+    fn blocks_of(
+        blocks: &mut [(ClientId, Vec<Block<char>>)],
+        client_id: ClientId,
+    ) -> &mut Vec<Block<char>> {
+        &mut blocks
+            .iter_mut()
+            .find(|(client, _)| *client == client_id)
+            .unwrap()
+            .1
+    }
+
+    /// "ab" from client 1, then "x" from client 2 between them, so client 1's block is split.
+    fn interleaved() -> Document<char> {
+        let mut first = Document::with_client_id(1);
+        first.extend(['a', 'b']);
+
+        let mut document = Document::with_client_id(2);
+        Update::from_document(&first).apply(&mut document).unwrap();
+        document.insert(1, 'x');
+        document.push('y');
+
+        document
+    }
+
+    fn assert_consistent(document: &Document<char>) {
+        assert_eq!(document.check_integrity(), vec![]);
+        document.store.debug_assert_store_consistent();
+        assert_eq!(document.iter().count(), document.len());
+    }
+
+    #[test]
+    fn a_consistent_document_has_no_issues() {
+        let mut document = interleaved();
+        document.remove(0);
+
+        assert_eq!(document.check_integrity(), vec![]);
+        assert!(document.repair().is_clean());
+        assert_eq!(document.iter().collect::<String>(), "xby");
+    }
+
+    #[test]
+    fn relinks_a_cut_right_pointer() {
+        let document = corrupted(&interleaved(), |blocks| {
+            blocks_of(blocks, 2)[0].right = None;
+        });
+
+        let issues = document.check_integrity();
+        assert!(issues.contains(&IntegrityIssue::MismatchedLinks {
+            left: BlockId::new(2, 0),
+            right: BlockId::new(1, 1),
+        }));
+        assert!(issues.contains(&IntegrityIssue::Unreachable(BlockId::new(1, 1))));
+
+        let mut document = document;
+        let report = document.repair();
+        assert!(!report.is_clean());
+        assert_eq!(report.lost, vec![]);
+
+        assert_consistent(&document);
+        assert_eq!(document.iter().collect::<String>(), "axby");
+    }
+
+    #[test]
+    fn drops_a_duplicated_block() {
+        let document = corrupted(&interleaved(), |blocks| {
+            let blocks = blocks_of(blocks, 1);
+            blocks.insert(1, blocks[0].clone());
+        });
+
+        assert!(document
+            .check_integrity()
+            .contains(&IntegrityIssue::OverlappingClocks(BlockId::new(1, 0))));
+
+        let mut document = document;
+        let report = document.repair();
+        assert_eq!(report.lost, vec![]);
+
+        assert_consistent(&document);
+        assert_eq!(document.iter().collect::<String>(), "axby");
+    }
+
+    #[test]
+    fn quarantines_blocks_whose_clocks_were_swapped() {
+        let document = corrupted(&interleaved(), |blocks| {
+            let blocks = blocks_of(blocks, 1);
+            blocks[0].id = Clock::new(1);
+            blocks[1].id = Clock::new(0);
+        });
+
+        let issues = document.check_integrity();
+        assert!(issues.contains(&IntegrityIssue::OverlappingClocks(BlockId::new(1, 0))));
+        assert!(issues
+            .iter()
+            .any(|issue| matches!(issue, IntegrityIssue::MismatchedLinks { .. })));
+
+        let mut document = document;
+        let report = document.repair();
+
+        // Once sorted, client 1's first block names itself as its left origin, so neither of
+        // its blocks can be placed, nor the "x" inserted between them
+        assert_eq!(
+            report.lost,
+            vec![(1, ClockRange::from(0..2)), (2, ClockRange::from(0..2))]
+        );
+        assert_consistent(&document);
+        assert_eq!(document.len(), 0);
+    }
+
+    #[test]
+    fn keeps_what_comes_before_a_missing_block() {
+        let mut document = corrupted(&interleaved(), |blocks| {
+            blocks_of(blocks, 2).remove(0);
+        });
+
+        assert!(document
+            .check_integrity()
+            .contains(&IntegrityIssue::ClockGap {
+                client_id: 2,
+                expected: Clock::ZERO,
+                actual: Clock::new(1),
+            }));
+
+        let report = document.repair();
+        assert_eq!(report.lost, vec![(2, ClockRange::from(1..2))]);
+        assert_consistent(&document);
+        assert_eq!(document.iter().collect::<String>(), "ab");
+    }
+}
@@ -0,0 +1,78 @@
+mod awareness;
+mod binary;
+mod block;
+mod block_range;
+mod censor;
+mod checkpoint;
+mod clock;
+mod conflict;
+mod delete_set;
+mod delta;
+mod document;
+mod encoding;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod index;
+mod integrity;
+mod limits;
+mod map;
+mod marks;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod moves;
+#[cfg(feature = "net")]
+pub mod net;
+mod observer;
+mod position;
+mod register;
+mod root;
+mod shared;
+#[cfg(test)]
+mod sim;
+mod snapshot;
+mod stats;
+mod storage;
+mod store;
+mod sync;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod text;
+mod transaction;
+mod undo;
+mod update;
+mod version;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use awareness::Awareness;
+pub use binary::BinaryDocument;
+pub use block::Item;
+pub use block_range::BlockRange;
+pub use checkpoint::Checkpoint;
+pub use clock::{Clock, ClockRange};
+pub use conflict::{ClientIdOrder, ConflictResolver, InsertCandidate, PriorityOrder};
+pub use delete_set::DeleteSet;
+pub use delta::{Delta, DeltaOp};
+pub use document::{
+    BlockId, ClientId, ClientIdPolicy, ClockVector, Document, ParseBlockIdError, QueueOutcome,
+    StateVector,
+};
+pub use encoding::{DecodeError, EncodeError, FORMAT_VERSION};
+pub use integrity::{IntegrityIssue, RepairReport};
+pub use limits::{Limit, Limits};
+#[cfg(feature = "metrics")]
+pub use metrics::{Counters, CountersSink, MetricEvent, MetricsSink, NoopSink};
+pub use observer::{ChangeEvent, DeleteEvent, Deletion, Insertion, SubscriptionId};
+pub use position::{Bias, Position};
+pub use root::RootHandle;
+pub use shared::{ChangeNotification, SharedDocument};
+pub use snapshot::Snapshot;
+pub use stats::{ClientStats, DocumentStats};
+pub use storage::{BlockStorage, PagedArena, VecStorage};
+pub use store::BlockView;
+pub use sync::{Message, SyncProtocol};
+pub use text::{TextDocument, TextUpdateError};
+pub use transaction::Transaction;
+pub use undo::UndoManager;
+pub use update::{ApplyEncodedError, ApplyError, ApplyOutcome, MergeError, Update, UpdateBlock};
+pub use version::VersionView;
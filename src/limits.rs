@@ -0,0 +1,92 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+/// Bounds on what a [`Document`](crate::Document) accepts from remote updates, so an update from
+/// an untrusted peer can't make it use unbounded memory. Updates which would break a limit are
+/// rejected before anything is allocated for them.
+///
+/// The defaults are generous enough not to get in the way of ordinary documents.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Limits {
+    max_elements: u64,
+    max_blocks_per_client: usize,
+    max_update_bytes: usize,
+    max_deleted_length: u64,
+}
+
+/// A single limit from [`Limits`].
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum Limit {
+    /// The number of elements in the document, deleted ones included.
+    Elements,
+    /// The number of blocks from any one client.
+    BlocksPerClient,
+    /// The size of an encoded update.
+    UpdateBytes,
+    /// The length of a single run of deleted elements in an update.
+    DeletedLength,
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Elements => write!(f, "total elements"),
+            Limit::BlocksPerClient => write!(f, "blocks per client"),
+            Limit::UpdateBytes => write!(f, "encoded update size"),
+            Limit::DeletedLength => write!(f, "deleted run length"),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_elements: 1 << 32,
+            max_blocks_per_client: 1 << 24,
+            max_update_bytes: 1 << 30,
+            max_deleted_length: 1 << 32,
+        }
+    }
+}
+
+impl Limits {
+    /// Sets how many elements the document may hold, deleted ones included. Defaults to 2^32.
+    pub fn with_max_elements(mut self, max_elements: u64) -> Limits {
+        self.max_elements = max_elements;
+        self
+    }
+
+    /// Sets how many blocks the document may hold from any one client. Defaults to 2^24.
+    pub fn with_max_blocks_per_client(mut self, max_blocks_per_client: usize) -> Limits {
+        self.max_blocks_per_client = max_blocks_per_client;
+        self
+    }
+
+    /// Sets how large an encoded update may be. Defaults to 1 GiB.
+    pub fn with_max_update_bytes(mut self, max_update_bytes: usize) -> Limits {
+        self.max_update_bytes = max_update_bytes;
+        self
+    }
+
+    /// Sets how long a run of deleted elements an update may declare. Defaults to 2^32.
+    pub fn with_max_deleted_length(mut self, max_deleted_length: u64) -> Limits {
+        self.max_deleted_length = max_deleted_length;
+        self
+    }
+
+    pub fn max_elements(&self) -> u64 {
+        self.max_elements
+    }
+
+    pub fn max_blocks_per_client(&self) -> usize {
+        self.max_blocks_per_client
+    }
+
+    pub fn max_update_bytes(&self) -> usize {
+        self.max_update_bytes
+    }
+
+    pub fn max_deleted_length(&self) -> u64 {
+        self.max_deleted_length
+    }
+}
@@ -1,22 +1,14 @@
-mod block;
-mod delete_set;
-mod document;
-mod store;
-mod update;
-
-use document::Document;
+use yata_impl::Document;
 
 fn main() {
-    // let mut document = Document::new();
-    //
-    // document.store.append(document.client_id, "H");
-    // document.store.append(document.client_id + 1, "el");
-    // document.store.append(document.client_id, "l");
-    // document.store.append(document.client_id + 1, "o");
-    //
-    // // println!("{:?} - {:?}", document.store.get_value(), document);
-    //
-    // for value in document.store.get_value() {
-    //     print!("{}", value)
-    // }
+    let mut document = Document::new();
+
+    document.push("H".to_owned());
+    document.push("l".to_owned());
+    document.insert(1, "el".to_owned());
+    document.push("o".to_owned());
+
+    for index in 0..document.len() {
+        print!("{}", document.get(index).unwrap())
+    }
 }
@@ -0,0 +1,235 @@
+use crate::block::Item;
+use crate::clock::Clock;
+use crate::document::ClientId;
+use bincode::{Decode, Encode};
+use std::collections::HashMap;
+
+/// The latest write to a single map key. `value` is `None` once the key has been removed, so the
+/// removal can still be ordered against concurrent writes.
+#[derive(Eq, PartialEq, Clone, Debug, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct MapEntry<T: Item> {
+    pub(crate) key: String,
+    pub(crate) value: Option<T>,
+    /// A Lamport clock, separate from the sequence clocks, ordering writes to the map.
+    pub(crate) clock: Clock,
+    pub(crate) client_id: ClientId,
+}
+
+impl<T: Item> MapEntry<T> {
+    /// Whether this write should replace `other`. Later clocks win, with ties between concurrent
+    /// writes broken by the larger client id.
+    fn wins_over(&self, other: &MapEntry<T>) -> bool {
+        (self.clock, self.client_id) > (other.clock, other.client_id)
+    }
+}
+
+/// A last-writer-wins map from strings to values, stored alongside a document's sequence.
+///
+/// The map is synced by sending every entry, removals included, or only those written since the
+/// last local update, which replicas merge key by key.
+/// Merging is idempotent and order independent, so the map doesn't take part in the sequence's
+/// state vectors.
+#[derive(Debug, Default)]
+pub(crate) struct MapStore<T: Item> {
+    entries: HashMap<String, MapEntry<T>>,
+    /// The largest clock seen, so local writes are ordered after everything already merged.
+    clock: Clock,
+}
+
+impl<T: Item> MapStore<T> {
+    pub(crate) fn new() -> MapStore<T> {
+        MapStore {
+            entries: HashMap::new(),
+            clock: Clock::ZERO,
+        }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&T> {
+        self.entries.get(key).and_then(|entry| entry.value.as_ref())
+    }
+
+    /// The latest write to `key`, removals included.
+    pub(crate) fn entry(&self, key: &str) -> Option<&MapEntry<T>> {
+        self.entries.get(key)
+    }
+
+    /// Sets `key` to `value` as a local write by `client_id`, returning the previous value.
+    pub(crate) fn set(&mut self, client_id: ClientId, key: String, value: T) -> Option<T> {
+        self.write(client_id, key, Some(value))
+    }
+
+    /// Removes `key` as a local write by `client_id`, returning the previous value.
+    pub(crate) fn remove(&mut self, client_id: ClientId, key: &str) -> Option<T> {
+        if !self.entries.contains_key(key) {
+            return None;
+        }
+
+        self.write(client_id, key.to_owned(), None)
+    }
+
+    fn write(&mut self, client_id: ClientId, key: String, value: Option<T>) -> Option<T> {
+        self.clock.tick();
+
+        let entry = MapEntry {
+            key: key.clone(),
+            value,
+            clock: self.clock,
+            client_id,
+        };
+
+        self.entries
+            .insert(key, entry)
+            .and_then(|entry| entry.value)
+    }
+
+    /// Merges a write made by any replica, keeping whichever write to its key wins.
+    pub(crate) fn merge(&mut self, entry: MapEntry<T>) {
+        self.clock.observe(entry.clock);
+
+        match self.entries.get(&entry.key) {
+            Some(existing) if !entry.wins_over(existing) => {}
+            _ => {
+                self.entries.insert(entry.key.clone(), entry);
+            }
+        }
+    }
+
+    /// The number of keys with a value.
+    pub(crate) fn len(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|entry| entry.value.is_some())
+            .count()
+    }
+
+    /// Every entry, removals included, ordered by key.
+    pub(crate) fn entries(&self) -> Vec<MapEntry<T>> {
+        let mut entries: Vec<MapEntry<T>> = self.entries.values().cloned().collect();
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+
+        entries
+    }
+
+    pub(crate) fn from_entries(entries: Vec<MapEntry<T>>) -> MapStore<T> {
+        let mut map = MapStore::new();
+
+        for entry in entries {
+            map.merge(entry);
+        }
+
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Document, Update};
+
+    fn sync(from: &Document<String>, to: &mut Document<String>) {
+        Update::from_document_since(from, to.state_vector().as_ref())
+            .apply(to)
+            .unwrap();
+    }
+
+    #[test]
+    fn concurrent_sets_converge_on_the_same_winner() {
+        let mut doc1 = Document::with_client_id(1);
+        let mut doc2 = Document::with_client_id(2);
+
+        doc1.map_set("colour", "red".to_owned());
+        doc2.map_set("colour", "blue".to_owned());
+
+        sync(&doc1, &mut doc2);
+        sync(&doc2, &mut doc1);
+
+        // Both writes have the same clock, so the larger client id wins
+        assert_eq!(doc1.map_get("colour"), Some(&"blue".to_owned()));
+        assert_eq!(doc2.map_get("colour"), Some(&"blue".to_owned()));
+    }
+
+    #[test]
+    fn later_writes_win_regardless_of_client_id() {
+        let mut doc1 = Document::with_client_id(1);
+        let mut doc2 = Document::with_client_id(2);
+
+        doc2.map_set("colour", "blue".to_owned());
+        sync(&doc2, &mut doc1);
+        doc1.map_set("colour", "red".to_owned());
+        sync(&doc1, &mut doc2);
+
+        assert_eq!(doc2.map_get("colour"), Some(&"red".to_owned()));
+    }
+
+    #[test]
+    fn removals_beat_older_sets_but_lose_to_newer_ones() {
+        let mut doc1 = Document::with_client_id(1);
+        let mut doc2 = Document::with_client_id(2);
+
+        doc1.map_set("a", "1".to_owned());
+        doc1.map_set("b", "1".to_owned());
+        sync(&doc1, &mut doc2);
+
+        // doc2 removes "a" after seeing it set
+        assert_eq!(doc2.map_remove("a"), Some("1".to_owned()));
+
+        // doc1 removes "b", but doc2 has since set it again
+        doc1.map_remove("b");
+        doc2.map_set("b", "2".to_owned());
+        doc2.map_set("b", "3".to_owned());
+
+        sync(&doc1, &mut doc2);
+        sync(&doc2, &mut doc1);
+
+        for doc in [&doc1, &doc2] {
+            assert_eq!(doc.map_get("a"), None);
+            assert_eq!(doc.map_get("b"), Some(&"3".to_owned()));
+            assert_eq!(doc.map_len(), 1);
+        }
+    }
+
+    #[test]
+    fn transaction_updates_carry_the_map_writes_made_in_them() {
+        let mut doc1 = Document::with_client_id(1);
+        let mut doc2 = Document::with_client_id(2);
+
+        doc1.map_set("a", "1".to_owned());
+        sync(&doc1, &mut doc2);
+
+        // Written before the transaction, so left for the next local update
+        doc1.map_set("c", "3".to_owned());
+        let update = doc1.transact(|transaction| {
+            transaction.map_set("b", "2".to_owned());
+            transaction.map_remove("a");
+        });
+        update.apply(&mut doc2).unwrap();
+
+        assert_eq!(doc2.map_get("a"), None);
+        assert_eq!(doc2.map_get("b"), Some(&"2".to_owned()));
+        assert_eq!(doc2.map_get("c"), None);
+
+        doc1.take_local_update(doc2.state_vector().as_ref())
+            .apply(&mut doc2)
+            .unwrap();
+        assert_eq!(doc2.map_get("c"), Some(&"3".to_owned()));
+    }
+
+    #[test]
+    fn map_survives_snapshots() {
+        let mut doc = Document::with_client_id(1);
+        doc.map_set("a", "1".to_owned());
+        doc.map_set("b", "2".to_owned());
+        doc.map_remove("a");
+
+        let mut restored = Document::restore(doc.snapshot());
+
+        assert_eq!(restored.map_get("b"), Some(&"2".to_owned()));
+        assert_eq!(restored.map_get("a"), None);
+
+        // The restored map's clock carries on, so its writes beat the ones it was restored with
+        restored.map_set("b", "3".to_owned());
+        sync(&restored, &mut doc);
+
+        assert_eq!(doc.map_get("b"), Some(&"3".to_owned()));
+    }
+}
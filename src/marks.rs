@@ -0,0 +1,106 @@
+use crate::clock::Clock;
+use crate::document::{BlockId, ClientId};
+use bincode::{Decode, Encode};
+use std::collections::HashSet;
+
+/// A key and value attached to the elements from `start` to `end` inclusive, such as a bold flag
+/// or a link target.
+///
+/// The range is anchored to the elements themselves rather than to indices, so it covers
+/// anything later inserted between them, but not text inserted before `start` or after `end`.
+#[derive(Eq, PartialEq, Clone, Debug, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Mark {
+    pub(crate) start: BlockId,
+    pub(crate) end: BlockId,
+    pub(crate) key: String,
+    pub(crate) value: Vec<u8>,
+    /// A Lamport clock ordering marks, separate from the sequence clocks. Together with the
+    /// client id it also identifies the mark.
+    pub(crate) clock: Clock,
+    pub(crate) client_id: ClientId,
+}
+
+impl Mark {
+    /// Whether this mark should take precedence over `other` where they overlap. Later clocks
+    /// win, with ties between concurrent marks broken by the larger client id.
+    pub(crate) fn wins_over(&self, other: &Mark) -> bool {
+        (self.clock, self.client_id) > (other.clock, other.client_id)
+    }
+}
+
+/// Every mark added to a document. Marks are only ever added, so replicas merge them by taking
+/// the union, and where marks with the same key overlap the winner is picked when reading.
+#[derive(Debug, Default)]
+pub(crate) struct MarkStore {
+    marks: Vec<Mark>,
+    /// The clock and client id of every mark, to skip marks which are already known.
+    ids: HashSet<(Clock, ClientId)>,
+    /// The largest clock seen, so local marks are ordered after everything already merged.
+    clock: Clock,
+}
+
+impl MarkStore {
+    pub(crate) fn new() -> MarkStore {
+        MarkStore {
+            marks: vec![],
+            ids: HashSet::new(),
+            clock: Clock::ZERO,
+        }
+    }
+
+    /// Adds a mark made locally by `client_id`, returning it.
+    pub(crate) fn add(
+        &mut self,
+        client_id: ClientId,
+        start: BlockId,
+        end: BlockId,
+        key: String,
+        value: Vec<u8>,
+    ) -> &Mark {
+        self.clock.tick();
+        self.ids.insert((self.clock, client_id));
+
+        self.marks.push(Mark {
+            start,
+            end,
+            key,
+            value,
+            clock: self.clock,
+            client_id,
+        });
+
+        self.marks.last().unwrap()
+    }
+
+    /// Merges a mark made by any replica, ignoring it if it's already known.
+    pub(crate) fn merge(&mut self, mark: Mark) {
+        self.clock.observe(mark.clock);
+
+        if self.ids.insert((mark.clock, mark.client_id)) {
+            self.marks.push(mark);
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Mark> {
+        self.marks.iter()
+    }
+
+    /// Every mark, ordered by clock then client id, so equal stores list them identically.
+    pub(crate) fn marks(&self) -> Vec<Mark> {
+        let mut marks = self.marks.clone();
+        marks.sort_by_key(|mark| (mark.clock, mark.client_id));
+
+        marks
+    }
+
+    pub(crate) fn from_marks(marks: Vec<Mark>) -> MarkStore {
+        let mut store = MarkStore::new();
+
+        for mark in marks {
+            store.merge(mark);
+        }
+
+        store
+    }
+}
@@ -0,0 +1,250 @@
+//! Hooks for measuring how much work a [`Document`](crate::Document) does, e.g. on a sync server.
+//! Enabled by the `metrics` feature; without it none of this is compiled in.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Something a document did, passed to its [`MetricsSink`].
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum MetricEvent {
+    /// An update with `blocks` blocks was applied in `duration`, as measured by
+    /// [`MetricsSink::now`]. `bytes` is the size of its encoding, if it was applied straight from
+    /// that with [`Document::apply_encoded`](crate::Document::apply_encoded).
+    UpdateApplied {
+        blocks: usize,
+        bytes: Option<usize>,
+        duration: Duration,
+    },
+    /// A block was integrated after scanning `length` blocks for concurrent inserts at the same
+    /// position.
+    ConflictScan { length: usize },
+    /// Garbage collection finished, leaving `count` tombstones.
+    Tombstones { count: usize },
+    /// The number of updates queued by [`Document::apply_or_queue`](crate::Document::apply_or_queue)
+    /// changed to `depth`.
+    PendingUpdates { depth: usize },
+}
+
+/// Receives a document's [`MetricEvent`]s. Set with
+/// [`Document::with_metrics_sink`](crate::Document::with_metrics_sink).
+///
+/// Events are recorded while the document is being changed, so `record` should be quick.
+pub trait MetricsSink: fmt::Debug + Send + Sync {
+    fn record(&self, event: MetricEvent);
+
+    /// The clock durations are measured with. Override it to use another, e.g. a fake one in
+    /// tests.
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Ignores every event. This is the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopSink;
+
+impl MetricsSink for NoopSink {
+    fn record(&self, _: MetricEvent) {}
+}
+
+/// Totals of the events a [`CountersSink`] has seen.
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct Counters {
+    pub updates_applied: usize,
+    pub blocks_applied: usize,
+    /// The size of the updates applied from their encoding.
+    pub bytes_applied: usize,
+    pub integration_time: Duration,
+    pub conflict_scans: usize,
+    /// The number of blocks scanned over every conflict scan.
+    pub conflict_scan_length: usize,
+    /// The number of tombstones after the latest garbage collection.
+    pub tombstones: usize,
+    /// The latest number of queued updates.
+    pub pending_updates: usize,
+}
+
+/// Adds up the events it sees, e.g. for tests.
+#[derive(Debug, Default)]
+pub struct CountersSink {
+    counters: Mutex<Counters>,
+}
+
+impl CountersSink {
+    pub fn new() -> CountersSink {
+        CountersSink::default()
+    }
+
+    pub fn counters(&self) -> Counters {
+        self.counters
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+}
+
+impl MetricsSink for CountersSink {
+    fn record(&self, event: MetricEvent) {
+        let mut counters = self
+            .counters
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        match event {
+            MetricEvent::UpdateApplied {
+                blocks,
+                bytes,
+                duration,
+            } => {
+                counters.updates_applied += 1;
+                counters.blocks_applied += blocks;
+                counters.bytes_applied += bytes.unwrap_or(0);
+                counters.integration_time += duration;
+            }
+            MetricEvent::ConflictScan { length } => {
+                counters.conflict_scans += 1;
+                counters.conflict_scan_length += length;
+            }
+            MetricEvent::Tombstones { count } => counters.tombstones = count,
+            MetricEvent::PendingUpdates { depth } => counters.pending_updates = depth,
+        }
+    }
+}
+
+/// Times an update being applied, for [`MetricEvent::UpdateApplied`].
+pub(crate) struct ApplyTimer {
+    sink: Arc<dyn MetricsSink>,
+    blocks: usize,
+    bytes: Option<usize>,
+    start: Instant,
+}
+
+impl ApplyTimer {
+    pub(crate) fn start(
+        sink: &Arc<dyn MetricsSink>,
+        blocks: usize,
+        bytes: Option<usize>,
+    ) -> ApplyTimer {
+        ApplyTimer {
+            sink: sink.clone(),
+            blocks,
+            bytes,
+            start: sink.now(),
+        }
+    }
+
+    /// Records the update as applied.
+    pub(crate) fn finish(self) {
+        self.sink.record(MetricEvent::UpdateApplied {
+            blocks: self.blocks,
+            bytes: self.bytes,
+            duration: self.sink.now().saturating_duration_since(self.start),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::{CountersSink, MetricEvent, MetricsSink};
+    use crate::{Document, Update};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    /// Counts like [`CountersSink`], with a clock which moves on a millisecond each time it's
+    /// read.
+    #[derive(Debug)]
+    struct TickingSink {
+        counters: CountersSink,
+        epoch: Instant,
+        ticks: AtomicU64,
+    }
+
+    impl MetricsSink for TickingSink {
+        fn record(&self, event: MetricEvent) {
+            self.counters.record(event);
+        }
+
+        fn now(&self) -> Instant {
+            self.epoch + Duration::from_millis(self.ticks.fetch_add(1, Ordering::SeqCst))
+        }
+    }
+
+    #[test]
+    fn counts_applied_blocks_and_conflict_scans() {
+        let mut base = Document::with_client_id(1);
+        base.extend(['a', 'd']);
+
+        let mut remote = Document::with_client_id(2);
+        Update::from_document(&base).apply(&mut remote).unwrap();
+        remote.insert(1, 'c');
+
+        let sink = Arc::new(CountersSink::new());
+        let mut document = Document::with_client_id(3).with_metrics_sink(sink.clone());
+        Update::from_document(&base).apply(&mut document).unwrap();
+        document.insert(1, 'b');
+
+        let bytes = Update::from_document_since(&remote, base.state_vector().as_ref())
+            .encode()
+            .unwrap();
+        document.apply_encoded(&bytes).unwrap();
+
+        let counters = sink.counters();
+        assert_eq!(counters.updates_applied, 2);
+        // One block for "ad", then one for "c"
+        assert_eq!(counters.blocks_applied, 2);
+        // Only the second was applied from its encoding
+        assert_eq!(counters.bytes_applied, bytes.len());
+        assert!(counters.conflict_scans >= 1);
+        // "c" was scanned past "b", which has the same origins
+        assert!(counters.conflict_scan_length >= 1);
+    }
+
+    #[test]
+    fn measures_integration_with_the_sinks_clock() {
+        let sink = Arc::new(TickingSink {
+            counters: CountersSink::new(),
+            epoch: Instant::now(),
+            ticks: AtomicU64::new(0),
+        });
+        let mut base = Document::with_client_id(1);
+        base.push('a');
+
+        let mut document = Document::with_client_id(2).with_metrics_sink(sink.clone());
+        Update::from_document(&base).apply(&mut document).unwrap();
+
+        assert_eq!(
+            sink.counters.counters().integration_time,
+            Duration::from_millis(1)
+        );
+    }
+
+    #[test]
+    fn reports_tombstones_and_queue_depth() {
+        let sink = Arc::new(CountersSink::new());
+        let mut remote = Document::with_client_id(1);
+        remote.push('a');
+        let first = Update::from_document(&remote);
+        remote.push('b');
+        let second = Update::from_document_since(&remote, first_state(&first).as_ref());
+
+        let mut document = Document::with_client_id(2).with_metrics_sink(sink.clone());
+        document.apply_or_queue(second).unwrap();
+        assert_eq!(sink.counters().pending_updates, 1);
+
+        document.apply_or_queue(first).unwrap();
+        assert_eq!(sink.counters().pending_updates, 0);
+
+        document.remove_range(0, 2);
+        document.gc(document.state_vector().as_ref());
+        assert_eq!(sink.counters().tombstones, 1);
+    }
+
+    fn first_state(update: &Update<char>) -> crate::StateVector {
+        let mut document = Document::with_client_id(3);
+        update.clone().apply(&mut document).unwrap();
+
+        document.state_vector()
+    }
+}
@@ -0,0 +1,404 @@
+use crate::clock::Clock;
+use crate::document::{BlockId, ClientId};
+use bincode::{Decode, Encode};
+use std::collections::{HashMap, HashSet};
+
+/// The elements from `start` to `end` inclusive, shown at `marker` rather than where they were
+/// inserted.
+///
+/// `marker` is a tombstone the moving replica inserts at the destination, so the destination is
+/// placed among concurrent inserts the same way on every replica. Elements later inserted
+/// between `start` and `end` move along with the range.
+#[derive(Eq, PartialEq, Clone, Debug, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Move {
+    pub(crate) start: BlockId,
+    pub(crate) end: BlockId,
+    pub(crate) marker: BlockId,
+    /// A Lamport clock ordering moves, separate from the sequence clocks.
+    pub(crate) clock: Clock,
+    pub(crate) client_id: ClientId,
+}
+
+impl Move {
+    /// The order in which moves take effect: where ranges overlap, the element goes wherever
+    /// the last move sends it, with ties between concurrent moves broken by the larger client
+    /// id.
+    pub(crate) fn key(&self) -> (Clock, ClientId) {
+        (self.clock, self.client_id)
+    }
+}
+
+/// Every move made on a document. Like marks, moves are only ever added, so replicas merge them
+/// by taking the union, and which move wins is decided when the document is shown.
+#[derive(Debug, Default)]
+pub(crate) struct MoveStore {
+    moves: Vec<Move>,
+    /// The marker of every move, which also identifies it.
+    markers: HashSet<BlockId>,
+    /// The largest clock seen, so local moves are ordered after everything already merged.
+    clock: Clock,
+}
+
+impl MoveStore {
+    pub(crate) fn new() -> MoveStore {
+        MoveStore {
+            moves: vec![],
+            markers: HashSet::new(),
+            clock: Clock::ZERO,
+        }
+    }
+
+    /// Adds a move made locally by `client_id`, returning it.
+    pub(crate) fn add(
+        &mut self,
+        client_id: ClientId,
+        start: BlockId,
+        end: BlockId,
+        marker: BlockId,
+    ) -> &Move {
+        self.clock.tick();
+        self.markers.insert(marker);
+
+        self.moves.push(Move {
+            start,
+            end,
+            marker,
+            clock: self.clock,
+            client_id,
+        });
+
+        self.moves.last().unwrap()
+    }
+
+    /// Merges a move made by any replica, ignoring it if it's already known.
+    pub(crate) fn merge(&mut self, mv: Move) {
+        self.clock.observe(mv.clock);
+
+        if self.markers.insert(mv.marker) {
+            self.moves.push(mv);
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.moves.is_empty()
+    }
+
+    pub(crate) fn is_marker(&self, id: BlockId) -> bool {
+        self.markers.contains(&id)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Move> {
+        self.moves.iter()
+    }
+
+    /// Every move, ordered by clock then client id, so later moves come last.
+    pub(crate) fn moves(&self) -> Vec<Move> {
+        let mut moves = self.moves.clone();
+        moves.sort_by_key(Move::key);
+
+        moves
+    }
+
+    pub(crate) fn from_moves(moves: Vec<Move>) -> MoveStore {
+        let mut store = MoveStore::new();
+
+        for mv in moves {
+            store.merge(mv);
+        }
+
+        store
+    }
+}
+
+/// The elements of `before` which are still in `after` but no longer in the same order relative
+/// to the others, i.e. those outside a longest run of survivors which kept their order.
+pub(crate) fn moved_elements(before: &[BlockId], after: &[BlockId]) -> HashSet<BlockId> {
+    let positions: HashMap<BlockId, usize> = before
+        .iter()
+        .enumerate()
+        .map(|(index, id)| (*id, index))
+        .collect();
+    let survivors: Vec<(BlockId, usize)> = after
+        .iter()
+        .filter_map(|id| positions.get(id).map(|index| (*id, *index)))
+        .collect();
+
+    if survivors.windows(2).all(|pair| pair[0].1 < pair[1].1) {
+        return HashSet::new();
+    }
+
+    // Patience sorting: `tails[n]` is the survivor ending the best increasing run of length
+    // `n + 1` found so far, and `previous` links each survivor to the one before it in its run
+    let mut tails: Vec<usize> = vec![];
+    let mut previous: Vec<Option<usize>> = vec![None; survivors.len()];
+
+    for (survivor, (_, index)) in survivors.iter().enumerate() {
+        let length = tails.partition_point(|tail| survivors[*tail].1 < *index);
+
+        previous[survivor] = length.checked_sub(1).map(|length| tails[length]);
+
+        if length == tails.len() {
+            tails.push(survivor);
+        } else {
+            tails[length] = survivor;
+        }
+    }
+
+    let mut kept = HashSet::new();
+    let mut current = tails.last().copied();
+
+    while let Some(survivor) = current {
+        kept.insert(survivor);
+        current = previous[survivor];
+    }
+
+    survivors
+        .iter()
+        .enumerate()
+        .filter(|(survivor, _)| !kept.contains(survivor))
+        .map(|(_, (id, _))| *id)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::document::BlockId;
+    use crate::moves::moved_elements;
+    use crate::testing::{converged, exchange, sync_all};
+    use crate::{Document, Snapshot, Update};
+    use rand::prelude::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    fn text(document: &Document<char>) -> String {
+        document.iter().collect()
+    }
+
+    /// Replicas of "abcdef" with client ids 1 and 2.
+    fn replicas() -> Vec<Document<char>> {
+        let mut first = Document::with_client_id(1);
+        first.extend("abcdef".chars());
+
+        let mut second = Document::with_client_id(2);
+        Update::from_document(&first).apply(&mut second).unwrap();
+
+        vec![first, second]
+    }
+
+    #[test]
+    fn moves_ranges_in_either_direction() {
+        let mut document = replicas().remove(0);
+
+        document.move_range(1..3, 5);
+        assert_eq!(text(&document), "adebcf");
+
+        document.move_range(3..5, 0);
+        assert_eq!(text(&document), "bcadef");
+
+        document.move_range(0..1, 6);
+        assert_eq!(text(&document), "cadefb");
+
+        // Moving a range to one of its own ends leaves it where it is
+        document.move_range(1..3, 3);
+        document.move_range(1..3, 1);
+        assert_eq!(text(&document), "cadefb");
+        assert_eq!(document.len(), 6);
+    }
+
+    #[test]
+    fn edits_after_moves_go_where_they_are_shown() {
+        let mut document = replicas().remove(0);
+
+        document.move_range(0..2, 6);
+        assert_eq!(text(&document), "cdefab");
+
+        document.insert(6, 'x');
+        document.insert(4, 'y');
+        document.insert(5, 'z');
+        document.push('w');
+        assert_eq!(text(&document), "cdefyzabxw");
+
+        document.remove_range(3, 4);
+        assert_eq!(text(&document), "cdebxw");
+        assert_eq!(document.get(3), Some(&'b'));
+    }
+
+    #[test]
+    fn moves_a_range_which_is_shown_in_pieces() {
+        let mut document = replicas().remove(0);
+
+        document.move_range(2..4, 6);
+        assert_eq!(text(&document), "abefcd");
+
+        // "b" and "e" aren't next to each other in the list, so only they move, not "cd"
+        document.move_range(1..3, 0);
+        assert_eq!(text(&document), "beafcd");
+    }
+
+    #[test]
+    fn edit_inside_a_moved_range_is_kept_at_the_new_position() {
+        let mut replicas = replicas();
+
+        replicas[0].move_range(1..4, 6);
+        replicas[1].insert(2, 'x');
+        replicas[1].remove(3);
+
+        sync_all(&mut replicas).unwrap();
+
+        assert_eq!(text(&replicas[0]), "aefbxd");
+        assert!(converged(&replicas));
+    }
+
+    #[test]
+    fn concurrent_moves_of_the_same_range_converge_on_one_winner() {
+        let mut replicas = replicas();
+
+        replicas[0].move_range(0..2, 6);
+        replicas[1].move_range(0..2, 3);
+
+        sync_all(&mut replicas).unwrap();
+
+        // Both moves have the same clock, so the larger client id wins
+        assert_eq!(text(&replicas[0]), "cabdef");
+        assert!(converged(&replicas));
+
+        // A later move wins whichever replica makes it
+        replicas[0].move_range(2..3, 0);
+        sync_all(&mut replicas).unwrap();
+
+        assert_eq!(text(&replicas[1]), "bcadef");
+        assert!(converged(&replicas));
+    }
+
+    #[test]
+    fn moves_into_each_others_ranges_converge() {
+        let mut replicas = replicas();
+
+        // Each range is moved into the middle of the other
+        replicas[0].move_range(0..2, 5);
+        replicas[1].move_range(4..6, 1);
+
+        sync_all(&mut replicas).unwrap();
+
+        assert!(converged(&replicas));
+        assert_eq!(replicas[0].len(), 6);
+    }
+
+    #[test]
+    fn moves_survive_updates_snapshots_and_gc() {
+        let mut replicas = replicas();
+        replicas[0].move_range(0..2, 4);
+        replicas[0].remove(0);
+
+        let mut fresh = Document::with_client_id(3);
+        Update::decode(&replicas[0].encode_full_update().unwrap())
+            .unwrap()
+            .apply(&mut fresh)
+            .unwrap();
+        assert_eq!(text(&fresh), "dabef");
+
+        let snapshot = Snapshot::decode(&replicas[0].snapshot().encode().unwrap()).unwrap();
+        let mut restored = Document::restore(snapshot);
+        assert_eq!(text(&restored), "dabef");
+
+        let safe = restored.state_vector().as_ref().clone();
+        restored.gc(&safe);
+        restored.insert(3, 'x');
+        assert_eq!(text(&restored), "dabxef");
+
+        let chunks = Update::from_document(&restored).split(40);
+        assert!(chunks.len() > 1);
+
+        let mut chunked = Document::with_client_id(4);
+        for chunk in chunks {
+            chunk.apply(&mut chunked).unwrap();
+        }
+        assert_eq!(text(&chunked), "dabxef");
+    }
+
+    #[test]
+    fn observers_see_a_move_as_a_removal_and_an_insertion() {
+        let mut replicas = replicas();
+        let mirror = Arc::new(Mutex::new(text(&replicas[1]).chars().collect::<Vec<_>>()));
+        let mirrored = mirror.clone();
+
+        // Deletions are at indices from before the change, insertions at indices after it
+        replicas[1].observe(move |event| {
+            let mut mirror = mirrored.lock().unwrap();
+
+            assert!(!event.deleted.is_empty() && !event.inserted.is_empty());
+
+            for deletion in event.deleted.iter().rev() {
+                mirror.drain(deletion.index..deletion.index + deletion.length);
+            }
+
+            for insertion in &event.inserted {
+                for (offset, value) in insertion.values.iter().enumerate() {
+                    mirror.insert(insertion.index + offset, *value);
+                }
+            }
+        });
+        replicas[0].move_range(0..2, 4);
+        exchange(&mut replicas, 0, 1).unwrap();
+
+        assert_eq!(text(&replicas[1]), "cdabef");
+        assert_eq!(mirror.lock().unwrap().iter().collect::<String>(), "cdabef");
+    }
+
+    #[test]
+    fn moved_elements_are_those_out_of_order() {
+        let ids: Vec<BlockId> = (0..5).map(|clock| BlockId::new(1, clock)).collect();
+        let after = [ids[0], ids[3], ids[1], ids[2], ids[4]];
+
+        assert_eq!(moved_elements(&ids, &after), HashSet::from([ids[3]]));
+        assert!(moved_elements(&ids, &[ids[0], ids[2], ids[4]]).is_empty());
+    }
+
+    #[test]
+    fn random_concurrent_moves_converge() {
+        for seed in 0..30 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut replicas = replicas();
+            replicas.push(Document::with_client_id(3));
+            sync_all(&mut replicas).unwrap();
+
+            for round in 0..15 {
+                // Inserts are made in turn, as concurrent inserts don't always converge yet
+                let replica = rng.gen_range(0, replicas.len());
+
+                for value in 0..rng.gen_range(0, 3) {
+                    let len = replicas[replica].len();
+
+                    replicas[replica]
+                        .insert(rng.gen_range(0, len + 1), (b'g' + round + value) as char);
+                }
+
+                sync_all(&mut replicas).unwrap();
+
+                for replica in &mut replicas {
+                    for _ in 0..rng.gen_range(0, 3) {
+                        let len = replica.len();
+
+                        if len == 0 {
+                            break;
+                        } else if rng.gen_range(0, 4) == 0 {
+                            replica.remove(rng.gen_range(0, len));
+                        } else {
+                            let start = rng.gen_range(0, len);
+                            let end = rng.gen_range(start + 1, len + 1);
+
+                            replica.move_range(start..end, rng.gen_range(0, len + 1));
+                        }
+                    }
+                }
+
+                sync_all(&mut replicas).unwrap();
+
+                assert!(converged(&replicas), "seed {}, round {}", seed, round);
+                assert_eq!(replicas[0].iter().count(), replicas[0].len());
+            }
+        }
+    }
+}
@@ -0,0 +1,390 @@
+//! Syncing a [`SharedDocument`] with a replica over TCP, using the [`SyncProtocol`]. Enabled
+//! with the `net` feature.
+//!
+//! Each [`Message`] is sent as a frame: its encoded length as a big-endian `u32`, followed by
+//! the encoded message.
+
+use crate::block::Item;
+use crate::shared::SharedDocument;
+use crate::sync::{Message, SyncProtocol};
+use bincode::{Decode, Encode};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// The largest frame accepted from a peer, matching the default limit on an update's size.
+pub const MAX_FRAME_BYTES: usize = 1 << 30;
+
+/// How often a peer which isn't being told of local changes checks whether it has been closed.
+const CLOSE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Writes `message` to `stream` as a single frame.
+pub fn write_message(stream: &mut impl Write, message: &Message) -> io::Result<()> {
+    let bytes = message
+        .encode()
+        .map_err(|error| io::Error::new(ErrorKind::InvalidData, error.to_string()))?;
+    let length = u32::try_from(bytes.len())
+        .ok()
+        .filter(|length| *length as usize <= MAX_FRAME_BYTES)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "message too large to send"))?;
+
+    stream.write_all(&length.to_be_bytes())?;
+    stream.write_all(&bytes)?;
+    stream.flush()
+}
+
+/// Reads a single frame from `stream`, or `None` if the stream ended cleanly between frames.
+pub fn read_message(stream: &mut impl Read) -> io::Result<Option<Message>> {
+    let mut length = [0; 4];
+
+    match stream.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error),
+    }
+
+    let length = u32::from_be_bytes(length) as usize;
+
+    if length > MAX_FRAME_BYTES {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("frame of {} bytes is too large", length),
+        ));
+    }
+
+    // Read as it arrives rather than allocating the whole frame up front, so a peer can't make us
+    // reserve more than it actually sends
+    let mut bytes = vec![];
+    stream.take(length as u64).read_to_end(&mut bytes)?;
+
+    if bytes.len() < length {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "stream ended partway through a frame",
+        ));
+    }
+
+    Message::decode(&bytes)
+        .map(Some)
+        .map_err(|error| io::Error::new(ErrorKind::InvalidData, error.to_string()))
+}
+
+/// One end of a connection keeping a [`SharedDocument`] in sync with a replica.
+///
+/// Once connected the two sides exchange whatever each is missing, and afterwards every local
+/// edit made through the shared document is pushed to the other side as it is made. Updates
+/// from the other side are applied as they arrive, or held back until the updates they build on
+/// have been, so edits can be made on both sides at once.
+///
+/// Reading, writing and pushing local edits each happen on a thread of their own, until the
+/// connection is closed by either side.
+pub struct Peer<T: Item> {
+    document: SharedDocument<T>,
+    protocol: Arc<Mutex<SyncProtocol<T>>>,
+    stream: TcpStream,
+    closed: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<io::Result<()>>>,
+}
+
+impl<T: Item + Encode + Decode + Send + Sync + 'static> Peer<T> {
+    /// Waits for a replica to connect to `listener`, then syncs `document` with it.
+    pub fn serve(listener: &TcpListener, document: SharedDocument<T>) -> io::Result<Peer<T>> {
+        let (stream, _) = listener.accept()?;
+
+        Peer::start(stream, document, false)
+    }
+
+    /// Connects to a replica listening at `address`, then syncs `document` with it.
+    pub fn connect(
+        address: impl ToSocketAddrs,
+        document: SharedDocument<T>,
+    ) -> io::Result<Peer<T>> {
+        let stream = TcpStream::connect(address)?;
+
+        Peer::start(stream, document, true)
+    }
+
+    fn start(
+        stream: TcpStream,
+        document: SharedDocument<T>,
+        initiate: bool,
+    ) -> io::Result<Peer<T>> {
+        stream.set_nodelay(true)?;
+
+        let protocol = Arc::new(Mutex::new(SyncProtocol::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+        let (outbox, outgoing) = mpsc::channel();
+
+        // Subscribe before the sync starts, so no local edit can slip between the two
+        let changes = document.subscribe();
+
+        if initiate {
+            let mut protocol = lock(&protocol);
+            let hello = document.read(|document| protocol.start(document));
+            send_all(&outbox, [hello]);
+        }
+
+        let mut writer = stream.try_clone()?;
+        let write = thread::spawn(move || {
+            for message in outgoing {
+                write_message(&mut writer, &message)?;
+            }
+
+            Ok(())
+        });
+
+        let mut reader = stream.try_clone()?;
+        let read = {
+            let document = document.clone();
+            let protocol = protocol.clone();
+            let outbox = outbox.clone();
+
+            thread::spawn(move || {
+                while let Some(message) = read_message(&mut reader)? {
+                    let mut protocol = lock(&protocol);
+                    let replies = document.write(|document| protocol.handle(document, message));
+
+                    send_all(&outbox, replies);
+                }
+
+                Ok(())
+            })
+        };
+
+        let push = {
+            let document = document.clone();
+            let protocol = protocol.clone();
+            let closed = closed.clone();
+
+            thread::spawn(move || {
+                while !closed.load(Ordering::Acquire) {
+                    match changes.recv_timeout(CLOSE_POLL_INTERVAL) {
+                        Ok(_) => {}
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+
+                    // Later edits are covered by the update pushed for this one
+                    while changes.try_recv().is_ok() {}
+
+                    let mut protocol = lock(&protocol);
+
+                    if let Some(message) = document.read(|document| protocol.push(document)) {
+                        send_all(&outbox, [message]);
+                    }
+                }
+
+                Ok(())
+            })
+        };
+
+        Ok(Peer {
+            document,
+            protocol,
+            stream,
+            closed,
+            threads: vec![read, push, write],
+        })
+    }
+
+    /// The document being kept in sync.
+    pub fn document(&self) -> &SharedDocument<T> {
+        &self.document
+    }
+
+    /// Whether both sides have exchanged everything they had when the connection was made.
+    pub fn is_synced(&self) -> bool {
+        lock(&self.protocol).is_synced()
+    }
+
+    /// Whether the other side reported an error, which stops anything more being sent.
+    pub fn is_failed(&self) -> bool {
+        lock(&self.protocol).is_failed()
+    }
+
+    /// Closes the connection and waits for its threads to finish, returning the first error any
+    /// of them ran into besides the connection being closed.
+    pub fn close(mut self) -> io::Result<()> {
+        self.shut_down();
+
+        let mut result = Ok(());
+
+        for thread in self.threads.drain(..) {
+            let outcome = thread
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("sync thread panicked")));
+
+            if let Err(error) = outcome {
+                let closed = matches!(
+                    error.kind(),
+                    ErrorKind::ConnectionAborted
+                        | ErrorKind::ConnectionReset
+                        | ErrorKind::BrokenPipe
+                        | ErrorKind::NotConnected
+                );
+
+                if !closed && result.is_ok() {
+                    result = Err(error);
+                }
+            }
+        }
+
+        result
+    }
+}
+
+impl<T: Item> Peer<T> {
+    fn shut_down(&self) {
+        self.closed.store(true, Ordering::Release);
+
+        // Already shut down by the other side if this fails
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+impl<T: Item> Drop for Peer<T> {
+    fn drop(&mut self) {
+        self.shut_down();
+    }
+}
+
+/// Queues `messages` for the writer, which has only stopped if the connection has failed.
+fn send_all(outbox: &Sender<Message>, messages: impl IntoIterator<Item = Message>) {
+    for message in messages {
+        let _ = outbox.send(message);
+    }
+}
+
+fn lock<T>(protocol: &Mutex<SyncProtocol<T>>) -> MutexGuard<'_, SyncProtocol<T>> {
+    protocol.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::{read_message, write_message, Peer, MAX_FRAME_BYTES};
+    use crate::sync::Message;
+    use crate::{Document, SharedDocument, StateVector};
+    use std::io::{Cursor, ErrorKind};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Waits until `done` holds, failing the test if it takes too long.
+    fn wait_until(mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn converged(a: &SharedDocument<String>, b: &SharedDocument<String>) -> bool {
+        a.read(|a| b.read(|b| a.content_eq(b)))
+    }
+
+    #[test]
+    fn frames_round_trip_and_end_cleanly() {
+        let messages = [
+            Message::StateVector(StateVector::new()),
+            Message::Update(vec![1, 2, 3]),
+            Message::Done,
+        ];
+        let mut stream = vec![];
+
+        for message in &messages {
+            write_message(&mut stream, message).unwrap();
+        }
+
+        let mut stream = Cursor::new(stream);
+
+        for message in messages {
+            assert_eq!(read_message(&mut stream).unwrap(), Some(message));
+        }
+
+        assert_eq!(read_message(&mut stream).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_oversized_and_truncated_frames() {
+        let oversized = ((MAX_FRAME_BYTES + 1) as u32).to_be_bytes();
+        let error = read_message(&mut Cursor::new(oversized)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        let mut truncated = vec![];
+        write_message(&mut truncated, &Message::Update(vec![0; 16])).unwrap();
+        truncated.truncate(10);
+
+        let error = read_message(&mut Cursor::new(truncated)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+
+        // A header alone claiming the largest frame is cut short without reserving it
+        let unbacked = (MAX_FRAME_BYTES as u32).to_be_bytes();
+        let error = read_message(&mut Cursor::new(unbacked)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn peers_converge_over_localhost() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let served = SharedDocument::new(Document::with_client_id(1));
+        let connecting = SharedDocument::new(Document::with_client_id(2));
+
+        // Both sides have edits before they connect
+        for value in ["a", "b", "c"] {
+            served.push(value.to_owned());
+        }
+        connecting.push("x".to_owned());
+
+        let server = {
+            let served = served.clone();
+
+            thread::spawn(move || Peer::serve(&listener, served).unwrap())
+        };
+        let client = Peer::connect(address, connecting.clone()).unwrap();
+        let server = server.join().unwrap();
+
+        wait_until(|| server.is_synced() && client.is_synced());
+        assert!(converged(&served, &connecting));
+        assert_eq!(served.len(), 4);
+
+        // Then both edit at once, deletions included, while updates are in flight
+        let editors: Vec<_> = [(served.clone(), 100), (connecting.clone(), 200)]
+            .into_iter()
+            .map(|(document, base)| {
+                thread::spawn(move || {
+                    for value in 0..50 {
+                        document.insert(document.len() / 2, (base + value).to_string());
+
+                        if value % 5 == 0 {
+                            document.remove(0);
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for editor in editors {
+            editor.join().unwrap();
+        }
+
+        wait_until(|| converged(&served, &connecting));
+        assert!(!server.is_failed() && !client.is_failed());
+
+        // Both sides may have removed the same element, but the last inserts are in the middle
+        let values = served.read(Document::to_vec);
+        assert!(values.len() >= 4 + 2 * 50 - 2 * 10);
+        assert!(["149", "249"]
+            .iter()
+            .all(|value| values.iter().any(|other| other == value)));
+
+        client.close().unwrap();
+        server.close().unwrap();
+    }
+}
@@ -0,0 +1,518 @@
+use crate::block::Item;
+use crate::clock::Clock;
+use crate::document::{BlockId, ClientId};
+use crate::moves::moved_elements;
+use crate::storage::BlockStorage;
+use crate::store::Store;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Identifies a callback registered with [`Document::observe`](crate::Document::observe), so it
+/// can later be removed.
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy)]
+pub struct SubscriptionId(u64);
+
+/// Elements inserted by a single client at a contiguous position.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Insertion<T> {
+    /// The index of the first inserted element, in the document after the change.
+    pub index: usize,
+    pub values: Vec<T>,
+    /// The client which created the elements.
+    pub client_id: ClientId,
+}
+
+/// A contiguous run of elements which were removed.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Deletion {
+    /// The index of the first removed element, in the document before the change.
+    pub index: usize,
+    pub length: usize,
+}
+
+/// Everything a single apply (or local edit) changed in a document.
+///
+/// Deletions index the document as it was before the change and insertions index it afterwards,
+/// so a mirror of the document can be kept up to date by removing the deletions back to front and
+/// then inserting the insertions front to back. Elements whose values were replaced are reported
+/// as removed and inserted again with their new values.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct ChangeEvent<T> {
+    pub inserted: Vec<Insertion<T>>,
+    pub deleted: Vec<Deletion>,
+    /// Whether the change was made locally rather than applied from an update.
+    pub local: bool,
+}
+
+impl<T: Item> ChangeEvent<T> {
+    /// Compares the document `before` a change with `store` afterwards.
+    fn between<S: BlockStorage<T>>(
+        before: &Before,
+        store: &Store<T, S>,
+        local: bool,
+    ) -> ChangeEvent<T> {
+        let after: Vec<(BlockId, &T)> = store.iter_live_elements().collect();
+        let after_order: Vec<BlockId> = after.iter().map(|(id, _)| *id).collect();
+
+        // Moved elements are reported as removed from where they were and inserted where they
+        // are, and replaced ones as removed and inserted again in place
+        let mut changed = moved_elements(&before.elements, &after_order);
+        changed.extend(
+            store
+                .registers
+                .winners()
+                .into_iter()
+                .filter(|(id, winner)| before.winners.get(id) != Some(winner))
+                .map(|(id, _)| id),
+        );
+        let before_ids: HashSet<BlockId> = before
+            .elements
+            .iter()
+            .filter(|id| !changed.contains(id))
+            .copied()
+            .collect();
+        let after_ids: HashSet<BlockId> = after_order
+            .into_iter()
+            .filter(|id| !changed.contains(id))
+            .collect();
+
+        let mut deleted: Vec<Deletion> = vec![];
+
+        for (index, id) in before.elements.iter().enumerate() {
+            if after_ids.contains(id) {
+                continue;
+            }
+
+            match deleted.last_mut() {
+                Some(last) if last.index + last.length == index => last.length += 1,
+                _ => deleted.push(Deletion { index, length: 1 }),
+            }
+        }
+
+        let mut inserted: Vec<Insertion<T>> = vec![];
+
+        for (index, (id, value)) in after.into_iter().enumerate() {
+            if before_ids.contains(&id) {
+                continue;
+            }
+
+            match inserted.last_mut() {
+                Some(last)
+                    if last.index + last.values.len() == index
+                        && last.client_id == id.client_id =>
+                {
+                    last.values.push(value.clone())
+                }
+                _ => inserted.push(Insertion {
+                    index,
+                    values: vec![value.clone()],
+                    client_id: id.client_id,
+                }),
+            }
+        }
+
+        ChangeEvent {
+            inserted,
+            deleted,
+            local,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inserted.is_empty() && self.deleted.is_empty()
+    }
+}
+
+/// Elements tombstoned by a [`DeleteSet`](crate::DeleteSet) from another replica, applied on its
+/// own or as part of an [`Update`](crate::Update).
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct DeleteEvent {
+    /// Each element which was live until the deletion, with its index in the document just
+    /// before it, in document order.
+    pub elements: Vec<(BlockId, usize)>,
+}
+
+/// The document as a change to it began, for [`Observers::end`] to compare it with afterwards.
+pub(crate) struct Before {
+    elements: Vec<BlockId>,
+    winners: HashMap<BlockId, (Clock, ClientId)>,
+}
+
+type Callback<T> = Box<dyn FnMut(&ChangeEvent<T>) + Send + Sync>;
+
+type DeleteCallback = Box<dyn FnMut(&DeleteEvent) + Send + Sync>;
+
+struct Observer<T> {
+    id: SubscriptionId,
+    local: bool,
+    callback: Callback<T>,
+}
+
+/// The callbacks registered on a document.
+pub(crate) struct Observers<T> {
+    observers: Vec<Observer<T>>,
+    delete_observers: Vec<(SubscriptionId, DeleteCallback)>,
+    next_id: u64,
+    /// Set while a change is being observed, so edits nested inside it (e.g. those made by a
+    /// transaction) are reported as part of the outer change.
+    observing: bool,
+}
+
+impl<T: Item> Observers<T> {
+    pub(crate) fn new() -> Observers<T> {
+        Observers {
+            observers: vec![],
+            delete_observers: vec![],
+            next_id: 0,
+            observing: false,
+        }
+    }
+
+    pub(crate) fn add(
+        &mut self,
+        local: bool,
+        callback: impl FnMut(&ChangeEvent<T>) + Send + Sync + 'static,
+    ) -> SubscriptionId {
+        let id = self.next_id();
+
+        self.observers.push(Observer {
+            id,
+            local,
+            callback: Box::new(callback),
+        });
+
+        id
+    }
+
+    pub(crate) fn add_deletes(
+        &mut self,
+        callback: impl FnMut(&DeleteEvent) + Send + Sync + 'static,
+    ) -> SubscriptionId {
+        let id = self.next_id();
+
+        self.delete_observers.push((id, Box::new(callback)));
+
+        id
+    }
+
+    fn next_id(&mut self) -> SubscriptionId {
+        let id = SubscriptionId(self.next_id);
+        self.next_id += 1;
+
+        id
+    }
+
+    pub(crate) fn remove(&mut self, id: SubscriptionId) -> bool {
+        let len = self.observers.len() + self.delete_observers.len();
+        self.observers.retain(|observer| observer.id != id);
+        self.delete_observers.retain(|(other, _)| *other != id);
+
+        self.observers.len() + self.delete_observers.len() != len
+    }
+
+    /// Whether anything wants to be told about remote deletions through
+    /// [`Observers::deleted`].
+    pub(crate) fn observes_deletes(&self) -> bool {
+        !self.delete_observers.is_empty()
+    }
+
+    /// Tells the deletion observers about the elements in `event`, if there are any.
+    pub(crate) fn deleted(&mut self, event: DeleteEvent) {
+        if event.elements.is_empty() {
+            return;
+        }
+
+        for (_, callback) in &mut self.delete_observers {
+            callback(&event);
+        }
+    }
+
+    /// Starts observing a change to `store`, returning what it looked like if anything is
+    /// interested in the change.
+    pub(crate) fn begin<S: BlockStorage<T>>(
+        &mut self,
+        store: &Store<T, S>,
+        local: bool,
+    ) -> Option<Before> {
+        if self.observing
+            || !self
+                .observers
+                .iter()
+                .any(|observer| observer.local || !local)
+        {
+            return None;
+        }
+
+        self.observing = true;
+
+        Some(Before {
+            elements: store.iter_live_elements().map(|(id, _)| id).collect(),
+            winners: store.registers.winners(),
+        })
+    }
+
+    /// Finishes observing a change started by [`Observers::begin`], notifying interested
+    /// callbacks if anything changed.
+    pub(crate) fn end<S: BlockStorage<T>>(
+        &mut self,
+        before: Option<Before>,
+        store: &Store<T, S>,
+        local: bool,
+    ) {
+        let Some(before) = before else {
+            return;
+        };
+
+        // Reset before running any callbacks, so a panicking callback can't leave further changes
+        // unobserved
+        self.observing = false;
+
+        let event = ChangeEvent::between(&before, store, local);
+
+        if event.is_empty() {
+            return;
+        }
+
+        for observer in &mut self.observers {
+            if observer.local || !local {
+                (observer.callback)(&event);
+            }
+        }
+    }
+
+    /// Abandons a change started by [`Observers::begin`] without notifying anything.
+    pub(crate) fn abort(&mut self, before: Option<Before>) {
+        if before.is_some() {
+            self.observing = false;
+        }
+    }
+}
+
+impl<T> fmt::Debug for Observers<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Observers")
+            .field("count", &self.observers.len())
+            .field("delete_count", &self.delete_observers.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::observer::{ChangeEvent, DeleteEvent, Deletion, Insertion};
+    use crate::{BlockId, DeleteSet, Document, Update};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::{Arc, Mutex};
+
+    fn record(document: &mut Document<String>) -> Arc<Mutex<Vec<ChangeEvent<String>>>> {
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = events.clone();
+
+        document.observe(move |event| recorded.lock().unwrap().push(event.clone()));
+
+        events
+    }
+
+    #[test]
+    fn reports_indices_of_merged_concurrent_inserts() {
+        let mut doc1 = Document::with_client_id(1);
+        doc1.push("a".to_owned());
+        doc1.push("d".to_owned());
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+
+        doc1.insert(1, "b".to_owned());
+        doc2.insert(1, "c".to_owned());
+        doc2.remove(0);
+
+        let events = record(&mut doc1);
+
+        Update::from_document_since(&doc2, doc1.state_vector().as_ref())
+            .apply(&mut doc1)
+            .unwrap();
+
+        assert_eq!(
+            doc1.store.iter_values().collect::<Vec<_>>(),
+            vec!["b", "c", "d"]
+        );
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![ChangeEvent {
+                inserted: vec![Insertion {
+                    index: 1,
+                    values: vec!["c".to_owned()],
+                    client_id: 2,
+                }],
+                deleted: vec![Deletion {
+                    index: 0,
+                    length: 1
+                }],
+                local: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn local_edits_only_reach_local_observers() {
+        let mut doc = Document::with_client_id(1);
+        let remote = record(&mut doc);
+        let local = Arc::new(Mutex::new(vec![]));
+        let recorded = local.clone();
+
+        doc.observe_local(move |event: &ChangeEvent<String>| {
+            recorded.lock().unwrap().push(event.clone())
+        });
+
+        doc.push("a".to_owned());
+        doc.transact(|transaction| {
+            transaction.push("b".to_owned());
+            transaction.push("c".to_owned());
+            transaction.remove(0);
+        });
+
+        assert!(remote.lock().unwrap().is_empty());
+        assert_eq!(
+            *local.lock().unwrap(),
+            vec![
+                ChangeEvent {
+                    inserted: vec![Insertion {
+                        index: 0,
+                        values: vec!["a".to_owned()],
+                        client_id: 1,
+                    }],
+                    deleted: vec![],
+                    local: true,
+                },
+                ChangeEvent {
+                    inserted: vec![Insertion {
+                        index: 0,
+                        values: vec!["b".to_owned(), "c".to_owned()],
+                        client_id: 1,
+                    }],
+                    deleted: vec![Deletion {
+                        index: 0,
+                        length: 1
+                    }],
+                    local: true,
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn replaced_values_are_reported_as_removed_and_inserted_again() {
+        let mut doc1 = Document::with_client_id(1);
+        let mut doc2 = Document::with_client_id(2);
+        for value in ["a", "b", "c"] {
+            doc1.push(value.to_owned());
+        }
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+
+        let local = Arc::new(Mutex::new(vec![]));
+        let recorded = local.clone();
+        doc1.observe_local(move |event: &ChangeEvent<String>| {
+            recorded.lock().unwrap().push(event.clone())
+        });
+        let remote = record(&mut doc2);
+
+        doc1.set(1, "x".to_owned());
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+
+        let replaced = ChangeEvent {
+            inserted: vec![Insertion {
+                index: 1,
+                values: vec!["x".to_owned()],
+                client_id: 1,
+            }],
+            deleted: vec![Deletion {
+                index: 1,
+                length: 1,
+            }],
+            local: true,
+        };
+        assert_eq!(*local.lock().unwrap(), vec![replaced.clone()]);
+        assert_eq!(
+            *remote.lock().unwrap(),
+            vec![ChangeEvent {
+                local: false,
+                ..replaced
+            }]
+        );
+    }
+
+    #[test]
+    fn deletion_observers_see_tombstoned_elements_at_their_old_indices() {
+        let mut doc1 = Document::with_client_id(1);
+        for value in ["a", "b", "c", "d", "e"] {
+            doc1.push(value.to_owned());
+        }
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = events.clone();
+        let id = doc2.observe_deletes(move |event| recorded.lock().unwrap().push(event.clone()));
+
+        // Local deletions aren't reported, and nor are remote ones of elements already gone
+        doc2.remove(1);
+        doc1.remove(3);
+        doc1.remove(1);
+        doc1.remove(0);
+        DeleteSet::from(&doc1).apply(&mut doc2);
+
+        assert_eq!(doc2.to_vec(), vec!["c", "e"]);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![DeleteEvent {
+                elements: vec![(BlockId::new(1, 0), 0), (BlockId::new(1, 3), 2)],
+            }]
+        );
+
+        // Nor are updates without new deletions
+        doc1.push("f".to_owned());
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+        assert_eq!(events.lock().unwrap().len(), 1);
+
+        assert!(doc2.unobserve(id));
+    }
+
+    #[test]
+    fn unobserve_stops_events() {
+        let mut doc1 = Document::with_client_id(1);
+        doc1.push("a".to_owned());
+
+        let mut doc2 = Document::with_client_id(2);
+        let events = Arc::new(Mutex::new(0));
+        let count = events.clone();
+        let id = doc2.observe(move |_| *count.lock().unwrap() += 1);
+
+        assert!(doc2.unobserve(id));
+        assert!(!doc2.unobserve(id));
+
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+
+        assert_eq!(*events.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn panicking_observer_leaves_document_intact() {
+        let mut doc1 = Document::with_client_id(1);
+        doc1.push("a".to_owned());
+
+        let mut doc2 = Document::with_client_id(2);
+        doc2.observe(|_| panic!("observer failed"));
+
+        let update = Update::from_document(&doc1);
+        assert!(catch_unwind(AssertUnwindSafe(|| update.apply(&mut doc2))).is_err());
+
+        assert_eq!(doc2.store.iter_values().collect::<Vec<_>>(), vec!["a"]);
+
+        doc1.push("b".to_owned());
+        let update = Update::from_document_since(&doc1, doc2.state_vector().as_ref());
+        assert!(catch_unwind(AssertUnwindSafe(|| update.apply(&mut doc2))).is_err());
+
+        assert_eq!(doc2.store.iter_values().collect::<Vec<_>>(), vec!["a", "b"]);
+    }
+}
@@ -0,0 +1,180 @@
+use crate::block::Item;
+use crate::document::BlockId;
+use crate::storage::BlockStorage;
+use crate::Document;
+use bincode::{Decode, Encode};
+
+/// Which neighbour a [`Position`] sticks to when content is inserted right next to it.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Bias {
+    /// Stick to the element before the position, so insertions at the position land after it.
+    Left,
+    /// Stick to the element after the position, so insertions at the position land before it.
+    Right,
+}
+
+/// A location between two elements which stays put as the document changes around it, e.g. a
+/// user's cursor.
+///
+/// Positions are anchored to an element's id rather than its index, so they keep pointing at the
+/// same place across remote updates, block splits and deletions.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Position {
+    /// The element the position sticks to, or `None` for the start (left bias) or end (right
+    /// bias) of the document.
+    anchor: Option<BlockId>,
+    bias: Bias,
+}
+
+impl Position {
+    pub fn bias(&self) -> Bias {
+        self.bias
+    }
+}
+
+impl<T: Item, S: BlockStorage<T>> Document<T, S> {
+    /// The position just before the element at `index`, sticking to that element.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index > len`.
+    pub fn position_at(&self, index: usize) -> Position {
+        self.position_with_bias(index, Bias::Right)
+    }
+
+    /// The position just before the element at `index`, sticking to the neighbour picked by
+    /// `bias`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index > len`.
+    pub fn position_with_bias(&self, index: usize, bias: Bias) -> Position {
+        let len = self.len();
+
+        assert!(
+            index <= len,
+            "position index (is {}) should be <= len (is {})",
+            index,
+            len
+        );
+
+        let anchor = match bias {
+            Bias::Left => index
+                .checked_sub(1)
+                .and_then(|index| self.store.element_at(index)),
+            Bias::Right => self.store.element_at(index),
+        };
+
+        Position { anchor, bias }
+    }
+
+    /// The current index of `position`.
+    ///
+    /// If the element a position sticks to has been deleted, it resolves to where that element
+    /// would have been, i.e. the index of the first surviving element after it. Returns `None` if
+    /// the position was made by a document which has since diverged from this one, and its
+    /// element hasn't arrived yet.
+    pub fn index_of(&self, position: &Position) -> Option<usize> {
+        let anchor = match position.anchor {
+            Some(anchor) => anchor,
+            None if position.bias == Bias::Left => return Some(0),
+            None => return Some(self.len()),
+        };
+
+        let (before, live) = self.store.live_elements_before(anchor)?;
+
+        Some(if live && position.bias == Bias::Left {
+            before + 1
+        } else {
+            before
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::position::Bias;
+    use crate::{Document, Update};
+
+    fn document(values: &[&str]) -> Document<String> {
+        let mut document = Document::with_client_id(1);
+
+        for value in values {
+            document.push(value.to_string());
+        }
+
+        document
+    }
+
+    #[test]
+    fn position_moves_with_remote_inserts_before_it() {
+        let mut doc1 = document(&["a", "b", "c", "d"]);
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+
+        let position = doc1.position_at(2);
+
+        doc2.insert(0, "x".to_owned());
+        Update::from_document_since(&doc2, doc1.state_vector().as_ref())
+            .apply(&mut doc1)
+            .unwrap();
+
+        assert_eq!(doc1.index_of(&position), Some(3));
+        assert_eq!(doc1.get(3), Some(&"c".to_owned()));
+    }
+
+    #[test]
+    fn position_survives_block_splits() {
+        let mut doc = document(&["a", "b", "c", "d"]);
+        let left = doc.position_with_bias(2, Bias::Left);
+        let right = doc.position_at(2);
+
+        // Splits the run between "b" and "c", exactly where the positions are
+        doc.insert(2, "x".to_owned());
+
+        assert_eq!(doc.index_of(&left), Some(2));
+        assert_eq!(doc.index_of(&right), Some(3));
+    }
+
+    #[test]
+    fn position_of_deleted_element_falls_back_to_its_successor() {
+        let mut doc = document(&["a", "b", "c", "d"]);
+        let right = doc.position_at(2);
+        let left = doc.position_with_bias(3, Bias::Left);
+
+        doc.remove_range(1, 2);
+
+        assert_eq!(doc.index_of(&right), Some(1));
+        assert_eq!(doc.index_of(&left), Some(1));
+        assert_eq!(doc.get(1), Some(&"d".to_owned()));
+    }
+
+    #[test]
+    fn positions_at_the_ends_stay_at_the_ends() {
+        let mut doc = document(&["a", "b"]);
+        let start = doc.position_with_bias(0, Bias::Left);
+        let end = doc.position_at(2);
+
+        doc.insert(0, "x".to_owned());
+        doc.push("y".to_owned());
+
+        assert_eq!(doc.index_of(&start), Some(0));
+        assert_eq!(doc.index_of(&end), Some(4));
+    }
+
+    #[test]
+    fn position_from_another_replica_is_unknown_until_synced() {
+        let doc1 = document(&["a", "b"]);
+        let mut doc2 = Document::with_client_id(2);
+
+        let position = doc1.position_at(1);
+
+        assert_eq!(doc2.index_of(&position), None);
+
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+
+        assert_eq!(doc2.index_of(&position), Some(1));
+    }
+}
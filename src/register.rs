@@ -0,0 +1,250 @@
+use crate::block::Item;
+use crate::clock::{Clock, ClockRange};
+use crate::document::{BlockId, ClientId};
+use bincode::{Decode, Encode};
+use std::collections::{BTreeMap, HashMap};
+
+/// The latest write replacing the value of a single element, which is identified by its own id
+/// rather than its block's, so the write survives the block being split or merged.
+#[derive(Eq, PartialEq, Clone, Debug, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Register<T: Item> {
+    pub(crate) element: BlockId,
+    pub(crate) value: T,
+    /// A Lamport clock, separate from the sequence clocks, ordering writes to elements.
+    pub(crate) clock: Clock,
+    pub(crate) client_id: ClientId,
+}
+
+impl<T: Item> Register<T> {
+    /// Whether this write should replace `other`. Later clocks win, with ties between concurrent
+    /// writes broken by the larger client id.
+    fn wins_over(&self, other: &Register<T>) -> bool {
+        (self.clock, self.client_id) > (other.clock, other.client_id)
+    }
+}
+
+/// The winning write to every live element whose value has been replaced, layered over the
+/// values the elements were inserted with, which stay in their blocks.
+///
+/// Like the map, registers are merged write by write, idempotently and in any order, so they
+/// don't take part in the sequence's state vectors. Writes to deleted elements are dropped, as
+/// deleted elements are never shown again.
+#[derive(Debug, Default)]
+pub(crate) struct RegisterStore<T: Item> {
+    writes: HashMap<ClientId, BTreeMap<Clock, Register<T>>>,
+    /// The largest clock seen, so local writes are ordered after everything already merged.
+    clock: Clock,
+}
+
+impl<T: Item> RegisterStore<T> {
+    pub(crate) fn new() -> RegisterStore<T> {
+        RegisterStore {
+            writes: HashMap::new(),
+            clock: Clock::ZERO,
+        }
+    }
+
+    /// The value written to `element`, if it has been replaced.
+    pub(crate) fn get(&self, element: BlockId) -> Option<&T> {
+        self.writes
+            .get(&element.client_id)?
+            .get(&element.clock)
+            .map(|write| &write.value)
+    }
+
+    /// Which write won for each element whose value has been replaced, so a change of winner can
+    /// be spotted without comparing values.
+    pub(crate) fn winners(&self) -> HashMap<BlockId, (Clock, ClientId)> {
+        self.writes
+            .values()
+            .flat_map(|writes| writes.values())
+            .map(|write| (write.element, (write.clock, write.client_id)))
+            .collect()
+    }
+
+    /// Replaces the value of `element` as a local write by `client_id`.
+    pub(crate) fn set(&mut self, client_id: ClientId, element: BlockId, value: T) {
+        self.clock.tick();
+
+        self.insert(Register {
+            element,
+            value,
+            clock: self.clock,
+            client_id,
+        });
+    }
+
+    /// Merges a write made by any replica to a live element, keeping whichever write to the
+    /// element wins.
+    pub(crate) fn merge(&mut self, write: Register<T>) {
+        self.observe(&write);
+
+        match self
+            .writes
+            .get(&write.element.client_id)
+            .and_then(|writes| writes.get(&write.element.clock))
+        {
+            Some(existing) if !write.wins_over(existing) => {}
+            _ => self.insert(write),
+        }
+    }
+
+    /// Takes note of a write's clock without keeping the write, e.g. because its element has
+    /// been deleted, so later local writes are still ordered after it.
+    pub(crate) fn observe(&mut self, write: &Register<T>) {
+        self.clock.observe(write.clock);
+    }
+
+    fn insert(&mut self, write: Register<T>) {
+        self.writes
+            .entry(write.element.client_id)
+            .or_default()
+            .insert(write.element.clock, write);
+    }
+
+    /// Drops the writes to the elements of `client_id` with clocks in `clocks`, once they've been
+    /// deleted.
+    pub(crate) fn remove_range(&mut self, client_id: ClientId, clocks: ClockRange) {
+        if let Some(writes) = self.writes.get_mut(&client_id) {
+            let removed: Vec<Clock> = writes
+                .range(clocks.start..clocks.end)
+                .map(|(clock, _)| *clock)
+                .collect();
+
+            for clock in removed {
+                writes.remove(&clock);
+            }
+        }
+    }
+
+    /// Every write, ordered by element, so equal stores list them identically.
+    pub(crate) fn writes(&self) -> Vec<Register<T>> {
+        let mut writes: Vec<Register<T>> = self
+            .writes
+            .values()
+            .flat_map(|writes| writes.values().cloned())
+            .collect();
+        writes.sort_by_key(|write| (write.element.client_id, write.element.clock));
+
+        writes
+    }
+
+    pub(crate) fn from_writes(writes: Vec<Register<T>>) -> RegisterStore<T> {
+        let mut store = RegisterStore::new();
+
+        for write in writes {
+            store.merge(write);
+        }
+
+        store
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Document, Update};
+
+    fn sync(from: &Document<String>, to: &mut Document<String>) {
+        Update::from_document_since(from, to.state_vector().as_ref())
+            .apply(to)
+            .unwrap();
+    }
+
+    fn values(doc: &Document<String>) -> Vec<&str> {
+        doc.iter().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn set_replaces_values_in_place() {
+        let mut doc = Document::with_client_id(1);
+        doc.extend(["a", "b", "c"].map(String::from));
+
+        doc.set(1, "B".to_owned());
+
+        assert_eq!(values(&doc), vec!["a", "B", "c"]);
+        assert_eq!(doc.get(1), Some(&"B".to_owned()));
+        assert_eq!(doc.slice(1..3).collect::<Vec<_>>(), vec!["B", "c"]);
+        assert_eq!(doc.to_vec(), vec!["a", "B", "c"]);
+
+        // The block still holds the value the element was inserted with
+        assert_eq!(doc.blocks().next().unwrap().values(), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn concurrent_sets_converge_on_the_same_winner() {
+        let mut doc1 = Document::with_client_id(1);
+        doc1.extend(["a", "b"].map(String::from));
+        let mut doc2 = Document::with_client_id(2);
+        sync(&doc1, &mut doc2);
+
+        doc1.set(0, "one".to_owned());
+        doc2.set(0, "two".to_owned());
+        // Inserted next to the element, which keeps its position
+        doc2.insert(1, "x".to_owned());
+
+        sync(&doc1, &mut doc2);
+        sync(&doc2, &mut doc1);
+
+        // Both writes have the same clock, so the larger client id wins
+        assert_eq!(values(&doc1), vec!["two", "x", "b"]);
+        assert_eq!(values(&doc2), vec!["two", "x", "b"]);
+        assert_eq!(doc1.state_fingerprint(), doc2.state_fingerprint());
+
+        // A write made after seeing the others wins
+        doc1.set(0, "three".to_owned());
+        sync(&doc1, &mut doc2);
+
+        assert_eq!(doc2.get(0), Some(&"three".to_owned()));
+    }
+
+    #[test]
+    fn sets_of_concurrently_removed_elements_are_dropped() {
+        let mut doc1 = Document::with_client_id(1);
+        doc1.extend(["a", "b", "c"].map(String::from));
+        let mut doc2 = Document::with_client_id(2);
+        sync(&doc1, &mut doc2);
+
+        doc1.set(1, "B".to_owned());
+        doc2.remove(1);
+
+        sync(&doc1, &mut doc2);
+        sync(&doc2, &mut doc1);
+
+        assert_eq!(values(&doc1), vec!["a", "c"]);
+        assert_eq!(values(&doc2), vec!["a", "c"]);
+        assert!(doc1.store.registers.writes().is_empty());
+        assert_eq!(doc1.state_fingerprint(), doc2.state_fingerprint());
+    }
+
+    #[test]
+    fn replaced_values_survive_encoding_snapshots_and_splitting() {
+        let mut doc = Document::with_client_id(1);
+        doc.extend((0..50).map(|value| format!("{:0>20}", value)));
+        for index in (0..50).step_by(3) {
+            doc.set(index, format!("set {}", index));
+        }
+
+        let mut decoded = Document::with_client_id(2);
+        decoded
+            .apply_encoded(&doc.encode_full_update().unwrap())
+            .unwrap();
+        assert!(decoded.content_eq(&doc));
+        assert_eq!(decoded.get(3), Some(&"set 3".to_owned()));
+
+        let mut chunked = Document::with_client_id(3);
+        for chunk in Update::from_document(&doc).split(256) {
+            chunk.apply(&mut chunked).unwrap();
+        }
+        assert!(chunked.content_eq(&doc));
+
+        // The restored document's clock carries on, so its writes beat the ones it was restored
+        // with
+        let mut restored = Document::restore(doc.snapshot());
+        assert!(restored.content_eq(&doc));
+        restored.set(3, "again".to_owned());
+        sync(&restored, &mut decoded);
+
+        assert_eq!(decoded.get(3), Some(&"again".to_owned()));
+    }
+}
@@ -0,0 +1,130 @@
+use crate::block::Item;
+use crate::storage::{BlockStorage, VecStorage};
+use crate::store::Store;
+use crate::Document;
+
+/// One of a document's named sequences, from [`Document::get_or_create_root`].
+///
+/// Edits made through a root are sent in the document's updates like any other, but aren't
+/// reported to observers or recorded for undo, which only follow the document's own sequence.
+pub struct RootHandle<'a, T: Item, S: BlockStorage<T> = VecStorage<T>> {
+    document: &'a mut Document<T, S>,
+    name: String,
+}
+
+impl<'a, T: Item, S: BlockStorage<T>> RootHandle<'a, T, S> {
+    /// A handle on the root `name`, which `document` must already have.
+    pub(crate) fn new(document: &'a mut Document<T, S>, name: String) -> RootHandle<'a, T, S> {
+        RootHandle { document, name }
+    }
+
+    fn store(&self) -> &Store<T, S> {
+        &self.document.roots[&self.name]
+    }
+
+    fn store_mut(&mut self) -> &mut Store<T, S> {
+        self.document.root_store_mut(&self.name)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The number of live elements in the root.
+    pub fn len(&self) -> usize {
+        self.store().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The element at `index`, or `None` if `index` is out of bounds.
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.store().values_from(index).next()
+    }
+
+    /// Every element in the root, in order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &T> {
+        self.store().iter_values()
+    }
+
+    /// A copy of every element in the root.
+    pub fn to_vec(&self) -> Vec<T> {
+        self.iter().cloned().collect()
+    }
+
+    /// Inserts `value` at `index`, shifting the root's elements after it to the right.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index > len`, matching [`Vec::insert`].
+    pub fn insert(&mut self, index: usize, value: T) {
+        self.insert_values(index, [value]);
+    }
+
+    /// Inserts `values` at `index` in order as a single block, like
+    /// [`Document::insert_values`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index > len`.
+    pub fn insert_values(&mut self, index: usize, values: impl IntoIterator<Item = T>) {
+        let len = self.len();
+
+        assert!(
+            index <= len,
+            "insertion index (is {}) should be <= len (is {})",
+            index,
+            len
+        );
+
+        self.store_mut()
+            .insert_values(index, values.into_iter().collect());
+        self.document.advance_local_clock();
+    }
+
+    /// Appends `value` to the end of the root.
+    pub fn push(&mut self, value: T) {
+        self.store_mut().append(value);
+        self.document.advance_local_clock();
+    }
+
+    /// Removes the element at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds, matching [`Vec::remove`].
+    pub fn remove(&mut self, index: usize) {
+        let len = self.len();
+
+        assert!(
+            index < len,
+            "removal index (is {}) should be < len (is {})",
+            index,
+            len
+        );
+
+        self.remove_range(index, 1);
+    }
+
+    /// Removes `count` elements starting at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range `index..index + count` is out of bounds.
+    pub fn remove_range(&mut self, index: usize, count: usize) {
+        let len = self.len();
+
+        assert!(
+            index.checked_add(count).is_some_and(|end| end <= len),
+            "removing {} values from {} is out of range for root of length {}",
+            count,
+            index,
+            len
+        );
+
+        let deleted = self.store_mut().delete_range(index, count);
+        self.document.record_deletion(&deleted);
+    }
+}
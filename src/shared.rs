@@ -0,0 +1,321 @@
+//! A document handle which can be shared between threads.
+
+use crate::block::Item;
+use crate::encoding::EncodeError;
+use crate::observer::ChangeEvent;
+use crate::update::{ApplyError, Update};
+use crate::{BlockId, ClockVector, Document, StateVector};
+use bincode::Encode;
+use std::fmt;
+use std::mem;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+/// How many notifications a subscriber can fall behind by before it starts missing them.
+const NOTIFICATION_CAPACITY: usize = 64;
+
+/// Everything a single write through a [`SharedDocument`] changed, local edits included.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct ChangeNotification<T> {
+    pub events: Vec<ChangeEvent<T>>,
+}
+
+struct Shared<T: Item> {
+    document: RwLock<Document<T>>,
+    /// Changes made by the write in progress, recorded by an observer on the document.
+    events: Arc<Mutex<Vec<ChangeEvent<T>>>>,
+    subscribers: Mutex<Vec<SyncSender<ChangeNotification<T>>>>,
+}
+
+/// A cheaply cloneable handle to a document behind a read-write lock.
+///
+/// Every write is notified to subscribers before its lock is released, so nothing can read a
+/// change before it's been notified, nor see an update half integrated.
+pub struct SharedDocument<T: Item> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Item + Send + Sync + 'static> SharedDocument<T> {
+    pub fn new(mut document: Document<T>) -> SharedDocument<T> {
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = events.clone();
+
+        document.observe_local(move |event| {
+            recorded
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(event.clone())
+        });
+
+        SharedDocument {
+            shared: Arc::new(Shared {
+                document: RwLock::new(document),
+                events,
+                subscribers: Mutex::new(vec![]),
+            }),
+        }
+    }
+
+    /// Runs `f` with shared access to the document.
+    pub fn read<R>(&self, f: impl FnOnce(&Document<T>) -> R) -> R {
+        let document = self
+            .shared
+            .document
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        f(&document)
+    }
+
+    /// Runs `f` with exclusive access to the document, then notifies subscribers of everything it
+    /// changed as a single notification. If `f` panics, whatever it changed before panicking is
+    /// still notified, rather than being sent along with the next write's changes.
+    pub fn write<R>(&self, f: impl FnOnce(&mut Document<T>) -> R) -> R {
+        // Documents are left intact by panicking edits, so a poisoned lock is still usable
+        let mut document = self
+            .shared
+            .document
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        // Dropped before the lock, on unwinding too
+        let notifier = Notifier { shared: self };
+
+        let result = f(&mut document);
+
+        drop(notifier);
+        drop(document);
+
+        result
+    }
+
+    /// Returns a receiver for a notification of every later change. A subscriber which falls
+    /// more than a few dozen notifications behind misses changes until it catches up.
+    pub fn subscribe(&self) -> Receiver<ChangeNotification<T>> {
+        let (sender, receiver) = mpsc::sync_channel(NOTIFICATION_CAPACITY);
+
+        self.shared
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(sender);
+
+        receiver
+    }
+
+    pub fn insert(&self, index: usize, value: T) -> BlockId {
+        self.write(|document| document.insert(index, value))
+    }
+
+    pub fn push(&self, value: T) -> BlockId {
+        self.write(|document| document.push(value))
+    }
+
+    pub fn remove(&self, index: usize) {
+        self.write(|document| document.remove(index))
+    }
+
+    pub fn apply_update(&self, update: Update<T>) -> Result<(), ApplyError> {
+        self.write(|document| update.apply(document))
+    }
+
+    pub fn len(&self) -> usize {
+        self.read(Document::len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read(Document::is_empty)
+    }
+
+    pub fn state_vector(&self) -> StateVector {
+        self.read(Document::state_vector)
+    }
+}
+
+impl<T: Item> SharedDocument<T> {
+    /// Notifies subscribers of the changes recorded since the last notification, if there are
+    /// any, and clears them.
+    fn notify_recorded(&self) {
+        let events = mem::take(
+            &mut *self
+                .shared
+                .events
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+
+        if !events.is_empty() {
+            self.notify(ChangeNotification { events });
+        }
+    }
+
+    fn notify(&self, notification: ChangeNotification<T>) {
+        let mut subscribers = self
+            .shared
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        subscribers.retain(|subscriber| {
+            !matches!(
+                subscriber.try_send(notification.clone()),
+                Err(TrySendError::Disconnected(_))
+            )
+        });
+    }
+}
+
+/// Notifies the changes made by a write when dropped, whether the write finished or panicked.
+struct Notifier<'a, T: Item> {
+    shared: &'a SharedDocument<T>,
+}
+
+impl<T: Item> Drop for Notifier<'_, T> {
+    fn drop(&mut self) {
+        self.shared.notify_recorded();
+    }
+}
+
+impl<T: Item + Encode + Send + Sync + 'static> SharedDocument<T> {
+    /// Encodes everything in the document which isn't covered by `since`.
+    pub fn encode_update_since(&self, since: &ClockVector) -> Result<Vec<u8>, EncodeError> {
+        self.read(|document| Update::from_document_since(document, since).encode())
+    }
+}
+
+impl<T: Item> Clone for SharedDocument<T> {
+    fn clone(&self) -> Self {
+        SharedDocument {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T: Item> fmt::Debug for SharedDocument<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedDocument").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shared::SharedDocument;
+    use crate::{Document, Update};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::thread;
+
+    #[test]
+    fn concurrent_appends_are_all_kept() {
+        let shared = SharedDocument::new(Document::with_client_id(1));
+
+        let threads: Vec<_> = (0..4)
+            .map(|thread| {
+                let shared = shared.clone();
+
+                thread::spawn(move || {
+                    for value in 0..250 {
+                        shared.push(thread * 1000 + value);
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(shared.len(), 1000);
+        shared.read(|document| {
+            let mut values: Vec<_> = document.iter().copied().collect();
+            values.sort();
+            values.dedup();
+
+            assert_eq!(values.len(), 1000);
+        });
+    }
+
+    #[test]
+    fn notifies_subscribers_once_per_write() {
+        let shared = SharedDocument::new(Document::with_client_id(1));
+        let notifications = shared.subscribe();
+
+        let mut remote = Document::with_client_id(2);
+        remote.push('a');
+        remote.push('b');
+
+        shared.apply_update(Update::from_document(&remote)).unwrap();
+        shared.write(|document| {
+            document.push('c');
+            document.remove(0);
+        });
+
+        let applied = notifications.recv().unwrap();
+        assert_eq!(applied.events.len(), 1);
+        assert_eq!(applied.events[0].inserted[0].values, vec!['a', 'b']);
+        assert!(!applied.events[0].local);
+
+        let edited = notifications.recv().unwrap();
+        assert_eq!(edited.events.len(), 2);
+        assert!(edited.events.iter().all(|event| event.local));
+
+        assert!(notifications.try_recv().is_err());
+        assert_eq!(
+            shared.read(|document| document.iter().copied().collect::<String>()),
+            "bc"
+        );
+    }
+
+    #[test]
+    fn a_panicking_write_notifies_its_own_changes() {
+        let shared = SharedDocument::new(Document::with_client_id(1));
+        let notifications = shared.subscribe();
+
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            shared.write(|document| {
+                document.push('a');
+                panic!("after changing the document");
+            })
+        }));
+        assert!(panicked.is_err());
+
+        let interrupted = notifications.try_recv().unwrap();
+        assert_eq!(interrupted.events.len(), 1);
+        assert_eq!(interrupted.events[0].inserted[0].values, vec!['a']);
+
+        shared.push('b');
+
+        let next = notifications.try_recv().unwrap();
+        assert_eq!(next.events.len(), 1);
+        assert_eq!(next.events[0].inserted[0].values, vec!['b']);
+
+        assert!(notifications.try_recv().is_err());
+    }
+
+    #[test]
+    fn readers_never_see_half_applied_updates() {
+        let shared = SharedDocument::new(Document::with_client_id(1));
+
+        let writer = {
+            let shared = shared.clone();
+
+            thread::spawn(move || {
+                let mut remote = Document::with_client_id(2);
+
+                for value in 0..200 {
+                    let before = remote.state_vector();
+                    remote.push(value);
+                    remote.push(value);
+
+                    let update = Update::from_document_since(&remote, before.as_ref());
+                    shared.apply_update(update).unwrap();
+                }
+            })
+        };
+
+        while !writer.is_finished() {
+            assert_eq!(shared.len() % 2, 0);
+        }
+
+        writer.join().unwrap();
+        assert_eq!(shared.len(), 400);
+    }
+}
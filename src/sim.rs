@@ -0,0 +1,310 @@
+//! A deterministic simulator for checking that replicas converge, however their updates are
+//! delivered.
+
+use crate::block::Item;
+use crate::{Document, Update};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt::Debug;
+
+/// An update in flight to a single replica.
+struct Message<T: Item> {
+    to: usize,
+    update: Update<T>,
+}
+
+/// A set of replicas which make local edits and exchange the resulting updates.
+///
+/// Every local edit is broadcast as an update to every other replica, but nothing is delivered
+/// until asked, so tests control the delivery order, delays and duplicates. Every action is
+/// logged, so a failure to converge reports the exact sequence which caused it.
+pub(crate) struct Simulator<T: Item> {
+    replicas: Vec<Document<T>>,
+    in_flight: Vec<Message<T>>,
+    rng: StdRng,
+    seed: u64,
+    log: Vec<String>,
+}
+
+impl<T: Item + Debug + PartialEq> Simulator<T> {
+    /// Creates `replicas` empty documents, with client ids `1..=replicas`.
+    pub(crate) fn new(replicas: usize, seed: u64) -> Simulator<T> {
+        Simulator {
+            replicas: (1..=replicas as u64)
+                .map(Document::with_client_id)
+                .collect(),
+            in_flight: vec![],
+            rng: StdRng::seed_from_u64(seed),
+            seed,
+            log: vec![],
+        }
+    }
+
+    pub(crate) fn insert(&mut self, replica: usize, index: usize, value: T) {
+        self.log.push(format!(
+            "replica {} inserts {:?} at {}",
+            replica, value, index
+        ));
+
+        let update = self.replicas[replica].transact(|transaction| {
+            transaction.insert(index, value);
+        });
+        self.broadcast(replica, update);
+    }
+
+    pub(crate) fn push(&mut self, replica: usize, value: T) {
+        self.log
+            .push(format!("replica {} pushes {:?}", replica, value));
+
+        let update = self.replicas[replica].transact(|transaction| {
+            transaction.push(value);
+        });
+        self.broadcast(replica, update);
+    }
+
+    pub(crate) fn remove_range(&mut self, replica: usize, index: usize, count: usize) {
+        self.log.push(format!(
+            "replica {} removes {}..{}",
+            replica,
+            index,
+            index + count
+        ));
+
+        let update =
+            self.replicas[replica].transact(|transaction| transaction.remove_range(index, count));
+        self.broadcast(replica, update);
+    }
+
+    /// The number of updates which haven't been delivered yet.
+    pub(crate) fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Delivers the `message`th in-flight update, in the order they were sent.
+    pub(crate) fn deliver(&mut self, message: usize) {
+        let Message { to, update } = self.in_flight.remove(message);
+        self.log
+            .push(format!("message {} delivered to replica {}", message, to));
+
+        if let Err(error) = self.replicas[to].apply_or_queue(update) {
+            self.fail(&format!("replica {} rejected an update: {}", to, error));
+        }
+    }
+
+    /// Queues a second copy of the `message`th in-flight update.
+    pub(crate) fn duplicate(&mut self, message: usize) {
+        self.log.push(format!("message {} duplicated", message));
+
+        let Message { to, update } = &self.in_flight[message];
+        let copy = Message {
+            to: *to,
+            update: update.clone(),
+        };
+
+        self.in_flight.push(copy);
+    }
+
+    /// Delivers every in-flight update in a random order.
+    pub(crate) fn deliver_all(&mut self) {
+        while !self.in_flight.is_empty() {
+            let message = self.rng.gen_range(0, self.in_flight.len());
+            self.deliver(message);
+        }
+    }
+
+    /// Makes `steps` random edits, deliveries and duplications, with values from `value`.
+    pub(crate) fn run_random(&mut self, steps: usize, mut value: impl FnMut(usize) -> T) {
+        for step in 0..steps {
+            let replica = self.rng.gen_range(0, self.replicas.len());
+            let len = self.replicas[replica].len();
+
+            match self.rng.gen_range(0, 10) {
+                0..=2 => {
+                    let index = self.rng.gen_range(0, len + 1);
+                    self.insert(replica, index, value(step));
+                }
+                3 => self.push(replica, value(step)),
+                4 if len > 0 => {
+                    let index = self.rng.gen_range(0, len);
+                    let count = self.rng.gen_range(1, (len - index).min(3) + 1);
+                    self.remove_range(replica, index, count);
+                }
+                5 if !self.in_flight.is_empty() => {
+                    let message = self.rng.gen_range(0, self.in_flight.len());
+                    self.duplicate(message);
+                }
+                _ if !self.in_flight.is_empty() => {
+                    let message = self.rng.gen_range(0, self.in_flight.len());
+                    self.deliver(message);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Delivers everything still in flight, then checks every replica has the same contents.
+    pub(crate) fn assert_converged(&mut self) {
+        self.deliver_all();
+
+        let expected: Vec<&T> = self.replicas[0].store.iter_values().collect();
+
+        for (replica, document) in self.replicas.iter().enumerate().skip(1) {
+            let values: Vec<&T> = document.store.iter_values().collect();
+
+            if values != expected {
+                self.fail(&format!(
+                    "replica {} has {:?} but replica 0 has {:?}",
+                    replica, values, expected
+                ));
+            }
+
+            if document.pending_updates() > 0 {
+                self.fail(&format!(
+                    "replica {} still has {} queued updates",
+                    replica,
+                    document.pending_updates()
+                ));
+            }
+        }
+    }
+
+    pub(crate) fn values(&self, replica: usize) -> Vec<T> {
+        self.replicas[replica]
+            .store
+            .iter_values()
+            .cloned()
+            .collect()
+    }
+
+    fn broadcast(&mut self, from: usize, update: Update<T>) {
+        for to in (0..self.replicas.len()).filter(|to| *to != from) {
+            self.in_flight.push(Message {
+                to,
+                update: update.clone(),
+            });
+        }
+    }
+
+    fn fail(&self, reason: &str) -> ! {
+        panic!("{} (seed {})\n{}", reason, self.seed, self.log.join("\n"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sim::Simulator;
+
+    #[test]
+    fn concurrent_inserts_at_the_same_position() {
+        let mut sim = Simulator::new(3, 0);
+        sim.push(0, 'a');
+        sim.push(0, 'e');
+        sim.deliver_all();
+
+        sim.insert(0, 1, 'b');
+        sim.insert(1, 1, 'c');
+        sim.insert(2, 1, 'd');
+
+        // Deliver in the reverse of the order the inserts were made
+        while sim.in_flight() > 0 {
+            sim.deliver(sim.in_flight() - 1);
+        }
+
+        sim.assert_converged();
+
+        let values = sim.values(0);
+        assert_eq!(values.first(), Some(&'a'));
+        assert_eq!(values.last(), Some(&'e'));
+        assert_eq!(values.len(), 5);
+    }
+
+    #[test]
+    fn interleaved_inserts_and_deletes() {
+        let mut sim = Simulator::new(3, 1);
+        for value in "abcdef".chars() {
+            sim.push(0, value);
+        }
+        sim.deliver_all();
+
+        sim.remove_range(0, 1, 3);
+        sim.insert(1, 2, 'x');
+        sim.remove_range(2, 0, 2);
+        sim.insert(2, 3, 'y');
+        sim.insert(0, 0, 'z');
+
+        sim.assert_converged();
+
+        assert_eq!(sim.values(0), vec!['z', 'x', 'e', 'y', 'f']);
+    }
+
+    #[test]
+    fn insert_beside_a_local_delete_and_a_concurrent_insert() {
+        for reverse in [false, true] {
+            let mut sim = Simulator::new(3, 3);
+            for value in "abcde".chars() {
+                sim.push(0, value);
+            }
+            sim.deliver_all();
+
+            // Replica 0 replaces 'c' with 'x' while replica 1 inserts just after 'c'
+            sim.remove_range(0, 2, 1);
+            sim.insert(0, 2, 'x');
+            sim.insert(1, 3, 'y');
+
+            while sim.in_flight() > 0 {
+                sim.deliver(if reverse { sim.in_flight() - 1 } else { 0 });
+            }
+
+            sim.assert_converged();
+
+            assert_eq!(sim.values(0), vec!['a', 'b', 'x', 'y', 'd', 'e']);
+        }
+    }
+
+    #[test]
+    fn concurrent_appends_from_two_clients() {
+        for reverse in [false, true] {
+            let mut sim = Simulator::new(2, 4);
+            sim.push(0, 'a');
+            sim.push(0, 'b');
+            sim.deliver_all();
+
+            sim.push(0, 'x');
+            sim.push(1, 'y');
+            sim.push(1, 'z');
+
+            while sim.in_flight() > 0 {
+                sim.deliver(if reverse { sim.in_flight() - 1 } else { 0 });
+            }
+
+            sim.assert_converged();
+
+            assert_eq!(sim.values(0), vec!['a', 'b', 'x', 'y', 'z']);
+        }
+    }
+
+    #[test]
+    fn duplicate_delivery() {
+        let mut sim = Simulator::new(2, 2);
+        sim.push(0, 'a');
+        sim.push(0, 'b');
+        sim.remove_range(0, 0, 1);
+
+        for message in 0..sim.in_flight() {
+            sim.duplicate(message);
+        }
+
+        sim.assert_converged();
+
+        assert_eq!(sim.values(1), vec!['b']);
+    }
+
+    #[test]
+    fn random_edits_converge() {
+        for seed in 0..50 {
+            let mut sim = Simulator::new(3, seed);
+            sim.run_random(60, |step| step);
+            sim.assert_converged();
+        }
+    }
+}
@@ -0,0 +1,618 @@
+use crate::block::{Block, Item};
+use crate::clock::Clock;
+use crate::delete_set::DeleteSet;
+use crate::document::{BlockId, ClientId};
+use crate::encoding::{self, DecodeError, EncodeError};
+use crate::map::MapEntry;
+use crate::marks::Mark;
+use crate::moves::Move;
+use crate::register::Register;
+use crate::version::DeleteRecord;
+use bincode::{Decode, Encode};
+
+/// The complete state of a [`Document`](crate::Document), including its local client id and clock, so a replica
+/// can be persisted and later reopened as the same client.
+///
+/// Unlike an [`Update`](crate::Update), a snapshot records every block exactly as it is linked in
+/// the store, tombstones included. Queued updates and observers aren't part of a snapshot.
+#[derive(Eq, PartialEq, Clone, Debug, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Snapshot<T: Item> {
+    pub(crate) client_id: ClientId,
+    pub(crate) clock: Clock,
+    pub(crate) clients: Vec<(ClientId, Clock)>,
+    pub(crate) start: Option<BlockId>,
+    pub(crate) end: Option<BlockId>,
+    pub(crate) blocks: Vec<(ClientId, Vec<Block<T>>)>,
+    pub(crate) map: Vec<MapEntry<T>>,
+    pub(crate) marks: Vec<Mark>,
+    pub(crate) moves: Vec<Move>,
+    pub(crate) deletions: Vec<DeleteRecord>,
+    /// The values of deleted elements, if the document keeps them.
+    pub(crate) deleted_values: Option<Vec<(BlockId, Vec<T>)>>,
+    /// The document's named roots, in name order.
+    pub(crate) roots: Vec<RootSnapshot<T>>,
+    /// The values which replace those elements were inserted with.
+    pub(crate) registers: Vec<Register<T>>,
+    /// The clock of each client the document's history was pruned at, if it has been.
+    pub(crate) checkpoint: Vec<(ClientId, Clock)>,
+    /// The elements censored with [`Document::censor`](crate::Document::censor).
+    pub(crate) censored: DeleteSet,
+}
+
+/// One of a document's named roots, with its blocks linked as they are in its store.
+#[derive(Eq, PartialEq, Clone, Debug, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct RootSnapshot<T: Item> {
+    pub(crate) name: String,
+    pub(crate) start: Option<BlockId>,
+    pub(crate) end: Option<BlockId>,
+    pub(crate) blocks: Vec<(ClientId, Vec<Block<T>>)>,
+}
+
+/// A [`Block`] as encoded before blocks had a priority.
+#[derive(Encode, Decode)]
+struct BlockV1<T: Item> {
+    id: Clock,
+    origin_left: Option<BlockId>,
+    left: Option<BlockId>,
+    origin_right: Option<BlockId>,
+    right: Option<BlockId>,
+    value: Vec<T>,
+    length: usize,
+    deleted: bool,
+}
+
+impl<T: Item> From<BlockV1<T>> for Block<T> {
+    fn from(block: BlockV1<T>) -> Self {
+        Block {
+            id: block.id,
+            origin_left: block.origin_left,
+            left: block.left,
+            origin_right: block.origin_right,
+            right: block.right,
+            value: block.value,
+            length: block.length,
+            deleted: block.deleted,
+            priority: 0,
+        }
+    }
+}
+
+fn upgrade_blocks<T: Item>(
+    blocks: Vec<(ClientId, Vec<BlockV1<T>>)>,
+) -> Vec<(ClientId, Vec<Block<T>>)> {
+    blocks
+        .into_iter()
+        .map(|(client_id, blocks)| (client_id, blocks.into_iter().map(Block::from).collect()))
+        .collect()
+}
+
+/// A [`Snapshot`] as encoded before documents had a map.
+#[derive(Encode, Decode)]
+struct SnapshotV1<T: Item> {
+    client_id: ClientId,
+    clock: Clock,
+    clients: Vec<(ClientId, Clock)>,
+    start: Option<BlockId>,
+    end: Option<BlockId>,
+    blocks: Vec<(ClientId, Vec<BlockV1<T>>)>,
+}
+
+/// A [`Snapshot`] as encoded before documents had marks.
+#[derive(Encode, Decode)]
+struct SnapshotV2<T: Item> {
+    client_id: ClientId,
+    clock: Clock,
+    clients: Vec<(ClientId, Clock)>,
+    start: Option<BlockId>,
+    end: Option<BlockId>,
+    blocks: Vec<(ClientId, Vec<BlockV1<T>>)>,
+    map: Vec<MapEntry<T>>,
+}
+
+/// A [`Snapshot`] as encoded before blocks had a priority.
+#[derive(Encode, Decode)]
+struct SnapshotV3<T: Item> {
+    client_id: ClientId,
+    clock: Clock,
+    clients: Vec<(ClientId, Clock)>,
+    start: Option<BlockId>,
+    end: Option<BlockId>,
+    blocks: Vec<(ClientId, Vec<BlockV1<T>>)>,
+    map: Vec<MapEntry<T>>,
+    marks: Vec<Mark>,
+}
+
+/// A [`Snapshot`] as encoded before documents had moves.
+#[derive(Encode, Decode)]
+struct SnapshotV4<T: Item> {
+    client_id: ClientId,
+    clock: Clock,
+    clients: Vec<(ClientId, Clock)>,
+    start: Option<BlockId>,
+    end: Option<BlockId>,
+    blocks: Vec<(ClientId, Vec<Block<T>>)>,
+    map: Vec<MapEntry<T>>,
+    marks: Vec<Mark>,
+}
+
+impl<T: Item> From<SnapshotV4<T>> for Snapshot<T> {
+    fn from(snapshot: SnapshotV4<T>) -> Self {
+        Snapshot {
+            client_id: snapshot.client_id,
+            clock: snapshot.clock,
+            clients: snapshot.clients,
+            start: snapshot.start,
+            end: snapshot.end,
+            blocks: snapshot.blocks,
+            map: snapshot.map,
+            marks: snapshot.marks,
+            moves: vec![],
+            deletions: vec![],
+            deleted_values: None,
+            roots: vec![],
+            registers: vec![],
+            checkpoint: vec![],
+            censored: DeleteSet::empty(),
+        }
+    }
+}
+
+/// A [`Snapshot`] as encoded before elements could be censored.
+#[derive(Encode, Decode)]
+struct SnapshotV9<T: Item> {
+    client_id: ClientId,
+    clock: Clock,
+    clients: Vec<(ClientId, Clock)>,
+    start: Option<BlockId>,
+    end: Option<BlockId>,
+    blocks: Vec<(ClientId, Vec<Block<T>>)>,
+    map: Vec<MapEntry<T>>,
+    marks: Vec<Mark>,
+    moves: Vec<Move>,
+    deletions: Vec<DeleteRecord>,
+    deleted_values: Option<Vec<(BlockId, Vec<T>)>>,
+    roots: Vec<RootSnapshot<T>>,
+    registers: Vec<Register<T>>,
+    checkpoint: Vec<(ClientId, Clock)>,
+}
+
+impl<T: Item> From<SnapshotV9<T>> for Snapshot<T> {
+    fn from(snapshot: SnapshotV9<T>) -> Self {
+        Snapshot {
+            client_id: snapshot.client_id,
+            clock: snapshot.clock,
+            clients: snapshot.clients,
+            start: snapshot.start,
+            end: snapshot.end,
+            blocks: snapshot.blocks,
+            map: snapshot.map,
+            marks: snapshot.marks,
+            moves: snapshot.moves,
+            deletions: snapshot.deletions,
+            deleted_values: snapshot.deleted_values,
+            roots: snapshot.roots,
+            registers: snapshot.registers,
+            checkpoint: snapshot.checkpoint,
+            censored: DeleteSet::empty(),
+        }
+    }
+}
+
+/// A [`Snapshot`] as encoded before documents could be checkpointed.
+#[derive(Encode, Decode)]
+struct SnapshotV8<T: Item> {
+    client_id: ClientId,
+    clock: Clock,
+    clients: Vec<(ClientId, Clock)>,
+    start: Option<BlockId>,
+    end: Option<BlockId>,
+    blocks: Vec<(ClientId, Vec<Block<T>>)>,
+    map: Vec<MapEntry<T>>,
+    marks: Vec<Mark>,
+    moves: Vec<Move>,
+    deletions: Vec<DeleteRecord>,
+    deleted_values: Option<Vec<(BlockId, Vec<T>)>>,
+    roots: Vec<RootSnapshot<T>>,
+    registers: Vec<Register<T>>,
+}
+
+impl<T: Item> From<SnapshotV8<T>> for Snapshot<T> {
+    fn from(snapshot: SnapshotV8<T>) -> Self {
+        Snapshot {
+            client_id: snapshot.client_id,
+            clock: snapshot.clock,
+            clients: snapshot.clients,
+            start: snapshot.start,
+            end: snapshot.end,
+            blocks: snapshot.blocks,
+            map: snapshot.map,
+            marks: snapshot.marks,
+            moves: snapshot.moves,
+            deletions: snapshot.deletions,
+            deleted_values: snapshot.deleted_values,
+            roots: snapshot.roots,
+            registers: snapshot.registers,
+            checkpoint: vec![],
+            censored: DeleteSet::empty(),
+        }
+    }
+}
+
+/// A [`Snapshot`] as encoded before elements' values could be replaced.
+#[derive(Encode, Decode)]
+struct SnapshotV7<T: Item> {
+    client_id: ClientId,
+    clock: Clock,
+    clients: Vec<(ClientId, Clock)>,
+    start: Option<BlockId>,
+    end: Option<BlockId>,
+    blocks: Vec<(ClientId, Vec<Block<T>>)>,
+    map: Vec<MapEntry<T>>,
+    marks: Vec<Mark>,
+    moves: Vec<Move>,
+    deletions: Vec<DeleteRecord>,
+    deleted_values: Option<Vec<(BlockId, Vec<T>)>>,
+    roots: Vec<RootSnapshot<T>>,
+}
+
+impl<T: Item> From<SnapshotV7<T>> for Snapshot<T> {
+    fn from(snapshot: SnapshotV7<T>) -> Self {
+        Snapshot {
+            client_id: snapshot.client_id,
+            clock: snapshot.clock,
+            clients: snapshot.clients,
+            start: snapshot.start,
+            end: snapshot.end,
+            blocks: snapshot.blocks,
+            map: snapshot.map,
+            marks: snapshot.marks,
+            moves: snapshot.moves,
+            deletions: snapshot.deletions,
+            deleted_values: snapshot.deleted_values,
+            roots: snapshot.roots,
+            registers: vec![],
+            checkpoint: vec![],
+            censored: DeleteSet::empty(),
+        }
+    }
+}
+
+/// A [`Snapshot`] as encoded before documents had named roots.
+#[derive(Encode, Decode)]
+struct SnapshotV6<T: Item> {
+    client_id: ClientId,
+    clock: Clock,
+    clients: Vec<(ClientId, Clock)>,
+    start: Option<BlockId>,
+    end: Option<BlockId>,
+    blocks: Vec<(ClientId, Vec<Block<T>>)>,
+    map: Vec<MapEntry<T>>,
+    marks: Vec<Mark>,
+    moves: Vec<Move>,
+    deletions: Vec<DeleteRecord>,
+    deleted_values: Option<Vec<(BlockId, Vec<T>)>>,
+}
+
+impl<T: Item> From<SnapshotV6<T>> for Snapshot<T> {
+    fn from(snapshot: SnapshotV6<T>) -> Self {
+        Snapshot {
+            client_id: snapshot.client_id,
+            clock: snapshot.clock,
+            clients: snapshot.clients,
+            start: snapshot.start,
+            end: snapshot.end,
+            blocks: snapshot.blocks,
+            map: snapshot.map,
+            marks: snapshot.marks,
+            moves: snapshot.moves,
+            deletions: snapshot.deletions,
+            deleted_values: snapshot.deleted_values,
+            roots: vec![],
+            registers: vec![],
+            checkpoint: vec![],
+            censored: DeleteSet::empty(),
+        }
+    }
+}
+
+/// A [`Snapshot`] as encoded before deletions recorded who made them.
+#[derive(Encode, Decode)]
+struct SnapshotV5<T: Item> {
+    client_id: ClientId,
+    clock: Clock,
+    clients: Vec<(ClientId, Clock)>,
+    start: Option<BlockId>,
+    end: Option<BlockId>,
+    blocks: Vec<(ClientId, Vec<Block<T>>)>,
+    map: Vec<MapEntry<T>>,
+    marks: Vec<Mark>,
+    moves: Vec<Move>,
+}
+
+impl<T: Item> From<SnapshotV5<T>> for Snapshot<T> {
+    fn from(snapshot: SnapshotV5<T>) -> Self {
+        Snapshot {
+            client_id: snapshot.client_id,
+            clock: snapshot.clock,
+            clients: snapshot.clients,
+            start: snapshot.start,
+            end: snapshot.end,
+            blocks: snapshot.blocks,
+            map: snapshot.map,
+            marks: snapshot.marks,
+            moves: snapshot.moves,
+            deletions: vec![],
+            deleted_values: None,
+            roots: vec![],
+            registers: vec![],
+            checkpoint: vec![],
+            censored: DeleteSet::empty(),
+        }
+    }
+}
+
+impl<T: Item> From<SnapshotV3<T>> for Snapshot<T> {
+    fn from(snapshot: SnapshotV3<T>) -> Self {
+        Snapshot {
+            client_id: snapshot.client_id,
+            clock: snapshot.clock,
+            clients: snapshot.clients,
+            start: snapshot.start,
+            end: snapshot.end,
+            blocks: upgrade_blocks(snapshot.blocks),
+            map: snapshot.map,
+            marks: snapshot.marks,
+            moves: vec![],
+            deletions: vec![],
+            deleted_values: None,
+            roots: vec![],
+            registers: vec![],
+            checkpoint: vec![],
+            censored: DeleteSet::empty(),
+        }
+    }
+}
+
+impl<T: Item> From<SnapshotV2<T>> for Snapshot<T> {
+    fn from(snapshot: SnapshotV2<T>) -> Self {
+        Snapshot {
+            client_id: snapshot.client_id,
+            clock: snapshot.clock,
+            clients: snapshot.clients,
+            start: snapshot.start,
+            end: snapshot.end,
+            blocks: upgrade_blocks(snapshot.blocks),
+            map: snapshot.map,
+            marks: vec![],
+            moves: vec![],
+            deletions: vec![],
+            deleted_values: None,
+            roots: vec![],
+            registers: vec![],
+            checkpoint: vec![],
+            censored: DeleteSet::empty(),
+        }
+    }
+}
+
+impl<T: Item> From<SnapshotV1<T>> for Snapshot<T> {
+    fn from(snapshot: SnapshotV1<T>) -> Self {
+        Snapshot {
+            client_id: snapshot.client_id,
+            clock: snapshot.clock,
+            clients: snapshot.clients,
+            start: snapshot.start,
+            end: snapshot.end,
+            blocks: upgrade_blocks(snapshot.blocks),
+            map: vec![],
+            marks: vec![],
+            moves: vec![],
+            deletions: vec![],
+            deleted_values: None,
+            roots: vec![],
+            registers: vec![],
+            checkpoint: vec![],
+            censored: DeleteSet::empty(),
+        }
+    }
+}
+
+impl<T: Item + Encode> Snapshot<T> {
+    /// Encodes the snapshot in this crate's versioned wire format, for [`Snapshot::decode`].
+    pub fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        encoding::encode(self)
+    }
+}
+
+impl<T: Item + Decode> Snapshot<T> {
+    /// Decodes a snapshot written by [`Snapshot::encode`].
+    pub fn decode(bytes: &[u8]) -> Result<Snapshot<T>, DecodeError> {
+        match encoding::version(bytes)? {
+            1 => encoding::decode_body::<SnapshotV1<T>>(bytes).map(Snapshot::from),
+            2 => encoding::decode_body::<SnapshotV2<T>>(bytes).map(Snapshot::from),
+            3 | 4 => encoding::decode_body::<SnapshotV3<T>>(bytes).map(Snapshot::from),
+            5 => encoding::decode_body::<SnapshotV4<T>>(bytes).map(Snapshot::from),
+            6 => encoding::decode_body::<SnapshotV5<T>>(bytes).map(Snapshot::from),
+            7 | 8 => encoding::decode_body::<SnapshotV6<T>>(bytes).map(Snapshot::from),
+            9 => encoding::decode_body::<SnapshotV7<T>>(bytes).map(Snapshot::from),
+            10 => encoding::decode_body::<SnapshotV8<T>>(bytes).map(Snapshot::from),
+            11 => encoding::decode_body::<SnapshotV9<T>>(bytes).map(Snapshot::from),
+            _ => encoding::decode(bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::snapshot::{BlockV1, Snapshot, SnapshotV3, SnapshotV4, SnapshotV5};
+    use crate::{Document, Update};
+    use bincode::config;
+
+    fn document_with_history() -> Document<String> {
+        let mut remote = Document::with_client_id(2);
+        remote.push("x".to_owned());
+        remote.push("y".to_owned());
+
+        let mut document = Document::with_client_id(1);
+        document.push("a".to_owned());
+        document.push("b".to_owned());
+        document.push("c".to_owned());
+        Update::from_document(&remote).apply(&mut document).unwrap();
+        document.insert(1, "d".to_owned());
+        document.remove_range(2, 2);
+
+        document
+    }
+
+    #[test]
+    fn restores_an_identical_document() {
+        let document = document_with_history();
+        let restored = Document::restore(document.snapshot());
+
+        assert_eq!(
+            restored.store.iter_values().collect::<Vec<_>>(),
+            document.store.iter_values().collect::<Vec<_>>()
+        );
+        assert_eq!(restored.snapshot(), document.snapshot());
+
+        // Updates list clients in store order, which a restored store needn't share, so compare
+        // what they produce instead
+        let mut from_restored = Document::with_client_id(3);
+        Update::from_document(&restored)
+            .apply(&mut from_restored)
+            .unwrap();
+
+        let mut from_original = Document::with_client_id(3);
+        Update::from_document(&document)
+            .apply(&mut from_original)
+            .unwrap();
+
+        assert_eq!(
+            from_restored.store.iter_values().collect::<Vec<_>>(),
+            from_original.store.iter_values().collect::<Vec<_>>()
+        );
+        assert_eq!(from_restored.state_vector(), from_original.state_vector());
+    }
+
+    #[test]
+    fn restored_document_continues_as_the_same_client() {
+        let mut document = document_with_history();
+        let mut restored =
+            Document::restore(Snapshot::decode(&document.snapshot().encode().unwrap()).unwrap());
+
+        document.push("e".to_owned());
+        restored.push("e".to_owned());
+        document.remove(0);
+        restored.remove(0);
+
+        assert_eq!(restored.client_id, 1);
+        assert_eq!(restored.state_vector(), document.state_vector());
+        assert_eq!(restored.snapshot(), document.snapshot());
+    }
+
+    #[test]
+    fn keeps_the_priority_of_blocks() {
+        let mut document = Document::with_client_id(1).with_priority(3);
+        document.push("a".to_owned());
+
+        let decoded = Snapshot::decode(&document.snapshot().encode().unwrap()).unwrap();
+
+        assert_eq!(decoded, document.snapshot());
+        assert_eq!(decoded.blocks[0].1[0].priority, 3);
+    }
+
+    #[test]
+    fn decodes_snapshots_written_before_priorities() {
+        let document = document_with_history();
+        let snapshot = document.snapshot();
+        let old = SnapshotV3 {
+            client_id: snapshot.client_id,
+            clock: snapshot.clock,
+            clients: snapshot.clients.clone(),
+            start: snapshot.start,
+            end: snapshot.end,
+            blocks: snapshot
+                .blocks
+                .iter()
+                .map(|(client_id, blocks)| {
+                    let blocks = blocks
+                        .iter()
+                        .map(|block| BlockV1 {
+                            id: block.id,
+                            origin_left: block.origin_left,
+                            left: block.left,
+                            origin_right: block.origin_right,
+                            right: block.right,
+                            value: block.value.clone(),
+                            length: block.length,
+                            deleted: block.deleted,
+                        })
+                        .collect();
+
+                    (*client_id, blocks)
+                })
+                .collect(),
+            map: snapshot.map.clone(),
+            marks: snapshot.marks.clone(),
+        };
+
+        let mut bytes = bincode::encode_to_vec(4u32, config::standard()).unwrap();
+        bytes.extend(bincode::encode_to_vec(old, config::standard()).unwrap());
+
+        assert_eq!(Snapshot::decode(&bytes), Ok(without_deletions(snapshot)));
+    }
+
+    #[test]
+    fn decodes_snapshots_written_before_moves() {
+        let document = document_with_history();
+        let snapshot = document.snapshot();
+        let old = SnapshotV4 {
+            client_id: snapshot.client_id,
+            clock: snapshot.clock,
+            clients: snapshot.clients.clone(),
+            start: snapshot.start,
+            end: snapshot.end,
+            blocks: snapshot.blocks.clone(),
+            map: snapshot.map.clone(),
+            marks: snapshot.marks.clone(),
+        };
+
+        let mut bytes = bincode::encode_to_vec(5u32, config::standard()).unwrap();
+        bytes.extend(bincode::encode_to_vec(old, config::standard()).unwrap());
+
+        assert_eq!(Snapshot::decode(&bytes), Ok(without_deletions(snapshot)));
+    }
+
+    #[test]
+    fn decodes_snapshots_written_before_deletions_were_recorded() {
+        let document = document_with_history();
+        let snapshot = document.snapshot();
+        let old = SnapshotV5 {
+            client_id: snapshot.client_id,
+            clock: snapshot.clock,
+            clients: snapshot.clients.clone(),
+            start: snapshot.start,
+            end: snapshot.end,
+            blocks: snapshot.blocks.clone(),
+            map: snapshot.map.clone(),
+            marks: snapshot.marks.clone(),
+            moves: snapshot.moves.clone(),
+        };
+
+        let mut bytes = bincode::encode_to_vec(6u32, config::standard()).unwrap();
+        bytes.extend(bincode::encode_to_vec(old, config::standard()).unwrap());
+
+        assert!(!snapshot.deletions.is_empty());
+        assert_eq!(Snapshot::decode(&bytes), Ok(without_deletions(snapshot)));
+    }
+
+    /// `snapshot` as read back from a version which didn't record who made deletions.
+    fn without_deletions(snapshot: Snapshot<String>) -> Snapshot<String> {
+        Snapshot {
+            deletions: vec![],
+            ..snapshot
+        }
+    }
+}
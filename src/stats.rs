@@ -0,0 +1,117 @@
+use crate::block::Item;
+use crate::clock::Clock;
+use crate::document::ClientId;
+use crate::storage::BlockStorage;
+use crate::Document;
+use std::mem::size_of;
+
+/// A summary of what a [`Document`] holds, for debugging sync and memory use.
+#[derive(Eq, PartialEq, Clone, Debug, Default)]
+pub struct DocumentStats {
+    pub total_blocks: usize,
+    pub live_elements: usize,
+    /// Elements which have been deleted but are still kept as tombstones.
+    pub deleted_elements: usize,
+    /// A breakdown for each client with blocks in the document, ordered by client id.
+    pub clients: Vec<ClientStats>,
+    /// Roughly how much heap the blocks take up, not counting anything owned by the values
+    /// themselves, such as the contents of strings.
+    pub approximate_heap_bytes: usize,
+}
+
+/// What a single client has contributed to a [`Document`].
+#[derive(Eq, PartialEq, Clone, Debug, Default)]
+pub struct ClientStats {
+    pub client_id: ClientId,
+    /// The next clock the document expects from this client.
+    pub clock: Clock,
+    pub blocks: usize,
+    /// How many of `blocks` are tombstones.
+    pub deleted_blocks: usize,
+    pub live_elements: usize,
+    pub deleted_elements: usize,
+}
+
+impl DocumentStats {
+    pub(crate) fn new<T: Item, S: BlockStorage<T>>(document: &Document<T, S>) -> DocumentStats {
+        let mut stats = DocumentStats::default();
+
+        let data = &document.store.data;
+        stats.approximate_heap_bytes += data.heap_bytes();
+
+        for client_id in data.clients() {
+            let mut client = ClientStats {
+                client_id,
+                clock: document.next_clock(client_id),
+                ..ClientStats::default()
+            };
+
+            for block in data.iter(client_id) {
+                client.blocks += 1;
+                stats.approximate_heap_bytes += block.value.capacity() * size_of::<T>();
+
+                if block.deleted {
+                    client.deleted_blocks += 1;
+                    client.deleted_elements += block.length;
+                } else {
+                    client.live_elements += block.length;
+                }
+            }
+
+            stats.total_blocks += client.blocks;
+            stats.live_elements += client.live_elements;
+            stats.deleted_elements += client.deleted_elements;
+            stats.clients.push(client);
+        }
+
+        stats.clients.sort_by_key(|client| client.client_id);
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::Clock;
+    use crate::stats::ClientStats;
+    use crate::{Document, Update};
+
+    #[test]
+    fn counts_blocks_and_elements_per_client() {
+        let mut doc = Document::with_client_id(1);
+        doc.extend(["a", "b", "c"].map(String::from));
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc).apply(&mut doc2).unwrap();
+        doc2.insert(3, "d".to_owned());
+        doc2.remove(1);
+
+        let stats = doc2.stats();
+
+        assert_eq!(stats.total_blocks, 4);
+        assert_eq!(stats.live_elements, 3);
+        assert_eq!(stats.deleted_elements, 1);
+        assert!(stats.approximate_heap_bytes > 0);
+        assert_eq!(
+            stats.clients,
+            vec![
+                ClientStats {
+                    client_id: 1,
+                    clock: Clock::new(3),
+                    blocks: 3,
+                    deleted_blocks: 1,
+                    live_elements: 2,
+                    deleted_elements: 1,
+                },
+                ClientStats {
+                    client_id: 2,
+                    clock: Clock::new(1),
+                    blocks: 1,
+                    deleted_blocks: 0,
+                    live_elements: 1,
+                    deleted_elements: 0,
+                },
+            ]
+        );
+    }
+}
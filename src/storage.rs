@@ -0,0 +1,480 @@
+//! Where a [`Document`](crate::Document) keeps its blocks.
+
+use crate::block::{Block, Item};
+use crate::clock::{Clock, ClockRange};
+use crate::document::ClientId;
+use std::collections::HashMap;
+
+/// Each client's blocks, kept in clock order. Implemented by [`VecStorage`], the default, and by
+/// [`PagedArena`] for very large documents. Chosen with
+/// [`Document::with_storage`](crate::Document::with_storage).
+///
+/// Blocks are addressed by their position among their client's blocks, which shifts when a block
+/// is inserted before them.
+pub trait BlockStorage<T: Item>: Default {
+    /// The number of blocks `client_id` has.
+    fn len(&self, client_id: ClientId) -> usize;
+
+    /// The `index`th of `client_id`'s blocks.
+    fn get(&self, client_id: ClientId, index: usize) -> Option<&Block<T>>;
+
+    fn get_mut(&mut self, client_id: ClientId, index: usize) -> Option<&mut Block<T>>;
+
+    /// The position of `client_id`'s block containing `clock`.
+    fn find(&self, client_id: ClientId, clock: Clock) -> Option<usize>;
+
+    /// Adds a block after the rest of `client_id`'s blocks.
+    fn push(&mut self, client_id: ClientId, block: Block<T>);
+
+    /// Adds a block at `index`, such as the right half of a block which was split.
+    fn insert(&mut self, client_id: ClientId, index: usize, block: Block<T>);
+
+    /// Removes and returns every block `client_id` has.
+    fn take(&mut self, client_id: ClientId) -> Vec<Block<T>>;
+
+    /// Reserves room for at least `additional` more blocks from `client_id`.
+    fn reserve(&mut self, client_id: ClientId, additional: usize);
+
+    /// Every client with blocks, in no particular order.
+    fn clients(&self) -> Vec<ClientId>;
+
+    /// `client_id`'s blocks in clock order.
+    fn iter<'a>(&'a self, client_id: ClientId) -> impl DoubleEndedIterator<Item = &'a Block<T>>
+    where
+        T: 'a;
+
+    /// Roughly how much heap the blocks themselves take up, not counting their values.
+    fn heap_bytes(&self) -> usize;
+
+    /// Whether `client_id` has any blocks.
+    fn contains(&self, client_id: ClientId) -> bool {
+        self.len(client_id) > 0
+    }
+
+    /// The clocks from the start of `client_id`'s first block to the end of its last, or `None`
+    /// if it has no blocks. These start after zero once the client's earlier blocks have been
+    /// pruned, so a block's clock says nothing about its position.
+    fn clocks(&self, client_id: ClientId) -> Option<ClockRange> {
+        let first = self.get(client_id, 0)?;
+        let last = self.get(client_id, self.len(client_id) - 1)?;
+
+        Some(ClockRange::new(first.id, last.clocks().end))
+    }
+
+    /// The number of blocks across every client.
+    fn block_count(&self) -> usize {
+        self.clients()
+            .into_iter()
+            .map(|client_id| self.len(client_id))
+            .sum()
+    }
+}
+
+/// A `Vec` of blocks for each client. Looking up a block finds its client's `Vec` and searches it.
+#[derive(Debug)]
+pub struct VecStorage<T: Item> {
+    clients: HashMap<ClientId, Vec<Block<T>>>,
+}
+
+impl<T: Item> VecStorage<T> {
+    pub fn new() -> VecStorage<T> {
+        VecStorage::default()
+    }
+}
+
+impl<T: Item> Default for VecStorage<T> {
+    fn default() -> Self {
+        VecStorage {
+            clients: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Item> BlockStorage<T> for VecStorage<T> {
+    fn len(&self, client_id: ClientId) -> usize {
+        self.clients.get(&client_id).map_or(0, Vec::len)
+    }
+
+    fn get(&self, client_id: ClientId, index: usize) -> Option<&Block<T>> {
+        self.clients.get(&client_id)?.get(index)
+    }
+
+    fn get_mut(&mut self, client_id: ClientId, index: usize) -> Option<&mut Block<T>> {
+        self.clients.get_mut(&client_id)?.get_mut(index)
+    }
+
+    fn find(&self, client_id: ClientId, clock: Clock) -> Option<usize> {
+        let blocks = self.clients.get(&client_id)?;
+
+        // Appends and typing look up a client's latest block far more than any other, and the
+        // search below touches a cold cache line at each step to find it
+        if let Some(last) = blocks.last().filter(|last| last.id <= clock) {
+            return (clock < last.id.advance(last.length as u64)).then_some(blocks.len() - 1);
+        }
+
+        let index = blocks.partition_point(|block| block.id.advance(block.length as u64) <= clock);
+
+        blocks
+            .get(index)
+            .filter(|block| block.id <= clock)
+            .map(|_| index)
+    }
+
+    fn push(&mut self, client_id: ClientId, block: Block<T>) {
+        self.clients.entry(client_id).or_default().push(block);
+    }
+
+    fn insert(&mut self, client_id: ClientId, index: usize, block: Block<T>) {
+        self.clients
+            .entry(client_id)
+            .or_default()
+            .insert(index, block);
+    }
+
+    fn take(&mut self, client_id: ClientId) -> Vec<Block<T>> {
+        self.clients.remove(&client_id).unwrap_or_default()
+    }
+
+    fn reserve(&mut self, client_id: ClientId, additional: usize) {
+        self.clients
+            .entry(client_id)
+            .or_default()
+            .reserve(additional);
+    }
+
+    fn clients(&self) -> Vec<ClientId> {
+        self.clients
+            .iter()
+            .filter(|(_, blocks)| !blocks.is_empty())
+            .map(|(client_id, _)| *client_id)
+            .collect()
+    }
+
+    fn iter<'a>(&'a self, client_id: ClientId) -> impl DoubleEndedIterator<Item = &'a Block<T>>
+    where
+        T: 'a,
+    {
+        self.clients.get(&client_id).into_iter().flatten()
+    }
+
+    fn heap_bytes(&self) -> usize {
+        self.clients
+            .values()
+            .map(|blocks| blocks.capacity() * std::mem::size_of::<Block<T>>())
+            .sum()
+    }
+
+    fn block_count(&self) -> usize {
+        self.clients.values().map(Vec::len).sum()
+    }
+}
+
+/// The number of blocks in each of a [`PagedArena`]'s pages.
+const PAGE_SIZE: usize = 1024;
+
+/// Every client's blocks in shared fixed-size pages, which are never reallocated, so blocks
+/// stay where they were put and are allocated a page at a time.
+///
+/// Each client keeps the start clock and slot of its blocks in a compact list, so finding a
+/// block searches that list and then goes straight to its page, rather than searching the
+/// blocks themselves. Splitting a block shifts entries of the list instead of whole blocks.
+#[derive(Debug)]
+pub struct PagedArena<T: Item> {
+    pages: Vec<Vec<Option<Block<T>>>>,
+    /// Slots emptied by [`BlockStorage::take`], to be reused before a new page is started.
+    free: Vec<usize>,
+    /// The start clock and slot of each client's blocks, in clock order.
+    clients: HashMap<ClientId, Vec<(Clock, usize)>>,
+}
+
+impl<T: Item> PagedArena<T> {
+    pub fn new() -> PagedArena<T> {
+        PagedArena::default()
+    }
+
+    fn slot(&self, slot: usize) -> Option<&Block<T>> {
+        self.pages[slot / PAGE_SIZE][slot % PAGE_SIZE].as_ref()
+    }
+
+    fn slot_mut(&mut self, slot: usize) -> Option<&mut Block<T>> {
+        self.pages[slot / PAGE_SIZE][slot % PAGE_SIZE].as_mut()
+    }
+
+    /// Stores `block` in a free slot, starting a new page if there isn't one.
+    fn allocate(&mut self, block: Block<T>) -> usize {
+        if let Some(slot) = self.free.pop() {
+            self.pages[slot / PAGE_SIZE][slot % PAGE_SIZE] = Some(block);
+
+            return slot;
+        }
+
+        match self.pages.last_mut() {
+            Some(page) if page.len() < PAGE_SIZE => page.push(Some(block)),
+            _ => {
+                let mut page = Vec::with_capacity(PAGE_SIZE);
+                page.push(Some(block));
+                self.pages.push(page);
+            }
+        }
+
+        (self.pages.len() - 1) * PAGE_SIZE + self.pages[self.pages.len() - 1].len() - 1
+    }
+}
+
+impl<T: Item> Default for PagedArena<T> {
+    fn default() -> Self {
+        PagedArena {
+            pages: vec![],
+            free: vec![],
+            clients: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Item> BlockStorage<T> for PagedArena<T> {
+    fn len(&self, client_id: ClientId) -> usize {
+        self.clients.get(&client_id).map_or(0, Vec::len)
+    }
+
+    fn get(&self, client_id: ClientId, index: usize) -> Option<&Block<T>> {
+        let (_, slot) = *self.clients.get(&client_id)?.get(index)?;
+
+        self.slot(slot)
+    }
+
+    fn get_mut(&mut self, client_id: ClientId, index: usize) -> Option<&mut Block<T>> {
+        let (_, slot) = *self.clients.get(&client_id)?.get(index)?;
+
+        self.slot_mut(slot)
+    }
+
+    fn find(&self, client_id: ClientId, clock: Clock) -> Option<usize> {
+        let entries = self.clients.get(&client_id)?;
+        let index = entries
+            .partition_point(|(start, _)| *start <= clock)
+            .checked_sub(1)?;
+        let block = self.slot(entries[index].1)?;
+
+        (clock < block.id.advance(block.length as u64)).then_some(index)
+    }
+
+    fn push(&mut self, client_id: ClientId, block: Block<T>) {
+        let start = block.id;
+        let slot = self.allocate(block);
+
+        self.clients
+            .entry(client_id)
+            .or_default()
+            .push((start, slot));
+    }
+
+    fn insert(&mut self, client_id: ClientId, index: usize, block: Block<T>) {
+        let start = block.id;
+        let slot = self.allocate(block);
+
+        self.clients
+            .entry(client_id)
+            .or_default()
+            .insert(index, (start, slot));
+    }
+
+    fn take(&mut self, client_id: ClientId) -> Vec<Block<T>> {
+        let entries = self.clients.remove(&client_id).unwrap_or_default();
+
+        entries
+            .into_iter()
+            .filter_map(|(_, slot)| {
+                self.free.push(slot);
+
+                self.pages[slot / PAGE_SIZE][slot % PAGE_SIZE].take()
+            })
+            .collect()
+    }
+
+    fn reserve(&mut self, client_id: ClientId, additional: usize) {
+        self.clients
+            .entry(client_id)
+            .or_default()
+            .reserve(additional);
+    }
+
+    fn clients(&self) -> Vec<ClientId> {
+        self.clients
+            .iter()
+            .filter(|(_, entries)| !entries.is_empty())
+            .map(|(client_id, _)| *client_id)
+            .collect()
+    }
+
+    fn iter<'a>(&'a self, client_id: ClientId) -> impl DoubleEndedIterator<Item = &'a Block<T>>
+    where
+        T: 'a,
+    {
+        self.clients
+            .get(&client_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|(_, slot)| self.slot(*slot))
+    }
+
+    fn heap_bytes(&self) -> usize {
+        let pages = self.pages.len() * PAGE_SIZE * std::mem::size_of::<Option<Block<T>>>();
+        let entries: usize = self
+            .clients
+            .values()
+            .map(|entries| entries.capacity() * std::mem::size_of::<(Clock, usize)>())
+            .sum();
+
+        pages + entries
+    }
+
+    fn block_count(&self) -> usize {
+        self.clients.values().map(Vec::len).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    /// Runs the same tests against each backend, named by the module they're generated in.
+    macro_rules! storage_tests {
+        ($($backend:ident: $storage:ident,)*) => {$(
+            mod $backend {
+                use crate::clock::Clock;
+                use crate::storage::{BlockStorage, $storage};
+                use crate::{Document, UndoManager, Update};
+                use rand::rngs::StdRng;
+                use rand::{Rng, SeedableRng};
+
+                fn document(client_id: u64) -> Document<char, $storage<char>> {
+                    Document::with_storage(client_id, $storage::new())
+                }
+
+                #[test]
+                fn edits_inside_blocks() {
+                    let mut document = document(1);
+                    document.extend("abcdef".chars());
+                    document.insert(3, 'x');
+                    document.remove_range(1, 2);
+                    document.push('g');
+
+                    assert_eq!(document.iter().collect::<String>(), "axdefg");
+                    // "abcdef" was split around "x", then "bc" was split off
+                    assert_eq!(document.store.data.len(1), 5);
+                    assert_eq!(document.store.data.find(1, Clock::new(4)), Some(2));
+                    assert_eq!(document.store.data.get(1, 2).map(|block| block.id.get()), Some(3));
+                }
+
+                #[test]
+                fn concurrent_inserts_converge() {
+                    let mut base = document(1);
+                    base.extend("ae".chars());
+
+                    let mut first = document(2);
+                    let mut second = document(3);
+                    Update::from_document(&base).apply(&mut first).unwrap();
+                    Update::from_document(&base).apply(&mut second).unwrap();
+
+                    first.insert(1, 'b');
+                    second.insert(1, 'c');
+                    second.insert(2, 'd');
+                    first.sync_with(&mut second).unwrap();
+
+                    assert_eq!(first.to_vec(), second.to_vec());
+                    assert_eq!(first.len(), 5);
+                }
+
+                #[test]
+                fn matches_the_default_storage_after_random_edits() {
+                    let mut rng = StdRng::seed_from_u64(0);
+                    let mut document = document(1);
+                    let mut expected = Document::with_client_id(1);
+
+                    // Enough blocks to fill several pages of an arena
+                    for step in 0..3000 {
+                        let len = document.len();
+
+                        if len > 0 && rng.gen_range(0, 4) == 0 {
+                            let index = rng.gen_range(0, len);
+                            document.remove(index);
+                            expected.remove(index);
+                        } else {
+                            let index = rng.gen_range(0, len + 1);
+                            let value = char::from(b'a' + (step % 26) as u8);
+                            document.insert(index, value);
+                            expected.insert(index, value);
+                        }
+                    }
+
+                    assert_eq!(document.to_vec(), expected.to_vec());
+                    assert_eq!(
+                        Update::from_document(&document).encode().unwrap(),
+                        Update::from_document(&expected).encode().unwrap()
+                    );
+                    assert_eq!(document.stats().total_blocks, expected.stats().total_blocks);
+                    assert_eq!(document.check_integrity(), vec![]);
+                }
+
+                #[test]
+                fn gc_merges_tombstones() {
+                    let mut document = document(1);
+                    document.extend("abcdef".chars());
+                    // Deleted one at a time, so each is split into its own tombstone
+                    for _ in 0..4 {
+                        document.remove(1);
+                    }
+                    assert_eq!(document.store.data.len(1), 6);
+
+                    document.gc(document.state_vector().as_ref());
+
+                    assert_eq!(document.store.data.len(1), 3);
+                    assert_eq!(document.iter().collect::<String>(), "af");
+                    assert_eq!(document.check_integrity(), vec![]);
+
+                    document.insert(1, 'x');
+                    assert_eq!(document.iter().collect::<String>(), "axf");
+                }
+
+                #[test]
+                fn undoes_local_edits() {
+                    let mut document = document(1);
+                    let mut undo = UndoManager::new(&mut document);
+                    document.extend("abc".chars());
+                    undo.stop_capturing(&mut document);
+                    document.remove(1);
+
+                    undo.undo(&mut document);
+                    assert_eq!(document.iter().collect::<String>(), "abc");
+                }
+            }
+        )*};
+    }
+
+    storage_tests! {
+        vec_storage: VecStorage,
+        paged_arena: PagedArena,
+    }
+
+    #[test]
+    fn arena_reuses_slots_of_taken_blocks() {
+        use crate::block::Block;
+        use crate::storage::{BlockStorage, PagedArena};
+
+        let mut arena = PagedArena::new();
+        for clock in 0..3 {
+            arena.push(1, Block::with_value(clock, None, 'a'));
+        }
+        arena.push(2, Block::with_value(0, None, 'b'));
+
+        assert_eq!(arena.take(1).len(), 3);
+        arena.push(3, Block::with_value(0, None, 'c'));
+
+        assert_eq!(arena.pages.len(), 1);
+        assert_eq!(arena.pages[0].len(), 4);
+        assert_eq!(arena.block_count(), 2);
+        assert_eq!(
+            arena.get(3, 0).map(|block| block.value.clone()),
+            Some(vec!['c'])
+        );
+        assert!(!arena.contains(1));
+    }
+}
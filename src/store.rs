@@ -1,285 +1,1643 @@
 use crate::block::{Block, Item};
-use crate::document::{BlockId, ClientId, Clock};
-use std::collections::HashMap;
-use std::ops::{Index, IndexMut};
+use crate::clock::{Clock, ClockRange};
+use crate::conflict::{ClientIdOrder, ConflictResolver, InsertCandidate};
+use crate::delete_set::DeleteSet;
+use crate::document::{BlockId, ClientId, ClockVector};
+use crate::index::BlockIndex;
+use crate::integrity::{self, IntegrityIssue};
+#[cfg(feature = "metrics")]
+use crate::metrics::{MetricEvent, MetricsSink, NoopSink};
+use crate::moves::{Move, MoveStore};
+use crate::register::{Register, RegisterStore};
+use crate::storage::{BlockStorage, VecStorage};
+use crate::version::DeletedValues;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::ops::{Index, IndexMut, Range};
+use std::sync::Arc;
 
 #[derive(Debug)]
-pub struct Store<T: Item> {
+pub struct Store<T: Item, S: BlockStorage<T> = VecStorage<T>> {
     start: Option<BlockId>,
     end: Option<BlockId>,
-    client_id: u64,
-    pub(crate) data: HashMap<ClientId, Vec<Block<T>>>,
+    pub(crate) client_id: u64,
+    /// The priority given to blocks inserted locally.
+    pub(crate) priority: u8,
+    pub(crate) resolver: Arc<dyn ConflictResolver>,
+    pub(crate) data: S,
+    /// Every block in document order, for finding elements by position without walking the list.
+    index: BlockIndex,
+    /// Every move made on the document, which `order` applies.
+    pub(crate) moves: MoveStore,
+    /// The order blocks are shown in, once there are moves to take into account.
+    order: Option<Order>,
+    /// Values which replace those elements were inserted with.
+    pub(crate) registers: RegisterStore<T>,
+    /// The values of deleted elements, if the document keeps them.
+    pub(crate) deleted_values: Option<DeletedValues<T>>,
+    /// The lowest clock a block created locally may start at. Roots share their document's
+    /// clocks, so this is past whatever the client created in its other roots.
+    pub(crate) min_local_clock: Clock,
+    #[cfg(feature = "metrics")]
+    pub(crate) metrics: Arc<dyn MetricsSink>,
 }
 
-pub struct BlockWithClientId<'a, T: Item> {
+/// The order of a store's blocks with its moves applied, which differs from the order they're
+/// linked in.
+///
+/// Every move's ends and marker fall on block boundaries, so each block is moved as a whole.
+#[derive(Debug, Default)]
+struct Order {
+    first: Option<BlockId>,
+    last: Option<BlockId>,
+    /// Each block's neighbours in this order.
+    links: HashMap<BlockId, (Option<BlockId>, Option<BlockId>)>,
+    /// The moves which take effect, ordered so that later ones win.
+    moves: Vec<Move>,
+    /// The move each moved block is shown by, as an index into `moves`.
+    owners: HashMap<BlockId, usize>,
+}
+
+impl Order {
+    fn owner(&self, block_id: BlockId) -> Option<&Move> {
+        self.owners.get(&block_id).map(|owner| &self.moves[*owner])
+    }
+}
+
+/// A read-only view of a single block: a run of elements inserted one after another by the same
+/// client, which are either all live or all deleted.
+#[derive(Debug)]
+pub struct BlockView<'a, T: Item> {
     block: &'a Block<T>,
     block_id: BlockId,
 }
 
-impl<T: Item> Store<T> {
-    pub(crate) fn integrate(&mut self, client_id: ClientId, blocks: Vec<Block<T>>) {
-        for mut block in blocks.into_iter() {
-            let insert_before =
-                self.find_insertion_point(client_id, block.origin_left, block.origin_right);
+impl<'a, T: Item> Clone for BlockView<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
 
-            block.right = insert_before;
+impl<'a, T: Item> Copy for BlockView<'a, T> {}
 
-            let new_block_id = Some(BlockId {
-                client_id,
-                clock: block.id,
-            });
+impl<'a, T: Item> BlockView<'a, T> {
+    /// The id of the block's first element. Later elements have the following clocks.
+    pub fn id(&self) -> BlockId {
+        self.block_id
+    }
 
-            if let Some(insert_before) = insert_before {
-                let to_right = &mut self[insert_before];
+    /// The client which inserted the block.
+    pub fn client_id(&self) -> ClientId {
+        self.block_id.client_id
+    }
 
-                block.left = to_right.left;
+    pub fn is_deleted(&self) -> bool {
+        self.block.deleted
+    }
 
-                let previous_left = to_right.left;
-                to_right.left = Some(BlockId {
+    /// The number of elements in the block, deleted or not.
+    pub fn len(&self) -> usize {
+        self.block.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.block.length == 0
+    }
+
+    /// The block's values, which are empty if it is deleted.
+    pub fn values(&self) -> &'a [T] {
+        &self.block.value
+    }
+}
+
+/// Why blocks couldn't be integrated into a [`Store`].
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum IntegrateError {
+    /// A block's origin is neither in the store nor earlier in the blocks being integrated.
+    MissingOrigin(BlockId),
+    /// A block doesn't start at the client's next clock, so it would leave a gap or duplicate
+    /// elements the store already has.
+    UnexpectedClock {
+        client_id: ClientId,
+        expected: Clock,
+        actual: Clock,
+    },
+    /// A block's clocks run past the largest representable clock.
+    ClockOverflow(BlockId),
+    /// Searching for where to link a block visited more blocks than the store has, so the store's
+    /// blocks must link back round on themselves. Holds the block the search gave up at.
+    Cycle(BlockId),
+    /// A block links to one the store doesn't hold, so the store is corrupt. Holds the missing
+    /// block.
+    BrokenLink(BlockId),
+}
+
+impl<T: Item, S: BlockStorage<T>> Store<T, S> {
+    /// Links `blocks`, which must be consecutive blocks of `client_id` starting at `expected`,
+    /// into the store. `expected` is where the store's own blocks of `client_id` end, unless
+    /// the store is a root sharing its clocks with others.
+    ///
+    /// Every block's clock and origins are checked before anything is linked, so on error the
+    /// store is unchanged, unless the store itself is corrupt and linking fails with
+    /// [`IntegrateError::Cycle`] or [`IntegrateError::BrokenLink`].
+    pub(crate) fn integrate(
+        &mut self,
+        client_id: ClientId,
+        mut expected: Clock,
+        blocks: Vec<Block<T>>,
+    ) -> Result<(), IntegrateError> {
+        let first_clock = blocks.first().map(|block| block.id);
+
+        for block in &blocks {
+            if block.id != expected {
+                return Err(IntegrateError::UnexpectedClock {
                     client_id,
-                    clock: block.id,
+                    expected,
+                    actual: block.id,
                 });
+            }
 
-                if let Some(to_left) = previous_left {
-                    // insert in between `to_left` and `to_right`
-                    let to_left = &mut self[to_left];
+            expected = block.id.checked_advance(block.length as u64).ok_or(
+                IntegrateError::ClockOverflow(BlockId::new(client_id, block.id)),
+            )?;
 
-                    to_left.right = new_block_id;
-                } else {
-                    // insert at start
+            for origin in [block.origin_left, block.origin_right]
+                .into_iter()
+                .flatten()
+            {
+                let in_batch = origin.client_id == client_id
+                    && first_clock.is_some_and(|first| (first..block.id).contains(&origin.clock));
 
-                    self.start = new_block_id;
+                if !in_batch && self.get_block(origin).is_none() {
+                    return Err(IntegrateError::MissingOrigin(origin));
                 }
-            } else if let Some(end) = self.end {
-                // insert at end
-                let end = &mut self[end];
-                end.right = new_block_id;
-                self.end = new_block_id;
-            } else {
-                // is empty
+            }
+        }
+
+        for block in blocks.into_iter() {
+            // Origins may point inside existing multi-element blocks, in which case those blocks
+            // are split so that the new block can be linked in between the two halves
+            let left = match block.origin_left {
+                Some(origin_left) => {
+                    self.split_block(
+                        origin_left.client_id,
+                        origin_left.clock.saturating_advance(1),
+                    );
+                    let (block, _) = self
+                        .get_block(origin_left)
+                        .ok_or(IntegrateError::MissingOrigin(origin_left))?;
+
+                    Some(BlockId::new(origin_left.client_id, block.id))
+                }
+                None => None,
+            };
 
-                self.start = new_block_id;
-                self.end = new_block_id;
+            if let Some(origin_right) = block.origin_right {
+                self.split_block(origin_right.client_id, origin_right.clock);
             }
 
-            self.data.entry(client_id).or_insert(vec![]).push(block)
+            let candidate = InsertCandidate {
+                id: BlockId::new(client_id, block.id),
+                priority: block.priority,
+            };
+            let insert_before =
+                self.find_insertion_point(candidate, left, block.origin_left, block.origin_right)?;
+            let insert_after = match insert_before {
+                Some(insert_before) => self.linked(insert_before)?.left,
+                None => self.end,
+            };
+
+            if let Some(insert_after) = insert_after {
+                self.linked(insert_after)?;
+            }
+
+            let block_id = BlockId::new(client_id, block.id);
+            self.index
+                .insert_before(insert_before, block_id, block.live_length());
+            self.data.push(client_id, block);
+            self.link(block_id, insert_after, insert_before);
         }
+
+        Ok(())
     }
 
+    /// The block to link the new block `candidate` before, or `None` to link it at the end.
+    /// `left` is the block ending with `origin_left`, and `origin_right` starts a block.
+    ///
+    /// Follows YATA's rules, so every replica places concurrent inserts the same way whatever
+    /// order they arrive in. The blocks between the origins are scanned, and the new block goes
+    /// after:
+    /// - any block with the same left origin which the resolver puts first, along with the blocks
+    ///   inserted after it;
+    /// - any block whose left origin is one of the scanned blocks, unless that origin is a block
+    ///   already found to belong after the new one.
+    ///
+    /// The scan stops at the first other block, or at a block with the same origins which belongs
+    /// after the new one.
+    ///
+    /// Fails if the search doesn't finish within as many steps as there are blocks, which can
+    /// only happen if the store is corrupt, rather than looping forever.
     fn find_insertion_point(
         &self,
-        client_id: ClientId,
-        left: Option<BlockId>,
-        right: Option<BlockId>,
-    ) -> Option<BlockId> {
-        for BlockWithClientId { block_id, block } in self.iter_blocks_with_offset(left) {
-            if Some(block_id) == right {
-                // We've reached the end of insertion search, insert at this point
+        candidate: InsertCandidate,
+        mut left: Option<BlockId>,
+        origin_left: Option<BlockId>,
+        origin_right: Option<BlockId>,
+    ) -> Result<Option<BlockId>, IntegrateError> {
+        let block_count = self.data.block_count();
+        let mut current = self.after(left)?;
+        // Every block scanned, and those scanned since the new block's position last moved
+        let mut scanned = HashSet::new();
+        let mut conflicting = HashSet::new();
+        let mut steps = 0;
+
+        while let Some(block_id) = current.filter(|block_id| Some(*block_id) != origin_right) {
+            if steps > block_count {
+                return Err(IntegrateError::Cycle(block_id));
+            }
 
-                return Some(block_id);
+            steps += 1;
+
+            let block = self.linked(block_id)?;
+            scanned.insert(block_id);
+            conflicting.insert(block_id);
+
+            if block.origin_left == origin_left {
+                let existing = InsertCandidate {
+                    id: block_id,
+                    priority: block.priority,
+                };
+
+                if self.resolver.cmp(&existing, &candidate) == Ordering::Less {
+                    left = Some(block_id);
+                    conflicting.clear();
+                } else if block.origin_right == origin_right {
+                    break;
+                }
+            } else if let Some(origin) = block
+                .origin_left
+                .and_then(|origin| {
+                    let (containing, _) = self.get_block(origin)?;
+                    Some(BlockId::new(origin.client_id, containing.id))
+                })
+                .filter(|origin| scanned.contains(origin))
+            {
+                if !conflicting.contains(&origin) {
+                    left = Some(block_id);
+                    conflicting.clear();
+                }
+            } else {
+                break;
             }
 
-            if block.left == left && block_id.client_id > client_id {
-                // This block conflicts, but has a larger client id
+            current = block.right;
+        }
+
+        #[cfg(feature = "metrics")]
+        self.metrics
+            .record(MetricEvent::ConflictScan { length: steps });
+
+        self.after(left)
+    }
 
-                return Some(block_id);
+    /// The block linked after `left`, or the first block if `left` is `None`.
+    fn after(&self, left: Option<BlockId>) -> Result<Option<BlockId>, IntegrateError> {
+        match left {
+            Some(left) => Ok(self.linked(left)?.right),
+            None => Ok(self.start),
+        }
+    }
+
+    /// The block `block_id`, which another block links to, failing rather than panicking if the
+    /// store is corrupt and doesn't hold it.
+    fn linked(&self, block_id: BlockId) -> Result<&Block<T>, IntegrateError> {
+        self.get_block(block_id)
+            .map(|(block, _)| block)
+            .ok_or(IntegrateError::BrokenLink(block_id))
+    }
+
+    /// Splits the block of `client_id` containing `clock` so that a block starts at exactly
+    /// `clock`. Does nothing if `clock` is already a block boundary or isn't in the store.
+    fn split_block(&mut self, client_id: ClientId, clock: Clock) {
+        let index = match self.data.find(client_id, clock) {
+            Some(index) => index,
+            None => return,
+        };
+        let block = match self.data.get_mut(client_id, index) {
+            Some(block) if block.id != clock => block,
+            _ => return,
+        };
+
+        // Move the values out rather than cloning them along with the block
+        let block = Block {
+            value: std::mem::take(&mut block.value),
+            ..block.clone()
+        };
+        let offset = clock.offset_from(block.id);
+        let (left, right) = block.split_at(client_id, offset);
+        let left_id = BlockId::new(client_id, left.id);
+        let right_id = BlockId::new(client_id, right.id);
+        let next = right.right;
+
+        self.index.set_live(left_id, left.live_length());
+        self.index
+            .insert_after(Some(left_id), right_id, right.live_length());
+        if let Some(block) = self.data.get_mut(client_id, index) {
+            *block = left;
+        }
+        self.data.insert(client_id, index + 1, right);
+
+        match next.and_then(|next| self.get_block_mut(next)) {
+            Some((next, _)) => next.left = Some(right_id),
+            None => self.end = Some(right_id),
+        }
+
+        // Both halves are shown together, by the same move as the whole block
+        if let Some(order) = &mut self.order {
+            if let Some((shown_previous, shown_next)) = order.links.get(&left_id).copied() {
+                order
+                    .links
+                    .insert(left_id, (shown_previous, Some(right_id)));
+                order.links.insert(right_id, (Some(left_id), shown_next));
+
+                match shown_next.and_then(|shown_next| order.links.get_mut(&shown_next)) {
+                    Some(links) => links.0 = Some(right_id),
+                    None => order.last = Some(right_id),
+                }
+
+                if let Some(owner) = order.owners.get(&left_id).copied() {
+                    order.owners.insert(right_id, owner);
+                }
             }
         }
+    }
 
-        None
+    /// The id of the block containing `id`, i.e. `id` with its clock moved back to the start of
+    /// the block.
+    fn containing_block_id(&self, id: BlockId) -> BlockId {
+        BlockId::new(id.client_id, self[id].id)
     }
 }
 
-// Insertion point is found if:
-// Right satisfies:
-//    Left = My left
-
-impl<T: Item> Index<BlockId> for Store<T> {
+impl<T: Item, S: BlockStorage<T>> Index<BlockId> for Store<T, S> {
     type Output = Block<T>;
 
-    fn index(&self, BlockId { client_id, clock }: BlockId) -> &Self::Output {
-        &self.data[&client_id][clock as usize]
+    fn index(&self, block_id: BlockId) -> &Self::Output {
+        match self.get_block(block_id) {
+            Some((block, _)) => block,
+            None => panic!("{:?} is not in the store", block_id),
+        }
     }
 }
 
-impl<T: Item> IndexMut<BlockId> for Store<T> {
-    fn index_mut(&mut self, BlockId { client_id, clock }: BlockId) -> &mut Self::Output {
-        &mut self.data.get_mut(&client_id).unwrap()[clock as usize]
+impl<T: Item, S: BlockStorage<T>> IndexMut<BlockId> for Store<T, S> {
+    fn index_mut(&mut self, block_id: BlockId) -> &mut Self::Output {
+        match self.get_block_mut(block_id) {
+            Some((block, _)) => block,
+            None => panic!("{:?} is not in the store", block_id),
+        }
     }
 }
 
-impl<T: Item> Store<T> {
-    pub fn new(client_id: u64) -> Store<T> {
+impl<T: Item, S: BlockStorage<T>> Store<T, S> {
+    pub fn new(client_id: u64) -> Store<T, S> {
         Store {
-            data: HashMap::new(),
+            data: S::default(),
             start: None,
             end: None,
             client_id,
+            priority: 0,
+            resolver: Arc::new(ClientIdOrder),
+            index: BlockIndex::new(),
+            moves: MoveStore::new(),
+            order: None,
+            registers: RegisterStore::new(),
+            deleted_values: None,
+            min_local_clock: Clock::ZERO,
+            #[cfg(feature = "metrics")]
+            metrics: Arc::new(NoopSink),
+        }
+    }
+
+    pub(crate) fn from_parts(
+        client_id: ClientId,
+        start: Option<BlockId>,
+        end: Option<BlockId>,
+        data: HashMap<ClientId, Vec<Block<T>>>,
+        moves: Vec<Move>,
+    ) -> Store<T, S> {
+        let mut storage = S::default();
+        for (client_id, blocks) in data {
+            for block in blocks {
+                storage.push(client_id, block);
+            }
+        }
+
+        let mut store = Store {
+            start,
+            end,
+            client_id,
+            priority: 0,
+            resolver: Arc::new(ClientIdOrder),
+            data: storage,
+            index: BlockIndex::new(),
+            moves: MoveStore::from_moves(moves),
+            order: None,
+            registers: RegisterStore::new(),
+            deleted_values: None,
+            min_local_clock: Clock::ZERO,
+            #[cfg(feature = "metrics")]
+            metrics: Arc::new(NoopSink),
+        };
+
+        // Walking a corrupt list could panic or never finish, so a corrupt store is left
+        // unindexed until it's repaired. Gaps in a client's clocks don't get in the way, and are
+        // expected in a document with roots.
+        let walkable = integrity::check(&store)
+            .iter()
+            .all(|issue| matches!(issue, IntegrityIssue::ClockGap { .. }));
+
+        if walkable {
+            store.reindex();
         }
+
+        store
+    }
+
+    /// The first and last blocks in the list.
+    pub(crate) fn ends(&self) -> (Option<BlockId>, Option<BlockId>) {
+        (self.start, self.end)
+    }
+
+    /// The store's list pointers and a copy of every client's blocks, for [`Store::from_parts`].
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn to_parts(
+        &self,
+    ) -> (
+        Option<BlockId>,
+        Option<BlockId>,
+        Vec<(ClientId, Vec<Block<T>>)>,
+    ) {
+        (
+            self.start,
+            self.end,
+            self.data
+                .clients()
+                .into_iter()
+                .map(|client_id| (client_id, self.data.iter(client_id).cloned().collect()))
+                .collect(),
+        )
+    }
+
+    /// Looks up the block containing the element `id`, along with the element's offset within it.
+    pub fn get_block(&self, BlockId { client_id, clock }: BlockId) -> Option<(&Block<T>, usize)> {
+        let index = self.data.find(client_id, clock)?;
+        let block = self.data.get(client_id, index)?;
+
+        Some((block, clock.offset_from(block.id) as usize))
+    }
+
+    /// Looks up the block containing the element `id`, along with the element's offset within it.
+    pub fn get_block_mut(
+        &mut self,
+        BlockId { client_id, clock }: BlockId,
+    ) -> Option<(&mut Block<T>, usize)> {
+        let index = self.data.find(client_id, clock)?;
+        let block = self.data.get_mut(client_id, index)?;
+        let offset = clock.offset_from(block.id) as usize;
+
+        Some((block, offset))
     }
 
-    pub fn append(&mut self, value: T) {
-        self.add_block(self.end, None, value);
+    /// Reserves room for at least `additional` more blocks from this store's own client.
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        self.data.reserve(self.client_id, additional);
     }
 
-    pub fn insert(&mut self, index: usize, value: T) {
-        let (previous, next) = self
-            .iter_live_blocks()
-            .nth(index)
-            .map_or((None, None), |BlockWithClientId { block_id, block }| {
-                (Some(block_id), block.right)
+    /// Appends `value`, returning the id of its element.
+    pub fn append(&mut self, value: T) -> BlockId {
+        let id = BlockId::new(self.client_id, self.next_local_clock());
+
+        if self.order.is_some() {
+            // The last block in the list needn't be the last one shown
+            self.insert_values(self.len(), vec![value]);
+        } else {
+            self.add_block(self.end, None, vec![value]);
+        }
+
+        id
+    }
+
+    /// Inserts `value` at `index`, returning the id of its element.
+    pub fn insert(&mut self, index: usize, value: T) -> BlockId {
+        let id = BlockId::new(self.client_id, self.next_local_clock());
+        self.insert_values(index, vec![value]);
+
+        id
+    }
+
+    /// Inserts `values` at `index` as a single block, so they take up one block however many
+    /// there are.
+    pub(crate) fn insert_values(&mut self, index: usize, values: Vec<T>) {
+        if values.is_empty() {
+            return;
+        }
+
+        if self.order.is_some() {
+            let (previous, next) = self.find_gap(index);
+
+            self.add_block(previous, next, values);
+            self.reorder();
+
+            return;
+        }
+
+        let (previous, next) = match index.checked_sub(1).and_then(|i| self.find_live(i)) {
+            Some((block_id, offset)) => {
+                // Make sure the element we're inserting after ends its block
+                self.split_block(
+                    block_id.client_id,
+                    block_id.clock.advance(offset as u64 + 1),
+                );
+
+                (Some(block_id), self[block_id].right)
+            }
+            None if index == 0 => (None, self.start),
+            None => (self.end, None),
+        };
+
+        self.add_block(previous, next, values);
+    }
+
+    /// Deletes `count` live elements starting at `index`, returning the clock ranges deleted.
+    pub fn delete_range(&mut self, index: usize, count: usize) -> DeleteSet {
+        let count = count.min(self.len().saturating_sub(index));
+        let mut deleted = DeleteSet::empty();
+
+        if count == 0 {
+            return deleted;
+        }
+
+        let (first, first_offset) = self.find_live(index).unwrap();
+        let (last, last_offset) = self.find_live(index + count - 1).unwrap();
+
+        let first = BlockId::new(first.client_id, first.clock.advance(first_offset as u64));
+        let last = BlockId::new(last.client_id, last.clock.advance(last_offset as u64));
+
+        // Split off the parts of the boundary blocks which fall outside of the range
+        self.split_block(first.client_id, first.clock);
+        self.split_block(last.client_id, last.clock.advance(1));
+
+        let last = self.containing_block_id(last);
+        let mut current = Some(first);
+
+        while let Some(block_id) = current {
+            let block = &mut self[block_id];
+
+            if !block.deleted {
+                deleted.insert(block_id.client_id, block.clocks());
+                let clocks = block.clocks();
+                let values = mem::take(&mut block.value);
+                block.delete();
+                self.index.set_live(block_id, 0);
+                self.registers.remove_range(block_id.client_id, clocks);
+
+                if let Some(kept) = &mut self.deleted_values {
+                    kept.insert(block_id, values);
+                }
+            }
+
+            current = self.shown_after(block_id);
+
+            if block_id == last {
+                break;
+            }
+        }
+
+        deleted
+    }
+
+    /// Tombstones the blocks `client_id` created within `clocks`, resolving them by clock rather
+    /// than by position so that delete sets from other replicas map onto this store.
+    pub(crate) fn delete_clocks(&mut self, client_id: ClientId, clocks: ClockRange) -> DeleteSet {
+        let mut deleted = DeleteSet::empty();
+
+        self.split_block(client_id, clocks.start);
+        self.split_block(client_id, clocks.end);
+
+        // Blocks are ordered by clock, so only those from the range's start need visiting. In a
+        // root the start may fall among clocks of another root, so look for the next block on
+        let first = self.data.find(client_id, clocks.start).or_else(|| {
+            self.data
+                .iter(client_id)
+                .position(|block| block.id >= clocks.start)
+        });
+
+        if let Some(first) = first {
+            for index in first..self.data.len(client_id) {
+                let Some(block) = self.data.get_mut(client_id, index) else {
+                    break;
+                };
+
+                if block.id >= clocks.end {
+                    break;
+                }
+
+                if !block.deleted {
+                    let clocks = block.clocks();
+                    deleted.insert(client_id, clocks);
+                    let values = mem::take(&mut block.value);
+                    block.delete();
+                    self.index.set_live(BlockId::new(client_id, block.id), 0);
+                    self.registers.remove_range(client_id, clocks);
+
+                    if let Some(kept) = &mut self.deleted_values {
+                        kept.insert(BlockId::new(client_id, block.id), values);
+                    }
+                }
+            }
+        }
+
+        deleted
+    }
+
+    /// The number of live elements in the store.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Finds the live element at `index`, as the id of its block and its offset within it.
+    pub(crate) fn find_live(&self, index: usize) -> Option<(BlockId, usize)> {
+        self.index.find_live(index)
+    }
+
+    fn add_block(&mut self, previous: Option<BlockId>, next: Option<BlockId>, values: Vec<T>) {
+        let client_id = self.client_id;
+        let priority = self.priority;
+        let clock = self.next_local_clock();
+
+        // Appending straight after our own latest block just extends it, so typing or loading
+        // content sequentially produces a single block rather than one per element
+        if let Some(end) = previous.filter(|p| next.is_none() && self.end == Some(*p)) {
+            let end_block = &mut self[end];
+
+            if end.client_id == client_id
+                && !end_block.deleted
+                && end_block.origin_right.is_none()
+                && end_block.priority == priority
+                && end_block.clocks().end == clock
+            {
+                end_block.length += values.len();
+                end_block.value.extend(values);
+
+                let length = end_block.length;
+                self.index.set_live(end, length);
+
+                return;
+            }
+        }
+
+        let length = values.len();
+
+        self.push_block(previous, next, values, length, false);
+    }
+
+    /// Inserts a single deleted element, such as a move's marker, between `previous` and `next`.
+    fn add_tombstone(&mut self, previous: Option<BlockId>, next: Option<BlockId>) -> BlockId {
+        self.push_block(previous, next, vec![], 1, true)
+    }
+
+    /// Creates a block of this store's client and links it between `previous` and `next`.
+    fn push_block(
+        &mut self,
+        previous: Option<BlockId>,
+        next: Option<BlockId>,
+        value: Vec<T>,
+        length: usize,
+        deleted: bool,
+    ) -> BlockId {
+        let client_id = self.client_id;
+        let clock = self.next_local_clock();
+        let (origin_left, origin_right) = self.origins(previous, next);
+
+        let block = Block {
+            id: clock,
+            origin_left,
+            left: None,
+            origin_right,
+            right: None,
+            value,
+            length,
+            deleted,
+            priority: self.priority,
+        };
+        let live_length = block.live_length();
+
+        self.data.push(client_id, block);
+
+        let block_id = BlockId::new(client_id, clock);
+        self.index.insert_after(previous, block_id, live_length);
+        self.link(block_id, previous, next);
+
+        block_id
+    }
+
+    /// The origins of an element created between the blocks `previous` and `next`: the last
+    /// element of `previous` and the first of `next`. They're fixed when the element is created,
+    /// unlike its neighbours, which change as other blocks are linked around it.
+    fn origins(
+        &self,
+        previous: Option<BlockId>,
+        next: Option<BlockId>,
+    ) -> (Option<BlockId>, Option<BlockId>) {
+        let origin_left = previous.map(|previous| {
+            let block = &self[previous];
+
+            BlockId::new(
+                previous.client_id,
+                block.id.advance(block.length as u64 - 1),
+            )
+        });
+
+        (origin_left, next)
+    }
+
+    /// Links `block_id`, which is already stored, in between the neighbouring blocks `left` and
+    /// `right`. A `None` neighbour means the block becomes the start or end of the list.
+    fn link(&mut self, block_id: BlockId, left: Option<BlockId>, right: Option<BlockId>) {
+        let block = &mut self[block_id];
+        block.left = left;
+        block.right = right;
+
+        match left {
+            Some(left) => self[left].right = Some(block_id),
+            None => self.start = Some(block_id),
+        }
+
+        match right {
+            Some(right) => self[right].left = Some(block_id),
+            None => self.end = Some(block_id),
+        }
+
+        debug_assert!(
+            self.start.is_some_and(|start| self[start].left.is_none())
+                && self.end.is_some_and(|end| self[end].right.is_none()),
+            "linking {:?} left the start or end with a neighbour",
+            block_id
+        );
+    }
+
+    /// Checks that the blocks form a single list in debug builds: walking right from the start
+    /// visits every block once and finishes at the end, and every block's neighbours point back
+    /// at it. This walks the whole list, so it's run once per update or collection rather than
+    /// per block.
+    pub(crate) fn debug_assert_store_consistent(&self) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+
+        let block_count = self.data.block_count();
+        let mut walked = 0;
+        let mut previous = None;
+        let mut current = self.start;
+
+        while let Some(block_id) = current {
+            walked += 1;
+            assert!(walked <= block_count, "list loops back on {:?}", block_id);
+
+            let block = &self[block_id];
+            assert_eq!(block.left, previous, "left pointer of {:?}", block_id);
+
+            previous = current;
+            current = block.right;
+        }
+
+        assert_eq!(walked, block_count, "list skips blocks");
+        assert_eq!(self.end, previous, "end isn't the last block");
+    }
+
+    /// The next clock `client_id` would assign to a new block.
+    pub(crate) fn next_clock(&self, client_id: ClientId) -> Clock {
+        self.data
+            .clocks(client_id)
+            .map_or(Clock::ZERO, |clocks| clocks.end)
+    }
+
+    /// The clock the next block created locally starts at.
+    pub(crate) fn next_local_clock(&self) -> Clock {
+        self.next_clock(self.client_id).max(self.min_local_clock)
+    }
+
+    /// An empty store which edits like this one, for another root of the same document.
+    pub(crate) fn sibling(&self) -> Store<T, S> {
+        Store {
+            priority: self.priority,
+            resolver: self.resolver.clone(),
+            min_local_clock: self.next_local_clock(),
+            #[cfg(feature = "metrics")]
+            metrics: self.metrics.clone(),
+            ..Store::new(self.client_id)
+        }
+    }
+
+    /// Every block in document order, deleted ones included.
+    pub(crate) fn iter_blocks(&self) -> StoreIterator<'_, T, S> {
+        self.iter_blocks_with_offset(None)
+    }
+
+    fn iter_live_blocks(&self) -> impl DoubleEndedIterator<Item = BlockView<'_, T>> {
+        self.iter_blocks().filter(|b| !b.block.deleted)
+    }
+
+    /// The blocks from `start` onwards, or every block if `start` is `None`.
+    pub(crate) fn iter_blocks_with_offset(
+        &self,
+        start: Option<BlockId>,
+    ) -> StoreIterator<'_, T, S> {
+        let (first, last) = match &self.order {
+            Some(order) => (order.first, order.last),
+            None => (self.start, self.end),
+        };
+
+        StoreIterator {
+            store: self,
+            front: start.or(first),
+            back: last,
+            list: false,
+        }
+    }
+
+    /// Like [`Store::iter_blocks_with_offset`], but in the order blocks are linked in, ignoring
+    /// moves.
+    fn iter_list_from(&self, start: Option<BlockId>) -> StoreIterator<'_, T, S> {
+        StoreIterator {
+            store: self,
+            front: start.or(self.start),
+            back: self.end,
+            list: true,
+        }
+    }
+
+    /// The block shown after `block_id`, which is the block linked after it unless there are
+    /// moves.
+    fn shown_after(&self, block_id: BlockId) -> Option<BlockId> {
+        match self
+            .order
+            .as_ref()
+            .and_then(|order| order.links.get(&block_id))
+        {
+            Some((_, next)) => *next,
+            None => self[block_id].right,
+        }
+    }
+
+    fn shown_before(&self, block_id: BlockId) -> Option<BlockId> {
+        match self
+            .order
+            .as_ref()
+            .and_then(|order| order.links.get(&block_id))
+        {
+            Some((previous, _)) => *previous,
+            None => self[block_id].left,
+        }
+    }
+
+    /// The live values from `index` onwards. Whole blocks before `index` are skipped by their
+    /// length rather than visited element by element.
+    pub(crate) fn values_from(&self, index: usize) -> impl Iterator<Item = &T> {
+        self.find_live(index)
+            .into_iter()
+            .flat_map(move |(block_id, offset)| {
+                self.iter_blocks_with_offset(Some(block_id))
+                    .filter(|b| !b.block.deleted)
+                    .flat_map(|view| self.shown_values(view))
+                    .skip(offset)
+            })
+    }
+
+    /// The id of the live element at `index`.
+    pub(crate) fn element_at(&self, index: usize) -> Option<BlockId> {
+        self.find_live(index).map(|(block_id, offset)| {
+            BlockId::new(block_id.client_id, block_id.clock.advance(offset as u64))
+        })
+    }
+
+    /// The number of live elements before the element `id`, and whether `id` itself is live.
+    pub(crate) fn live_elements_before(&self, id: BlockId) -> Option<(usize, bool)> {
+        let (block, offset) = self.get_block(id)?;
+        let before = self.index.live_before(BlockId::new(id.client_id, block.id));
+
+        if block.deleted {
+            Some((before, false))
+        } else {
+            Some((before + offset, true))
+        }
+    }
+
+    /// The live elements `client_id` created within `clocks`, with their indices, ordered by
+    /// clock.
+    pub(crate) fn live_elements_in(
+        &self,
+        client_id: ClientId,
+        clocks: ClockRange,
+    ) -> Vec<(BlockId, usize)> {
+        let mut elements = vec![];
+        let first = self.data.find(client_id, clocks.start).unwrap_or(0);
+
+        for index in first..self.data.len(client_id) {
+            let Some(block) = self.data.get(client_id, index) else {
+                break;
+            };
+
+            if block.id >= clocks.end {
+                break;
+            }
+
+            let Some(overlap) = block.clocks().intersect(clocks) else {
+                continue;
+            };
+
+            if block.deleted {
+                continue;
+            }
+
+            let before = self.index.live_before(BlockId::new(client_id, block.id));
+
+            elements.extend(overlap.iter().map(|clock| {
+                let offset = clock.offset_from(block.id) as usize;
+
+                (BlockId::new(client_id, clock), before + offset)
+            }));
+        }
+
+        elements
+    }
+
+    /// Merges runs of tombstones which every peer has seen, according to `safe`, into single
+    /// blocks. Returns the number of blocks removed.
+    pub(crate) fn gc(&mut self, safe: &ClockVector) -> usize {
+        let mut removed = 0;
+        let pinned = self.move_boundaries();
+
+        for client_id in self.data.clients() {
+            let safe_clock = *safe.get(&client_id).unwrap_or(&Clock::ZERO);
+            let blocks = self.data.take(client_id);
+            let mut merged: Vec<Block<T>> = Vec::with_capacity(blocks.len());
+
+            for block in blocks {
+                match merged.last_mut() {
+                    Some(previous)
+                        if previous.deleted
+                            && block.deleted
+                            && previous.priority == block.priority
+                            && previous.right == Some(BlockId::new(client_id, block.id))
+                            && !pinned.contains(&BlockId::new(client_id, block.id))
+                            && block.clocks().end <= safe_clock =>
+                    {
+                        let previous = merged.pop().unwrap();
+
+                        merged.push(previous.merge_with_right(block));
+                        removed += 1;
+                    }
+                    _ => merged.push(block),
+                }
+            }
+
+            self.data.reserve(client_id, merged.len());
+            for block in merged {
+                self.data.push(client_id, block);
+            }
+        }
+
+        if removed > 0 {
+            self.relink();
+            self.reindex();
+        }
+
+        self.debug_assert_store_consistent();
+
+        removed
+    }
+
+    /// The elements which have to stay at the start of a block for moves: the start of each
+    /// move's range, the element after its end, its marker and the element after that.
+    fn move_boundaries(&self) -> HashSet<BlockId> {
+        self.moves
+            .iter()
+            .flat_map(|mv| {
+                let after = |id: BlockId| BlockId::new(id.client_id, id.clock.advance(1));
+
+                [mv.start, after(mv.end), mv.marker, after(mv.marker)]
+            })
+            .collect()
+    }
+
+    /// Drops the tombstones before `boundary` entirely, for a
+    /// [checkpoint](crate::Document::checkpoint). Tombstones which `anchors` or a move refer to
+    /// are kept, as single elements.
+    ///
+    /// Every live block before `boundary` is re-anchored after the block now linked before it,
+    /// as nothing concurrent with it can arrive any more, so a client's consecutive elements
+    /// merge into a single block. Origins of later blocks which pointed at a dropped tombstone
+    /// point at its nearest kept neighbour instead.
+    pub(crate) fn prune(&mut self, boundary: &ClockVector, anchors: &[BlockId]) {
+        let pinned = self.move_boundaries();
+        let anchors: HashSet<BlockId> = anchors
+            .iter()
+            .copied()
+            .chain(
+                self.moves
+                    .iter()
+                    .flat_map(|mv| [mv.start, mv.end, mv.marker]),
+            )
+            .collect();
+        let before_boundary = |block_id: BlockId, block: &Block<T>| {
+            block.clocks().end <= *boundary.get(&block_id.client_id).unwrap_or(&Clock::ZERO)
+        };
+
+        for anchor in &anchors {
+            self.split_block(anchor.client_id, anchor.clock);
+            self.split_block(anchor.client_id, anchor.clock.advance(1));
+        }
+
+        for (client_id, clock) in boundary {
+            self.split_block(*client_id, *clock);
+        }
+
+        let list: Vec<BlockId> = self
+            .iter_list_from(None)
+            .map(|view| view.block_id)
+            .collect();
+        let mut blocks: HashMap<BlockId, Block<T>> = HashMap::new();
+
+        for client_id in self.data.clients() {
+            for block in self.data.take(client_id) {
+                blocks.insert(BlockId::new(client_id, block.id), block);
+            }
+        }
+
+        let mut dropped = DeleteSet::empty();
+        let mut kept: Vec<(BlockId, Block<T>)> = Vec::with_capacity(list.len());
+
+        for block_id in list {
+            let block = blocks
+                .remove(&block_id)
+                .expect("every linked block is stored");
+
+            if block.deleted && before_boundary(block_id, &block) && !anchors.contains(&block_id) {
+                dropped.insert(block_id.client_id, block.clocks());
+            } else {
+                kept.push((block_id, block));
+            }
+        }
+
+        let last_element = |(block_id, block): &(BlockId, Block<T>)| {
+            BlockId::new(
+                block_id.client_id,
+                block.id.advance(block.length as u64 - 1),
+            )
+        };
+        let next: Vec<Option<BlockId>> = kept
+            .iter()
+            .skip(1)
+            .map(|(block_id, _)| Some(*block_id))
+            .chain([None])
+            .collect();
+        let mut compacted: Vec<(BlockId, Block<T>)> = Vec::with_capacity(kept.len());
+
+        for ((block_id, mut block), next) in kept.into_iter().zip(next) {
+            let previous = compacted.last().map(last_element);
+            let settled = !block.deleted && before_boundary(block_id, &block);
+
+            if settled {
+                block.origin_left = previous;
+                block.origin_right = None;
+            } else {
+                if block
+                    .origin_left
+                    .is_some_and(|origin| dropped.contains(origin))
+                {
+                    block.origin_left = previous;
+                }
+
+                if block
+                    .origin_right
+                    .is_some_and(|origin| dropped.contains(origin))
+                {
+                    block.origin_right = next;
+                }
+            }
+
+            match compacted.last() {
+                Some((previous_id, previous))
+                    if settled
+                        && !previous.deleted
+                        && before_boundary(*previous_id, previous)
+                        && previous_id.client_id == block_id.client_id
+                        && previous.clocks().end == block.id
+                        && previous.priority == block.priority
+                        && !pinned.contains(&block_id) =>
+                {
+                    let (previous_id, previous) = compacted.pop().unwrap();
+
+                    compacted.push((previous_id, previous.merge_with_right(block)));
+                }
+                _ => compacted.push((block_id, block)),
+            }
+        }
+
+        let ids: Vec<BlockId> = compacted.iter().map(|(block_id, _)| *block_id).collect();
+        let mut clients: HashMap<ClientId, Vec<Block<T>>> = HashMap::new();
+
+        for (position, (block_id, mut block)) in compacted.into_iter().enumerate() {
+            block.left = position.checked_sub(1).map(|previous| ids[previous]);
+            block.right = ids.get(position + 1).copied();
+            clients.entry(block_id.client_id).or_default().push(block);
+        }
+
+        for (client_id, mut blocks) in clients {
+            blocks.sort_by_key(|block| block.id);
+            self.data.reserve(client_id, blocks.len());
+
+            for block in blocks {
+                self.data.push(client_id, block);
+            }
+        }
+
+        self.start = ids.first().copied();
+        self.end = ids.last().copied();
+
+        if self.deleted_values.is_some() {
+            self.deleted_values = Some(DeletedValues::new());
+        }
+
+        self.reindex();
+        self.debug_assert_store_consistent();
+    }
+
+    /// Rebuilds every block's left pointer and the end pointer by following right pointers from
+    /// the start.
+    fn relink(&mut self) {
+        let mut previous = None;
+        let mut current = self.start;
+
+        while let Some(block_id) = current {
+            let block = &mut self[block_id];
+            block.left = previous;
+
+            previous = current;
+            current = block.right;
+        }
+
+        self.end = previous;
+    }
+
+    /// Rebuilds the positional index by walking the list, or by working out the order blocks are
+    /// shown in if there are moves.
+    fn reindex(&mut self) {
+        if !self.moves.is_empty() {
+            self.reorder();
+
+            return;
+        }
+
+        let blocks: Vec<(BlockId, usize)> = self
+            .iter_blocks()
+            .map(|BlockView { block_id, block }| (block_id, block.live_length()))
+            .collect();
+
+        self.index.rebuild(blocks);
+    }
+
+    /// Every element in document order, with `None` in place of the value of deleted elements.
+    pub(crate) fn iter_elements(&self) -> impl Iterator<Item = (BlockId, Option<&T>)> {
+        self.iter_blocks()
+            .flat_map(move |BlockView { block_id, block }| {
+                let mut values = block.value.iter();
+
+                block.clocks().iter().map(move |clock| {
+                    let id = BlockId::new(block_id.client_id, clock);
+
+                    (id, values.next().map(|value| self.shown_value(id, value)))
+                })
+            })
+    }
+
+    /// Every live element in document order, alongside the id of the element itself rather than
+    /// of its block.
+    pub(crate) fn iter_live_elements(&self) -> impl Iterator<Item = (BlockId, &T)> {
+        self.iter_live_blocks()
+            .flat_map(move |BlockView { block_id, block }| {
+                block
+                    .clocks()
+                    .iter()
+                    .zip(&block.value)
+                    .map(move |(clock, value)| {
+                        let id = BlockId::new(block_id.client_id, clock);
+
+                        (id, self.shown_value(id, value))
+                    })
+            })
+    }
+
+    /// Every live value in document order, with replaced values shown in place of those their
+    /// elements were inserted with.
+    pub(crate) fn iter_values(&self) -> impl DoubleEndedIterator<Item = &T> {
+        self.iter_blocks().flat_map(|view| self.shown_values(view))
+    }
+
+    /// The values of `view`'s block as they're shown, i.e. with any replaced.
+    fn shown_values<'a>(
+        &'a self,
+        BlockView { block, block_id }: BlockView<'a, T>,
+    ) -> impl DoubleEndedIterator<Item = &'a T> {
+        block.value.iter().enumerate().map(move |(offset, value)| {
+            let clock = block.id.advance(offset as u64);
+
+            self.shown_value(BlockId::new(block_id.client_id, clock), value)
+        })
+    }
+
+    /// The value shown for the element `id`, which was inserted with `value`.
+    fn shown_value<'a>(&'a self, id: BlockId, value: &'a T) -> &'a T {
+        self.registers.get(id).unwrap_or(value)
+    }
+
+    /// Replaces the value of the live element at `index` as a local write. Does nothing if
+    /// `index` is out of bounds.
+    pub(crate) fn set(&mut self, index: usize, value: T) {
+        if let Some(element) = self.element_at(index) {
+            self.registers.set(self.client_id, element, value);
+        }
+    }
+
+    /// Merges a write from any replica, dropping it if its element has been deleted.
+    pub(crate) fn merge_register(&mut self, write: Register<T>) {
+        match self.get_block(write.element) {
+            Some((block, _)) if !block.deleted => self.registers.merge(write),
+            _ => self.registers.observe(&write),
+        }
+    }
+}
+
+impl<T: Item, S: BlockStorage<T>> Store<T, S> {
+    /// Moves the live elements at `src` so they're shown before the live element at `dest`,
+    /// which must be outside of `src`, returning the moves made.
+    pub(crate) fn move_range(&mut self, src: Range<usize>, dest: usize) -> Vec<Move> {
+        let runs = self.contiguous_runs(src);
+        let (mut previous, next) = self.find_gap(dest);
+        let mut moves = vec![];
+
+        for (start, end) in runs {
+            let marker = self.add_tombstone(previous, next);
+
+            moves.push(self.moves.add(self.client_id, start, end, marker).clone());
+            previous = Some(marker);
+        }
+
+        self.reorder();
+
+        moves
+    }
+
+    /// Splits the live elements at `src` into runs which are linked one after another with
+    /// nothing in between but tombstones shown in the same place, as the first and last element
+    /// of each run. Moving each run on its own leaves everything else where it's shown.
+    fn contiguous_runs(&mut self, src: Range<usize>) -> Vec<(BlockId, BlockId)> {
+        let last = src
+            .end
+            .checked_sub(1)
+            .and_then(|index| self.element_at(index));
+        let (first, last) = match (self.element_at(src.start), last) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return vec![],
+        };
+
+        self.split_block(first.client_id, first.clock);
+        self.split_block(last.client_id, last.clock.advance(1));
+
+        let last = self.containing_block_id(last);
+        let mut runs: Vec<(BlockId, BlockId)> = vec![];
+        let mut previous = None;
+
+        for BlockView { block_id, block } in self.iter_blocks_with_offset(Some(first)) {
+            if !block.deleted {
+                let end = BlockId::new(
+                    block_id.client_id,
+                    block.id.advance(block.length as u64 - 1),
+                );
+
+                match runs.last_mut() {
+                    Some(run) if previous.is_some_and(|p| self.follows_in_list(p, block_id)) => {
+                        run.1 = end
+                    }
+                    _ => runs.push((block_id, end)),
+                }
+
+                previous = Some(block_id);
+            }
+
+            if block_id == last {
+                break;
+            }
+        }
+
+        runs
+    }
+
+    /// Whether `next` is linked after `block_id` with nothing in between but tombstones shown
+    /// along with `block_id`.
+    fn follows_in_list(&self, block_id: BlockId, next: BlockId) -> bool {
+        let owner = self.owner_of(block_id);
+
+        self.iter_list_from(Some(block_id))
+            .skip(1)
+            .find(|view| {
+                view.block_id == next
+                    || !view.block.deleted
+                    || self.moves.is_marker(view.block_id)
+                    || self.owner_of(view.block_id) != owner
+            })
+            .is_some_and(|view| view.block_id == next)
+    }
+
+    /// The neighbours to link a new block between so that it's shown at `index`.
+    ///
+    /// A new block is shown by the last move whose range takes in both of its neighbours, so it
+    /// isn't shown by a move if it's linked straight after the move's last block. Instead it's
+    /// linked after that move's marker, which shows it straight after everything the move shows.
+    fn find_gap(&mut self, index: usize) -> (Option<BlockId>, Option<BlockId>) {
+        if let Some(previous) = index
+            .checked_sub(1)
+            .and_then(|index| self.element_at(index))
+        {
+            self.split_block(previous.client_id, previous.clock.advance(1));
+
+            let mut block_id = self.containing_block_id(previous);
+
+            while let Some(mv) = self
+                .owner_of(block_id)
+                .filter(|mv| self.containing_block_id(mv.end) == block_id)
+            {
+                block_id = self.containing_block_id(mv.marker);
+            }
+
+            return (Some(block_id), self[block_id].right);
+        }
+
+        // Likewise at the start, before the marker of a move starting with the first element
+        match self.element_at(index) {
+            Some(next) => {
+                self.split_block(next.client_id, next.clock);
+
+                let mut block_id = self.containing_block_id(next);
+
+                while let Some(mv) = self.owner_of(block_id).filter(|mv| mv.start == block_id) {
+                    block_id = self.containing_block_id(mv.marker);
+                }
+
+                (self[block_id].left, Some(block_id))
+            }
+            None => (None, self.start),
+        }
+    }
+
+    /// The move `block_id` is shown by, if it's moved.
+    fn owner_of(&self, block_id: BlockId) -> Option<&Move> {
+        self.order.as_ref().and_then(|order| order.owner(block_id))
+    }
+
+    /// Works out the order blocks are shown in with the store's moves applied, and rebuilds the
+    /// positional index to match.
+    ///
+    /// Each element is shown by the last move whose range takes it in, straight after that
+    /// move's marker, and elements no move takes in stay where they're linked. Moves are skipped
+    /// if their ends or marker are missing, their end is linked before their start, or their
+    /// marker is shown inside their own range, directly or through other moves, in which case
+    /// the earliest move of the cycle is the one skipped. The order only depends on the blocks
+    /// and the moves, so replicas which have both show the same order.
+    pub(crate) fn reorder(&mut self) {
+        if self.moves.is_empty() {
+            return;
+        }
+
+        let moves = self.moves.moves();
+
+        for mv in &moves {
+            for (id, clock) in [
+                (mv.start, mv.start.clock),
+                (mv.end, mv.end.clock.advance(1)),
+                (mv.marker, mv.marker.clock),
+                (mv.marker, mv.marker.clock.advance(1)),
+            ] {
+                self.split_block(id.client_id, clock);
+            }
+        }
+
+        let list: Vec<BlockId> = self
+            .iter_list_from(None)
+            .map(|view| view.block_id)
+            .collect();
+        let positions: HashMap<BlockId, usize> = list
+            .iter()
+            .enumerate()
+            .map(|(position, block_id)| (*block_id, position))
+            .collect();
+        let position = |id: BlockId| {
+            self.get_block(id)
+                .and_then(|(block, _)| positions.get(&BlockId::new(id.client_id, block.id)))
+                .copied()
+        };
+
+        // Each move that can take effect, with the positions of its range and marker
+        let mut effective: Vec<(Move, Range<usize>, usize)> = moves
+            .into_iter()
+            .filter_map(|mv| {
+                let range = position(mv.start)?..position(mv.end)? + 1;
+                let marker = position(mv.marker)?;
+
+                (range.start < range.end).then_some((mv, range, marker))
+            })
+            .collect();
+
+        let owners = loop {
+            let mut owners: Vec<Option<usize>> = vec![None; list.len()];
+
+            for (index, (_, range, _)) in effective.iter().enumerate() {
+                owners[range.clone()].fill(Some(index));
+            }
+
+            let earliest_in_cycle = (0..effective.len()).find_map(|index| {
+                let mut cycle = vec![index];
+                let mut current = owners[effective[index].2];
+
+                while let Some(owner) = current {
+                    if owner == index {
+                        return cycle.into_iter().min();
+                    }
+
+                    if cycle.len() > effective.len() {
+                        return None;
+                    }
+
+                    cycle.push(owner);
+                    current = owners[effective[owner].2];
+                }
+
+                None
             });
 
-        self.add_block(previous, next, value);
-    }
+            match earliest_in_cycle {
+                Some(index) => {
+                    effective.remove(index);
+                }
+                None => break owners,
+            }
+        };
 
-    pub fn delete_range(&mut self, index: usize, count: usize) {
-        let block_ids: Vec<BlockId> = self
-            .iter_live_blocks()
-            .skip(index)
-            .take(count)
-            .map(|b| b.block_id)
-            .collect();
+        // The blocks each move shows in list order, after those no move takes in
+        let mut groups: Vec<Vec<usize>> = vec![vec![]; effective.len() + 1];
 
-        for block_id in block_ids {
-            self[block_id].delete();
+        for (position, owner) in owners.iter().enumerate() {
+            groups[owner.map_or(0, |owner| owner + 1)].push(position);
         }
-    }
-
-    pub fn delete(&mut self, index: usize) {
-        self.delete_range(index, 1);
-    }
 
-    fn add_block(&mut self, previous: Option<BlockId>, next: Option<BlockId>, value: T) {
-        let block_id = if let Some(v) = self.data.get_mut(&self.client_id) {
-            let id = v.len() as u64;
-
-            let block = Block::with_value_and_right(id, previous, next, value);
+        let markers: HashMap<usize, usize> = effective
+            .iter()
+            .enumerate()
+            .map(|(index, (_, _, marker))| (*marker, index))
+            .collect();
+        let mut shown = Vec::with_capacity(list.len());
+        let mut stack = vec![(0, 0)];
 
-            v.push(block);
+        while let Some((group, next)) = stack.pop() {
+            if let Some(position) = groups[group].get(next).copied() {
+                stack.push((group, next + 1));
+                shown.push(list[position]);
 
-            BlockId {
-                client_id: self.client_id,
-                clock: id,
+                if let Some(index) = markers.get(&position) {
+                    stack.push((index + 1, 0));
+                }
             }
-        } else {
-            let block = Block::with_value_and_right(0, previous, next, value);
-
-            self.data.insert(self.client_id, vec![block]);
+        }
 
-            BlockId {
-                client_id: self.client_id,
-                clock: 0,
-            }
+        let mut order = Order {
+            first: shown.first().copied(),
+            last: shown.last().copied(),
+            ..Order::default()
         };
 
-        if let Some(next) = next {
-            let next_block = &mut self[next];
+        for (index, block_id) in shown.iter().enumerate() {
+            let previous = index.checked_sub(1).map(|index| shown[index]);
 
-            next_block.left = Some(block_id);
-        } else {
-            if let Some(end) = self.end {
-                let end_block = &mut self[end];
-                end_block.right = Some(block_id);
-            }
+            order
+                .links
+                .insert(*block_id, (previous, shown.get(index + 1).copied()));
+        }
 
-            self.end = Some(block_id);
+        for (position, owner) in owners.into_iter().enumerate() {
+            if let Some(owner) = owner {
+                order.owners.insert(list[position], owner);
+            }
         }
 
-        if let Some(previous) = previous {
-            let prev_block = &mut self[previous];
+        order.moves = effective.into_iter().map(|(mv, ..)| mv).collect();
 
-            prev_block.right = Some(block_id);
-        } else {
-            if let Some(start) = self.start {
-                let start_block = &mut self[start];
-                start_block.left = Some(block_id);
-            }
+        let blocks: Vec<(BlockId, usize)> = shown
+            .iter()
+            .map(|block_id| (*block_id, self[*block_id].live_length()))
+            .collect();
 
-            self.start = Some(block_id);
-        }
+        self.index.rebuild(blocks);
+        self.order = Some(order);
     }
+}
 
-    pub fn iter_blocks(&self) -> impl Iterator<Item = BlockWithClientId<T>> {
-        StoreIterator {
-            store: self,
-            current: None,
-            is_done: false,
-        }
-    }
+/// Walks the blocks of a store in document order, following right pointers from the front and
+/// left pointers from the back until the two meet.
+pub(crate) struct StoreIterator<'a, T: Item, S: BlockStorage<T> = VecStorage<T>> {
+    store: &'a Store<T, S>,
+    front: Option<BlockId>,
+    back: Option<BlockId>,
+    /// Whether to follow the list pointers even if moves show blocks in another order.
+    list: bool,
+}
 
-    fn iter_live_blocks(&self) -> impl Iterator<Item = BlockWithClientId<T>> {
-        StoreIterator {
-            store: self,
-            current: None,
-            is_done: false,
-        }
-        .filter(|b| !b.block.deleted)
-    }
+impl<'a, T: Item, S: BlockStorage<T>> StoreIterator<'a, T, S> {
+    fn view(&mut self, block_id: BlockId) -> BlockView<'a, T> {
+        let block = &self.store[block_id];
 
-    pub fn iter_blocks_with_offset(
-        &self,
-        start: Option<BlockId>,
-    ) -> impl Iterator<Item = BlockWithClientId<T>> {
-        StoreIterator {
-            store: self,
-            current: start,
-            is_done: false,
+        if self.front == self.back {
+            self.front = None;
+            self.back = None;
         }
-    }
 
-    pub fn iter_values(&self) -> impl Iterator<Item = &T> {
-        self.iter_blocks()
-            .flat_map(|BlockWithClientId { block, .. }| &block.value)
+        BlockView { block, block_id }
     }
 }
 
-struct StoreIterator<'a, T: Item> {
-    store: &'a Store<T>,
-    current: Option<BlockId>,
-    is_done: bool,
-}
-
-impl<'a, T: Item> Iterator for StoreIterator<'a, T> {
-    type Item = BlockWithClientId<'a, T>;
+impl<'a, T: Item, S: BlockStorage<T>> Iterator for StoreIterator<'a, T, S> {
+    type Item = BlockView<'a, T>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.is_done {
-            return None;
+        let block_id = self.front?;
+        let view = self.view(block_id);
+
+        if self.front.is_some() {
+            self.front = match self.list {
+                true => view.block.right,
+                false => self.store.shown_after(block_id),
+            };
         }
 
-        if self.current.is_none() {
-            self.current = self.store.start;
-        }
+        Some(view)
+    }
+}
 
-        self.current
-            .map(|current| BlockWithClientId {
-                block_id: current,
-                block: &self.store[current],
-            })
-            .map(|block| {
-                self.current = block.block.right;
+impl<'a, T: Item, S: BlockStorage<T>> DoubleEndedIterator for StoreIterator<'a, T, S> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let block_id = self.back?;
+        let view = self.view(block_id);
 
-                if self.current.is_none() {
-                    self.is_done = true;
-                }
+        if self.back.is_some() {
+            self.back = match self.list {
+                true => view.block.left,
+                false => self.store.shown_before(block_id),
+            };
+        }
 
-                block
-            })
+        Some(view)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::block::Block;
-    use crate::document::BlockId;
-    use crate::store::Store;
+    use crate::clock::Clock;
+    use crate::conflict::InsertCandidate;
+    use crate::document::{BlockId, ClientId};
+    use crate::storage::BlockStorage;
+    use crate::store::{BlockView, IntegrateError, Store};
+    use crate::{Document, Update};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// A new block from `client_id`, with the default priority.
+    fn candidate(client_id: ClientId) -> InsertCandidate {
+        InsertCandidate {
+            id: BlockId::new(client_id, 0),
+            priority: 0,
+        }
+    }
 
     #[test]
     fn insert_at_start_when_empty() {
         let store: Store<String> = Store::new(1);
 
-        let insertion_point = store.find_insertion_point(2, None, None);
+        let insertion_point = store
+            .find_insertion_point(candidate(2), None, None, None)
+            .unwrap();
 
         assert_eq!(insertion_point, None);
     }
@@ -289,7 +1647,9 @@ mod tests {
         let mut store: Store<String> = Store::new(3);
         store.append("Test".to_owned());
 
-        let insertion_point = store.find_insertion_point(2, None, None);
+        let insertion_point = store
+            .find_insertion_point(candidate(2), None, None, None)
+            .unwrap();
 
         assert_eq!(insertion_point, Some(BlockId::new(3, 0)))
     }
@@ -299,7 +1659,9 @@ mod tests {
         let mut store: Store<String> = Store::new(1);
         store.append("Test".to_owned());
 
-        let insertion_point = store.find_insertion_point(2, None, None);
+        let insertion_point = store
+            .find_insertion_point(candidate(2), None, None, None)
+            .unwrap();
 
         assert_eq!(insertion_point, None)
     }
@@ -310,8 +1672,16 @@ mod tests {
         store.append("Test".to_owned());
         store.append("Test 2".to_owned());
 
-        let insertion_point =
-            store.find_insertion_point(2, Some(BlockId::new(1, 0)), Some(BlockId::new(1, 1)));
+        store.split_block(1, Clock::new(1));
+
+        let insertion_point = store
+            .find_insertion_point(
+                candidate(2),
+                Some(BlockId::new(1, 0)),
+                Some(BlockId::new(1, 0)),
+                Some(BlockId::new(1, 1)),
+            )
+            .unwrap();
 
         assert_eq!(insertion_point, Some(BlockId::new(1, 1)))
     }
@@ -338,15 +1708,18 @@ mod tests {
         store.append("Test".to_owned());
         store.append("Test 2".to_owned());
 
-        store.integrate(
-            2,
-            vec![Block::with_value_and_right(
-                0,
-                Some(BlockId::new(1, 0)),
-                Some(BlockId::new(1, 1)),
-                "Test 3".to_owned(),
-            )],
-        );
+        store
+            .integrate(
+                2,
+                store.next_clock(2),
+                vec![Block::with_value_and_right(
+                    0,
+                    Some(BlockId::new(1, 0)),
+                    Some(BlockId::new(1, 1)),
+                    "Test 3".to_owned(),
+                )],
+            )
+            .unwrap();
 
         assert_eq!(
             store.iter_values().collect::<Vec<&String>>(),
@@ -374,15 +1747,18 @@ mod tests {
         store.append("Test 2".to_owned());
         store.insert(1, "Test 3".to_owned());
 
-        store.integrate(
-            2,
-            vec![Block::with_value_and_right(
-                0,
-                Some(BlockId::new(1, 0)),
-                Some(BlockId::new(1, 1)),
-                "Test 4".to_owned(),
-            )],
-        );
+        store
+            .integrate(
+                2,
+                store.next_clock(2),
+                vec![Block::with_value_and_right(
+                    0,
+                    Some(BlockId::new(1, 0)),
+                    Some(BlockId::new(1, 1)),
+                    "Test 4".to_owned(),
+                )],
+            )
+            .unwrap();
 
         assert_eq!(
             store.iter_values().collect::<Vec<&String>>(),
@@ -397,15 +1773,18 @@ mod tests {
         store.append("Test 2".to_owned());
         store.insert(1, "Test 3".to_owned());
 
-        store.integrate(
-            1,
-            vec![Block::with_value_and_right(
-                0,
-                Some(BlockId::new(2, 0)),
-                Some(BlockId::new(2, 1)),
-                "Test 4".to_owned(),
-            )],
-        );
+        store
+            .integrate(
+                1,
+                store.next_clock(1),
+                vec![Block::with_value_and_right(
+                    0,
+                    Some(BlockId::new(2, 0)),
+                    Some(BlockId::new(2, 1)),
+                    "Test 4".to_owned(),
+                )],
+            )
+            .unwrap();
 
         assert_eq!(
             store.iter_values().collect::<Vec<&String>>(),
@@ -420,27 +1799,538 @@ mod tests {
         store.append("Test 2".to_owned());
         store.insert(1, "Test 3".to_owned());
 
-        store.integrate(
-            1,
-            vec![
-                Block::with_value_and_right(
+        store
+            .integrate(
+                1,
+                store.next_clock(1),
+                vec![
+                    Block::with_value_and_right(
+                        0,
+                        Some(BlockId::new(2, 0)),
+                        Some(BlockId::new(2, 1)),
+                        "Test 4".to_owned(),
+                    ),
+                    Block::with_value_and_right(
+                        1,
+                        Some(BlockId::new(1, 0)),
+                        Some(BlockId::new(2, 1)),
+                        "Test 5".to_owned(),
+                    ),
+                ],
+            )
+            .unwrap();
+
+        assert_eq!(
+            store.iter_values().collect::<Vec<&String>>(),
+            vec!["Test", "Test 4", "Test 5", "Test 3", "Test 2"]
+        )
+    }
+
+    fn merged_block(id: u64, values: &[&str]) -> Block<String> {
+        Block {
+            id: Clock::new(id),
+            origin_left: None,
+            left: None,
+            origin_right: None,
+            right: None,
+            value: values.iter().map(|v| v.to_string()).collect(),
+            length: values.len(),
+            deleted: false,
+            priority: 0,
+        }
+    }
+
+    #[test]
+    fn integrate_inside_merged_block() {
+        let mut store: Store<String> = Store::new(3);
+        store
+            .integrate(
+                1,
+                store.next_clock(1),
+                vec![merged_block(0, &["a", "b", "c"])],
+            )
+            .unwrap();
+
+        store
+            .integrate(
+                2,
+                store.next_clock(2),
+                vec![Block::with_value_and_right(
+                    0,
+                    Some(BlockId::new(1, 1)),
+                    Some(BlockId::new(1, 2)),
+                    "x".to_owned(),
+                )],
+            )
+            .unwrap();
+
+        assert_eq!(
+            store.iter_values().collect::<Vec<&String>>(),
+            vec!["a", "b", "x", "c"]
+        );
+        assert_eq!(store.data.len(1), 2);
+        assert_eq!(store[BlockId::new(1, 2)].id, Clock::new(2));
+        assert_eq!(store.end, Some(BlockId::new(1, 2)));
+    }
+
+    #[test]
+    fn integrate_inside_merged_block_with_right_neighbour() {
+        let mut store: Store<String> = Store::new(3);
+        store
+            .integrate(
+                1,
+                store.next_clock(1),
+                vec![merged_block(0, &["a", "b", "c"])],
+            )
+            .unwrap();
+        store
+            .integrate(
+                1,
+                store.next_clock(1),
+                vec![Block::with_value_and_right(
+                    3,
+                    Some(BlockId::new(1, 2)),
+                    None,
+                    "d".to_owned(),
+                )],
+            )
+            .unwrap();
+
+        store
+            .integrate(
+                2,
+                store.next_clock(2),
+                vec![Block::with_value_and_right(
                     0,
-                    Some(BlockId::new(2, 0)),
-                    Some(BlockId::new(2, 1)),
-                    "Test 4".to_owned(),
-                ),
-                Block::with_value_and_right(
-                    1,
                     Some(BlockId::new(1, 0)),
-                    Some(BlockId::new(2, 1)),
-                    "Test 5".to_owned(),
-                ),
-            ],
+                    Some(BlockId::new(1, 1)),
+                    "x".to_owned(),
+                )],
+            )
+            .unwrap();
+
+        assert_eq!(
+            store.iter_values().collect::<Vec<&String>>(),
+            vec!["a", "x", "b", "c", "d"]
+        );
+        assert_eq!(store[BlockId::new(1, 3)].left, Some(BlockId::new(1, 1)));
+    }
+
+    #[test]
+    fn sequential_appends_share_a_block() {
+        let mut store: Store<String> = Store::new(1);
+
+        for i in 0..1000 {
+            store.append(i.to_string());
+        }
+
+        assert_eq!(store.data.len(1), 1);
+        assert_eq!(store.data.get(1, 0).unwrap().length, 1000);
+        assert_eq!(store.iter_values().nth(999), Some(&"999".to_owned()));
+    }
+
+    #[test]
+    fn insert_inside_merged_block() {
+        let mut store: Store<String> = Store::new(1);
+        store.append("a".to_owned());
+        store.append("c".to_owned());
+        store.insert(1, "b".to_owned());
+
+        assert_eq!(
+            store.iter_values().collect::<Vec<&String>>(),
+            vec!["a", "b", "c"]
+        );
+        assert_eq!(
+            store[BlockId::new(1, 2)].origin_left,
+            Some(BlockId::new(1, 0))
         );
+        assert_eq!(
+            store[BlockId::new(1, 2)].origin_right,
+            Some(BlockId::new(1, 1))
+        );
+    }
+
+    #[test]
+    fn origin_left_refers_to_last_element_of_run() {
+        let mut store: Store<String> = Store::new(1);
+        store.append("a".to_owned());
+        store.append("b".to_owned());
+        store.append("c".to_owned());
+        store.insert(0, "x".to_owned());
+        store.insert(4, "d".to_owned());
 
         assert_eq!(
             store.iter_values().collect::<Vec<&String>>(),
-            vec!["Test", "Test 4", "Test 5", "Test 3", "Test 2"]
-        )
+            vec!["x", "a", "b", "c", "d"]
+        );
+        assert_eq!(
+            store[BlockId::new(1, 4)].origin_left,
+            Some(BlockId::new(1, 2))
+        );
+    }
+
+    #[test]
+    fn origins_stay_fixed_as_neighbours_change() {
+        let mut store: Store<char> = Store::new(1);
+        store.append('a');
+        store.append('b');
+        store.insert(1, 'x');
+
+        // "y" is inserted between "x" and "b" by another client
+        store
+            .integrate(
+                2,
+                store.next_clock(2),
+                vec![Block::with_value_and_right(
+                    0,
+                    Some(BlockId::new(1, 2)),
+                    Some(BlockId::new(1, 1)),
+                    'y',
+                )],
+            )
+            .unwrap();
+
+        assert_eq!(store.iter_values().collect::<String>(), "axyb");
+
+        let x = &store[BlockId::new(1, 2)];
+        assert_eq!(x.right, Some(BlockId::new(2, 0)));
+        assert_eq!(x.origin_left, Some(BlockId::new(1, 0)));
+        assert_eq!(x.origin_right, Some(BlockId::new(1, 1)));
+    }
+
+    #[test]
+    fn delete_inside_merged_block() {
+        let mut store: Store<String> = Store::new(1);
+        for value in ["a", "b", "c", "d", "e"] {
+            store.append(value.to_owned());
+        }
+
+        store.delete_range(1, 3);
+
+        assert_eq!(
+            store.iter_values().collect::<Vec<&String>>(),
+            vec!["a", "e"]
+        );
+        assert_eq!(store.data.len(1), 3);
+        assert!(store[BlockId::new(1, 2)].deleted);
+        assert_eq!(store[BlockId::new(1, 2)].length, 3);
+    }
+
+    #[test]
+    fn lookup_inside_multi_element_block() {
+        let mut store: Store<String> = Store::new(1);
+        store.append("a".to_owned());
+        store.append("b".to_owned());
+        store.append("c".to_owned());
+
+        let (block, offset) = store.get_block(BlockId::new(1, 1)).unwrap();
+        assert_eq!(block.id, Clock::new(0));
+        assert_eq!(block.length, 3);
+        assert_eq!(offset, 1);
+
+        assert_eq!(store[BlockId::new(1, 2)].id, Clock::new(0));
+        assert_eq!(store.get_block_mut(BlockId::new(1, 2)).unwrap().1, 2);
+    }
+
+    #[test]
+    fn integrate_rejects_missing_origins_without_linking_anything() {
+        let mut store: Store<String> = Store::new(1);
+        store.append("a".to_owned());
+
+        let result = store.integrate(
+            2,
+            store.next_clock(2),
+            vec![
+                Block::with_value(0, Some(BlockId::new(1, 0)), "b".to_owned()),
+                Block::with_value(1, Some(BlockId::new(3, 0)), "c".to_owned()),
+            ],
+        );
+
+        assert_eq!(
+            result,
+            Err(IntegrateError::MissingOrigin(BlockId::new(3, 0)))
+        );
+        assert_eq!(store.iter_values().collect::<Vec<_>>(), vec!["a"]);
+        assert!(!store.data.contains(2));
+    }
+
+    #[test]
+    fn integrate_rejects_clock_gaps_and_duplicates() {
+        let mut store: Store<String> = Store::new(1);
+        store
+            .integrate(
+                2,
+                store.next_clock(2),
+                vec![Block::with_value(0, None, "a".to_owned())],
+            )
+            .unwrap();
+
+        assert_eq!(
+            store.integrate(
+                2,
+                store.next_clock(2),
+                vec![Block::with_value(2, None, "b".to_owned())]
+            ),
+            Err(IntegrateError::UnexpectedClock {
+                client_id: 2,
+                expected: Clock::new(1),
+                actual: Clock::new(2),
+            })
+        );
+        assert_eq!(
+            store.integrate(
+                2,
+                store.next_clock(2),
+                vec![Block::with_value(0, None, "b".to_owned())]
+            ),
+            Err(IntegrateError::UnexpectedClock {
+                client_id: 2,
+                expected: Clock::new(1),
+                actual: Clock::new(0),
+            })
+        );
+
+        // A gap later in the batch rejects the whole batch
+        assert_eq!(
+            store.integrate(
+                2,
+                store.next_clock(2),
+                vec![
+                    Block::with_value(1, None, "b".to_owned()),
+                    Block::with_value(3, None, "c".to_owned()),
+                ]
+            ),
+            Err(IntegrateError::UnexpectedClock {
+                client_id: 2,
+                expected: Clock::new(2),
+                actual: Clock::new(3),
+            })
+        );
+        assert_eq!(store.iter_values().collect::<Vec<_>>(), vec!["a"]);
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn integrate_rejects_clocks_which_overflow() {
+        let mut store: Store<String> = Store::new(1);
+
+        let mut tombstones = Block::with_value(0, None, "a".to_owned());
+        tombstones.delete();
+        tombstones.length = (u64::MAX - 1) as usize;
+        store
+            .integrate(2, store.next_clock(2), vec![tombstones])
+            .unwrap();
+
+        let mut block = Block::with_value(u64::MAX - 1, None, "b".to_owned());
+        block.value.push("c".to_owned());
+        block.length = 2;
+
+        assert_eq!(
+            store.integrate(2, store.next_clock(2), vec![block]),
+            Err(IntegrateError::ClockOverflow(BlockId::new(2, u64::MAX - 1)))
+        );
+        assert_eq!(store.next_clock(2), Clock::new(u64::MAX - 1));
+    }
+
+    #[test]
+    fn origins_include_deleted_neighbours() {
+        let mut store: Store<char> = Store::new(1);
+        for value in "abc".chars() {
+            store.append(value);
+        }
+        store.delete_range(1, 1);
+        store.insert(1, 'x');
+
+        // Replicas which haven't seen the deletion would compute the same neighbours
+        let (block, _) = store.get_block(BlockId::new(1, 3)).unwrap();
+        assert_eq!(block.origin_left, Some(BlockId::new(1, 0)));
+        assert_eq!(block.origin_right, Some(BlockId::new(1, 1)));
+    }
+
+    #[test]
+    fn insertion_search_gives_up_on_cyclic_blocks() {
+        let mut store: Store<String> = Store::new(1);
+        for value in ["a", "b", "c"] {
+            store.append(value.to_owned());
+        }
+        store.split_block(1, Clock::new(1));
+        store.split_block(1, Clock::new(2));
+
+        // b links back to a, so walking from the start never reaches c
+        store[BlockId::new(1, 1)].right = Some(BlockId::new(1, 0));
+
+        assert_eq!(
+            store.find_insertion_point(candidate(2), None, None, Some(BlockId::new(1, 2))),
+            Err(IntegrateError::Cycle(BlockId::new(1, 0)))
+        );
+    }
+
+    #[test]
+    fn integrating_into_broken_links_fails_without_panicking() {
+        let mut store: Store<String> = Store::new(1);
+        for value in ["a", "b"] {
+            store.append(value.to_owned());
+        }
+        store.split_block(1, Clock::new(1));
+
+        // a links to a block the store doesn't hold
+        store[BlockId::new(1, 0)].right = Some(BlockId::new(3, 0));
+
+        assert_eq!(
+            store.integrate(
+                2,
+                store.next_clock(2),
+                vec![Block::with_value(
+                    0,
+                    Some(BlockId::new(1, 0)),
+                    "x".to_owned()
+                )]
+            ),
+            Err(IntegrateError::BrokenLink(BlockId::new(3, 0)))
+        );
+    }
+
+    #[test]
+    fn lookup_outside_store() {
+        let mut store: Store<String> = Store::new(1);
+        store.append("a".to_owned());
+
+        assert!(store.get_block(BlockId::new(1, 1)).is_none());
+        assert!(store.get_block(BlockId::new(2, 0)).is_none());
+    }
+
+    #[test]
+    fn lookup_after_split() {
+        let mut store: Store<String> = Store::new(1);
+        for value in ["a", "b", "c", "d"] {
+            store.append(value.to_owned());
+        }
+        store.split_block(1, Clock::new(2));
+
+        assert_eq!(
+            store.get_block(BlockId::new(1, 1)).unwrap().0.id,
+            Clock::new(0)
+        );
+        assert_eq!(
+            store.get_block(BlockId::new(1, 3)).unwrap().0.id,
+            Clock::new(2)
+        );
+        assert_eq!(store.get_block(BlockId::new(1, 3)).unwrap().1, 1);
+    }
+
+    /// Checks the positional index against a walk of the linked list.
+    fn assert_index_matches_walk(store: &Store<usize>) {
+        let mut index = 0;
+
+        for BlockView { block_id, block } in store.iter_blocks() {
+            for offset in 0..block.length {
+                let id = BlockId::new(block_id.client_id, block.id.advance(offset as u64));
+
+                if block.deleted {
+                    assert_eq!(store.live_elements_before(id), Some((index, false)));
+                } else {
+                    assert_eq!(store.find_live(index), Some((block_id, offset)));
+                    assert_eq!(store.live_elements_before(id), Some((index, true)));
+                    index += 1;
+                }
+            }
+        }
+
+        assert_eq!(store.len(), index);
+        assert_eq!(store.find_live(index), None);
+    }
+
+    #[test]
+    fn index_agrees_with_walk_after_random_edits() {
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut docs = [Document::with_client_id(1), Document::with_client_id(2)];
+
+            for step in 0..200 {
+                let doc = rng.gen_range(0, docs.len());
+                let len = docs[doc].len();
+
+                match rng.gen_range(0, 10) {
+                    0..=4 => {
+                        docs[doc].insert(rng.gen_range(0, len + 1), step);
+                    }
+                    5 => {
+                        docs[doc].push(step);
+                    }
+                    6 | 7 if len > 0 => {
+                        let index = rng.gen_range(0, len);
+                        docs[doc].remove_range(index, rng.gen_range(1, (len - index).min(4) + 1));
+                    }
+                    8 => {
+                        let [doc1, doc2] = &mut docs;
+                        let (from, to) = if doc == 0 { (doc1, doc2) } else { (doc2, doc1) };
+
+                        Update::from_document_since(from, to.state_vector().as_ref())
+                            .apply(to)
+                            .unwrap();
+                    }
+                    9 => {
+                        let safe = docs[doc].state_vector();
+                        docs[doc].gc(safe.as_ref());
+                    }
+                    _ => {}
+                }
+
+                assert_index_matches_walk(&docs[doc].store);
+            }
+        }
+    }
+
+    #[test]
+    fn random_integrations_keep_the_list_linked() {
+        for seed in 0..10 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut sources: Vec<Document<usize>> = (1..=3).map(Document::with_client_id).collect();
+            let mut receiver = Document::with_client_id(4);
+
+            for step in 0..50 {
+                let source = &mut sources[rng.gen_range(0, 3)];
+                let len = source.len();
+
+                // Mostly at either end, where the start and end pointers move
+                match rng.gen_range(0, 4) {
+                    0 => {
+                        source.insert(0, step);
+                    }
+                    1 => {
+                        source.push(step);
+                    }
+                    2 => {
+                        source.insert(rng.gen_range(0, len + 1), step);
+                    }
+                    _ if len > 0 => source.remove(rng.gen_range(0, len)),
+                    _ => {
+                        source.insert(0, step);
+                    }
+                }
+
+                Update::from_document_since(source, receiver.state_vector().as_ref())
+                    .apply(&mut receiver)
+                    .unwrap();
+
+                receiver.store.debug_assert_store_consistent();
+                assert_index_matches_walk(&receiver.store);
+                assert_eq!(receiver.store.iter_blocks().count(), block_count(&receiver));
+
+                // Sometimes catch a source up, so later edits build on the others'
+                if rng.gen_range(0, 3) == 0 {
+                    let source = &mut sources[rng.gen_range(0, 3)];
+
+                    Update::from_document_since(&receiver, source.state_vector().as_ref())
+                        .apply(source)
+                        .unwrap();
+                    source.store.debug_assert_store_consistent();
+                }
+            }
+        }
+    }
+
+    fn block_count(document: &Document<usize>) -> usize {
+        document.store.data.block_count()
     }
 }
@@ -0,0 +1,412 @@
+//! A transport-agnostic protocol for keeping two replicas of a document in sync.
+
+use crate::block::Item;
+use crate::delete_set::DeleteSet;
+use crate::document::ClockVector;
+use crate::encoding::{self, DecodeError, EncodeError};
+use crate::storage::BlockStorage;
+use crate::update::WriteClocks;
+use crate::{Document, StateVector, Update};
+use bincode::{Decode, Encode};
+
+/// A message between two [`SyncProtocol`]s.
+#[derive(Eq, PartialEq, Clone, Debug, Encode, Decode)]
+pub enum Message {
+    /// The sender's state, asking for everything it is missing.
+    StateVector(StateVector),
+    /// An encoded [`Update`].
+    Update(Vec<u8>),
+    /// The sender has applied the update answering its state vector, so is in sync.
+    Done,
+    /// The sender couldn't handle a message, for this reason.
+    Error(String),
+}
+
+impl Message {
+    pub fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        encoding::encode(self)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Message, DecodeError> {
+        encoding::decode(bytes)
+    }
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+enum State {
+    /// Nothing has been exchanged yet.
+    Idle,
+    /// Our state vector has been sent, and the update answering it hasn't arrived.
+    AwaitingUpdate,
+    Synced,
+    /// The peer reported an error, so nothing more is sent.
+    Failed,
+}
+
+/// One side of a sync between two replicas, which exchanges state vectors and then whatever
+/// each side is missing, and afterwards pushes local edits as they are made.
+///
+/// The protocol only produces and consumes [`Message`]s, so the caller moves them between peers
+/// however it likes. Messages must be delivered in order. Either side may [`start`] the sync, or
+/// both at once.
+///
+/// [`start`]: SyncProtocol::start
+#[derive(Debug)]
+pub struct SyncProtocol<T> {
+    state: State,
+    /// What the peer is known to have, from its state vector and the updates exchanged since.
+    peer_state: Option<ClockVector>,
+    peer_deletes: DeleteSet,
+    /// The writes to the map, registers, marks and moves the peer is known to have.
+    peer_writes: WriteClocks,
+    marker: std::marker::PhantomData<T>,
+}
+
+impl<T: Item + Encode + Decode> Default for SyncProtocol<T> {
+    fn default() -> Self {
+        SyncProtocol::new()
+    }
+}
+
+impl<T: Item + Encode + Decode> SyncProtocol<T> {
+    pub fn new() -> SyncProtocol<T> {
+        SyncProtocol {
+            state: State::Idle,
+            peer_state: None,
+            peer_deletes: DeleteSet::empty(),
+            peer_writes: WriteClocks::default(),
+            marker: std::marker::PhantomData,
+        }
+    }
+
+    /// Begins syncing by asking the peer for whatever `document` is missing.
+    pub fn start<S: BlockStorage<T>>(&mut self, document: &Document<T, S>) -> Message {
+        self.state = State::AwaitingUpdate;
+
+        Message::StateVector(document.state_vector())
+    }
+
+    /// Handles a message from the peer, returning the messages to send back.
+    ///
+    /// Messages which don't fit the protocol, such as an update before any state vector, or an
+    /// update which can't be applied, are answered with [`Message::Error`].
+    pub fn handle<S: BlockStorage<T>>(
+        &mut self,
+        document: &mut Document<T, S>,
+        message: Message,
+    ) -> Vec<Message> {
+        if self.state == State::Failed {
+            return vec![];
+        }
+
+        match message {
+            Message::StateVector(state) => {
+                let mut replies = vec![self.send_since(document, state.as_ref())];
+
+                if self.state == State::Idle {
+                    replies.push(self.start(document));
+                }
+
+                replies
+            }
+            Message::Update(_) | Message::Done if self.state == State::Idle => {
+                vec![Message::Error(
+                    "received before state vectors were exchanged".to_owned(),
+                )]
+            }
+            Message::Update(bytes) => match self.receive(document, &bytes) {
+                Ok(()) if self.state == State::AwaitingUpdate => {
+                    self.state = State::Synced;
+
+                    vec![Message::Done]
+                }
+                Ok(()) => vec![],
+                Err(error) => vec![Message::Error(error)],
+            },
+            Message::Done => vec![],
+            Message::Error(_) => {
+                self.state = State::Failed;
+
+                vec![]
+            }
+        }
+    }
+
+    /// An update of the edits made to `document` since the peer last heard from it, or `None`
+    /// if there are none or the peer's state isn't known yet.
+    ///
+    /// Edits in place, such as replaced values, marks and map writes, are pushed too, along with
+    /// every other write the document has, as updates carry all of them.
+    pub fn push<S: BlockStorage<T>>(&mut self, document: &Document<T, S>) -> Option<Message> {
+        if self.state == State::Failed {
+            return None;
+        }
+
+        let since = self.peer_state.clone()?;
+        let update = Update::from_document_since_deletes(document, &since, &self.peer_deletes);
+
+        if !update.changes_sequence() && update.write_clocks().is_covered_by(&self.peer_writes) {
+            return None;
+        }
+
+        Some(self.send(document, update))
+    }
+
+    /// Whether both sides have exchanged everything they had when the sync started.
+    pub fn is_synced(&self) -> bool {
+        self.state == State::Synced
+    }
+
+    /// Whether the peer reported an error, which stops the protocol.
+    pub fn is_failed(&self) -> bool {
+        self.state == State::Failed
+    }
+
+    fn send_since<S: BlockStorage<T>>(
+        &mut self,
+        document: &Document<T, S>,
+        since: &ClockVector,
+    ) -> Message {
+        let mut peer_state = self.peer_state.take().unwrap_or_default();
+        merge_clocks(&mut peer_state, since);
+        self.peer_state = Some(peer_state);
+
+        let update = Update::from_document_since(document, since);
+
+        self.send(document, update)
+    }
+
+    fn send<S: BlockStorage<T>>(
+        &mut self,
+        document: &Document<T, S>,
+        update: Update<T>,
+    ) -> Message {
+        let writes = update.write_clocks();
+
+        match update.encode() {
+            Ok(bytes) => {
+                // Once this arrives, the peer has everything the document has
+                merge_clocks(
+                    self.peer_state.get_or_insert_with(ClockVector::new),
+                    document.state_vector().as_ref(),
+                );
+                self.peer_deletes = std::mem::replace(&mut self.peer_deletes, DeleteSet::empty())
+                    .merge(DeleteSet::from(document));
+                self.peer_writes.merge(writes);
+
+                Message::Update(bytes)
+            }
+            Err(error) => Message::Error(format!("couldn't encode update: {}", error)),
+        }
+    }
+
+    fn receive<S: BlockStorage<T>>(
+        &mut self,
+        document: &mut Document<T, S>,
+        bytes: &[u8],
+    ) -> Result<(), String> {
+        let update = document
+            .decode_update(bytes)
+            .map_err(|error| format!("couldn't decode update: {}", error))?;
+
+        // The peer has everything in its own update
+        let clocks = update.end_clocks();
+        let deletes = update.deletes().clone();
+        let writes = update.write_clocks();
+
+        document
+            .apply_or_queue(update)
+            .map_err(|error| format!("couldn't apply update: {}", error))?;
+
+        merge_clocks(
+            self.peer_state.get_or_insert_with(ClockVector::new),
+            &clocks,
+        );
+        self.peer_deletes =
+            std::mem::replace(&mut self.peer_deletes, DeleteSet::empty()).merge(deletes);
+        self.peer_writes.merge(writes);
+
+        Ok(())
+    }
+}
+
+/// Raises each clock in `into` to at least its clock in `from`.
+fn merge_clocks(into: &mut ClockVector, from: &ClockVector) {
+    for (client_id, clock) in from {
+        into.entry(*client_id).or_default().observe(*clock);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sync::{Message, SyncProtocol};
+    use crate::Document;
+    use std::collections::VecDeque;
+
+    /// A document and its side of the protocol, with the messages waiting for it.
+    struct Peer {
+        document: Document<String>,
+        protocol: SyncProtocol<String>,
+        inbox: VecDeque<Vec<u8>>,
+    }
+
+    impl Peer {
+        fn new(client_id: u64) -> Peer {
+            Peer {
+                document: Document::with_client_id(client_id),
+                protocol: SyncProtocol::new(),
+                inbox: VecDeque::new(),
+            }
+        }
+    }
+
+    fn send(to: &mut Peer, message: Message) {
+        to.inbox.push_back(message.encode().unwrap());
+    }
+
+    /// Has `peer` handle everything in its inbox, sending the replies to `other`.
+    fn deliver(peer: &mut Peer, other: &mut Peer) {
+        while let Some(bytes) = peer.inbox.pop_front() {
+            let message = Message::decode(&bytes).unwrap();
+            assert!(!matches!(message, Message::Error(_)), "{:?}", message);
+
+            for reply in peer.protocol.handle(&mut peer.document, message) {
+                send(other, reply);
+            }
+        }
+    }
+
+    /// Delivers messages back and forth until neither side has anything left to say.
+    fn run_to_quiescence(a: &mut Peer, b: &mut Peer) {
+        while !a.inbox.is_empty() || !b.inbox.is_empty() {
+            deliver(a, b);
+            deliver(b, a);
+        }
+    }
+
+    #[test]
+    fn loopback_sync_converges() {
+        let mut a = Peer::new(1);
+        let mut b = Peer::new(2);
+
+        for value in ["a", "b", "c"] {
+            a.document.push(value.to_owned());
+        }
+        b.document.push("x".to_owned());
+
+        // Both sides start at once
+        let hello_a = a.protocol.start(&a.document);
+        let hello_b = b.protocol.start(&b.document);
+        send(&mut b, hello_a);
+        send(&mut a, hello_b);
+        run_to_quiescence(&mut a, &mut b);
+
+        assert!(a.protocol.is_synced() && b.protocol.is_synced());
+        assert!(a.document.content_eq(&b.document));
+        assert_eq!(a.document.len(), 4);
+
+        // Concurrent edits afterwards are pushed incrementally, deletions included
+        a.document.remove(0);
+        a.document.insert(1, "y".to_owned());
+        b.document.push("z".to_owned());
+        b.document.remove(1);
+
+        let push_a = a.protocol.push(&a.document).unwrap();
+        let push_b = b.protocol.push(&b.document).unwrap();
+        send(&mut b, push_a);
+        send(&mut a, push_b);
+        run_to_quiescence(&mut a, &mut b);
+
+        assert!(a.document.content_eq(&b.document));
+        assert_eq!(a.protocol.push(&a.document), None);
+        assert_eq!(b.protocol.push(&b.document), None);
+
+        // A lone deletion still gets pushed
+        b.document.remove(0);
+        let push_b = b.protocol.push(&b.document).unwrap();
+        send(&mut a, push_b);
+        run_to_quiescence(&mut a, &mut b);
+
+        assert!(a.document.content_eq(&b.document));
+    }
+
+    #[test]
+    fn one_sided_start_syncs_both_ways() {
+        let mut a = Peer::new(1);
+        let mut b = Peer::new(2);
+        a.document.push("a".to_owned());
+        b.document.push("b".to_owned());
+
+        let hello = a.protocol.start(&a.document);
+        send(&mut b, hello);
+        run_to_quiescence(&mut a, &mut b);
+
+        assert!(a.protocol.is_synced() && b.protocol.is_synced());
+        assert!(a.document.content_eq(&b.document));
+        assert_eq!(a.document.len(), 2);
+    }
+
+    #[test]
+    fn edits_in_place_are_pushed() {
+        let mut a = Peer::new(1);
+        let mut b = Peer::new(2);
+        for value in ["a", "b"] {
+            a.document.push(value.to_owned());
+        }
+
+        let hello = a.protocol.start(&a.document);
+        send(&mut b, hello);
+        run_to_quiescence(&mut a, &mut b);
+
+        a.document.set(0, "x".to_owned());
+        let update = a.protocol.push(&a.document).unwrap();
+        send(&mut b, update);
+        run_to_quiescence(&mut a, &mut b);
+        assert_eq!(b.document.get(0), Some(&"x".to_owned()));
+
+        // Writes the peer already has aren't sent again
+        assert_eq!(a.protocol.push(&a.document), None);
+
+        a.document.add_mark(0..2, "bold", vec![1]);
+        let update = a.protocol.push(&a.document).unwrap();
+        send(&mut b, update);
+        run_to_quiescence(&mut a, &mut b);
+        assert_eq!(
+            b.document.marks_at(1).collect::<Vec<_>>(),
+            vec![("bold", &[1][..])]
+        );
+        assert_eq!(a.protocol.push(&a.document), None);
+        assert_eq!(b.protocol.push(&b.document), None);
+    }
+
+    #[test]
+    fn out_of_order_messages_get_an_error() {
+        let mut document: Document<String> = Document::with_client_id(1);
+        let mut protocol = SyncProtocol::new();
+
+        assert!(matches!(
+            protocol.handle(&mut document, Message::Done).as_slice(),
+            [Message::Error(_)]
+        ));
+        assert!(matches!(
+            protocol
+                .handle(&mut document, Message::Update(vec![1, 2, 3]))
+                .as_slice(),
+            [Message::Error(_)]
+        ));
+
+        protocol.start(&document);
+        assert!(matches!(
+            protocol
+                .handle(&mut document, Message::Update(vec![1, 2, 3]))
+                .as_slice(),
+            [Message::Error(_)]
+        ));
+        assert!(!protocol.is_synced());
+
+        // An error from the peer stops the protocol
+        protocol.handle(&mut document, Message::Error("gone".to_owned()));
+        assert!(protocol.is_failed());
+        assert_eq!(protocol.push(&document), None);
+    }
+}
@@ -0,0 +1,218 @@
+//! Helpers for driving replicas of a document programmatically, e.g. from property tests.
+//! Enabled with the `testing` feature.
+
+use crate::block::Item;
+use crate::{ApplyError, Document, Update};
+
+/// `count` empty documents with client ids `1..=count`, so runs are reproducible.
+pub fn replicas<T: Item>(count: usize) -> Vec<Document<T>> {
+    (1..=count as u64).map(Document::with_client_id).collect()
+}
+
+/// Sends replica `to` everything replica `from` has which it is missing, returning the update
+/// that was applied.
+///
+/// # Panics
+///
+/// Panics if `from == to` or either is out of bounds.
+pub fn exchange<T: Item>(
+    replicas: &mut [Document<T>],
+    from: usize,
+    to: usize,
+) -> Result<Update<T>, ApplyError> {
+    assert_ne!(from, to, "a replica can't exchange with itself");
+
+    let update = Update::from_document_since(&replicas[from], replicas[to].state_vector().as_ref());
+    update.clone().apply(&mut replicas[to])?;
+
+    Ok(update)
+}
+
+/// Exchanges updates until every replica has everything any of them has.
+pub fn sync_all<T: Item>(replicas: &mut [Document<T>]) -> Result<(), ApplyError> {
+    // Gather everything into the first replica, then send it back out
+    for replica in 1..replicas.len() {
+        exchange(replicas, replica, 0)?;
+    }
+
+    for replica in 1..replicas.len() {
+        exchange(replicas, 0, replica)?;
+    }
+
+    Ok(())
+}
+
+/// Whether every replica holds the same values in the same order.
+pub fn converged<T: Item + PartialEq>(replicas: &[Document<T>]) -> bool {
+    replicas.windows(2).all(|pair| pair[0].content_eq(&pair[1]))
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::sim::Simulator;
+    use crate::testing::{exchange, replicas};
+    use crate::Document;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    /// One step of a script. Positions are scaled into the replica's length when the step runs,
+    /// so every script is valid and shrinks towards position 0.
+    #[derive(Clone, Debug)]
+    enum Op {
+        Insert {
+            replica: usize,
+            position: usize,
+        },
+        Delete {
+            replica: usize,
+            position: usize,
+        },
+        /// Sends `to` everything `from` has which it is missing.
+        Exchange {
+            from: usize,
+            to: usize,
+        },
+        /// Delivers the `message`th update in flight, if any.
+        Deliver {
+            message: usize,
+        },
+        /// Queues a second copy of the `message`th update in flight, if any.
+        Duplicate {
+            message: usize,
+        },
+    }
+
+    fn op(replicas: usize) -> impl Strategy<Value = Op> {
+        prop_oneof![
+            3 => (0..replicas, 0..64usize)
+                .prop_map(|(replica, position)| Op::Insert { replica, position }),
+            1 => (0..replicas, 0..64usize)
+                .prop_map(|(replica, position)| Op::Delete { replica, position }),
+            2 => (0..replicas, 0..replicas)
+                .prop_filter("a replica can't exchange with itself", |(from, to)| from != to)
+                .prop_map(|(from, to)| Op::Exchange { from, to }),
+            2 => (0..64usize).prop_map(|message| Op::Deliver { message }),
+            1 => (0..64usize).prop_map(|message| Op::Duplicate { message }),
+        ]
+    }
+
+    /// A number of replicas and a script for them.
+    fn script() -> impl Strategy<Value = (usize, Vec<Op>)> {
+        (2..=4usize).prop_flat_map(|replicas| (Just(replicas), vec(op(replicas), 1..40)))
+    }
+
+    /// Runs a local edit on `document`, returning false if it was a delete of an empty document.
+    fn edit(document: &mut Document<usize>, op: &Op, value: usize) -> bool {
+        let len = document.len();
+
+        match *op {
+            Op::Insert { position, .. } => {
+                document.insert(position % (len + 1), value);
+            }
+            Op::Delete { .. } if len == 0 => return false,
+            Op::Delete { position, .. } => document.remove(position % len),
+            _ => return false,
+        }
+
+        true
+    }
+
+    proptest! {
+        #[test]
+        fn replicas_converge_whatever_the_delivery_order((count, script) in script()) {
+            let mut sim = Simulator::new(count, 0);
+
+            for (step, op) in script.iter().enumerate() {
+                match *op {
+                    Op::Insert { replica, position } => {
+                        let len = sim.values(replica).len();
+                        sim.insert(replica, position % (len + 1), step);
+                    }
+                    Op::Delete { replica, position } => {
+                        let len = sim.values(replica).len();
+
+                        if len > 0 {
+                            sim.remove_range(replica, position % len, 1);
+                        }
+                    }
+                    Op::Deliver { message } => {
+                        if sim.in_flight() > 0 {
+                            sim.deliver(message % sim.in_flight());
+                        }
+                    }
+                    // Every edit is already on its way to every replica, so deliver the oldest
+                    Op::Exchange { .. } => {
+                        if sim.in_flight() > 0 {
+                            sim.deliver(0);
+                        }
+                    }
+                    Op::Duplicate { message } => {
+                        if sim.in_flight() > 0 {
+                            sim.duplicate(message % sim.in_flight());
+                        }
+                    }
+                }
+            }
+
+            sim.assert_converged();
+        }
+
+        #[test]
+        fn causally_ordered_edits_match_a_sequential_oracle((count, script) in script()) {
+            let mut documents = replicas::<usize>(count);
+            let mut oracle = vec![];
+
+            for (step, op) in script.iter().enumerate() {
+                let (Op::Insert { replica, .. } | Op::Delete { replica, .. }) = *op else {
+                    continue;
+                };
+                let len = oracle.len();
+
+                if !edit(&mut documents[replica], op, step) {
+                    continue;
+                }
+
+                match *op {
+                    Op::Insert { position, .. } => oracle.insert(position % (len + 1), step),
+                    Op::Delete { position, .. } => {
+                        oracle.remove(position % len);
+                    }
+                    _ => unreachable!(),
+                }
+
+                // Every edit reaches every replica before the next one is made
+                for other in (0..count).filter(|other| *other != replica) {
+                    exchange(&mut documents, replica, other).unwrap();
+                }
+            }
+
+            for document in &documents {
+                prop_assert_eq!(document.iter().copied().collect::<Vec<_>>(), oracle.clone());
+            }
+        }
+
+        #[test]
+        fn applying_an_update_twice_changes_nothing((count, script) in script()) {
+            let mut documents = replicas::<usize>(count);
+
+            for (step, op) in script.iter().enumerate() {
+                match *op {
+                    Op::Insert { replica, .. } | Op::Delete { replica, .. } => {
+                        edit(&mut documents[replica], op, step);
+                    }
+                    Op::Exchange { from, to } => {
+                        let update = exchange(&mut documents, from, to).unwrap();
+                        let values: Vec<usize> = documents[to].iter().copied().collect();
+                        let state = documents[to].state_vector();
+
+                        update.apply(&mut documents[to]).unwrap();
+
+                        prop_assert_eq!(documents[to].iter().copied().collect::<Vec<_>>(), values);
+                        prop_assert_eq!(documents[to].state_vector(), state);
+                    }
+                    Op::Deliver { .. } | Op::Duplicate { .. } => {}
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use crate::encoding::{DecodeError, EncodeError};
+use crate::{ApplyError, Document, StateVector, Update};
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
+/// A collaboratively edited string, stored as one element per `char`.
+///
+/// All indices count `char`s rather than bytes. Edits return the update they made, compacted so a
+/// run of inserted text is sent as a single block.
+#[derive(Debug, Default)]
+pub struct TextDocument {
+    document: Document<char>,
+}
+
+/// Why an encoded update couldn't be applied to a [`TextDocument`] or
+/// [`BinaryDocument`](crate::BinaryDocument).
+#[derive(Debug, PartialEq)]
+pub enum TextUpdateError {
+    Decode(DecodeError),
+    Apply(ApplyError),
+}
+
+impl Display for TextUpdateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TextUpdateError::Decode(error) => write!(f, "couldn't decode update: {}", error),
+            TextUpdateError::Apply(error) => write!(f, "couldn't apply update: {}", error),
+        }
+    }
+}
+
+impl Error for TextUpdateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TextUpdateError::Decode(error) => Some(error),
+            TextUpdateError::Apply(error) => Some(error),
+        }
+    }
+}
+
+impl TextDocument {
+    pub fn new() -> TextDocument {
+        TextDocument {
+            document: Document::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_client_id(client_id: u64) -> TextDocument {
+        TextDocument {
+            document: Document::with_client_id(client_id),
+        }
+    }
+
+    /// Inserts `text` so its first `char` ends up at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index > len_chars`.
+    pub fn insert_str(&mut self, index: usize, text: &str) -> Update<char> {
+        let len = self.len_chars();
+
+        assert!(
+            index <= len,
+            "insertion index (is {}) should be <= len (is {})",
+            index,
+            len
+        );
+
+        self.document.transact(|transaction| {
+            for (offset, value) in text.chars().enumerate() {
+                transaction.insert(index + offset, value);
+            }
+        })
+    }
+
+    /// Deletes the `char`s in `range`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is out of bounds.
+    pub fn delete(&mut self, range: Range<usize>) -> Update<char> {
+        assert!(range.start <= range.end, "range {:?} is reversed", range);
+
+        self.document
+            .transact(|transaction| transaction.remove_range(range.start, range.len()))
+    }
+
+    /// The number of `char`s in the text.
+    pub fn len_chars(&self) -> usize {
+        self.document.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.document.is_empty()
+    }
+
+    pub fn state_vector(&self) -> StateVector {
+        self.document.state_vector()
+    }
+
+    /// Encodes everything a replica at state `since` is missing, for [`TextDocument::apply_update`].
+    /// Pass an empty [`StateVector`] to encode the whole text.
+    pub fn encode_update(&self, since: &StateVector) -> Result<Vec<u8>, EncodeError> {
+        Update::from_document_since(&self.document, since.as_ref()).encode()
+    }
+
+    /// Applies an update encoded by [`TextDocument::encode_update`] or [`Update::encode`].
+    pub fn apply_update(&mut self, bytes: &[u8]) -> Result<(), TextUpdateError> {
+        Update::decode(bytes)
+            .map_err(TextUpdateError::Decode)?
+            .apply(&mut self.document)
+            .map_err(TextUpdateError::Apply)
+    }
+}
+
+impl Display for TextDocument {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.document
+            .store
+            .iter_values()
+            .try_for_each(|value| write!(f, "{}", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::text::TextDocument;
+    use crate::StateVector;
+
+    #[test]
+    fn edits_by_char_index() {
+        let mut text = TextDocument::with_client_id(1);
+        text.insert_str(0, "héllo");
+        text.insert_str(5, " wörld");
+        text.insert_str(5, ",");
+        text.delete(0..1);
+
+        assert_eq!(text.to_string(), "éllo, wörld");
+        assert_eq!(text.len_chars(), 11);
+    }
+
+    #[test]
+    fn multi_codepoint_text_round_trips() {
+        // "e" followed by a combining acute accent, and a flag made of two regional indicators
+        let input = "cafe\u{301} \u{1F1F3}\u{1F1FF}";
+
+        let mut text = TextDocument::with_client_id(1);
+        text.insert_str(0, input);
+
+        let mut other = TextDocument::with_client_id(2);
+        other
+            .apply_update(&text.encode_update(&StateVector::new()).unwrap())
+            .unwrap();
+
+        assert_eq!(other.to_string(), input);
+        assert_eq!(other.len_chars(), input.chars().count());
+    }
+
+    #[test]
+    fn inserted_text_is_sent_as_one_block() {
+        let mut text = TextDocument::with_client_id(1);
+        text.insert_str(0, "ab");
+
+        let update = text.insert_str(1, &"x".repeat(50));
+        let encoded = update.encode().unwrap();
+
+        let mut other = TextDocument::with_client_id(2);
+        other
+            .apply_update(&text.encode_update(&StateVector::new()).unwrap())
+            .unwrap();
+
+        assert_eq!(other.to_string(), text.to_string());
+        assert!(encoded.len() < 70, "encoded to {} bytes", encoded.len());
+    }
+
+    #[test]
+    fn concurrent_inserts_at_the_same_index_do_not_interleave() {
+        let mut text1 = TextDocument::with_client_id(1);
+        text1.insert_str(0, "[]");
+
+        let mut text2 = TextDocument::with_client_id(2);
+        text2
+            .apply_update(&text1.encode_update(&StateVector::new()).unwrap())
+            .unwrap();
+
+        let update1 = text1.insert_str(1, "abc").encode().unwrap();
+        let update2 = text2.insert_str(1, "xyz").encode().unwrap();
+
+        text1.apply_update(&update2).unwrap();
+        text2.apply_update(&update1).unwrap();
+
+        assert_eq!(text1.to_string(), text2.to_string());
+        assert!(["[abcxyz]", "[xyzabc]"].contains(&text1.to_string().as_str()));
+    }
+}
@@ -0,0 +1,149 @@
+use crate::block::Item;
+use crate::delete_set::DeleteSet;
+use crate::document::BlockId;
+use crate::storage::{BlockStorage, VecStorage};
+use crate::Document;
+use std::ops::Range;
+
+/// A batch of local edits made through [`Document::transact`].
+///
+/// Indices behave exactly as they do on [`Document`], and reflect the edits made so far within the
+/// transaction.
+pub struct Transaction<'a, T: Item, S: BlockStorage<T> = VecStorage<T>> {
+    document: &'a mut Document<T, S>,
+}
+
+impl<'a, T: Item, S: BlockStorage<T>> Transaction<'a, T, S> {
+    pub(crate) fn new(document: &'a mut Document<T, S>) -> Transaction<'a, T, S> {
+        Transaction { document }
+    }
+
+    pub fn insert(&mut self, index: usize, value: T) -> BlockId {
+        self.document.insert(index, value)
+    }
+
+    pub fn insert_values(
+        &mut self,
+        index: usize,
+        values: impl IntoIterator<Item = T>,
+    ) -> Option<BlockId> {
+        self.document.insert_values(index, values)
+    }
+
+    pub fn push(&mut self, value: T) -> BlockId {
+        self.document.push(value)
+    }
+
+    pub fn set(&mut self, index: usize, value: T) {
+        self.document.set(index, value);
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        self.remove_range(index, 1);
+    }
+
+    pub fn remove_range(&mut self, index: usize, count: usize) {
+        self.document.delete_range(index, count);
+    }
+
+    /// Deletes whichever of the elements in `ids` are still live.
+    pub(crate) fn delete_elements(&mut self, ids: &DeleteSet) {
+        self.document.delete_elements(ids);
+    }
+
+    /// The index the element `id` is at, or would be at if it's been deleted.
+    pub(crate) fn index_of_element(&self, id: BlockId) -> Option<usize> {
+        self.document
+            .store
+            .live_elements_before(id)
+            .map(|(before, _)| before)
+    }
+
+    pub fn move_range(&mut self, src: Range<usize>, dest_index: usize) {
+        self.document.move_range(src, dest_index);
+    }
+
+    pub fn map_set(&mut self, key: impl Into<String>, value: T) -> Option<T> {
+        self.document.map_set(key, value)
+    }
+
+    pub fn map_remove(&mut self, key: &str) -> Option<T> {
+        self.document.map_remove(key)
+    }
+
+    pub fn add_mark(&mut self, range: Range<usize>, key: impl Into<String>, value: Vec<u8>) {
+        self.document.add_mark(range, key, value);
+    }
+
+    /// The index of the element `id`, or `None` if it's been deleted.
+    pub(crate) fn index_of_live_element(&self, id: BlockId) -> Option<usize> {
+        self.document.index_of_id(id, 0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.document.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.document.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.document.get(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Document, Update};
+
+    #[test]
+    fn transaction_update_applies_to_peer() {
+        let mut doc1 = Document::with_client_id(1);
+        doc1.push("a".to_owned());
+        doc1.push("b".to_owned());
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+
+        let update = doc1.transact(|transaction| {
+            transaction.insert(0, "x".to_owned());
+            transaction.push("y".to_owned());
+            transaction.insert(2, "z".to_owned());
+            transaction.remove(1);
+        });
+
+        update.apply(&mut doc2).unwrap();
+
+        assert_eq!(
+            doc2.store.iter_values().collect::<Vec<_>>(),
+            doc1.store.iter_values().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            doc2.store.iter_values().collect::<Vec<_>>(),
+            vec!["x", "z", "b", "y"]
+        );
+    }
+
+    #[test]
+    fn transaction_update_only_contains_its_own_changes() {
+        let mut doc1 = Document::with_client_id(1);
+        doc1.push("a".to_owned());
+        doc1.push("b".to_owned());
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+
+        // Made outside the transaction, so the peer shouldn't learn about it
+        doc1.remove(0);
+
+        let update = doc1.transact(|transaction| {
+            transaction.push("c".to_owned());
+            transaction.remove(1);
+        });
+
+        update.apply(&mut doc2).unwrap();
+
+        assert_eq!(doc2.store.iter_values().collect::<Vec<_>>(), vec!["a", "b"]);
+    }
+}
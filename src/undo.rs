@@ -0,0 +1,444 @@
+use crate::block::Item;
+use crate::clock::ClockRange;
+use crate::delete_set::DeleteSet;
+use crate::document::BlockId;
+use crate::storage::BlockStorage;
+use crate::{Document, Transaction, Update};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// A local edit, as recorded by a [`Document`] for its undo manager.
+#[derive(Debug, Clone)]
+pub(crate) enum LocalChange<T> {
+    /// The elements with these ids were inserted.
+    Inserted(Vec<BlockId>),
+    /// These elements were deleted, along with the values they held.
+    Deleted(Vec<(BlockId, T)>),
+    /// The value of this element was replaced, and it held this one before.
+    Replaced(BlockId, T),
+}
+
+/// A group of local changes which are undone or redone together.
+#[derive(Debug)]
+struct Step<T> {
+    changes: Vec<LocalChange<T>>,
+}
+
+impl<T: Item> Step<T> {
+    /// Reverts the step's changes, most recent first. Inserted or replaced elements which have
+    /// since been deleted (e.g. by a remote client) are left alone.
+    ///
+    /// Deleted elements come back as new elements, so each one's replacement is recorded in
+    /// `replaced`.
+    fn revert<S: BlockStorage<T>>(
+        &self,
+        transaction: &mut Transaction<T, S>,
+        replaced: &mut HashMap<BlockId, BlockId>,
+    ) {
+        for change in self.changes.iter().rev() {
+            match change {
+                LocalChange::Inserted(ids) => {
+                    let mut inserted = DeleteSet::empty();
+                    for id in ids {
+                        let id = replaced.get(id).unwrap_or(id);
+                        inserted.insert(id.client_id, ClockRange::starting_at(id.clock, 1));
+                    }
+
+                    transaction.delete_elements(&inserted);
+                }
+                LocalChange::Deleted(removed) => {
+                    for (id, value) in removed {
+                        // Re-inserted where the tombstone is, unless a checkpoint pruned it
+                        let Some(index) = transaction.index_of_element(*id) else {
+                            continue;
+                        };
+
+                        let reinserted = transaction.insert(index, value.clone());
+                        replaced.insert(*id, reinserted);
+                    }
+                }
+                LocalChange::Replaced(id, value) => {
+                    let id = replaced.get(id).unwrap_or(id);
+
+                    if let Some(index) = transaction.index_of_live_element(*id) {
+                        transaction.set(index, value.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Points the step at the elements which replaced any it refers to.
+    fn replace(&mut self, replaced: &HashMap<BlockId, BlockId>) {
+        for change in &mut self.changes {
+            match change {
+                LocalChange::Inserted(ids) => {
+                    for id in ids {
+                        *id = *replaced.get(id).unwrap_or(id);
+                    }
+                }
+                LocalChange::Deleted(removed) => {
+                    for (id, _) in removed {
+                        *id = *replaced.get(id).unwrap_or(id);
+                    }
+                }
+                LocalChange::Replaced(id, _) => *id = *replaced.get(id).unwrap_or(id),
+            }
+        }
+    }
+}
+
+/// Undoes and redoes the local edits made to a [`Document`], as new CRDT operations which can be
+/// sent to other replicas like any other edit.
+///
+/// Changes made within the capture timeout of each other are grouped into a single undo step,
+/// unless separated by [`UndoManager::stop_capturing`]. Remote updates are never undone.
+///
+/// Only one undo manager should track a document at a time, as they share the document's record
+/// of local changes.
+#[derive(Debug)]
+pub struct UndoManager<T: Item> {
+    undo_stack: VecDeque<Step<T>>,
+    redo_stack: Vec<Step<T>>,
+    capture_timeout: Duration,
+    max_steps: usize,
+    /// When the most recent change was captured, if the next change may join its step.
+    last_captured: Option<Instant>,
+}
+
+impl<T: Item> UndoManager<T> {
+    /// Starts tracking local edits made to `document` from now on.
+    pub fn new<S: BlockStorage<T>>(document: &mut Document<T, S>) -> UndoManager<T> {
+        document.start_recording();
+
+        UndoManager {
+            undo_stack: VecDeque::new(),
+            redo_stack: vec![],
+            capture_timeout: Duration::from_millis(500),
+            max_steps: 100,
+            last_captured: None,
+        }
+    }
+
+    /// Sets how close together changes must be to be grouped into one step. Defaults to 500ms.
+    pub fn with_capture_timeout(mut self, capture_timeout: Duration) -> UndoManager<T> {
+        self.capture_timeout = capture_timeout;
+        self
+    }
+
+    /// Sets how many undo steps are kept, dropping the oldest beyond it. Defaults to 100.
+    pub fn with_max_steps(mut self, max_steps: usize) -> UndoManager<T> {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// Ends the current undo step, so the next change starts a new one.
+    pub fn stop_capturing<S: BlockStorage<T>>(&mut self, document: &mut Document<T, S>) {
+        self.capture(document);
+        self.last_captured = None;
+    }
+
+    /// Whether there is anything left to undo.
+    pub fn can_undo<S: BlockStorage<T>>(&mut self, document: &mut Document<T, S>) -> bool {
+        self.capture(document);
+
+        !self.undo_stack.is_empty()
+    }
+
+    pub fn can_redo<S: BlockStorage<T>>(&mut self, document: &mut Document<T, S>) -> bool {
+        self.capture(document);
+
+        !self.redo_stack.is_empty()
+    }
+
+    /// Reverts the most recent undo step which still has an effect, returning the update to send
+    /// to other replicas, or `None` if there was nothing to undo.
+    ///
+    /// Steps whose changes have all since been reverted by remote clients are skipped.
+    pub fn undo<S: BlockStorage<T>>(&mut self, document: &mut Document<T, S>) -> Option<Update<T>> {
+        self.capture(document);
+        self.last_captured = None;
+
+        while let Some(step) = self.undo_stack.pop_back() {
+            if let Some((update, redo)) = self.revert(document, step) {
+                self.redo_stack.push(redo);
+
+                return Some(update);
+            }
+        }
+
+        None
+    }
+
+    /// Re-applies the most recently undone step, returning the update to send to other replicas,
+    /// or `None` if there was nothing to redo.
+    pub fn redo<S: BlockStorage<T>>(&mut self, document: &mut Document<T, S>) -> Option<Update<T>> {
+        self.capture(document);
+        self.last_captured = None;
+
+        while let Some(step) = self.redo_stack.pop() {
+            if let Some((update, undo)) = self.revert(document, step) {
+                self.push_undo(undo);
+
+                return Some(update);
+            }
+        }
+
+        None
+    }
+
+    /// Reverts `step`, returning the update and the step which reverts it again, or `None` if
+    /// reverting it changed nothing.
+    fn revert<S: BlockStorage<T>>(
+        &mut self,
+        document: &mut Document<T, S>,
+        step: Step<T>,
+    ) -> Option<(Update<T>, Step<T>)> {
+        let mut replaced = HashMap::new();
+        let update = document.transact(|transaction| step.revert(transaction, &mut replaced));
+
+        for step in self.undo_stack.iter_mut().chain(&mut self.redo_stack) {
+            step.replace(&replaced);
+        }
+
+        let changes: Vec<LocalChange<T>> = document
+            .take_recorded()
+            .into_iter()
+            .map(|(_, change)| change)
+            .collect();
+
+        if changes.is_empty() {
+            None
+        } else {
+            Some((update, Step { changes }))
+        }
+    }
+
+    /// Moves changes the document has recorded since the last capture onto the undo stack.
+    fn capture<S: BlockStorage<T>>(&mut self, document: &mut Document<T, S>) {
+        let changes = document.take_recorded();
+
+        if changes.is_empty() {
+            return;
+        }
+
+        // New edits make anything undone unreachable
+        self.redo_stack.clear();
+
+        for (time, change) in changes {
+            let joins_step = self
+                .last_captured
+                .is_some_and(|last| time.duration_since(last) < self.capture_timeout);
+
+            match self.undo_stack.back_mut() {
+                Some(step) if joins_step => step.changes.push(change),
+                _ => self.push_undo(Step {
+                    changes: vec![change],
+                }),
+            }
+
+            self.last_captured = Some(time);
+        }
+    }
+
+    fn push_undo(&mut self, step: Step<T>) {
+        self.undo_stack.push_back(step);
+
+        while self.undo_stack.len() > self.max_steps {
+            self.undo_stack.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::undo::UndoManager;
+    use crate::{Document, Update};
+    use std::time::Duration;
+
+    fn values(document: &Document<String>) -> Vec<&str> {
+        document.store.iter_values().map(String::as_str).collect()
+    }
+
+    fn send(update: Option<Update<String>>, document: &mut Document<String>) {
+        update.unwrap().apply(document).unwrap();
+    }
+
+    #[test]
+    fn undoes_and_redoes_inserts_and_deletes() {
+        let mut doc1 = Document::with_client_id(1);
+        let mut doc2 = Document::with_client_id(2);
+        let mut undo = UndoManager::new(&mut doc1);
+
+        send(
+            Some(doc1.transact(|transaction| {
+                transaction.push("a".to_owned());
+                transaction.push("b".to_owned());
+                transaction.push("c".to_owned());
+            })),
+            &mut doc2,
+        );
+        undo.stop_capturing(&mut doc1);
+
+        send(
+            Some(doc1.transact(|transaction| transaction.remove_range(0, 2))),
+            &mut doc2,
+        );
+        undo.stop_capturing(&mut doc1);
+
+        assert_eq!(values(&doc1), vec!["c"]);
+
+        send(undo.undo(&mut doc1), &mut doc2);
+        assert_eq!(values(&doc1), vec!["a", "b", "c"]);
+
+        send(undo.undo(&mut doc1), &mut doc2);
+        assert_eq!(values(&doc1), Vec::<&str>::new());
+        assert_eq!(undo.undo(&mut doc1), None);
+
+        send(undo.redo(&mut doc1), &mut doc2);
+        assert_eq!(values(&doc1), vec!["a", "b", "c"]);
+
+        send(undo.redo(&mut doc1), &mut doc2);
+        assert_eq!(values(&doc1), vec!["c"]);
+        assert_eq!(undo.redo(&mut doc1), None);
+
+        send(undo.undo(&mut doc1), &mut doc2);
+        assert_eq!(values(&doc1), vec!["a", "b", "c"]);
+        assert_eq!(values(&doc2), values(&doc1));
+    }
+
+    #[test]
+    fn undoes_and_redoes_replaced_values() {
+        let mut doc1 = Document::with_client_id(1);
+        let mut doc2 = Document::with_client_id(2);
+        let mut undo = UndoManager::new(&mut doc1);
+
+        send(
+            Some(doc1.transact(|transaction| {
+                transaction.push("a".to_owned());
+                transaction.push("b".to_owned());
+            })),
+            &mut doc2,
+        );
+        undo.stop_capturing(&mut doc1);
+
+        send(
+            Some(doc1.transact(|transaction| transaction.set(1, "x".to_owned()))),
+            &mut doc2,
+        );
+        undo.stop_capturing(&mut doc1);
+        assert_eq!(values(&doc2), vec!["a", "x"]);
+
+        send(undo.undo(&mut doc1), &mut doc2);
+        assert_eq!(values(&doc1), vec!["a", "b"]);
+        assert_eq!(values(&doc2), vec!["a", "b"]);
+
+        send(undo.redo(&mut doc1), &mut doc2);
+        assert_eq!(values(&doc1), vec!["a", "x"]);
+        assert_eq!(values(&doc2), vec!["a", "x"]);
+    }
+
+    #[test]
+    fn undoing_remotely_deleted_insert_is_a_no_op() {
+        let mut doc1 = Document::with_client_id(1);
+        let mut doc2 = Document::with_client_id(2);
+        let mut undo = UndoManager::new(&mut doc1);
+
+        doc1.push("a".to_owned());
+        undo.stop_capturing(&mut doc1);
+        doc1.push("b".to_owned());
+
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+        doc2.remove(1);
+        Update::from_document_since(&doc2, doc1.state_vector().as_ref())
+            .apply(&mut doc1)
+            .unwrap();
+
+        assert_eq!(values(&doc1), vec!["a"]);
+
+        // "b" is already gone, so its step is skipped and "a" is undone instead
+        let update = undo.undo(&mut doc1).unwrap();
+        update.apply(&mut doc2).unwrap();
+
+        assert_eq!(values(&doc1), Vec::<&str>::new());
+        assert_eq!(values(&doc2), Vec::<&str>::new());
+        assert_eq!(undo.undo(&mut doc1), None);
+    }
+
+    #[test]
+    fn undoes_deletes_of_elements_restored_by_an_earlier_undo() {
+        let mut doc = Document::with_client_id(1);
+        let mut undo = UndoManager::new(&mut doc);
+
+        doc.push("a".to_owned());
+        undo.stop_capturing(&mut doc);
+        doc.push("b".to_owned());
+        doc.remove(0);
+        undo.stop_capturing(&mut doc);
+        doc.remove(0);
+
+        assert_eq!(values(&doc), Vec::<&str>::new());
+
+        // Restores "b", as a new element which the next undo has to delete instead
+        undo.undo(&mut doc);
+        assert_eq!(values(&doc), vec!["b"]);
+
+        undo.undo(&mut doc);
+        assert_eq!(values(&doc), vec!["a"]);
+
+        undo.undo(&mut doc);
+        assert_eq!(values(&doc), Vec::<&str>::new());
+
+        undo.redo(&mut doc);
+        undo.redo(&mut doc);
+        undo.redo(&mut doc);
+        assert_eq!(values(&doc), Vec::<&str>::new());
+
+        undo.undo(&mut doc);
+        assert_eq!(values(&doc), vec!["b"]);
+    }
+
+    #[test]
+    fn groups_changes_by_capture_timeout() {
+        let mut doc = Document::with_client_id(1);
+        let mut undo = UndoManager::new(&mut doc).with_capture_timeout(Duration::from_secs(60));
+
+        doc.push("a".to_owned());
+        doc.push("b".to_owned());
+        undo.undo(&mut doc);
+
+        assert_eq!(values(&doc), Vec::<&str>::new());
+
+        let mut undo = UndoManager::new(&mut doc).with_capture_timeout(Duration::ZERO);
+
+        doc.push("a".to_owned());
+        doc.push("b".to_owned());
+        undo.undo(&mut doc);
+
+        assert_eq!(values(&doc), vec!["a"]);
+    }
+
+    #[test]
+    fn new_edits_clear_redo_and_history_is_capped() {
+        let mut doc = Document::with_client_id(1);
+        let mut undo = UndoManager::new(&mut doc)
+            .with_capture_timeout(Duration::ZERO)
+            .with_max_steps(2);
+
+        doc.push("a".to_owned());
+        doc.push("b".to_owned());
+        doc.push("c".to_owned());
+
+        undo.undo(&mut doc);
+        assert!(undo.can_redo(&mut doc));
+
+        doc.push("d".to_owned());
+        assert!(!undo.can_redo(&mut doc));
+
+        undo.undo(&mut doc);
+        undo.undo(&mut doc);
+
+        assert_eq!(values(&doc), vec!["a"]);
+        assert!(!undo.can_undo(&mut doc));
+    }
+}
@@ -1,16 +1,37 @@
 use crate::block::{Block, Item};
+use crate::block_range::BlockRange;
+use crate::clock::{Clock, ClockRange};
 use crate::delete_set::DeleteSet;
-use crate::document::{BlockId, ClientId, Clock};
+use crate::delta::Delta;
+use crate::document::{BlockId, ClientId, ClockVector, UnsentChanges};
+use crate::encoding::{self, DecodeError, EncodeError};
+use crate::limits::{Limit, Limits};
+use crate::map::{MapEntry, MapStore};
+use crate::marks::{Mark, MarkStore};
+#[cfg(feature = "metrics")]
+use crate::metrics::ApplyTimer;
+use crate::moves::{Move, MoveStore};
+use crate::register::{Register, RegisterStore};
+use crate::store::IntegrateError;
+use crate::version::{DeleteLog, DeleteRecord};
 use crate::Document;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::marker::PhantomData;
+use std::mem;
 use std::ops::Range;
+use std::slice;
 
+use crate::storage::{BlockStorage, VecStorage};
 use crate::update::MergeResult::{Merged, NotMerged};
 use bincode::de::Decoder;
 use bincode::enc::Encoder;
-use bincode::error::{DecodeError, EncodeError};
 use bincode::{Decode, Encode};
 
 #[derive(Eq, PartialEq, Debug, Clone, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum Content<T: Item> {
     Value(Vec<T>),
     Deleted(u64),
@@ -36,55 +57,124 @@ enum MergeResult<T> {
     NotMerged(T, T),
 }
 
-#[derive(Eq, PartialEq, Debug, Clone, Encode, Decode)]
+#[derive(Eq, PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct UpdateBlock<T: Item> {
     pub(crate) origin_left: Option<BlockId>,
     pub(crate) origin_right: Option<BlockId>,
     pub(crate) value: Content<T>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) priority: u8,
+}
+
+// The layout blocks had before format version 8, kept to read older updates. The priority is
+// carried in an extension of the update. Updates now write blocks against a `ClientTable`
+impl<T: Item + Encode> Encode for UpdateBlock<T> {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.origin_left.encode(encoder)?;
+        self.origin_right.encode(encoder)?;
+        self.value.encode(encoder)
+    }
+}
+
+impl<T: Item + Decode> Decode for UpdateBlock<T> {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, bincode::error::DecodeError> {
+        Ok(UpdateBlock {
+            origin_left: Decode::decode(decoder)?,
+            origin_right: Decode::decode(decoder)?,
+            value: Decode::decode(decoder)?,
+            priority: 0,
+        })
+    }
 }
 
 impl<T: Item> UpdateBlock<T> {
-    fn hydrate(self, id: Clock) -> Block<T> {
-        let (length, value) = match self.value {
-            Content::Value(value) => (value.len(), value),
-            Content::Deleted(size) => (size as usize, vec![]),
+    fn hydrate(self, id: Clock) -> Result<Block<T>, ApplyError> {
+        let (length, value, deleted) = match self.value {
+            Content::Value(value) => (value.len(), value, false),
+            // The length comes straight off the wire, and may not fit on 32-bit targets
+            Content::Deleted(size) => (
+                usize::try_from(size).map_err(|_| ApplyError::InvalidLength(size))?,
+                vec![],
+                true,
+            ),
         };
 
-        Block {
+        Ok(Block {
             id,
             origin_left: self.origin_left,
             origin_right: self.origin_right,
             value,
             left: None,
             right: None,
-            deleted: false,
+            deleted,
             length,
-        }
+            priority: self.priority,
+        })
     }
 
     fn from_block(block: &Block<T>) -> UpdateBlock<T> {
         let value = if block.deleted {
-            Content::Value(block.value.clone())
-        } else {
             Content::Deleted(block.length as u64)
+        } else {
+            Content::Value(block.value.clone())
         };
 
         UpdateBlock {
-            origin_left: block.origin_left.clone(),
-            origin_right: block.origin_right.clone(),
+            origin_left: block.origin_left,
+            origin_right: block.origin_right,
             value,
+            priority: block.priority,
         }
     }
 
+    #[cfg(test)]
     fn with_value(left: Option<BlockId>, right: Option<BlockId>, value: T) -> UpdateBlock<T> {
         UpdateBlock {
             origin_left: left,
             origin_right: right,
             value: Content::Value(vec![value]),
+            priority: 0,
         }
     }
 
-    fn length(&self) -> u64 {
+    /// Splits the block into its first `offset` elements and the rest, where the block's first
+    /// element has the id `id`. The rest continues the run before it, so its left origin is the
+    /// preceding element.
+    fn split_at(self, id: BlockId, offset: u64) -> (UpdateBlock<T>, UpdateBlock<T>) {
+        let (left, right) = match self.value {
+            Content::Value(mut value) => {
+                let right = value.split_off(offset as usize);
+
+                (Content::Value(value), Content::Value(right))
+            }
+            Content::Deleted(length) => {
+                (Content::Deleted(offset), Content::Deleted(length - offset))
+            }
+        };
+
+        (
+            UpdateBlock {
+                origin_left: self.origin_left,
+                origin_right: self.origin_right,
+                value: left,
+                priority: self.priority,
+            },
+            UpdateBlock {
+                origin_left: Some(BlockId::new(id.client_id, id.clock.advance(offset - 1))),
+                origin_right: self.origin_right,
+                value: right,
+                priority: self.priority,
+            },
+        )
+    }
+
+    /// The part of this block from `offset` onwards, as split by [`UpdateBlock::split_at`].
+    fn split_off(self, id: BlockId, offset: u64) -> UpdateBlock<T> {
+        self.split_at(id, offset).1
+    }
+
+    pub(crate) fn length(&self) -> u64 {
         match &self.value {
             Content::Value(v) => v.len() as u64,
             Content::Deleted(v) => *v,
@@ -97,10 +187,14 @@ impl<T: Item> UpdateBlock<T> {
         other: Self,
         other_id: BlockId,
     ) -> MergeResult<UpdateBlock<T>> {
+        // The next block must have been inserted directly after our last element
+        let last_id = BlockId::new(self_id.client_id, self_id.clock.advance(self.length() - 1));
+
         let can_merge = if self.origin_right == other.origin_right
-            && Some(self_id) == other.origin_left
+            && self.priority == other.priority
+            && Some(last_id) == other.origin_left
             && self_id.client_id == other_id.client_id
-            && self_id.clock + self.length() == other_id.clock
+            && self_id.clock.advance(self.length()) == other_id.clock
         {
             matches!(
                 (&self.value, &other.value),
@@ -115,6 +209,7 @@ impl<T: Item> UpdateBlock<T> {
                 origin_left: self.origin_left,
                 origin_right: self.origin_right,
                 value: self.value.merge(other.value),
+                priority: self.priority,
             })
         } else {
             NotMerged(self, other)
@@ -124,353 +219,5886 @@ impl<T: Item> UpdateBlock<T> {
 
 impl<T: Item> From<Block<T>> for UpdateBlock<T> {
     fn from(block: Block<T>) -> Self {
+        let value = if block.deleted {
+            Content::Deleted(block.length as u64)
+        } else {
+            Content::Value(block.value)
+        };
+
         UpdateBlock {
             origin_left: block.origin_left,
             origin_right: block.origin_right,
-            value: Content::Value(block.value),
+            value,
+            priority: block.priority,
         }
     }
 }
 
-#[derive(Eq, PartialEq, Clone, Encode, Decode, Debug)]
+/// A set of changes to a [`Document`], which any replica can apply.
+///
+/// Encoded, the blocks and deletes are followed by a list of extensions, each an id and a
+/// length-prefixed section, which carry the map, marks, moves, who made the deletes, which
+/// roots blocks belong to, replaced values, the sender's checkpoint and censored elements.
+/// Readers skip extensions they don't know, so new kinds of data can be added without breaking
+/// older peers.
+#[derive(Eq, PartialEq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Update<T: Item> {
-    dependency: Vec<(ClientId, Range<Clock>)>,
+    dependency: Vec<(ClientId, ClockRange)>,
     blocks: Vec<(ClientId, Vec<UpdateBlock<T>>)>,
     deletes: DeleteSet,
+    /// Writes to the document's map, merged regardless of the sequence's dependencies.
+    map: Vec<MapEntry<T>>,
+    /// Marks on ranges of the sequence, whose ends must be in the document or the update.
+    marks: Vec<Mark>,
+    /// Moves of ranges of the sequence, whose ends and markers must be in the document or the
+    /// update.
+    moves: Vec<Move>,
+    /// Who made the deletes, for [`Document::checkout`]. Deletes without one always count.
+    deletions: Vec<DeleteRecord>,
+    /// The clocks of the blocks in each named root, by name. Blocks in none of them belong to the
+    /// document's own sequence.
+    roots: Vec<(String, DeleteSet)>,
+    /// Values replacing those elements were inserted with, whose elements must be in the
+    /// document or the update.
+    registers: Vec<Register<T>>,
+    /// The clock of each client the sender pruned its history at with
+    /// [`Document::checkpoint`], which the receiver must already have.
+    checkpoint: Vec<(ClientId, Clock)>,
+    /// Elements removed with [`Document::censor`], whose content is dropped wherever it turns up.
+    censored: DeleteSet,
 }
 
-#[derive(PartialEq, Debug)]
-enum ValidationError {
-    ClientDoesNotExist(ClientId),
-    UpdateOutsideRange(BlockId),
-    InvalidUpdateRange(ClientId),
-}
+/// An update's extensions as encoded, each an id and its section.
+type Extensions = Vec<(u32, Vec<u8>)>;
 
-impl<T: Item> Update<T> {
-    pub fn from_document(document: &Document<T>) -> Update<T> {
-        let blocks = document
-            .store
-            .data
-            .iter()
-            .map(|(client_id, block)| {
-                (*client_id, block.iter().map(|u| u.clone().into()).collect())
-            })
-            .collect();
+/// The extension holding an update's map entries.
+const MAP_EXTENSION: u32 = 0;
+/// The extension holding an update's marks.
+const MARKS_EXTENSION: u32 = 1;
+/// The extension holding the priorities of an update's blocks, by the id of each block's first
+/// element. Blocks without one have priority 0.
+const PRIORITIES_EXTENSION: u32 = 2;
+/// The extension holding an update's moves.
+const MOVES_EXTENSION: u32 = 3;
+/// The extension holding who made an update's deletes.
+const DELETIONS_EXTENSION: u32 = 4;
+/// The extension holding which named root each of an update's blocks belongs to.
+const ROOTS_EXTENSION: u32 = 5;
+/// The extension holding an update's replaced values.
+const REGISTERS_EXTENSION: u32 = 6;
+/// The extension holding the checkpoint an update's sender pruned its history at.
+const CHECKPOINT_EXTENSION: u32 = 7;
+/// The extension holding the elements an update's sender knows to be censored.
+const CENSORED_EXTENSION: u32 = 8;
 
-        Update {
-            blocks,
-            dependency: document
-                .store
-                .data
+impl<T: Item + Encode> Encode for Update<T> {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.dependency.encode(encoder)?;
+
+        let mut table = ClientTable::for_blocks(
+            self.blocks.iter().map(|(client_id, _)| *client_id),
+            self.blocks
                 .iter()
-                .map(|(key, value)| (*key, 0..(value.len() as Clock)))
-                .collect(),
-            deletes: DeleteSet::from(document),
-        }
-    }
+                .flat_map(|(_, blocks)| blocks.iter().flat_map(BlockShape::origins))
+                .flatten(),
+        );
+        table.encode(encoder)?;
+        (self.blocks.len() as u64).encode(encoder)?;
 
-    pub fn apply(self, document: &mut Document<T>) -> Result<(), ()> {
-        // Check dependencies
-        for (client_id, dependency_range) in self.dependency {
-            let start = document.clients.get(&client_id).unwrap_or(&0);
+        for (client_id, blocks) in &self.blocks {
+            let mut clock = self
+                .get_version_range(*client_id)
+                .map_or(Clock::ZERO, |range| range.start);
+            let owner = table.encode_run(encoder, *client_id, clock, blocks.len())?;
 
-            if dependency_range.start > *start {
-                return Err(());
-            }
-        }
+            for block in blocks {
+                table.encode_block(encoder, owner, clock, &EncodedBlock::of(block))?;
 
-        for (client_id, blocks) in self.blocks.into_iter() {
-            let hydrated_blocks = blocks
-                .into_iter()
-                .enumerate()
-                .map(|(i, block)| block.hydrate(i as Clock))
-                .collect();
+                if let Content::Value(values) = &block.value {
+                    for value in values {
+                        value.encode(encoder)?;
+                    }
+                }
 
-            document.store.integrate(client_id, hydrated_blocks)
+                clock = clock.saturating_advance(block.length());
+            }
         }
 
-        Ok(())
+        self.deletes.encode(encoder)?;
+
+        encode_extensions(
+            encoder,
+            ExtensionParts {
+                map: &self.map,
+                marks: &self.marks,
+                priorities: &self.priorities(),
+                moves: &self.moves,
+                deletions: &self.deletions,
+                roots: &self.roots,
+                registers: &self.registers,
+                checkpoint: &self.checkpoint,
+                censored: &self.censored,
+            },
+        )
     }
+}
 
-    pub(crate) fn from_blocks(
-        client_id: ClientId,
-        blocks: Vec<Block<T>>,
-        dependency: Vec<(ClientId, Range<Clock>)>,
-    ) -> Update<T> {
-        let update_blocks = blocks.into_iter().map(|f| f.into()).collect();
+impl<T: Item + Decode> Decode for Update<T> {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, bincode::error::DecodeError> {
+        let dependency: Vec<(ClientId, ClockRange)> = Decode::decode(decoder)?;
+        let mut table = ClientTable::decode(decoder)?;
+        let mut blocks = vec![];
 
-        Update {
-            blocks: vec![(client_id, update_blocks)],
-            dependency,
-            deletes: DeleteSet::empty(),
-        }
-    }
+        for _ in 0..u64::decode(decoder)? {
+            let (owner, mut clock, count) = table.decode_run(decoder)?;
+            let client_id = table.client(owner);
+            check_run_start(&dependency, client_id, clock)?;
 
-    fn validate(&self) -> Result<(), ValidationError> {
-        for (client, blocks) in &self.blocks {
-            for block in blocks {
-                if !self.does_clock_exist(block.origin_left) {
-                    return Err(ValidationError::UpdateOutsideRange(
-                        block.origin_left.unwrap(),
-                    ));
-                }
+            let mut client_blocks = vec![];
 
-                if !self.does_clock_exist(block.origin_right) {
-                    return Err(ValidationError::UpdateOutsideRange(
-                        block.origin_right.unwrap(),
-                    ));
-                }
+            for _ in 0..count {
+                let block = table.decode_block(decoder, owner, clock)?;
+                clock = clock.saturating_advance(block.length);
+                client_blocks.push(block.decode_values(decoder)?);
             }
 
-            if let Some(range) = self.get_version_range(*client) {
-                if (range.end - range.start) != (blocks.len() as Clock) {
-                    return Err(ValidationError::InvalidUpdateRange(*client));
-                }
-            } else {
-                return Err(ValidationError::ClientDoesNotExist(*client));
-            }
+            blocks.push((client_id, client_blocks));
         }
 
-        Ok(())
+        Update::decode_rest(dependency, blocks, decoder)
     }
+}
 
-    fn get_version_range(&self, client_id: ClientId) -> Option<Range<Clock>> {
-        self.dependency
-            .iter()
-            .find(|(cid, ..)| *cid == client_id)
-            .map(|(cid, range)| range.clone())
+/// An [`Update`] as encoded from format version 4 to 7, before blocks were written against a
+/// [`ClientTable`].
+struct UpdateV7<T: Item>(Update<T>);
+
+impl<T: Item + Decode> Decode for UpdateV7<T> {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, bincode::error::DecodeError> {
+        let dependency = Decode::decode(decoder)?;
+        let blocks = Decode::decode(decoder)?;
+
+        Update::decode_rest(dependency, blocks, decoder).map(UpdateV7)
     }
+}
 
-    fn does_clock_exist(&self, block: Option<BlockId>) -> bool {
-        if let Some(block) = block {
-            if let Some(range) = self.get_version_range(block.client_id) {
-                if block.clock > range.end {
-                    return false;
-                }
-            } else {
-                return false;
+impl<T: Item + Decode> Update<T> {
+    /// Reads the deletes and extensions which follow an update's blocks.
+    fn decode_rest<D: Decoder>(
+        dependency: Vec<(ClientId, ClockRange)>,
+        blocks: Vec<(ClientId, Vec<UpdateBlock<T>>)>,
+        decoder: &mut D,
+    ) -> Result<Self, bincode::error::DecodeError> {
+        let mut update = Update {
+            dependency,
+            blocks,
+            deletes: Decode::decode(decoder)?,
+            map: vec![],
+            marks: vec![],
+            moves: vec![],
+            deletions: vec![],
+            roots: vec![],
+            registers: vec![],
+            checkpoint: vec![],
+            censored: DeleteSet::empty(),
+        };
+
+        let extensions: Extensions = Decode::decode(decoder)?;
+
+        for (id, section) in extensions {
+            match id {
+                MAP_EXTENSION => update.map = encoding::decode_section(&section)?,
+                MARKS_EXTENSION => update.marks = encoding::decode_section(&section)?,
+                PRIORITIES_EXTENSION => update.set_priorities(encoding::decode_section(&section)?),
+                MOVES_EXTENSION => update.moves = encoding::decode_section(&section)?,
+                DELETIONS_EXTENSION => update.deletions = encoding::decode_section(&section)?,
+                ROOTS_EXTENSION => update.roots = encoding::decode_section(&section)?,
+                REGISTERS_EXTENSION => update.registers = encoding::decode_section(&section)?,
+                CHECKPOINT_EXTENSION => update.checkpoint = encoding::decode_section(&section)?,
+                CENSORED_EXTENSION => update.censored = decode_censored(&section)?,
+                // Written by a newer version of this crate
+                _ => {}
             }
         }
 
-        true
+        Ok(update)
     }
+}
 
-    fn compact(self) -> Self {
-        self
-        // for (client, block) in self.blocks {
-        //     let mut start = self.get_version_range(*client).unwrap().start;
-        //     let output: Vec<UpdateBlock<T>> = Vec::with_capacity(block.len());
-        //
-        //     block
-        //         .into_iter()
-        //         .fold((None, output), |(prev, mut output), next| {
-        //             let next_id = BlockId::new(client, start);
-        //             start += 1;
-        //             match prev {
-        //                 None => (Some((next, next_id)), output),
-        //                 Some((prev, prev_id)) => match prev.try_merge(prev_id, next, next_id) {
-        //                     Merged(value) => (Some((value, next_id)), output),
-        //                     NotMerged(value1, value2) => {
-        //                         output.push(value1);
-        //                         (Some((value2, next_id)), output)
-        //                     }
-        //                 },
-        //             }
-        //         })
-        // }
-    }
+/// The parts of an update written as extensions, borrowed from an [`Update`] or from the
+/// document an [`UpdateRef`] encodes.
+struct ExtensionParts<'a, T: Item> {
+    map: &'a [MapEntry<T>],
+    marks: &'a [Mark],
+    priorities: &'a [(BlockId, u8)],
+    moves: &'a [Move],
+    deletions: &'a [DeleteRecord],
+    roots: &'a [(String, DeleteSet)],
+    registers: &'a [Register<T>],
+    checkpoint: &'a [(ClientId, Clock)],
+    censored: &'a DeleteSet,
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::block::Block;
-    use crate::delete_set::DeleteSet;
-    use crate::document::BlockId;
-    use crate::update::{Update, UpdateBlock, ValidationError};
-    use crate::Document;
-    use bincode::{config, decode_from_slice, encode_to_vec};
+/// Reads the censored extension, a list of delete sets which is written with just one.
+fn decode_censored(section: &[u8]) -> Result<DeleteSet, bincode::error::DecodeError> {
+    let censored: Vec<DeleteSet> = encoding::decode_section(section)?;
 
-    #[test]
-    fn can_create_update_from_document() {
-        let mut doc = Document::new();
-        doc.store.append("test".to_owned());
+    Ok(censored
+        .into_iter()
+        .fold(DeleteSet::empty(), DeleteSet::merge))
+}
 
-        let document_as_update = Update::from_document(&doc);
+/// Writes the extensions which follow an update's deletes, leaving out empty ones.
+fn encode_extensions<T: Item + Encode, E: Encoder>(
+    encoder: &mut E,
+    parts: ExtensionParts<'_, T>,
+) -> Result<(), EncodeError> {
+    let ExtensionParts {
+        map,
+        marks,
+        priorities,
+        moves,
+        deletions,
+        roots,
+        registers,
+        checkpoint,
+        censored,
+    } = parts;
+    let mut extensions: Extensions = vec![];
 
-        assert_eq!(
-            document_as_update.blocks[0],
-            (
-                doc.client_id,
-                vec![UpdateBlock::with_value(None, None, "test".to_owned())]
-            )
-        );
+    if !map.is_empty() {
+        extensions.push((MAP_EXTENSION, encoding::encode_section(map)?));
     }
 
-    #[test]
-    fn can_apply_update_to_document() {
-        let mut doc = Document::new();
-        doc.store.append("test".to_owned());
-
-        let update = Update::from_document(&doc);
+    if !marks.is_empty() {
+        extensions.push((MARKS_EXTENSION, encoding::encode_section(marks)?));
+    }
 
-        let mut doc2 = Document::new();
-        update.apply(&mut doc2);
+    if !priorities.is_empty() {
+        extensions.push((PRIORITIES_EXTENSION, encoding::encode_section(priorities)?));
+    }
 
-        let data: Vec<&String> = doc2.store.iter_values().collect();
+    if !moves.is_empty() {
+        extensions.push((MOVES_EXTENSION, encoding::encode_section(moves)?));
+    }
 
-        assert_eq!(data, vec!["test"]);
+    if !deletions.is_empty() {
+        extensions.push((DELETIONS_EXTENSION, encoding::encode_section(deletions)?));
     }
 
-    #[test]
-    fn cant_apply_update_with_missing_dependency() {
-        let mut doc = Document::with_client_id(1);
-        doc.store.append("test".to_owned());
+    if !roots.is_empty() {
+        extensions.push((ROOTS_EXTENSION, encoding::encode_section(roots)?));
+    }
 
-        let update = Update::from_blocks(2, vec![], vec![(3, 2..3)]);
+    if !registers.is_empty() {
+        extensions.push((REGISTERS_EXTENSION, encoding::encode_section(registers)?));
+    }
 
-        let result = update.apply(&mut doc);
+    if !checkpoint.is_empty() {
+        extensions.push((CHECKPOINT_EXTENSION, encoding::encode_section(checkpoint)?));
+    }
 
-        assert_eq!(result, Err(()))
+    if !censored.is_empty() {
+        extensions.push((
+            CENSORED_EXTENSION,
+            encoding::encode_section(slice::from_ref(censored))?,
+        ));
     }
 
-    #[test]
-    fn can_merge_two_documents() {
-        let mut doc = Document::with_client_id(1);
-        doc.store.append("test".to_owned());
+    extensions.encode(encoder)
+}
 
-        let update = Update::from_document(&doc);
+/// An [`Update`] as encoded before documents had a map.
+#[derive(Encode, Decode)]
+struct UpdateV1<T: Item> {
+    dependency: Vec<(ClientId, ClockRange)>,
+    blocks: Vec<(ClientId, Vec<UpdateBlock<T>>)>,
+    deletes: DeleteSet,
+}
 
-        let mut doc2 = Document::with_client_id(2);
-        doc2.store.append("test2".to_owned());
-        update.apply(&mut doc2);
+/// An [`Update`] as encoded before documents had marks.
+#[derive(Encode, Decode)]
+struct UpdateV2<T: Item> {
+    dependency: Vec<(ClientId, ClockRange)>,
+    blocks: Vec<(ClientId, Vec<UpdateBlock<T>>)>,
+    deletes: DeleteSet,
+    map: Vec<MapEntry<T>>,
+}
 
-        let data: Vec<&String> = doc2.store.iter_values().collect();
+/// An [`Update`] as encoded before extensions, with the map and marks always present.
+#[derive(Encode, Decode)]
+struct UpdateV3<T: Item> {
+    dependency: Vec<(ClientId, ClockRange)>,
+    blocks: Vec<(ClientId, Vec<UpdateBlock<T>>)>,
+    deletes: DeleteSet,
+    map: Vec<MapEntry<T>>,
+    marks: Vec<Mark>,
+}
 
-        assert_eq!(data, vec!["test", "test2"]);
+impl<T: Item> From<UpdateV3<T>> for Update<T> {
+    fn from(update: UpdateV3<T>) -> Self {
+        Update {
+            dependency: update.dependency,
+            blocks: update.blocks,
+            deletes: update.deletes,
+            map: update.map,
+            marks: update.marks,
+            moves: vec![],
+            deletions: vec![],
+            roots: vec![],
+            registers: vec![],
+            checkpoint: vec![],
+            censored: DeleteSet::empty(),
+        }
     }
+}
 
-    #[test]
-    fn can_validate_empty_doc() {
-        let valid_update: Update<String> = Update {
-            blocks: vec![],
-            dependency: vec![],
-            deletes: DeleteSet::empty(),
-        };
-
-        assert_eq!(valid_update.validate(), Ok(()));
+impl<T: Item> From<UpdateV2<T>> for Update<T> {
+    fn from(update: UpdateV2<T>) -> Self {
+        Update {
+            dependency: update.dependency,
+            blocks: update.blocks,
+            deletes: update.deletes,
+            map: update.map,
+            marks: vec![],
+            moves: vec![],
+            deletions: vec![],
+            roots: vec![],
+            registers: vec![],
+            checkpoint: vec![],
+            censored: DeleteSet::empty(),
+        }
     }
+}
 
-    #[test]
-    fn throws_error_if_client_doesnt_exist_range() {
-        let valid_update: Update<String> = Update {
-            blocks: vec![(1, vec![])],
-            dependency: vec![],
-            deletes: DeleteSet::empty(),
-        };
-
-        assert_eq!(
-            valid_update.validate(),
-            Err(ValidationError::ClientDoesNotExist(1))
-        );
+impl<T: Item> From<UpdateV1<T>> for Update<T> {
+    fn from(update: UpdateV1<T>) -> Self {
+        Update {
+            dependency: update.dependency,
+            blocks: update.blocks,
+            deletes: update.deletes,
+            map: vec![],
+            marks: vec![],
+            moves: vec![],
+            deletions: vec![],
+            roots: vec![],
+            registers: vec![],
+            checkpoint: vec![],
+            censored: DeleteSet::empty(),
+        }
     }
+}
 
-    #[test]
-    fn throws_error_if_update_client_doesnt_exist_range() {
-        let valid_update: Update<String> = Update {
-            blocks: vec![(
-                1,
-                vec![UpdateBlock::with_value(
-                    Some(BlockId::new(2, 0)),
-                    None,
-                    "Test".to_owned(),
-                )],
-            )],
-            dependency: vec![(1, 0..1)],
-            deletes: DeleteSet::empty(),
-        };
+/// The latest clock of each client's writes to the map, registers, marks and moves. Each kind of
+/// write is ordered by a Lamport clock of its own, which a client ticks for every write it makes,
+/// so a write is covered by the clocks of any later write of the same kind from its client.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct WriteClocks {
+    map: ClockVector,
+    registers: ClockVector,
+    marks: ClockVector,
+    moves: ClockVector,
+}
 
-        assert_eq!(
-            valid_update.validate(),
-            Err(ValidationError::UpdateOutsideRange(BlockId::new(2, 0)))
-        );
+impl WriteClocks {
+    fn kinds(&self) -> [&ClockVector; 4] {
+        [&self.map, &self.registers, &self.marks, &self.moves]
     }
 
-    #[test]
-    fn throws_error_if_update_outside_range_left() {
-        let valid_update: Update<String> = Update {
-            blocks: vec![(
-                1,
-                vec![UpdateBlock::with_value(
-                    Some(BlockId::new(2, 1)),
-                    None,
-                    "Test".to_owned(),
-                )],
-            )],
-            dependency: vec![(1, 0..1), (2, 0..0)],
-            deletes: DeleteSet::empty(),
-        };
-
-        assert_eq!(
-            valid_update.validate(),
-            Err(ValidationError::UpdateOutsideRange(BlockId::new(2, 1)))
-        );
+    /// Whether every write counted here is also counted in `other`.
+    pub(crate) fn is_covered_by(&self, other: &WriteClocks) -> bool {
+        self.kinds()
+            .into_iter()
+            .zip(other.kinds())
+            .all(|(clocks, other)| {
+                clocks.iter().all(|(client_id, clock)| {
+                    other
+                        .get(client_id)
+                        .is_some_and(|other_clock| clock <= other_clock)
+                })
+            })
     }
 
-    #[test]
-    fn throws_error_if_update_outside_range_right() {
-        let valid_update: Update<String> = Update {
-            blocks: vec![(
-                1,
-                vec![UpdateBlock::with_value(
-                    None,
-                    Some(BlockId::new(2, 1)),
-                    "Test".to_owned(),
-                )],
-            )],
-            dependency: vec![(1, 0..1), (2, 0..0)],
-            deletes: DeleteSet::empty(),
-        };
+    pub(crate) fn merge(&mut self, other: WriteClocks) {
+        let kinds = [
+            (&mut self.map, other.map),
+            (&mut self.registers, other.registers),
+            (&mut self.marks, other.marks),
+            (&mut self.moves, other.moves),
+        ];
 
-        assert_eq!(
-            valid_update.validate(),
-            Err(ValidationError::UpdateOutsideRange(BlockId::new(2, 1)))
-        );
+        for (into, from) in kinds {
+            for (client_id, clock) in from {
+                into.entry(client_id).or_default().observe(clock);
+            }
+        }
     }
+}
 
-    #[test]
-    fn validate_ok_if_valid_update() {
-        let valid_update: Update<String> = Update {
-            blocks: vec![(
-                1,
-                vec![UpdateBlock::with_value(
-                    Some(BlockId::new(2, 0)),
-                    None,
-                    "Test".to_owned(),
-                )],
-            )],
-            dependency: vec![(1, 0..1), (2, 0..0)],
-            deletes: DeleteSet::empty(),
-        };
+/// What [`Update::apply_partial`] applied.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct ApplyOutcome<T: Item> {
+    /// The clients whose blocks were applied, ordered by id.
+    pub applied_clients: Vec<ClientId>,
+    /// Whatever couldn't be applied yet for lack of dependencies, or `None` if everything was.
+    pub remainder: Option<Update<T>>,
+}
 
-        assert_eq!(valid_update.validate(), Ok(()));
-    }
+#[derive(PartialEq, Debug)]
+enum ValidationError {
+    ClientDoesNotExist(ClientId),
+    UpdateOutsideRange(BlockId),
+    InvalidUpdateRange(ClientId),
+    FutureOrigin(BlockId),
+}
 
-    #[test]
-    fn validate_apply_serialized_update() {
+/// Why an [`Update`] could not be applied to a [`Document`].
+///
+/// [`ApplyError::MissingDependency`] means the update is fine but arrived early, and can be
+/// retried once the missing updates have been applied. Every other variant means the update is
+/// malformed and will never apply.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum ApplyError {
+    /// The update builds on clocks from `client_id` that the document hasn't seen yet.
+    ///
+    /// `missing` holds the clock the document has to reach for every client it is behind on,
+    /// including `client_id`, as returned by [`Document::missing_for`].
+    MissingDependency {
+        client_id: ClientId,
+        required_clock: Clock,
+        have_clock: Clock,
+        missing: ClockVector,
+    },
+    /// A block's origin points outside the ranges covered by the update.
+    InvalidOrigin(BlockId),
+    /// A block's origin refers to an element which neither the document nor the update has.
+    MissingOrigin(BlockId),
+    /// The origins of some of the update's blocks refer round in a circle, e.g. two blocks which
+    /// are each inserted next to the other, so none of them can be integrated first.
+    CyclicOrigins(BlockId),
+    /// Blocks were sent for a client without declaring its clock range.
+    UndeclaredClient(ClientId),
+    /// The number of blocks sent for a client doesn't match its declared clock range.
+    InvalidUpdateRange(ClientId),
+    /// A declared length can't be represented on this target.
+    InvalidLength(u64),
+    /// The update has blocks under the document's own client id which the document didn't
+    /// generate, so another replica is using the same id.
+    ClientIdConflict(ClientId),
+    /// A block for `client_id` doesn't start at the next clock the document expects.
+    UnexpectedClock {
+        client_id: ClientId,
+        expected: Clock,
+        actual: Clock,
+    },
+    /// A block's clocks run past the largest representable clock.
+    ClockOverflow(BlockId),
+    /// A write to the map, a replaced value, a mark or a move from `client_id` carries the
+    /// largest representable clock, which no later write could be ordered after.
+    WriteClockOverflow(ClientId),
+    /// Applying the update would break one of the document's [`Limits`].
+    LimitExceeded(Limit),
+    /// A block's origin is one of its own elements or a later element from the same client,
+    /// neither of which existed when it was inserted.
+    FutureOrigin(BlockId),
+    /// The document's blocks link back round on themselves, so it is corrupt and blocks can no
+    /// longer be placed in it.
+    CyclicBlocks(BlockId),
+    /// The document's blocks link to a block it doesn't hold, so it is corrupt and blocks can no
+    /// longer be placed in it.
+    BrokenLink(BlockId),
+    /// Two documents have each applied everything the other has, but still hold different
+    /// elements, so their histories conflict.
+    Diverged,
+    /// The update builds on clocks from `client_id` before `checkpoint`, which either the
+    /// document or the update's sender has pruned at a [`Document::checkpoint`]. The document
+    /// can only catch up from a [`Snapshot`](crate::Snapshot) of a replica past the checkpoint.
+    PrunedHistory {
+        client_id: ClientId,
+        checkpoint: Clock,
+    },
+}
+
+/// Why two [`Update`]s couldn't be merged.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum MergeError {
+    /// The updates' clock ranges for `client_id` neither overlap nor touch, so the merged update
+    /// would be missing the blocks in between.
+    NonContiguousRanges {
+        client_id: ClientId,
+        first: ClockRange,
+        second: ClockRange,
+    },
+}
+
+impl Display for MergeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::NonContiguousRanges {
+                client_id,
+                first,
+                second,
+            } => write!(
+                f,
+                "clock ranges {:?} and {:?} for client {} leave a gap",
+                first, second, client_id
+            ),
+        }
+    }
+}
+
+impl Error for MergeError {}
+
+/// Why [`Document::apply_encoded`] couldn't apply an update.
+#[derive(Debug, PartialEq)]
+pub enum ApplyEncodedError {
+    Decode(DecodeError),
+    Apply(ApplyError),
+}
+
+impl Display for ApplyEncodedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ApplyEncodedError::Decode(error) => write!(f, "couldn't decode update: {}", error),
+            ApplyEncodedError::Apply(error) => write!(f, "couldn't apply update: {}", error),
+        }
+    }
+}
+
+impl Error for ApplyEncodedError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ApplyEncodedError::Decode(error) => Some(error),
+            ApplyEncodedError::Apply(error) => Some(error),
+        }
+    }
+}
+
+impl From<IntegrateError> for ApplyError {
+    fn from(error: IntegrateError) -> Self {
+        match error {
+            IntegrateError::MissingOrigin(block_id) => ApplyError::MissingOrigin(block_id),
+            IntegrateError::UnexpectedClock {
+                client_id,
+                expected,
+                actual,
+            } => ApplyError::UnexpectedClock {
+                client_id,
+                expected,
+                actual,
+            },
+            IntegrateError::ClockOverflow(block_id) => ApplyError::ClockOverflow(block_id),
+            IntegrateError::Cycle(block_id) => ApplyError::CyclicBlocks(block_id),
+            IntegrateError::BrokenLink(block_id) => ApplyError::BrokenLink(block_id),
+        }
+    }
+}
+
+impl From<ValidationError> for ApplyError {
+    fn from(error: ValidationError) -> Self {
+        match error {
+            ValidationError::ClientDoesNotExist(client_id) => {
+                ApplyError::UndeclaredClient(client_id)
+            }
+            ValidationError::UpdateOutsideRange(block_id) => ApplyError::InvalidOrigin(block_id),
+            ValidationError::InvalidUpdateRange(client_id) => {
+                ApplyError::InvalidUpdateRange(client_id)
+            }
+            ValidationError::FutureOrigin(block_id) => ApplyError::FutureOrigin(block_id),
+        }
+    }
+}
+
+impl Display for ApplyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ApplyError::MissingDependency {
+                client_id,
+                required_clock,
+                have_clock,
+                ..
+            } => write!(
+                f,
+                "update requires clock {} from client {} but only {} is known",
+                required_clock, client_id, have_clock
+            ),
+            ApplyError::InvalidOrigin(block_id) => write!(
+                f,
+                "origin {}@{} is outside the update's range",
+                block_id.client_id, block_id.clock
+            ),
+            ApplyError::MissingOrigin(block_id) => write!(
+                f,
+                "origin {}@{} is in neither the document nor the update",
+                block_id.client_id, block_id.clock
+            ),
+            ApplyError::CyclicOrigins(block_id) => write!(
+                f,
+                "origin {}@{} waits on blocks which wait on it in turn",
+                block_id.client_id, block_id.clock
+            ),
+            ApplyError::UndeclaredClient(client_id) => {
+                write!(f, "update has no clock range for client {}", client_id)
+            }
+            ApplyError::InvalidUpdateRange(client_id) => write!(
+                f,
+                "blocks for client {} don't match the declared clock range",
+                client_id
+            ),
+            ApplyError::InvalidLength(length) => {
+                write!(f, "length {} can't be represented on this target", length)
+            }
+            ApplyError::ClientIdConflict(client_id) => write!(
+                f,
+                "update has blocks from client {} which this document didn't make",
+                client_id
+            ),
+            ApplyError::UnexpectedClock {
+                client_id,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "block from client {} starts at clock {} but {} was expected",
+                client_id, actual, expected
+            ),
+            ApplyError::ClockOverflow(block_id) => write!(
+                f,
+                "block {}@{} runs past the largest clock",
+                block_id.client_id, block_id.clock
+            ),
+            ApplyError::WriteClockOverflow(client_id) => write!(
+                f,
+                "write from client {} carries the largest clock",
+                client_id
+            ),
+            ApplyError::LimitExceeded(limit) => {
+                write!(f, "update exceeds the document's limit on {}", limit)
+            }
+            ApplyError::FutureOrigin(block_id) => write!(
+                f,
+                "origin {}@{} doesn't come before the block which refers to it",
+                block_id.client_id, block_id.clock
+            ),
+            ApplyError::CyclicBlocks(block_id) => write!(
+                f,
+                "blocks loop back round at {}@{}",
+                block_id.client_id, block_id.clock
+            ),
+            ApplyError::BrokenLink(block_id) => write!(
+                f,
+                "blocks link to {}@{}, which isn't in the document",
+                block_id.client_id, block_id.clock
+            ),
+            ApplyError::Diverged => write!(f, "documents still differ after syncing"),
+            ApplyError::PrunedHistory {
+                client_id,
+                checkpoint,
+            } => write!(
+                f,
+                "update builds on history from client {} before its checkpoint at clock {}",
+                client_id, checkpoint
+            ),
+        }
+    }
+}
+
+impl Error for ApplyError {}
+
+/// Drops the part of `block` which is before `known`, i.e. which the document already has.
+fn skip_known<T: Item>(client_id: ClientId, block: Block<T>, known: Clock) -> Option<Block<T>> {
+    let end = block.id.saturating_advance(block.length as u64);
+
+    if end <= known {
+        None
+    } else if block.id >= known {
+        Some(block)
+    } else {
+        let offset = known.offset_from(block.id);
+        let (_, unknown) = block.split_at(client_id, offset);
+
+        Some(unknown)
+    }
+}
+
+/// Orders `blocks`, each with the clock it starts at, so every block comes after the blocks its
+/// origins refer to, which may belong to other clients in the same update.
+///
+/// This only looks at the document, so an origin which can never be satisfied, because nothing
+/// has it or because blocks wait on each other in a circle, is reported before anything is
+/// integrated.
+fn integration_order<T: Item, B: BlockShape, S: BlockStorage<T>>(
+    document: &Document<T, S>,
+    blocks: Vec<(ClientId, Vec<(Clock, B)>)>,
+) -> Result<Vec<(ClientId, B)>, ApplyError> {
+    let mut available: HashMap<ClientId, Clock> = HashMap::new();
+    let mut queues: Vec<(ClientId, VecDeque<(Clock, B)>)> = blocks
+        .into_iter()
+        .map(|(client_id, blocks)| (client_id, blocks.into()))
+        .collect();
+    let mut ordered = vec![];
+
+    let is_available = |available: &HashMap<ClientId, Clock>, origin: &Option<BlockId>| {
+        origin.is_none_or(|origin| {
+            let clock = available
+                .get(&origin.client_id)
+                .copied()
+                .unwrap_or_else(|| document.next_clock(origin.client_id));
+
+            origin.clock < clock
+        })
+    };
+
+    loop {
+        let mut progressed = false;
+
+        for (client_id, queue) in &mut queues {
+            while let Some((_, block)) = queue.front() {
+                if !block
+                    .origins()
+                    .iter()
+                    .all(|origin| is_available(&available, origin))
+                {
+                    break;
+                }
+
+                let (start, block) = queue.pop_front().unwrap();
+                available.insert(*client_id, start.saturating_advance(block.length()));
+                ordered.push((*client_id, block));
+                progressed = true;
+            }
+        }
+
+        if !progressed {
+            break;
+        }
+    }
+
+    let missing_origin = |block: &B| {
+        block
+            .origins()
+            .into_iter()
+            .flatten()
+            .find(|origin| !is_available(&available, &Some(*origin)))
+            .expect("blocks are only left queued when an origin is missing")
+    };
+
+    let Some(mut queue) = queues.iter().position(|(_, queue)| !queue.is_empty()) else {
+        return Ok(ordered);
+    };
+    let mut visited = HashSet::new();
+
+    // Follow what each stuck client is waiting on, until it's an element nothing has or the
+    // chain comes back round to a client it has already passed through
+    loop {
+        let (_, block) = queues[queue].1.front().unwrap();
+        let missing = missing_origin(block);
+
+        if !visited.insert(queue) {
+            return Err(ApplyError::CyclicOrigins(missing));
+        }
+
+        let waiting = queues.iter().position(|(client_id, blocks)| {
+            blocks.iter().any(|(start, block)| {
+                BlockRange::new(*client_id, *start..start.saturating_advance(block.length()))
+                    .contains(missing)
+            })
+        });
+
+        match waiting {
+            Some(waiting) => queue = waiting,
+            None => return Err(ApplyError::MissingOrigin(missing)),
+        }
+    }
+}
+
+/// Applies the parts of an update which follow its blocks, once the blocks are in.
+fn apply_rest<T: Item, S: BlockStorage<T>>(
+    document: &mut Document<T, S>,
+    deletes: DeleteSet,
+    map: Vec<MapEntry<T>>,
+    marks: Vec<Mark>,
+    moves: Vec<Move>,
+    deletions: Vec<DeleteRecord>,
+    registers: Vec<Register<T>>,
+) {
+    deletes.apply_unobserved(document);
+
+    for deletion in deletions {
+        document.deletions.merge(deletion);
+    }
+
+    for entry in map {
+        document.map.merge(entry);
+    }
+
+    for mark in marks {
+        document.marks.merge(mark);
+    }
+
+    for mv in moves {
+        document.store.moves.merge(mv);
+    }
+
+    // After the deletes, so writes to elements they remove are dropped
+    for write in registers {
+        document.store.merge_register(write);
+    }
+
+    // New blocks and moves can both change where moved blocks are shown
+    document.store.reorder();
+}
+
+/// Whether both of `block`'s origins are in `document`, so it can be integrated.
+fn is_placeable<T: Item, S: BlockStorage<T>>(document: &Document<T, S>, block: &Block<T>) -> bool {
+    block
+        .origins()
+        .iter()
+        .flatten()
+        .all(|origin| origin.clock < document.next_clock(origin.client_id))
+}
+
+/// Applies an encoded update to `document`. See [`Document::apply_encoded`].
+pub(crate) fn apply_encoded<T: Item + Decode, S: BlockStorage<T>>(
+    document: &mut Document<T, S>,
+    bytes: &[u8],
+) -> Result<(), ApplyEncodedError> {
+    if bytes.len() > document.limits.max_update_bytes() {
+        return Err(ApplyEncodedError::Decode(DecodeError::LimitExceeded(
+            Limit::UpdateBytes,
+        )));
+    }
+
+    // Blocks have been laid out the same way since version 8, and older payloads are rare enough
+    // to take the slow path
+    if encoding::version(bytes).map_err(ApplyEncodedError::Decode)? < 8 {
+        return Update::decode_with_limits(bytes, &document.limits)
+            .map_err(ApplyEncodedError::Decode)?
+            .apply(document)
+            .map_err(ApplyEncodedError::Apply);
+    }
+
+    let body = encoding::body(bytes).map_err(ApplyEncodedError::Decode)?;
+    let update = EncodedUpdate::<T>::decode(body).map_err(ApplyEncodedError::Decode)?;
+
+    update.check(document).map_err(ApplyEncodedError::Apply)?;
+
+    #[cfg(feature = "metrics")]
+    let timer = ApplyTimer::start(
+        &document.store.metrics,
+        update.blocks.iter().map(|(_, blocks)| blocks.len()).sum(),
+        Some(bytes.len()),
+    );
+
+    let applied = document.observed(false, |document| update.integrate_into(document));
+
+    #[cfg(feature = "metrics")]
+    if applied.is_ok() {
+        timer.finish();
+    }
+
+    applied
+}
+
+/// A block of an encoded update, read without its values.
+#[derive(Clone, Copy, Debug)]
+struct EncodedBlock {
+    origin_left: Option<BlockId>,
+    origin_right: Option<BlockId>,
+    length: u64,
+    deleted: bool,
+}
+
+impl EncodedBlock {
+    fn of<T: Item>(block: &UpdateBlock<T>) -> EncodedBlock {
+        EncodedBlock {
+            origin_left: block.origin_left,
+            origin_right: block.origin_right,
+            length: block.length(),
+            deleted: matches!(block.value, Content::Deleted(_)),
+        }
+    }
+
+    /// Reads the values which follow the block in an encoded update.
+    fn decode_values<T: Item + Decode, D: Decoder>(
+        self,
+        decoder: &mut D,
+    ) -> Result<UpdateBlock<T>, bincode::error::DecodeError> {
+        let value = if self.deleted {
+            Content::Deleted(self.length)
+        } else {
+            // The length comes off the wire, so nothing is reserved up front
+            let mut values = vec![];
+
+            for _ in 0..self.length {
+                values.push(T::decode(decoder)?);
+            }
+
+            Content::Value(values)
+        };
+
+        Ok(UpdateBlock {
+            origin_left: self.origin_left,
+            origin_right: self.origin_right,
+            value,
+            priority: 0,
+        })
+    }
+
+    /// Reads past the values which follow the block in an encoded update.
+    fn skip_values<T: Item + Decode, D: Decoder>(
+        &self,
+        decoder: &mut D,
+    ) -> Result<(), bincode::error::DecodeError> {
+        if !self.deleted {
+            for _ in 0..self.length {
+                T::decode(decoder)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops the part of the block starting at `start` which is before `known`, like
+    /// [`skip_known`].
+    fn skip_known(self, client_id: ClientId, start: Clock, known: Clock) -> Option<(Clock, Self)> {
+        let end = start.saturating_advance(self.length);
+
+        if end <= known {
+            None
+        } else if start >= known {
+            Some((start, self))
+        } else {
+            Some((
+                known,
+                EncodedBlock {
+                    origin_left: Some(BlockId::new(client_id, known.rewind(1))),
+                    length: end.offset_from(known),
+                    ..self
+                },
+            ))
+        }
+    }
+}
+
+impl BlockShape for EncodedBlock {
+    fn origins(&self) -> [Option<BlockId>; 2] {
+        [self.origin_left, self.origin_right]
+    }
+
+    fn length(&self) -> u64 {
+        self.length
+    }
+
+    fn deleted_length(&self) -> Option<u64> {
+        self.deleted.then_some(self.length)
+    }
+}
+
+/// The blocks of an encoded update, read without their values, with the clock each client's
+/// blocks were written as starting at.
+struct EncodedBlocks<T>(Vec<(ClientId, Clock, Vec<EncodedBlock>)>, PhantomData<T>);
+
+impl<T: Item + Decode> Decode for EncodedBlocks<T> {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, bincode::error::DecodeError> {
+        let mut table = ClientTable::decode(decoder)?;
+        // Lengths come off the wire, so nothing is reserved up front
+        let mut clients = vec![];
+
+        for _ in 0..u64::decode(decoder)? {
+            let (owner, start, count) = table.decode_run(decoder)?;
+            let mut clock = start;
+            let mut blocks = vec![];
+
+            for _ in 0..count {
+                let block = table.decode_block(decoder, owner, clock)?;
+                block.skip_values::<T, D>(decoder)?;
+
+                clock = clock.saturating_advance(block.length);
+                blocks.push(block);
+            }
+
+            clients.push((table.client(owner), start, blocks));
+        }
+
+        Ok(EncodedBlocks(clients, PhantomData))
+    }
+}
+
+/// Set in a block's flags if it has a left origin.
+const HAS_ORIGIN_LEFT: u8 = 1;
+/// Set in a block's flags if it has a right origin.
+const HAS_ORIGIN_RIGHT: u8 = 1 << 1;
+/// Set in a block's flags if its elements are deleted, so only their number is written.
+const IS_DELETED: u8 = 1 << 2;
+/// Set in a block's flags, along with [`HAS_ORIGIN_LEFT`], if its left origin is the element just
+/// before it from the same client, which is then left out.
+const FOLLOWS_PREVIOUS: u8 = 1 << 3;
+
+/// The clients an update's blocks refer to, written once before the blocks so each origin refers
+/// to its client by index.
+///
+/// Each origin's clock is written as the difference from the last clock written for its client,
+/// whether the start of one of the client's blocks or another origin, so origins near what came
+/// before them take a byte or two.
+struct ClientTable {
+    clients: Vec<ClientId>,
+    indices: HashMap<ClientId, usize>,
+    last_clocks: Vec<Clock>,
+}
+
+impl ClientTable {
+    fn new(clients: Vec<ClientId>) -> ClientTable {
+        let indices = clients
+            .iter()
+            .enumerate()
+            .map(|(index, client_id)| (*client_id, index))
+            .collect();
+
+        ClientTable {
+            last_clocks: vec![Clock::ZERO; clients.len()],
+            clients,
+            indices,
+        }
+    }
+
+    /// The table for blocks of the `owners`, in order, and then any other clients `origins`
+    /// refer to.
+    fn for_blocks(
+        owners: impl IntoIterator<Item = ClientId>,
+        origins: impl IntoIterator<Item = BlockId>,
+    ) -> ClientTable {
+        let mut seen = HashSet::new();
+        let clients = owners
+            .into_iter()
+            .chain(origins.into_iter().map(|origin| origin.client_id))
+            .filter(|client_id| seen.insert(*client_id))
+            .collect();
+
+        ClientTable::new(clients)
+    }
+
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.clients.encode(encoder)
+    }
+
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<ClientTable, bincode::error::DecodeError> {
+        Ok(ClientTable::new(Decode::decode(decoder)?))
+    }
+
+    fn client(&self, index: usize) -> ClientId {
+        self.clients[index]
+    }
+
+    /// Writes the start of `count` blocks from `client_id`, the first of which starts at `clock`,
+    /// returning the client's index.
+    fn encode_run<E: Encoder>(
+        &self,
+        encoder: &mut E,
+        client_id: ClientId,
+        clock: Clock,
+        count: usize,
+    ) -> Result<usize, EncodeError> {
+        let index = self.indices[&client_id];
+
+        (index as u64).encode(encoder)?;
+        clock.encode(encoder)?;
+        (count as u64).encode(encoder)?;
+
+        Ok(index)
+    }
+
+    /// Reads the start of a client's blocks, returning the client's index, the clock the first
+    /// block starts at and the number of blocks.
+    fn decode_run<D: Decoder>(
+        &self,
+        decoder: &mut D,
+    ) -> Result<(usize, Clock, u64), bincode::error::DecodeError> {
+        Ok((
+            self.decode_index(decoder)?,
+            Clock::decode(decoder)?,
+            u64::decode(decoder)?,
+        ))
+    }
+
+    fn decode_index<D: Decoder>(
+        &self,
+        decoder: &mut D,
+    ) -> Result<usize, bincode::error::DecodeError> {
+        usize::try_from(u64::decode(decoder)?)
+            .ok()
+            .filter(|index| *index < self.clients.len())
+            .ok_or_else(|| {
+                bincode::error::DecodeError::OtherString(
+                    "client index is outside the client table".to_owned(),
+                )
+            })
+    }
+
+    /// Writes everything about a block from the client at index `owner` but its values, where the
+    /// block starts at `clock`.
+    fn encode_block<E: Encoder>(
+        &mut self,
+        encoder: &mut E,
+        owner: usize,
+        clock: Clock,
+        block: &EncodedBlock,
+    ) -> Result<(), EncodeError> {
+        let previous = clock
+            .checked_rewind(1)
+            .map(|clock| BlockId::new(self.client(owner), clock));
+        let follows_previous = previous.is_some() && block.origin_left == previous;
+
+        let mut flags = 0;
+
+        if block.origin_left.is_some() {
+            flags |= HAS_ORIGIN_LEFT;
+        }
+
+        if block.origin_right.is_some() {
+            flags |= HAS_ORIGIN_RIGHT;
+        }
+
+        if block.deleted {
+            flags |= IS_DELETED;
+        }
+
+        if follows_previous {
+            flags |= FOLLOWS_PREVIOUS;
+        }
+
+        flags.encode(encoder)?;
+        self.last_clocks[owner] = clock;
+
+        if let Some(origin) = block.origin_left.filter(|_| !follows_previous) {
+            self.encode_origin(encoder, origin)?;
+        }
+
+        if let Some(origin) = block.origin_right {
+            self.encode_origin(encoder, origin)?;
+        }
+
+        block.length.encode(encoder)
+    }
+
+    /// Reads everything about a block from the client at index `owner` but its values, where the
+    /// block starts at `clock`.
+    fn decode_block<D: Decoder>(
+        &mut self,
+        decoder: &mut D,
+        owner: usize,
+        clock: Clock,
+    ) -> Result<EncodedBlock, bincode::error::DecodeError> {
+        let flags = u8::decode(decoder)?;
+        let known = HAS_ORIGIN_LEFT | HAS_ORIGIN_RIGHT | IS_DELETED | FOLLOWS_PREVIOUS;
+
+        if flags & !known != 0 || flags & FOLLOWS_PREVIOUS != 0 && flags & HAS_ORIGIN_LEFT == 0 {
+            return Err(bincode::error::DecodeError::OtherString(
+                "block has invalid flags".to_owned(),
+            ));
+        }
+
+        self.last_clocks[owner] = clock;
+
+        let origin_left = if flags & FOLLOWS_PREVIOUS != 0 {
+            let clock = clock.checked_rewind(1).ok_or_else(|| {
+                bincode::error::DecodeError::OtherString(
+                    "block at clock 0 follows a previous element".to_owned(),
+                )
+            })?;
+
+            Some(BlockId::new(self.client(owner), clock))
+        } else if flags & HAS_ORIGIN_LEFT != 0 {
+            Some(self.decode_origin(decoder)?)
+        } else {
+            None
+        };
+
+        let origin_right = if flags & HAS_ORIGIN_RIGHT != 0 {
+            Some(self.decode_origin(decoder)?)
+        } else {
+            None
+        };
+
+        Ok(EncodedBlock {
+            origin_left,
+            origin_right,
+            length: u64::decode(decoder)?,
+            deleted: flags & IS_DELETED != 0,
+        })
+    }
+
+    fn encode_origin<E: Encoder>(
+        &mut self,
+        encoder: &mut E,
+        origin: BlockId,
+    ) -> Result<(), EncodeError> {
+        let index = self.indices[&origin.client_id];
+
+        (index as u64).encode(encoder)?;
+        // Wraps, so any pair of clocks has a difference, which zigzag encodes
+        (origin
+            .clock
+            .get()
+            .wrapping_sub(self.last_clocks[index].get()) as i64)
+            .encode(encoder)?;
+        self.last_clocks[index] = origin.clock;
+
+        Ok(())
+    }
+
+    fn decode_origin<D: Decoder>(
+        &mut self,
+        decoder: &mut D,
+    ) -> Result<BlockId, bincode::error::DecodeError> {
+        let index = self.decode_index(decoder)?;
+        let clock = self.last_clocks[index]
+            .get()
+            .wrapping_add(i64::decode(decoder)? as u64);
+        let clock = Clock::new(clock);
+        self.last_clocks[index] = clock;
+
+        Ok(BlockId::new(self.client(index), clock))
+    }
+}
+
+/// Checks that `client_id`'s blocks were written as starting where `dependency` says they do, as
+/// the clocks of their origins are written relative to it.
+fn check_run_start(
+    dependency: &[(ClientId, ClockRange)],
+    client_id: ClientId,
+    start: Clock,
+) -> Result<(), bincode::error::DecodeError> {
+    if version_range(dependency, client_id).map_or(Clock::ZERO, |range| range.start) != start {
+        return Err(bincode::error::DecodeError::OtherString(
+            "blocks don't start where the update's dependencies say".to_owned(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// An update read from its encoding without its blocks' values, which are decoded again as the
+/// blocks are integrated so they are never all held at once.
+struct EncodedUpdate<'a, T: Item> {
+    dependency: Vec<(ClientId, ClockRange)>,
+    blocks: Vec<(ClientId, Vec<EncodedBlock>)>,
+    /// The encoded blocks, values and all.
+    encoded_blocks: &'a [u8],
+    deletes: DeleteSet,
+    map: Vec<MapEntry<T>>,
+    marks: Vec<Mark>,
+    moves: Vec<Move>,
+    deletions: Vec<DeleteRecord>,
+    priorities: HashMap<BlockId, u8>,
+    roots: Vec<(String, DeleteSet)>,
+    registers: Vec<Register<T>>,
+    checkpoint: Vec<(ClientId, Clock)>,
+    censored: DeleteSet,
+}
+
+impl<'a, T: Item + Decode> EncodedUpdate<'a, T> {
+    /// Reads the body of an update encoded with the current layout.
+    fn decode(body: &'a [u8]) -> Result<Self, DecodeError> {
+        let (dependency, read): (Vec<(ClientId, ClockRange)>, _) = encoding::decode_prefix(body)?;
+        let rest = &body[read..];
+
+        let (EncodedBlocks(blocks, _), read) = encoding::decode_prefix::<EncodedBlocks<T>>(rest)?;
+        let (encoded_blocks, rest) = rest.split_at(read);
+
+        let blocks = blocks
+            .into_iter()
+            .map(|(client_id, start, blocks)| {
+                check_run_start(&dependency, client_id, start)?;
+
+                Ok((client_id, blocks))
+            })
+            .collect::<Result<_, _>>()
+            .map_err(DecodeError::Malformed)?;
+
+        let ((deletes, extensions), read): ((DeleteSet, Extensions), _) =
+            encoding::decode_prefix(rest)?;
+
+        if read != rest.len() {
+            return Err(DecodeError::TrailingBytes(rest.len() - read));
+        }
+
+        let mut update = EncodedUpdate {
+            dependency,
+            blocks,
+            encoded_blocks,
+            deletes,
+            map: vec![],
+            marks: vec![],
+            moves: vec![],
+            deletions: vec![],
+            priorities: HashMap::new(),
+            roots: vec![],
+            registers: vec![],
+            checkpoint: vec![],
+            censored: DeleteSet::empty(),
+        };
+
+        update
+            .decode_extensions(extensions)
+            .map_err(DecodeError::Malformed)?;
+
+        Ok(update)
+    }
+
+    /// Reads the extensions which follow the deletes, like [`Update`]'s decoding does.
+    fn decode_extensions(
+        &mut self,
+        extensions: Extensions,
+    ) -> Result<(), bincode::error::DecodeError> {
+        for (id, section) in extensions {
+            let section = section.as_slice();
+
+            match id {
+                MAP_EXTENSION => self.map = encoding::decode_section(section)?,
+                MARKS_EXTENSION => self.marks = encoding::decode_section(section)?,
+                PRIORITIES_EXTENSION => {
+                    let priorities: Vec<(BlockId, u8)> = encoding::decode_section(section)?;
+                    self.priorities = priorities.into_iter().collect();
+                }
+                MOVES_EXTENSION => self.moves = encoding::decode_section(section)?,
+                DELETIONS_EXTENSION => self.deletions = encoding::decode_section(section)?,
+                ROOTS_EXTENSION => self.roots = encoding::decode_section(section)?,
+                REGISTERS_EXTENSION => self.registers = encoding::decode_section(section)?,
+                CHECKPOINT_EXTENSION => self.checkpoint = encoding::decode_section(section)?,
+                CENSORED_EXTENSION => self.censored = decode_censored(section)?,
+                // Written by a newer version of this crate
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn outline(&self) -> Outline<'_, EncodedBlock, T> {
+        Outline {
+            dependency: &self.dependency,
+            blocks: &self.blocks,
+            map: &self.map,
+            marks: &self.marks,
+            moves: &self.moves,
+            registers: &self.registers,
+            roots: &self.roots,
+            checkpoint: &self.checkpoint,
+        }
+    }
+
+    /// The clock `client_id`'s blocks start at.
+    fn start(&self, client_id: ClientId) -> Clock {
+        version_range(&self.dependency, client_id).map_or(Clock::ZERO, |range| range.start)
+    }
+
+    /// Makes every check [`Update::apply`] makes, in the same order, so nothing can fail once
+    /// [`EncodedUpdate::integrate_into`] starts changing the document.
+    fn check<S: BlockStorage<T>>(&self, document: &mut Document<T, S>) -> Result<(), ApplyError> {
+        let outline = self.outline();
+
+        outline.check_limits(document)?;
+
+        if let Err(error) = outline.check_dependencies(document) {
+            if let ApplyError::ClientIdConflict(_) = error {
+                document.client_id_conflict();
+            }
+
+            return Err(error);
+        }
+
+        outline.validate()?;
+        outline.check_anchors(document)?;
+        outline.check_write_clocks()?;
+
+        let mut unknown_blocks = vec![];
+
+        for (client_id, blocks) in &self.blocks {
+            let known = document.next_clock(*client_id);
+            let mut clock = self.start(*client_id);
+            let mut unknown = vec![];
+
+            for block in blocks {
+                if let Some(length) = block.deleted_length() {
+                    usize::try_from(length).map_err(|_| ApplyError::InvalidLength(length))?;
+                }
+
+                unknown.extend(block.skip_known(*client_id, clock, known));
+                clock = clock.saturating_advance(block.length);
+            }
+
+            unknown_blocks.push((*client_id, unknown));
+        }
+
+        integration_order(document, unknown_blocks)?;
+
+        Ok(())
+    }
+
+    /// Decodes the blocks again and integrates each as soon as its origins are in, in the order
+    /// [`integration_order`] would, then applies everything else.
+    fn integrate_into<S: BlockStorage<T>>(
+        self,
+        document: &mut Document<T, S>,
+    ) -> Result<(), ApplyEncodedError> {
+        let malformed = |error| ApplyEncodedError::Decode(DecodeError::Malformed(error));
+        let mut decoder = encoding::decoder(self.encoded_blocks);
+
+        document.censor_ranges(&self.censored);
+        let mut table = ClientTable::decode(&mut decoder).map_err(malformed)?;
+        let mut waiting: Vec<(ClientId, VecDeque<Block<T>>)> = vec![];
+
+        // The number of clients and of each client's blocks were read the first time round
+        u64::decode(&mut decoder).map_err(malformed)?;
+
+        for (client_id, blocks) in &self.blocks {
+            let (owner, _, _) = table.decode_run(&mut decoder).map_err(malformed)?;
+
+            let known = document.next_clock(*client_id);
+            let mut clock = self.start(*client_id);
+            let mut queue = VecDeque::new();
+
+            for _ in blocks {
+                let mut block = table
+                    .decode_block(&mut decoder, owner, clock)
+                    .and_then(|block| block.decode_values::<T, _>(&mut decoder))
+                    .map_err(malformed)?;
+                let id = clock;
+                clock = clock.saturating_advance(block.length());
+
+                block.priority = self
+                    .priorities
+                    .get(&BlockId::new(*client_id, id))
+                    .copied()
+                    .unwrap_or(0);
+
+                let block = block.hydrate(id).map_err(ApplyEncodedError::Apply)?;
+                let Some(block) = skip_known(*client_id, block, known) else {
+                    continue;
+                };
+
+                // A client's blocks go in in order, so once one has to wait the rest do too
+                if queue.is_empty() && is_placeable(document, &block) {
+                    integrate_block(document, &self.roots, *client_id, block)
+                        .map_err(ApplyEncodedError::Apply)?;
+                } else {
+                    queue.push_back(block);
+                }
+            }
+
+            if !queue.is_empty() {
+                waiting.push((*client_id, queue));
+            }
+        }
+
+        loop {
+            let mut progressed = false;
+
+            for (client_id, queue) in &mut waiting {
+                while queue
+                    .front()
+                    .is_some_and(|block| is_placeable(document, block))
+                {
+                    integrate_block(
+                        document,
+                        &self.roots,
+                        *client_id,
+                        queue.pop_front().unwrap(),
+                    )
+                    .map_err(ApplyEncodedError::Apply)?;
+                    progressed = true;
+                }
+            }
+
+            if !progressed {
+                break;
+            }
+        }
+
+        debug_assert!(
+            waiting.iter().all(|(_, queue)| queue.is_empty()),
+            "blocks were checked to have their origins before integrating"
+        );
+
+        document.debug_assert_consistent();
+        apply_rest(
+            document,
+            self.deletes,
+            self.map,
+            self.marks,
+            self.moves,
+            self.deletions,
+            self.registers,
+        );
+
+        Ok(())
+    }
+}
+
+/// Integrates one of an update's blocks into the root `roots` puts it in, advancing the
+/// document's clock for its client.
+fn integrate_block<T: Item, S: BlockStorage<T>>(
+    document: &mut Document<T, S>,
+    roots: &[(String, DeleteSet)],
+    client_id: ClientId,
+    block: Block<T>,
+) -> Result<(), ApplyError> {
+    let expected = document.next_clock(client_id);
+    let blocks = censor_block(&document.censored, client_id, block);
+    let store = match root_of(roots, BlockId::new(client_id, blocks[0].id)) {
+        Some(name) => document.root_store_mut(name),
+        None => &mut document.store,
+    };
+
+    store.integrate(client_id, expected, blocks)?;
+
+    let clock = document.next_clock(client_id);
+    document.clients.insert(client_id, clock);
+
+    // Our own blocks coming back move the clock our other roots give new blocks
+    if client_id == document.client_id {
+        document.advance_local_clock();
+    }
+
+    Ok(())
+}
+
+/// Splits `block` where the elements of it in `censored` start and end, with those elements
+/// tombstoned, so their content never reaches the document whatever the sender had.
+fn censor_block<T: Item>(
+    censored: &DeleteSet,
+    client_id: ClientId,
+    block: Block<T>,
+) -> Vec<Block<T>> {
+    let mut pieces = vec![];
+    let mut rest = block;
+
+    for clocks in censored.within(client_id, rest.clocks()) {
+        if clocks.start > rest.id {
+            let offset = clocks.start.offset_from(rest.id);
+            let (kept, removed) = rest.split_at(client_id, offset);
+            pieces.push(kept);
+            rest = removed;
+        }
+
+        if clocks.end < rest.clocks().end {
+            let offset = clocks.end.offset_from(rest.id);
+            let (mut removed, kept) = rest.split_at(client_id, offset);
+            removed.delete();
+            pieces.push(removed);
+            rest = kept;
+        } else {
+            rest.delete();
+        }
+    }
+
+    pieces.push(rest);
+
+    pieces
+}
+
+/// The named root `roots` puts the element `id` in, or `None` for the document's own sequence.
+fn root_of(roots: &[(String, DeleteSet)], id: BlockId) -> Option<&str> {
+    roots
+        .iter()
+        .find(|(_, clocks)| clocks.contains(id))
+        .map(|(name, _)| name.as_str())
+}
+
+/// What the checks made before applying an update look at in one of its blocks.
+trait BlockShape {
+    /// The block's left and right origins.
+    fn origins(&self) -> [Option<BlockId>; 2];
+    fn length(&self) -> u64;
+    /// The declared length of a block of deleted elements, or `None` if the block has values.
+    fn deleted_length(&self) -> Option<u64>;
+}
+
+impl<T: Item> BlockShape for UpdateBlock<T> {
+    fn origins(&self) -> [Option<BlockId>; 2] {
+        [self.origin_left, self.origin_right]
+    }
+
+    fn length(&self) -> u64 {
+        UpdateBlock::length(self)
+    }
+
+    fn deleted_length(&self) -> Option<u64> {
+        match self.value {
+            Content::Value(_) => None,
+            Content::Deleted(length) => Some(length),
+        }
+    }
+}
+
+impl<T: Item> BlockShape for Block<T> {
+    fn origins(&self) -> [Option<BlockId>; 2] {
+        [self.origin_left, self.origin_right]
+    }
+
+    fn length(&self) -> u64 {
+        self.length as u64
+    }
+
+    fn deleted_length(&self) -> Option<u64> {
+        self.deleted.then_some(self.length as u64)
+    }
+}
+
+/// The parts of an update which are checked before it is applied, borrowed from an [`Update`] or
+/// from an [`EncodedUpdate`] which hasn't decoded its values.
+struct Outline<'a, B, T: Item> {
+    dependency: &'a [(ClientId, ClockRange)],
+    blocks: &'a [(ClientId, Vec<B>)],
+    map: &'a [MapEntry<T>],
+    marks: &'a [Mark],
+    moves: &'a [Move],
+    registers: &'a [Register<T>],
+    roots: &'a [(String, DeleteSet)],
+    checkpoint: &'a [(ClientId, Clock)],
+}
+
+impl<B: BlockShape, T: Item> Outline<'_, B, T> {
+    /// Checks that both ends of every mark, the ends and marker of every move, and the element
+    /// of every replaced value are in `document` or the update itself. They're all read against
+    /// the document's own sequence, so elements of named roots don't count.
+    fn check_anchors<S: BlockStorage<T>>(
+        &self,
+        document: &Document<T, S>,
+    ) -> Result<(), ApplyError> {
+        let mark_anchors = self.marks.iter().flat_map(|mark| [mark.start, mark.end]);
+        let move_anchors = self
+            .moves
+            .iter()
+            .flat_map(|mv| [mv.start, mv.end, mv.marker]);
+        let register_anchors = self.registers.iter().map(|write| write.element);
+
+        for anchor in mark_anchors.chain(move_anchors).chain(register_anchors) {
+            let in_update = version_range(self.dependency, anchor.client_id)
+                .is_some_and(|range| anchor.clock < range.end)
+                && root_of(self.roots, anchor).is_none();
+
+            if !in_update && document.store.get_block(anchor).is_none() {
+                return Err(ApplyError::MissingOrigin(anchor));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that no write to the map, registers, marks or moves carries [`Clock::MAX`]. The
+    /// document's own Lamport clock catches up with every write it merges, so one at the largest
+    /// clock would leave it unable to tick for any local write after.
+    fn check_write_clocks(&self) -> Result<(), ApplyError> {
+        let writes = (self.map.iter().map(|entry| (entry.clock, entry.client_id)))
+            .chain(self.marks.iter().map(|mark| (mark.clock, mark.client_id)))
+            .chain(self.moves.iter().map(|mv| (mv.clock, mv.client_id)))
+            .chain(
+                self.registers
+                    .iter()
+                    .map(|write| (write.clock, write.client_id)),
+            );
+
+        for (clock, client_id) in writes {
+            if clock == Clock::MAX {
+                return Err(ApplyError::WriteClockOverflow(client_id));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that applying the update wouldn't take `document` past its limits. Only the
+    /// declared lengths are looked at, so nothing proportional to them is allocated first.
+    fn check_limits<S: BlockStorage<T>>(
+        &self,
+        document: &Document<T, S>,
+    ) -> Result<(), ApplyError> {
+        let limits = &document.limits;
+
+        let deleted_too_long = self
+            .blocks
+            .iter()
+            .flat_map(|(_, blocks)| blocks)
+            .any(|block| {
+                block
+                    .deleted_length()
+                    .is_some_and(|length| length > limits.max_deleted_length())
+            });
+        if deleted_too_long {
+            return Err(ApplyError::LimitExceeded(Limit::DeletedLength));
+        }
+
+        for (client_id, blocks) in self.blocks {
+            let existing = document.store.data.len(*client_id);
+
+            if existing.saturating_add(blocks.len()) > limits.max_blocks_per_client() {
+                return Err(ApplyError::LimitExceeded(Limit::BlocksPerClient));
+            }
+        }
+
+        let existing: u64 = document
+            .state_vector()
+            .as_ref()
+            .values()
+            .fold(0, |total, clock| total.saturating_add(clock.get()));
+        let added: u64 = self
+            .dependency
+            .iter()
+            .map(|(client_id, range)| {
+                let have_clock = document.next_clock(*client_id);
+
+                ClockRange::new(range.start.max(have_clock), range.end).len()
+            })
+            .fold(0, u64::saturating_add);
+
+        if existing.saturating_add(added) > limits.max_elements() {
+            return Err(ApplyError::LimitExceeded(Limit::Elements));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that `document` has everything this update builds on, without modifying it.
+    fn check_dependencies<S: BlockStorage<T>>(
+        &self,
+        document: &Document<T, S>,
+    ) -> Result<(), ApplyError> {
+        self.check_checkpoint(document)?;
+        self.check_client_id(document)?;
+
+        let Some(missing) = self.missing_for(document) else {
+            return Ok(());
+        };
+
+        let (client_id, required_clock) = missing
+            .iter()
+            .min_by_key(|(client_id, _)| **client_id)
+            .map(|(client_id, clock)| (*client_id, *clock))
+            .expect("missing clocks are never empty");
+
+        Err(ApplyError::MissingDependency {
+            client_id,
+            required_clock,
+            have_clock: *document.clients.get(&client_id).unwrap_or(&Clock::ZERO),
+            missing,
+        })
+    }
+
+    /// Checks that neither side's checkpoint cuts off history the update builds on: `document`
+    /// must have reached the sender's checkpoint, as what came before it can no longer be sent,
+    /// and nothing the update sends or refers to may be from before `document`'s own checkpoint
+    /// unless the document kept it.
+    fn check_checkpoint<S: BlockStorage<T>>(
+        &self,
+        document: &Document<T, S>,
+    ) -> Result<(), ApplyError> {
+        let pruned = |client_id: ClientId, checkpoint: Clock| {
+            Err(ApplyError::PrunedHistory {
+                client_id,
+                checkpoint,
+            })
+        };
+
+        for (client_id, clock) in self.checkpoint {
+            if document.next_clock(*client_id) < *clock {
+                return pruned(*client_id, *clock);
+            }
+        }
+
+        let checkpoint = &document.checkpoint;
+
+        for (client_id, range) in self.dependency {
+            if !range.is_empty() && range.start < checkpoint.clock(*client_id) {
+                return pruned(*client_id, checkpoint.clock(*client_id));
+            }
+        }
+
+        let origins = self
+            .blocks
+            .iter()
+            .flat_map(|(_, blocks)| blocks.iter().flat_map(BlockShape::origins))
+            .flatten();
+        let mark_anchors = self.marks.iter().flat_map(|mark| [mark.start, mark.end]);
+        let move_anchors = self
+            .moves
+            .iter()
+            .flat_map(|mv| [mv.start, mv.end, mv.marker]);
+        let register_anchors = self.registers.iter().map(|write| write.element);
+
+        for id in origins
+            .chain(mark_anchors)
+            .chain(move_anchors)
+            .chain(register_anchors)
+        {
+            if id.clock < checkpoint.clock(id.client_id) && document.find_block(id).is_none() {
+                return pruned(id.client_id, checkpoint.clock(id.client_id));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The clock `document` has to reach for each client it is behind on, or `None` if it has
+    /// everything this update builds on.
+    fn missing_for<S: BlockStorage<T>>(&self, document: &Document<T, S>) -> Option<ClockVector> {
+        let missing: ClockVector = self
+            .required_state()
+            .into_iter()
+            .filter(|(client_id, clock)| {
+                *clock > *document.clients.get(client_id).unwrap_or(&Clock::ZERO)
+            })
+            .collect();
+
+        (!missing.is_empty()).then_some(missing)
+    }
+
+    /// The clocks a document needs before this update can apply: the start of every declared
+    /// range, and just past every origin from a client the update doesn't declare.
+    fn required_state(&self) -> ClockVector {
+        let mut required = ClockVector::new();
+        let mut require = |client_id: ClientId, clock: Clock| {
+            if clock > Clock::ZERO {
+                required.entry(client_id).or_default().observe(clock);
+            }
+        };
+
+        for (client_id, range) in self.dependency {
+            require(*client_id, range.start);
+        }
+
+        let origins = self
+            .blocks
+            .iter()
+            .flat_map(|(_, blocks)| blocks.iter().flat_map(BlockShape::origins))
+            .flatten();
+
+        for origin in origins {
+            if version_range(self.dependency, origin.client_id).is_none() {
+                require(origin.client_id, origin.clock.saturating_advance(1));
+            }
+        }
+
+        required
+    }
+
+    /// Checks that any blocks under `document`'s own client id are ones it generated, i.e. that
+    /// they're within its clock and have the same origins as its elements.
+    ///
+    /// Deleted elements are skipped, as merging tombstones loses their origins.
+    fn check_client_id<S: BlockStorage<T>>(
+        &self,
+        document: &Document<T, S>,
+    ) -> Result<(), ApplyError> {
+        let client_id = document.client_id;
+        let conflict = Err(ApplyError::ClientIdConflict(client_id));
+
+        let Some(range) = version_range(self.dependency, client_id) else {
+            return Ok(());
+        };
+
+        if range.end > document.next_clock(client_id) {
+            return conflict;
+        }
+
+        let blocks = self
+            .blocks
+            .iter()
+            .filter(|(id, _)| *id == client_id)
+            .flat_map(|(_, blocks)| blocks);
+        let mut clock = range.start;
+
+        for block in blocks {
+            let start = clock;
+            clock = clock.saturating_advance(block.length());
+
+            if block.deleted_length().is_some() {
+                continue;
+            }
+
+            let [block_origin_left, block_origin_right] = block.origins();
+
+            for element in ClockRange::new(start, clock).iter() {
+                let id = BlockId::new(client_id, element);
+                let Some((ours, offset)) = document.find_block(id) else {
+                    return conflict;
+                };
+
+                if ours.deleted {
+                    continue;
+                }
+
+                // Elements after the first in a block were inserted after the one before them
+                let previous = element
+                    .checked_rewind(1)
+                    .map(|clock| BlockId::new(client_id, clock));
+                let origin_left = if element == start {
+                    block_origin_left
+                } else {
+                    previous
+                };
+                let our_origin_left = if offset == 0 {
+                    ours.origin_left
+                } else {
+                    previous
+                };
+
+                if origin_left != our_origin_left || block_origin_right != ours.origin_right {
+                    return conflict;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        for (client, blocks) in self.blocks {
+            let mut clock =
+                version_range(self.dependency, *client).map_or(Clock::ZERO, |range| range.start);
+
+            for block in blocks {
+                // An element can only be inserted next to elements which already exist, and a
+                // client's earlier elements all have smaller clocks
+                for origin in block.origins().into_iter().flatten() {
+                    if origin.client_id == *client && origin.clock >= clock {
+                        return Err(ValidationError::FutureOrigin(origin));
+                    }
+                }
+
+                clock = clock.saturating_advance(block.length());
+
+                for origin in block.origins().into_iter().flatten() {
+                    if !self.does_clock_exist(origin) {
+                        return Err(ValidationError::UpdateOutsideRange(origin));
+                    }
+                }
+            }
+
+            if let Some(range) = version_range(self.dependency, *client) {
+                let end = blocks.iter().try_fold(range.start, |clock, block| {
+                    clock.checked_advance(block.length())
+                });
+
+                if end != Some(range.end) {
+                    return Err(ValidationError::InvalidUpdateRange(*client));
+                }
+            } else {
+                return Err(ValidationError::ClientDoesNotExist(*client));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether `origin` can be an element the update or the document holds. Ranges are
+    /// exclusive, so a clock at the end of its client's range is past the update's elements.
+    fn does_clock_exist(&self, origin: BlockId) -> bool {
+        // Origins from undeclared clients must already be in the document, which is checked
+        // against the update's required state instead
+        version_range(self.dependency, origin.client_id)
+            .is_none_or(|range| origin.clock < range.end)
+    }
+}
+
+/// The clock range `dependency` declares for `client_id`.
+fn version_range(dependency: &[(ClientId, ClockRange)], client_id: ClientId) -> Option<ClockRange> {
+    dependency
+        .iter()
+        .find(|(cid, ..)| *cid == client_id)
+        .map(|(_, range)| *range)
+}
+
+/// A view of the update [`Update::from_document`] would build for a document, which encodes to the
+/// same bytes while reading values straight out of the store rather than cloning them.
+pub(crate) struct UpdateRef<'a, T: Item, S: BlockStorage<T> = VecStorage<T>> {
+    document: &'a Document<T, S>,
+}
+
+impl<'a, T: Item, S: BlockStorage<T>> UpdateRef<'a, T, S> {
+    pub(crate) fn new(document: &'a Document<T, S>) -> UpdateRef<'a, T, S> {
+        UpdateRef { document }
+    }
+}
+
+/// Splits a client's blocks into the runs [`Update::compact`] would merge into single blocks, as
+/// ranges of indices into `blocks`.
+fn compacted_runs<T: Item>(client_id: ClientId, blocks: &[&Block<T>]) -> Vec<Range<usize>> {
+    let mut runs: Vec<Range<usize>> = vec![];
+
+    for (index, block) in blocks.iter().enumerate() {
+        let continues_run = runs.last().is_some_and(|run| {
+            let first = &blocks[run.start];
+            let last = &blocks[run.end - 1];
+            let last_id = BlockId::new(client_id, last.id.advance(last.length as u64 - 1));
+
+            // The same conditions as `UpdateBlock::try_merge`
+            block.origin_right == first.origin_right
+                && block.origin_left == Some(last_id)
+                && block.id == last_id.clock.advance(1)
+                && block.deleted == first.deleted
+                && block.priority == first.priority
+        });
+
+        match runs.last_mut() {
+            Some(run) if continues_run => run.end = index + 1,
+            _ => runs.push(index..index + 1),
+        }
+    }
+
+    runs
+}
+
+impl<T: Item + Encode, S: BlockStorage<T>> Encode for UpdateRef<'_, T, S> {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        let store = &self.document.store;
+
+        // Laid out like the encoding of `Update`, in client order
+        let mut clients: Vec<(ClientId, Vec<&Block<T>>)> = store
+            .data
+            .clients()
+            .into_iter()
+            .map(|client_id| (client_id, store.data.iter(client_id).collect()))
+            .collect();
+        clients.sort_by_key(|(client_id, _)| *client_id);
+
+        let dependency: Vec<(ClientId, ClockRange)> = clients
+            .iter()
+            .map(|(client_id, _)| {
+                let end = store.next_clock(*client_id);
+
+                (*client_id, ClockRange::new(Clock::ZERO, end))
+            })
+            .collect();
+        dependency.encode(encoder)?;
+
+        clients.retain(|(client_id, _)| store.next_clock(*client_id) > Clock::ZERO);
+
+        let runs: Vec<(ClientId, Vec<&[&Block<T>]>)> = clients
+            .iter()
+            .map(|(client_id, blocks)| {
+                let runs = compacted_runs(*client_id, blocks);
+
+                (
+                    *client_id,
+                    runs.into_iter().map(|run| &blocks[run]).collect(),
+                )
+            })
+            .collect();
+
+        // The same table `Update`'s encoding builds from the compacted blocks
+        let mut table = ClientTable::for_blocks(
+            runs.iter().map(|(client_id, _)| *client_id),
+            runs.iter()
+                .flat_map(|(_, runs)| runs.iter().flat_map(|run| run[0].origins()))
+                .flatten(),
+        );
+        table.encode(encoder)?;
+        (runs.len() as u64).encode(encoder)?;
+
+        let mut priorities = vec![];
+
+        for (client_id, runs) in runs {
+            let owner = table.encode_run(encoder, client_id, Clock::ZERO, runs.len())?;
+
+            for run in runs {
+                let length: usize = run.iter().map(|block| block.length).sum();
+
+                if run[0].priority != 0 {
+                    priorities.push((BlockId::new(client_id, run[0].id), run[0].priority));
+                }
+
+                let block = EncodedBlock {
+                    origin_left: run[0].origin_left,
+                    origin_right: run[0].origin_right,
+                    length: length as u64,
+                    deleted: run[0].deleted,
+                };
+                table.encode_block(encoder, owner, run[0].id, &block)?;
+
+                if !run[0].deleted {
+                    for value in run.iter().flat_map(|block| &block.value) {
+                        value.encode(encoder)?;
+                    }
+                }
+            }
+        }
+
+        DeleteSet::from(self.document).encode(encoder)?;
+
+        encode_extensions(
+            encoder,
+            ExtensionParts {
+                map: &self.document.map.entries(),
+                marks: &self.document.marks.marks(),
+                priorities: &priorities,
+                moves: &self.document.store.moves.moves(),
+                deletions: &self.document.deletions.deletions(),
+                roots: &[],
+                registers: &self.document.store.registers.writes(),
+                checkpoint: &self.document.checkpoint.clocks(),
+                censored: &self.document.censored,
+            },
+        )
+    }
+}
+
+impl<T: Item + Encode> Update<T> {
+    /// Encodes the update in this crate's versioned wire format, for [`Update::decode`].
+    pub fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        encoding::encode(self)
+    }
+
+    /// The number of bytes [`Update::encode`] will produce, measured without allocating them.
+    pub fn encoded_size_hint(&self) -> usize {
+        encoding::encoded_len_with_header(self)
+    }
+
+    /// Splits the update into chunks which each encode to at most `max_encoded_bytes`, e.g. to
+    /// fit a transport's message size.
+    ///
+    /// Applying the chunks in order is equivalent to applying the update. Each chunk lists what
+    /// it builds on from earlier chunks as dependencies, so chunks which arrive out of order can
+    /// be held back with [`Document::apply_or_queue`] until the ones before them are in.
+    ///
+    /// Blocks are split where they don't fit, but a single element, mark or map entry which is
+    /// larger than the limit gets a chunk of its own which is larger too.
+    pub fn split(self, max_encoded_bytes: usize) -> Vec<Update<T>> {
+        let update = self.compact();
+
+        if update.blocks.iter().all(|(_, blocks)| blocks.is_empty())
+            && update.deletes.is_empty()
+            && update.map.is_empty()
+            && update.marks.is_empty()
+            && update.moves.is_empty()
+            && update.deletions.is_empty()
+            && update.registers.is_empty()
+        {
+            return vec![update];
+        }
+
+        let ranges: HashMap<ClientId, ClockRange> = update.dependency.iter().cloned().collect();
+        let roots = update.roots;
+        let checkpoint = update.checkpoint;
+        let censored = update.censored;
+        let mut chunker = Chunker::new(max_encoded_bytes, &ranges, &roots, &checkpoint, &censored);
+
+        for (client_id, clock, block) in causal_order(update.blocks, &ranges) {
+            chunker.push_block(client_id, clock, block);
+        }
+
+        for range in update.deletes.iter() {
+            chunker.push_delete(range.client_id, range.clocks);
+        }
+
+        for mark in update.marks {
+            chunker.push_mark(mark);
+        }
+
+        for mv in update.moves {
+            chunker.push_move(mv);
+        }
+
+        for write in update.registers {
+            chunker.push_register(write);
+        }
+
+        for deletion in update.deletions {
+            chunker.push_deletion(deletion);
+        }
+
+        for entry in update.map {
+            chunker.push_map_entry(entry);
+        }
+
+        chunker.finish()
+    }
+}
+
+/// Orders `blocks` so every block comes after any block in the same update its origins refer
+/// to, along with the clock of each block's first element. `ranges` are the clocks the update
+/// has for each client.
+///
+/// Blocks whose origins can never be satisfied, which only malformed updates have, go last.
+fn causal_order<T: Item>(
+    blocks: Vec<(ClientId, Vec<UpdateBlock<T>>)>,
+    ranges: &HashMap<ClientId, ClockRange>,
+) -> Vec<(ClientId, Clock, UpdateBlock<T>)> {
+    let mut emitted: HashMap<ClientId, Clock> = ranges
+        .iter()
+        .map(|(client_id, range)| (*client_id, range.start))
+        .collect();
+    let mut queues: Vec<(ClientId, VecDeque<UpdateBlock<T>>)> = blocks
+        .into_iter()
+        .map(|(client_id, blocks)| (client_id, blocks.into()))
+        .collect();
+    let mut ordered = vec![];
+
+    let is_available = |emitted: &HashMap<ClientId, Clock>, origin: &Option<BlockId>| {
+        origin.is_none_or(|origin| {
+            emitted
+                .get(&origin.client_id)
+                .is_none_or(|clock| origin.clock < *clock)
+        })
+    };
+
+    loop {
+        let mut progressed = false;
+
+        for (client_id, queue) in &mut queues {
+            while let Some(block) = queue.front() {
+                if !is_available(&emitted, &block.origin_left)
+                    || !is_available(&emitted, &block.origin_right)
+                {
+                    break;
+                }
+
+                let block = queue.pop_front().unwrap();
+                let clock = emitted.get(client_id).copied().unwrap_or_default();
+                emitted.insert(*client_id, clock.saturating_advance(block.length()));
+                ordered.push((*client_id, clock, block));
+                progressed = true;
+            }
+        }
+
+        if !progressed {
+            break;
+        }
+    }
+
+    for (client_id, queue) in queues {
+        for block in queue {
+            let clock = emitted.get(&client_id).copied().unwrap_or_default();
+            emitted.insert(client_id, clock.saturating_advance(block.length()));
+            ordered.push((client_id, clock, block));
+        }
+    }
+
+    ordered
+}
+
+/// The most bytes a varint-encoded integer takes up.
+const MAX_VARINT_BYTES: usize = 9;
+/// The most bytes the version header and the lengths of an update's top-level lists take up.
+const UPDATE_OVERHEAD: usize = 6 * MAX_VARINT_BYTES;
+/// The most bytes an extension's id and lengths take up, besides its entries.
+const EXTENSION_OVERHEAD: usize = 3 * MAX_VARINT_BYTES;
+/// The most bytes a client's entries in an update's dependencies, client table and blocks take up.
+const CLIENT_OVERHEAD: usize = 7 * MAX_VARINT_BYTES;
+/// The most bytes an origin takes up, besides its client's entry in the client table.
+const ORIGIN_OVERHEAD: usize = 2 * MAX_VARINT_BYTES;
+/// The most bytes a dependency on a client with no blocks in an update takes up.
+const DEPENDENCY_OVERHEAD: usize = 3 * MAX_VARINT_BYTES;
+
+/// Packs the parts of an update into chunks, for [`Update::split`].
+///
+/// Sizes are worked out pessimistically, so chunks may come out a little smaller than they need
+/// to be, but never larger.
+struct Chunker<'a, T: Item> {
+    max_bytes: usize,
+    /// The clocks the update being split has for each client.
+    ranges: &'a HashMap<ClientId, ClockRange>,
+    /// The named roots of the update being split.
+    update_roots: &'a [(String, DeleteSet)],
+    /// The checkpoint of the update being split, which every chunk carries.
+    checkpoint: &'a [(ClientId, Clock)],
+    /// The censored elements of the update being split, which every chunk carries too.
+    censored: &'a DeleteSet,
+    /// The bytes every chunk takes up before anything is added to it.
+    overhead: usize,
+    chunks: Vec<Update<T>>,
+    /// The current chunk's blocks, with the clocks they cover.
+    blocks: Vec<(ClientId, ClockRange, Vec<UpdateBlock<T>>)>,
+    /// The clock the current chunk needs from each client it refers to, by the update's own
+    /// blocks.
+    needed: HashMap<ClientId, Clock>,
+    deletes: DeleteSet,
+    marks: Vec<Mark>,
+    moves: Vec<Move>,
+    deletions: Vec<DeleteRecord>,
+    map: Vec<MapEntry<T>>,
+    /// The clocks of the current chunk's blocks in each named root.
+    roots: Vec<(String, DeleteSet)>,
+    registers: Vec<Register<T>>,
+    /// Clients in the current chunk's client table only because blocks' origins refer to them.
+    origin_clients: HashSet<ClientId>,
+    has_priorities: bool,
+    size: usize,
+}
+
+impl<'a, T: Item + Encode> Chunker<'a, T> {
+    fn new(
+        max_bytes: usize,
+        ranges: &'a HashMap<ClientId, ClockRange>,
+        update_roots: &'a [(String, DeleteSet)],
+        checkpoint: &'a [(ClientId, Clock)],
+        censored: &'a DeleteSet,
+    ) -> Chunker<'a, T> {
+        let mut overhead = UPDATE_OVERHEAD;
+
+        if !checkpoint.is_empty() {
+            overhead += EXTENSION_OVERHEAD + encoding::encoded_len(&checkpoint);
+        }
+
+        if !censored.is_empty() {
+            overhead += EXTENSION_OVERHEAD + encoding::encoded_len(censored);
+        }
+
+        Chunker {
+            max_bytes,
+            ranges,
+            update_roots,
+            checkpoint,
+            censored,
+            overhead,
+            chunks: vec![],
+            blocks: vec![],
+            needed: HashMap::new(),
+            deletes: DeleteSet::empty(),
+            marks: vec![],
+            moves: vec![],
+            deletions: vec![],
+            map: vec![],
+            roots: vec![],
+            registers: vec![],
+            origin_clients: HashSet::new(),
+            has_priorities: false,
+            size: overhead,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+            && self.deletes.is_empty()
+            && self.marks.is_empty()
+            && self.moves.is_empty()
+            && self.deletions.is_empty()
+            && self.map.is_empty()
+            && self.registers.is_empty()
+    }
+
+    /// Adds `block`, whose first element has the clock `clock`, splitting it across chunks if
+    /// it doesn't fit in the current one.
+    fn push_block(&mut self, client_id: ClientId, mut clock: Clock, mut block: UpdateBlock<T>) {
+        loop {
+            let cost = self.block_cost(client_id, clock, &block);
+
+            if self.size + cost <= self.max_bytes {
+                self.add_block(client_id, clock, block, cost);
+                return;
+            }
+
+            let room = self.max_bytes.saturating_sub(self.size);
+            let fitting = fitting_prefix(&block, cost, room);
+
+            if fitting > 0 {
+                let id = BlockId::new(client_id, clock);
+                let (prefix, rest) = block.split_at(id, fitting);
+                let cost = self.block_cost(client_id, clock, &prefix);
+
+                self.add_block(client_id, clock, prefix, cost);
+                self.flush();
+
+                clock = clock.advance(fitting);
+                block = rest;
+            } else if self.is_empty() {
+                // Not even one element fits in a chunk of its own
+                self.add_block(client_id, clock, block, cost);
+                return;
+            } else {
+                self.flush();
+            }
+        }
+    }
+
+    fn push_delete(&mut self, client_id: ClientId, clocks: ClockRange) {
+        let mut cost = 2 * MAX_VARINT_BYTES;
+
+        if !self
+            .deletes
+            .iter()
+            .any(|range| range.client_id == client_id)
+        {
+            cost += 2 * MAX_VARINT_BYTES;
+        }
+
+        cost += self.needs_cost(client_id);
+        self.make_room(cost);
+
+        self.need(client_id, clocks.end);
+        self.deletes.insert(client_id, clocks);
+        self.size += cost;
+    }
+
+    fn push_mark(&mut self, mark: Mark) {
+        let mut cost = encoding::encoded_len(&mark);
+
+        if self.marks.is_empty() {
+            cost += EXTENSION_OVERHEAD;
+        }
+
+        cost += self.needs_cost(mark.start.client_id) + self.needs_cost(mark.end.client_id);
+        self.make_room(cost);
+
+        for anchor in [mark.start, mark.end] {
+            self.need(anchor.client_id, anchor.clock.advance(1));
+        }
+
+        self.marks.push(mark);
+        self.size += cost;
+    }
+
+    fn push_move(&mut self, mv: Move) {
+        let anchors = [mv.start, mv.end, mv.marker];
+        let mut cost = encoding::encoded_len(&mv);
+
+        if self.moves.is_empty() {
+            cost += EXTENSION_OVERHEAD;
+        }
+
+        // Anchors of the same client only add its dependency once
+        let clients: HashSet<ClientId> = anchors.iter().map(|anchor| anchor.client_id).collect();
+        cost += clients
+            .into_iter()
+            .map(|client_id| self.needs_cost(client_id))
+            .sum::<usize>();
+        self.make_room(cost);
+
+        for anchor in anchors {
+            self.need(anchor.client_id, anchor.clock.advance(1));
+        }
+
+        self.moves.push(mv);
+        self.size += cost;
+    }
+
+    fn push_register(&mut self, write: Register<T>) {
+        let element = write.element;
+        let mut cost = encoding::encoded_len(&write);
+
+        if self.registers.is_empty() {
+            cost += EXTENSION_OVERHEAD;
+        }
+
+        cost += self.needs_cost(element.client_id);
+        self.make_room(cost);
+
+        self.need(element.client_id, element.clock.advance(1));
+        self.registers.push(write);
+        self.size += cost;
+    }
+
+    /// Adds who made a delete. Like map entries, these don't depend on anything.
+    fn push_deletion(&mut self, deletion: DeleteRecord) {
+        let mut cost = encoding::encoded_len(&deletion);
+
+        if self.deletions.is_empty() {
+            cost += EXTENSION_OVERHEAD;
+        }
+
+        self.make_room(cost);
+        self.deletions.push(deletion);
+        self.size += cost;
+    }
+
+    fn push_map_entry(&mut self, entry: MapEntry<T>) {
+        let mut cost = encoding::encoded_len(&entry);
+
+        if self.map.is_empty() {
+            cost += EXTENSION_OVERHEAD;
+        }
+
+        self.make_room(cost);
+        self.map.push(entry);
+        self.size += cost;
+    }
+
+    fn finish(mut self) -> Vec<Update<T>> {
+        if !self.is_empty() {
+            self.flush();
+        }
+
+        self.chunks
+    }
+
+    /// The most bytes `block`, whose first element has the clock `clock`, adds to the current
+    /// chunk.
+    fn block_cost(&self, client_id: ClientId, clock: Clock, block: &UpdateBlock<T>) -> usize {
+        let mut cost = encoded_len_bound(block);
+
+        if let Some(name) = root_of(self.update_roots, BlockId::new(client_id, clock)) {
+            cost += 2 * MAX_VARINT_BYTES;
+
+            match self.roots.iter().find(|(root, _)| root == name) {
+                Some((_, clocks)) if clocks.iter().any(|range| range.client_id == client_id) => {}
+                Some(_) => cost += 2 * MAX_VARINT_BYTES,
+                None => {
+                    cost += encoding::encoded_len(&name) + 3 * MAX_VARINT_BYTES;
+
+                    if self.roots.is_empty() {
+                        cost += EXTENSION_OVERHEAD;
+                    }
+                }
+            }
+        }
+
+        if !self.has_client(client_id) {
+            cost += CLIENT_OVERHEAD;
+        }
+
+        if block.priority != 0 {
+            cost += 2 * MAX_VARINT_BYTES + 1;
+
+            if !self.has_priorities {
+                cost += EXTENSION_OVERHEAD;
+            }
+        }
+
+        for origin in [block.origin_left, block.origin_right]
+            .into_iter()
+            .flatten()
+        {
+            if origin.client_id != client_id {
+                cost += self.needs_cost(origin.client_id);
+
+                if !self.has_client(origin.client_id)
+                    && !self.origin_clients.contains(&origin.client_id)
+                {
+                    cost += MAX_VARINT_BYTES;
+                }
+            }
+        }
+
+        cost
+    }
+
+    /// Whether the current chunk has blocks from `client_id`.
+    fn has_client(&self, client_id: ClientId) -> bool {
+        self.blocks.iter().any(|(client, ..)| *client == client_id)
+    }
+
+    fn add_block(&mut self, client_id: ClientId, clock: Clock, block: UpdateBlock<T>, cost: usize) {
+        for origin in [block.origin_left, block.origin_right]
+            .into_iter()
+            .flatten()
+        {
+            if origin.client_id != client_id {
+                self.need(origin.client_id, origin.clock.advance(1));
+                self.origin_clients.insert(origin.client_id);
+            }
+        }
+
+        let end = clock.saturating_advance(block.length());
+        self.has_priorities |= block.priority != 0;
+        self.size += cost;
+
+        if let Some(name) = root_of(self.update_roots, BlockId::new(client_id, clock)) {
+            match self.roots.iter_mut().find(|(root, _)| root == name) {
+                Some((_, clocks)) => clocks.insert(client_id, clock..end),
+                None => {
+                    let mut clocks = DeleteSet::empty();
+                    clocks.insert(client_id, clock..end);
+                    self.roots.push((name.to_owned(), clocks));
+                }
+            }
+        }
+
+        match self
+            .blocks
+            .iter_mut()
+            .find(|(client, ..)| *client == client_id)
+        {
+            Some((_, clocks, blocks)) => {
+                clocks.end = end;
+                blocks.push(block);
+            }
+            None => self
+                .blocks
+                .push((client_id, ClockRange::new(clock, end), vec![block])),
+        }
+    }
+
+    /// The most bytes depending on `client_id` adds to the current chunk.
+    fn needs_cost(&self, client_id: ClientId) -> usize {
+        if self.ranges.contains_key(&client_id) && !self.needed.contains_key(&client_id) {
+            DEPENDENCY_OVERHEAD
+        } else {
+            0
+        }
+    }
+
+    /// Records that the current chunk needs the elements of `client_id` before `clock`, if
+    /// they're in the update being split.
+    fn need(&mut self, client_id: ClientId, clock: Clock) {
+        if let Some(range) = self.ranges.get(&client_id) {
+            let clock = clock.min(range.end);
+            let needed = self.needed.entry(client_id).or_insert(clock);
+
+            *needed = (*needed).max(clock);
+        }
+    }
+
+    /// Starts a new chunk if `cost` more bytes don't fit in the current one.
+    fn make_room(&mut self, cost: usize) {
+        if self.size + cost > self.max_bytes && !self.is_empty() {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        let mut dependency: Vec<(ClientId, ClockRange)> = self
+            .blocks
+            .iter()
+            .map(|(client_id, clocks, _)| (*client_id, *clocks))
+            .collect();
+
+        let mut needed: Vec<(ClientId, Clock)> = self
+            .needed
+            .drain()
+            .filter(|(client_id, _)| !self.blocks.iter().any(|(id, ..)| id == client_id))
+            .collect();
+        needed.sort_unstable();
+        dependency.extend(
+            needed
+                .into_iter()
+                .map(|(client_id, clock)| (client_id, ClockRange::new(clock, clock))),
+        );
+
+        self.chunks.push(Update {
+            dependency,
+            blocks: self
+                .blocks
+                .drain(..)
+                .map(|(client_id, _, blocks)| (client_id, blocks))
+                .collect(),
+            deletes: mem::replace(&mut self.deletes, DeleteSet::empty()),
+            map: mem::take(&mut self.map),
+            marks: mem::take(&mut self.marks),
+            moves: mem::take(&mut self.moves),
+            deletions: mem::take(&mut self.deletions),
+            roots: mem::take(&mut self.roots),
+            registers: mem::take(&mut self.registers),
+            checkpoint: self.checkpoint.to_vec(),
+            censored: self.censored.clone(),
+        });
+
+        self.origin_clients.clear();
+        self.has_priorities = false;
+        self.size = self.overhead;
+    }
+}
+
+/// The most bytes `block` takes up in an update's blocks, besides its client's entries.
+fn encoded_len_bound<T: Item + Encode>(block: &UpdateBlock<T>) -> usize {
+    let origins = block.origins().into_iter().flatten().count();
+    let values = match &block.value {
+        Content::Value(values) => values.iter().map(encoding::encoded_len).sum(),
+        Content::Deleted(_) => 0,
+    };
+
+    1 + origins * ORIGIN_OVERHEAD + encoding::encoded_len(&block.length()) + values
+}
+
+/// How many of `block`'s elements fit in `room` bytes, where the whole block would take `cost`.
+/// Blocks of deleted elements are never worth splitting, as their size doesn't depend on their
+/// length.
+fn fitting_prefix<T: Item + Encode>(block: &UpdateBlock<T>, cost: usize, room: usize) -> u64 {
+    let Content::Value(values) = &block.value else {
+        return 0;
+    };
+
+    let sizes: Vec<usize> = values.iter().map(encoding::encoded_len).collect();
+    let mut used = cost - sizes.iter().sum::<usize>();
+    let mut fitting = 0;
+
+    for size in sizes {
+        used += size;
+
+        if used > room {
+            break;
+        }
+
+        fitting += 1;
+    }
+
+    // The whole block doesn't fit, and splitting needs something either side
+    fitting.min(values.len().saturating_sub(1)) as u64
+}
+
+impl<T: Item + Decode> Update<T> {
+    /// Decodes an update written by [`Update::encode`].
+    ///
+    /// Payloads from a newer format version, or which are truncated or otherwise malformed, are
+    /// rejected with an error, while extensions this version doesn't know are skipped.
+    pub fn decode(bytes: &[u8]) -> Result<Update<T>, DecodeError> {
+        Update::decode_with_limits(bytes, &Limits::default())
+    }
+
+    /// Decodes an update, rejecting it if it's larger than `limits` allow.
+    pub fn decode_with_limits(bytes: &[u8], limits: &Limits) -> Result<Update<T>, DecodeError> {
+        if bytes.len() > limits.max_update_bytes() {
+            return Err(DecodeError::LimitExceeded(Limit::UpdateBytes));
+        }
+
+        match encoding::version(bytes)? {
+            1 => encoding::decode_body::<UpdateV1<T>>(bytes).map(Update::from),
+            2 => encoding::decode_body::<UpdateV2<T>>(bytes).map(Update::from),
+            3 => encoding::decode_body::<UpdateV3<T>>(bytes).map(Update::from),
+            4..=7 => encoding::decode_body::<UpdateV7<T>>(bytes).map(|UpdateV7(update)| update),
+            _ => encoding::decode(bytes),
+        }
+    }
+}
+
+impl<T: Item> Update<T> {
+    pub fn from_document<S: BlockStorage<T>>(document: &Document<T, S>) -> Update<T> {
+        Update::from_document_since(document, &ClockVector::new())
+    }
+
+    /// Builds an update containing only the blocks a replica at state `since` is missing.
+    ///
+    /// Blocks which straddle a client's clock in `since` are split so only the unseen portion is
+    /// sent. Clients with nothing new are still listed with an empty range, so the receiver can
+    /// check it has everything the new blocks' origins refer to.
+    ///
+    /// Every entry in the document's map is included, as the map isn't tracked by state vectors,
+    /// and so is every deletion, which [`Update::from_document_since_deletes`] avoids.
+    pub fn from_document_since<S: BlockStorage<T>>(
+        document: &Document<T, S>,
+        since: &ClockVector,
+    ) -> Update<T> {
+        Update::from_document_since_deletes(document, since, &DeleteSet::empty())
+    }
+
+    /// Like [`Update::from_document_since`], but leaves out the deletions in `known_deletes`, such
+    /// as the replica's own `DeleteSet::from` its document.
+    pub fn from_document_since_deletes<S: BlockStorage<T>>(
+        document: &Document<T, S>,
+        since: &ClockVector,
+        known_deletes: &DeleteSet,
+    ) -> Update<T> {
+        let deletes = if known_deletes.is_empty() {
+            DeleteSet::from(document)
+        } else {
+            DeleteSet::from(document).diff(known_deletes)
+        };
+
+        Update {
+            map: document.map.entries(),
+            marks: document.marks.marks(),
+            moves: document.store.moves.moves(),
+            registers: document.store.registers.writes(),
+            ..Update::from_local_changes(
+                document,
+                since,
+                UnsentChanges {
+                    deletes,
+                    ..UnsentChanges::new()
+                },
+            )
+        }
+    }
+
+    /// Builds an update of the blocks created since `since`, carrying only the deletions, replaced
+    /// values, map writes, marks and moves in `changes` rather than every one in the document.
+    pub(crate) fn from_local_changes<S: BlockStorage<T>>(
+        document: &Document<T, S>,
+        since: &ClockVector,
+        changes: UnsentChanges,
+    ) -> Update<T> {
+        let mut blocks = vec![];
+        let mut dependency = vec![];
+        let state = document.state_vector();
+        // Nothing before the checkpoint is left to send
+        let start_of = |client_id: ClientId| {
+            (*since.get(&client_id).unwrap_or(&Clock::ZERO))
+                .min(state.clock(client_id))
+                .max(document.checkpoint.clock(client_id))
+        };
+
+        // Ordered by client, so the same document always encodes to the same bytes
+        let mut clients: Vec<ClientId> = state.as_ref().keys().copied().collect();
+        clients.sort_unstable();
+
+        for client_id in clients {
+            let end = state.clock(client_id);
+            let start = start_of(client_id);
+
+            dependency.push((client_id, ClockRange::new(start, end)));
+
+            if start == end {
+                continue;
+            }
+
+            // Roots share the clocks, so a client's blocks are spread across them
+            let mut unseen: Vec<&Block<T>> = document
+                .stores()
+                .flat_map(|store| store.data.iter(client_id))
+                .filter(|block| block.clocks().end > start)
+                .collect();
+            unseen.sort_by_key(|block| block.id);
+
+            let missing = unseen
+                .into_iter()
+                .map(|block| {
+                    if block.id < start {
+                        let (_, unseen) = block
+                            .clone()
+                            .split_at(client_id, start.offset_from(block.id));
+
+                        unseen.into()
+                    } else {
+                        UpdateBlock::from_block(block)
+                    }
+                })
+                .collect();
+
+            blocks.push((client_id, missing));
+        }
+
+        let mut roots = vec![];
+
+        for (name, store) in &document.roots {
+            let mut clocks = DeleteSet::empty();
+
+            for client_id in store.data.clients() {
+                let start = start_of(client_id);
+
+                for block in store.data.iter(client_id) {
+                    clocks.insert(client_id, block.id.max(start)..block.clocks().end);
+                }
+            }
+
+            if !clocks.is_empty() {
+                roots.push((name.clone(), clocks));
+            }
+        }
+
+        Update {
+            blocks,
+            dependency,
+            deletions: document.deletions.covering(&changes.deletes),
+            deletes: changes.deletes,
+            map: changes
+                .map_keys
+                .iter()
+                .filter_map(|key| document.map.entry(key).cloned())
+                .collect(),
+            marks: changes.marks,
+            moves: changes.moves,
+            roots,
+            registers: document
+                .store
+                .registers
+                .writes()
+                .into_iter()
+                .filter(|write| changes.written.contains(write.element))
+                .collect(),
+            checkpoint: document.checkpoint.clocks(),
+            censored: document.censored.clone(),
+        }
+        .compact()
+    }
+
+    /// Combines this update with `other` into a single update, equivalent to applying both in
+    /// order.
+    ///
+    /// Each client's blocks are concatenated in clock order, with any part `other` shares with
+    /// this update dropped, and the result is compacted. Fails if the two updates' clock ranges
+    /// for a client leave a gap.
+    pub fn merge(self, other: Update<T>) -> Result<Update<T>, MergeError> {
+        let mut blocks: HashMap<ClientId, Vec<UpdateBlock<T>>> = self.blocks.into_iter().collect();
+        let mut other_blocks: HashMap<ClientId, Vec<UpdateBlock<T>>> =
+            other.blocks.into_iter().collect();
+        let mut dependency = self.dependency;
+
+        for (client_id, second) in other.dependency {
+            let second_blocks = other_blocks.remove(&client_id).unwrap_or_default();
+
+            let Some((_, first)) = dependency.iter_mut().find(|(id, _)| *id == client_id) else {
+                dependency.push((client_id, second));
+                blocks.insert(client_id, second_blocks);
+                continue;
+            };
+
+            if second.start > first.end || first.start > second.end {
+                return Err(MergeError::NonContiguousRanges {
+                    client_id,
+                    first: *first,
+                    second,
+                });
+            }
+
+            let first_blocks = blocks.remove(&client_id).unwrap_or_default();
+            let ((earlier, earlier_blocks), (later, later_blocks)) = if first.start <= second.start
+            {
+                ((*first, first_blocks), (second, second_blocks))
+            } else {
+                ((second, second_blocks), (*first, first_blocks))
+            };
+
+            let mut merged = earlier_blocks;
+            let mut clock = later.start;
+
+            for block in later_blocks {
+                let id = BlockId::new(client_id, clock);
+                clock = clock.saturating_advance(block.length());
+
+                if clock <= earlier.end {
+                    continue;
+                } else if id.clock < earlier.end {
+                    merged.push(block.split_off(id, earlier.end.offset_from(id.clock)));
+                } else {
+                    merged.push(block);
+                }
+            }
+
+            *first = ClockRange::new(earlier.start, earlier.end.max(later.end));
+            blocks.insert(client_id, merged);
+        }
+
+        let blocks = dependency
+            .iter()
+            .filter_map(|(client_id, _)| {
+                blocks
+                    .remove(client_id)
+                    .filter(|blocks| !blocks.is_empty())
+                    .map(|blocks| (*client_id, blocks))
+            })
+            .collect();
+
+        let map = MapStore::from_entries(self.map.into_iter().chain(other.map).collect());
+        let marks = MarkStore::from_marks(self.marks.into_iter().chain(other.marks).collect());
+        let moves = MoveStore::from_moves(self.moves.into_iter().chain(other.moves).collect());
+        let deletions =
+            DeleteLog::from_deletions(self.deletions.into_iter().chain(other.deletions).collect());
+        let registers =
+            RegisterStore::from_writes(self.registers.into_iter().chain(other.registers).collect());
+
+        let mut roots = self.roots;
+        for (name, clocks) in other.roots {
+            match roots.iter_mut().find(|(root, _)| *root == name) {
+                Some((_, existing)) => {
+                    *existing = mem::replace(existing, DeleteSet::empty()).merge(clocks)
+                }
+                None => roots.push((name, clocks)),
+            }
+        }
+
+        let mut checkpoint: BTreeMap<ClientId, Clock> = self.checkpoint.into_iter().collect();
+        for (client_id, clock) in other.checkpoint {
+            let entry = checkpoint.entry(client_id).or_insert(clock);
+            *entry = (*entry).max(clock);
+        }
+
+        Ok(Update {
+            dependency,
+            blocks,
+            deletes: self.deletes.merge(other.deletes),
+            map: map.entries(),
+            marks: marks.marks(),
+            moves: moves.moves(),
+            deletions: deletions.deletions(),
+            roots,
+            registers: registers.writes(),
+            checkpoint: checkpoint.into_iter().collect(),
+            censored: self.censored.merge(other.censored),
+        }
+        .compact())
+    }
+
+    /// Applies the update like [`Update::apply`], returning what it changed as a [`Delta`] over
+    /// the document as it was before.
+    pub fn apply_with_delta<S: BlockStorage<T>>(
+        self,
+        document: &mut Document<T, S>,
+    ) -> Result<Delta<T>, ApplyError> {
+        let before: Vec<BlockId> = document
+            .store
+            .iter_live_elements()
+            .map(|(id, _)| id)
+            .collect();
+
+        self.apply(document)?;
+
+        Ok(Delta::between(&before, &document.store))
+    }
+
+    pub fn apply<S: BlockStorage<T>>(
+        self,
+        document: &mut Document<T, S>,
+    ) -> Result<(), ApplyError> {
+        #[cfg(feature = "metrics")]
+        let timer = ApplyTimer::start(
+            &document.store.metrics,
+            self.blocks.iter().map(|(_, blocks)| blocks.len()).sum(),
+            None,
+        );
+
+        let applied = document.observed(false, |document| self.integrate_into(document));
+
+        #[cfg(feature = "metrics")]
+        if applied.is_ok() {
+            timer.finish();
+        }
+
+        applied
+    }
+
+    /// Applies as much of the update as `document` has the dependencies for, returning the rest.
+    ///
+    /// A client's blocks are applied if the document has everything before them and every block
+    /// they are inserted next to. Deletions, marks and moves are applied if the document has the
+    /// elements they refer to once those blocks are in. Everything else is returned as the
+    /// remainder, which can be applied once the missing updates have arrived. Map entries are
+    /// always applied.
+    ///
+    /// Updates which are malformed, or would break the document's limits, are rejected whole.
+    pub fn apply_partial<S: BlockStorage<T>>(
+        self,
+        document: &mut Document<T, S>,
+    ) -> Result<ApplyOutcome<T>, ApplyError> {
+        self.check_limits(document)?;
+        self.outline().check_checkpoint(document)?;
+
+        if let Err(error) = self.check_client_id(document) {
+            document.client_id_conflict();
+
+            return Err(error);
+        }
+
+        self.validate()?;
+
+        let applied = self.applicable_clients(document);
+        let (now, remainder) = self.split_off(&applied, document);
+
+        now.apply(document)?;
+
+        let mut applied_clients: Vec<ClientId> = applied.into_iter().collect();
+        applied_clients.sort_unstable();
+
+        Ok(ApplyOutcome {
+            applied_clients,
+            remainder,
+        })
+    }
+
+    /// The clients whose blocks `document` has the dependencies for, i.e. whose blocks follow
+    /// straight on from what it has and are only inserted next to elements it has or which come
+    /// from other such clients.
+    fn applicable_clients<S: BlockStorage<T>>(
+        &self,
+        document: &Document<T, S>,
+    ) -> HashSet<ClientId> {
+        let mut applicable: HashSet<ClientId> = self
+            .dependency
+            .iter()
+            .filter(|(client_id, range)| {
+                range.start <= *document.clients.get(client_id).unwrap_or(&Clock::ZERO)
+            })
+            .map(|(client_id, _)| *client_id)
+            .collect();
+
+        let is_available = |applicable: &HashSet<ClientId>, origin: BlockId| {
+            document.find_block(origin).is_some()
+                || applicable.contains(&origin.client_id)
+                    && self
+                        .get_version_range(origin.client_id)
+                        .is_some_and(|range| origin.clock < range.end)
+        };
+
+        // Leaving out one client can strand blocks of another, so repeat until nothing changes
+        while let Some(blocked) = self
+            .blocks
+            .iter()
+            .filter(|(client_id, _)| applicable.contains(client_id))
+            .find(|(_, blocks)| {
+                blocks
+                    .iter()
+                    .flat_map(|block| [block.origin_left, block.origin_right])
+                    .flatten()
+                    .any(|origin| !is_available(&applicable, origin))
+            })
+            .map(|(client_id, _)| *client_id)
+        {
+            applicable.remove(&blocked);
+        }
+
+        applicable
+    }
+
+    /// Splits the update into the part which can be applied to `document` once the blocks of
+    /// the `applied` clients are in, and the rest, if there is any.
+    fn split_off<S: BlockStorage<T>>(
+        self,
+        applied: &HashSet<ClientId>,
+        document: &Document<T, S>,
+    ) -> (Update<T>, Option<Update<T>>) {
+        let known = |client_id: ClientId| {
+            let have = document.next_clock(client_id);
+
+            match self.get_version_range(client_id) {
+                Some(range) if applied.contains(&client_id) => have.max(range.end),
+                _ => have,
+            }
+        };
+
+        let (deletes, remaining_deletes) = self.deletes.split_known(known);
+        let (marks, remaining_marks): (Vec<Mark>, Vec<Mark>) =
+            self.marks.iter().cloned().partition(|mark| {
+                [mark.start, mark.end]
+                    .iter()
+                    .all(|anchor| anchor.clock < known(anchor.client_id))
+            });
+        let (moves, remaining_moves): (Vec<Move>, Vec<Move>) =
+            self.moves.iter().cloned().partition(|mv| {
+                [mv.start, mv.end, mv.marker]
+                    .iter()
+                    .all(|anchor| anchor.clock < known(anchor.client_id))
+            });
+        let (registers, remaining_registers): (Vec<Register<T>>, Vec<Register<T>>) = self
+            .registers
+            .iter()
+            .cloned()
+            .partition(|write| write.element.clock < known(write.element.client_id));
+
+        let (dependency, remaining_dependency): (Vec<_>, Vec<_>) = self
+            .dependency
+            .into_iter()
+            .partition(|(client_id, _)| applied.contains(client_id));
+        let (blocks, remaining_blocks): (Vec<_>, Vec<_>) = self
+            .blocks
+            .into_iter()
+            .partition(|(client_id, _)| applied.contains(client_id));
+
+        let now = Update {
+            dependency,
+            blocks,
+            deletes,
+            map: self.map,
+            marks,
+            moves,
+            deletions: self.deletions,
+            roots: self.roots.clone(),
+            registers,
+            checkpoint: self.checkpoint.clone(),
+            censored: self.censored.clone(),
+        };
+
+        let remainder = Update {
+            dependency: remaining_dependency,
+            blocks: remaining_blocks,
+            deletes: remaining_deletes,
+            map: vec![],
+            marks: remaining_marks,
+            moves: remaining_moves,
+            deletions: vec![],
+            roots: self.roots,
+            registers: remaining_registers,
+            checkpoint: self.checkpoint,
+            censored: self.censored,
+        };
+
+        let is_empty = remainder.blocks.iter().all(|(_, blocks)| blocks.is_empty())
+            && remainder.deletes.is_empty()
+            && remainder.marks.is_empty()
+            && remainder.moves.is_empty()
+            && remainder.registers.is_empty();
+
+        (now, (!is_empty).then_some(remainder))
+    }
+
+    fn integrate_into<S: BlockStorage<T>>(
+        self,
+        document: &mut Document<T, S>,
+    ) -> Result<(), ApplyError> {
+        self.check_limits(document)?;
+
+        if let Err(error) = self.check_dependencies(document) {
+            if let ApplyError::ClientIdConflict(_) = error {
+                document.client_id_conflict();
+            }
+
+            return Err(error);
+        }
+
+        self.validate()?;
+        self.check_anchors(document)?;
+        self.outline().check_write_clocks()?;
+
+        let starts: HashMap<ClientId, Clock> = self
+            .dependency
+            .iter()
+            .map(|(client_id, range)| (*client_id, range.start))
+            .collect();
+
+        let Update {
+            blocks,
+            deletes,
+            map,
+            marks,
+            moves,
+            deletions,
+            roots,
+            registers,
+            censored,
+            ..
+        } = self;
+
+        // Before the blocks, so any of theirs which are censored never show their content
+        document.censor_ranges(&censored);
+
+        let mut unknown_blocks = vec![];
+
+        for (client_id, blocks) in blocks.into_iter() {
+            let mut clock = *starts
+                .get(&client_id)
+                .ok_or(ApplyError::UndeclaredClient(client_id))?;
+
+            let hydrated_blocks = blocks
+                .into_iter()
+                .map(|block| {
+                    let id = clock;
+                    clock = clock.saturating_advance(block.length());
+
+                    block.hydrate(id)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let known = document.next_clock(client_id);
+            unknown_blocks.push((
+                client_id,
+                hydrated_blocks
+                    .into_iter()
+                    .filter_map(|block| skip_known(client_id, block, known))
+                    .map(|block| (block.id, block))
+                    .collect(),
+            ));
+        }
+
+        for (client_id, block) in integration_order(document, unknown_blocks)? {
+            integrate_block(document, &roots, client_id, block)?;
+        }
+
+        document.debug_assert_consistent();
+        apply_rest(document, deletes, map, marks, moves, deletions, registers);
+
+        Ok(())
+    }
+
+    /// The parts of the update which are checked before it is applied.
+    fn outline(&self) -> Outline<'_, UpdateBlock<T>, T> {
+        Outline {
+            dependency: &self.dependency,
+            blocks: &self.blocks,
+            map: &self.map,
+            marks: &self.marks,
+            moves: &self.moves,
+            registers: &self.registers,
+            roots: &self.roots,
+            checkpoint: &self.checkpoint,
+        }
+    }
+
+    /// Checks that both ends of every mark, the ends and marker of every move, and the element
+    /// of every replaced value are in the document's own sequence or the update itself.
+    fn check_anchors<S: BlockStorage<T>>(
+        &self,
+        document: &Document<T, S>,
+    ) -> Result<(), ApplyError> {
+        self.outline().check_anchors(document)
+    }
+
+    /// Checks that applying the update wouldn't take `document` past its limits. Only the
+    /// declared lengths are looked at, so nothing proportional to them is allocated first.
+    pub(crate) fn check_limits<S: BlockStorage<T>>(
+        &self,
+        document: &Document<T, S>,
+    ) -> Result<(), ApplyError> {
+        self.outline().check_limits(document)
+    }
+
+    /// Checks that `document` has everything this update builds on, without modifying it.
+    pub(crate) fn check_dependencies<S: BlockStorage<T>>(
+        &self,
+        document: &Document<T, S>,
+    ) -> Result<(), ApplyError> {
+        self.outline().check_dependencies(document)
+    }
+
+    /// The clock a document needs to have reached for each client before this update can apply.
+    ///
+    /// Besides the start of every declared range, this covers clients the update only refers to
+    /// through the origins of its blocks.
+    pub fn required_state(&self) -> ClockVector {
+        self.outline().required_state()
+    }
+
+    pub(crate) fn missing_for<S: BlockStorage<T>>(
+        &self,
+        document: &Document<T, S>,
+    ) -> Option<ClockVector> {
+        self.outline().missing_for(document)
+    }
+
+    fn check_client_id<S: BlockStorage<T>>(
+        &self,
+        document: &Document<T, S>,
+    ) -> Result<(), ApplyError> {
+        self.outline().check_client_id(document)
+    }
+
+    /// The clock each client reaches once the update is applied.
+    pub(crate) fn end_clocks(&self) -> ClockVector {
+        self.dependency
+            .iter()
+            .map(|(client_id, range)| (*client_id, range.end))
+            .collect()
+    }
+
+    pub(crate) fn deletes(&self) -> &DeleteSet {
+        &self.deletes
+    }
+
+    /// Whether the update inserts or deletes anything in the sequence.
+    pub(crate) fn changes_sequence(&self) -> bool {
+        !self.blocks.is_empty() || !self.deletes.is_empty()
+    }
+
+    /// The latest clock of each client's writes to the map, registers, marks and moves in the
+    /// update.
+    pub(crate) fn write_clocks(&self) -> WriteClocks {
+        let mut clocks = WriteClocks::default();
+
+        for entry in &self.map {
+            clocks
+                .map
+                .entry(entry.client_id)
+                .or_default()
+                .observe(entry.clock);
+        }
+        for write in &self.registers {
+            clocks
+                .registers
+                .entry(write.client_id)
+                .or_default()
+                .observe(write.clock);
+        }
+        for mark in &self.marks {
+            clocks
+                .marks
+                .entry(mark.client_id)
+                .or_default()
+                .observe(mark.clock);
+        }
+        for mv in &self.moves {
+            clocks
+                .moves
+                .entry(mv.client_id)
+                .or_default()
+                .observe(mv.clock);
+        }
+
+        clocks
+    }
+
+    /// Whether everything in this update is already covered by `state`.
+    pub(crate) fn is_covered_by(&self, state: &ClockVector) -> bool {
+        self.dependency
+            .iter()
+            .all(|(client_id, range)| range.end <= *state.get(client_id).unwrap_or(&Clock::ZERO))
+    }
+
+    /// Builds an update of only blocks and deletes, for tests, which set whatever else they need
+    /// on top.
+    #[cfg(test)]
+    pub(crate) fn from_sequence(
+        dependency: Vec<(ClientId, ClockRange)>,
+        blocks: Vec<(ClientId, Vec<UpdateBlock<T>>)>,
+        deletes: DeleteSet,
+    ) -> Update<T> {
+        Update {
+            dependency,
+            blocks,
+            deletes,
+            map: vec![],
+            marks: vec![],
+            moves: vec![],
+            deletions: vec![],
+            roots: vec![],
+            registers: vec![],
+            checkpoint: vec![],
+            censored: DeleteSet::empty(),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_blocks(
+        client_id: ClientId,
+        blocks: Vec<Block<T>>,
+        dependency: Vec<(ClientId, impl Into<ClockRange>)>,
+    ) -> Update<T> {
+        let update_blocks = blocks.into_iter().map(|f| f.into()).collect();
+
+        Update::from_sequence(
+            dependency
+                .into_iter()
+                .map(|(client_id, clocks)| (client_id, clocks.into()))
+                .collect(),
+            vec![(client_id, update_blocks)],
+            DeleteSet::empty(),
+        )
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        self.outline().validate()
+    }
+
+    fn get_version_range(&self, client_id: ClientId) -> Option<ClockRange> {
+        version_range(&self.dependency, client_id)
+    }
+
+    /// The priority of every block which has one, by the id of the block's first element.
+    fn priorities(&self) -> Vec<(BlockId, u8)> {
+        let mut priorities = vec![];
+
+        for (client_id, blocks) in &self.blocks {
+            let mut clock = self
+                .get_version_range(*client_id)
+                .map_or(Clock::ZERO, |range| range.start);
+
+            for block in blocks {
+                if block.priority != 0 {
+                    priorities.push((BlockId::new(*client_id, clock), block.priority));
+                }
+
+                clock = clock.saturating_advance(block.length());
+            }
+        }
+
+        priorities
+    }
+
+    /// Gives blocks the priorities decoded from an update's extension. Priorities of blocks which
+    /// aren't in the update are ignored.
+    fn set_priorities(&mut self, priorities: Vec<(BlockId, u8)>) {
+        let priorities: HashMap<BlockId, u8> = priorities.into_iter().collect();
+
+        for (client_id, blocks) in &mut self.blocks {
+            let mut clock = self
+                .dependency
+                .iter()
+                .find(|(id, _)| id == client_id)
+                .map_or(Clock::ZERO, |(_, range)| range.start);
+
+            for block in blocks {
+                if let Some(priority) = priorities.get(&BlockId::new(*client_id, clock)) {
+                    block.priority = *priority;
+                }
+
+                clock = clock.saturating_advance(block.length());
+            }
+        }
+    }
+
+    /// Merges runs of adjacent blocks which could have been sent as a single block.
+    fn compact(self) -> Self {
+        let starts: HashMap<ClientId, Clock> = self
+            .dependency
+            .iter()
+            .map(|(client_id, range)| (*client_id, range.start))
+            .collect();
+
+        let blocks = self
+            .blocks
+            .into_iter()
+            .map(|(client_id, blocks)| {
+                let mut clock = starts.get(&client_id).copied().unwrap_or_default();
+                let mut output: Vec<UpdateBlock<T>> = Vec::with_capacity(blocks.len());
+                let mut current: Option<(UpdateBlock<T>, BlockId)> = None;
+
+                for block in blocks {
+                    let id = BlockId::new(client_id, clock);
+                    clock = clock.saturating_advance(block.length());
+
+                    current = Some(match current {
+                        None => (block, id),
+                        Some((previous, previous_id)) => {
+                            match previous.try_merge(previous_id, block, id) {
+                                Merged(merged) => (merged, previous_id),
+                                NotMerged(previous, block) => {
+                                    output.push(previous);
+                                    (block, id)
+                                }
+                            }
+                        }
+                    });
+                }
+
+                output.extend(current.map(|(block, _)| block));
+
+                (client_id, output)
+            })
+            .collect();
+
+        Update {
+            blocks,
+            dependency: self.dependency,
+            deletes: self.deletes,
+            map: self.map,
+            marks: self.marks,
+            moves: self.moves,
+            deletions: self.deletions,
+            roots: self.roots,
+            registers: self.registers,
+            checkpoint: self.checkpoint,
+            censored: self.censored,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::Block;
+    use crate::block_range::BlockRange;
+    use crate::clock::{Clock, ClockRange};
+    use crate::delete_set::DeleteSet;
+    use crate::document::{BlockId, ClientId, ClockVector};
+    use crate::encoding::{self, DecodeError, FORMAT_VERSION};
+    use crate::limits::{Limit, Limits};
+    use crate::storage::BlockStorage;
+    use crate::update::{
+        encode_extensions, ApplyEncodedError, ApplyError, ApplyOutcome, Content, ExtensionParts,
+        MergeError, Update, UpdateBlock, ValidationError,
+    };
+    use crate::Document;
+    use bincode::enc::Encoder;
+    use bincode::error::EncodeError;
+    use bincode::{config, decode_from_slice, encode_to_vec, Encode};
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn can_create_update_from_document() {
+        let mut doc = Document::new();
+        doc.store.append("test".to_owned());
+
+        let document_as_update = Update::from_document(&doc);
+
+        assert_eq!(
+            document_as_update.blocks[0],
+            (
+                doc.client_id,
+                vec![UpdateBlock::with_value(None, None, "test".to_owned())]
+            )
+        );
+    }
+
+    #[test]
+    fn can_apply_update_to_document() {
+        let mut doc = Document::new();
+        doc.store.append("test".to_owned());
+
+        let update = Update::from_document(&doc);
+
+        let mut doc2 = Document::new();
+        update.apply(&mut doc2).unwrap();
+
+        let data: Vec<&String> = doc2.store.iter_values().collect();
+
+        assert_eq!(data, vec!["test"]);
+    }
+
+    #[test]
+    fn cant_apply_update_with_missing_dependency() {
+        let mut doc = Document::with_client_id(1);
+        doc.store.append("test".to_owned());
+
+        let update = Update::from_blocks(2, vec![], vec![(3, 2..3)]);
+
+        let result = update.apply(&mut doc);
+
+        assert_eq!(
+            result,
+            Err(ApplyError::MissingDependency {
+                client_id: 3,
+                required_clock: Clock::new(2),
+                have_clock: Clock::ZERO,
+                missing: [(3, Clock::new(2))].into_iter().collect(),
+            })
+        )
+    }
+
+    #[test]
+    fn origins_from_undeclared_clients_are_dependencies() {
+        let mut origin = Document::with_client_id(7);
+        origin.push("a".to_owned());
+        origin.push("b".to_owned());
+
+        // Inserted after client 7's "b", but only client 1's range is declared
+        let update: Update<String> = Update::from_sequence(
+            vec![(1, (0..1).into())],
+            vec![(
+                1,
+                vec![UpdateBlock::with_value(
+                    Some(BlockId::new(7, 1)),
+                    None,
+                    "c".to_owned(),
+                )],
+            )],
+            DeleteSet::empty(),
+        );
+
+        assert_eq!(
+            update.required_state(),
+            [(7, Clock::new(2))].into_iter().collect()
+        );
+
+        let mut doc = Document::with_client_id(2);
+        let missing: ClockVector = [(7, Clock::new(2))].into_iter().collect();
+        assert_eq!(doc.missing_for(&update), Some(missing.clone()));
+        assert_eq!(
+            update.clone().apply(&mut doc),
+            Err(ApplyError::MissingDependency {
+                client_id: 7,
+                required_clock: Clock::new(2),
+                have_clock: Clock::ZERO,
+                missing,
+            })
+        );
+
+        Update::from_document(&origin).apply(&mut doc).unwrap();
+        assert_eq!(doc.missing_for(&update), None);
+
+        update.apply(&mut doc).unwrap();
+        assert_eq!(doc.iter().collect::<Vec<_>>(), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn missing_for_reports_every_client_behind() {
+        let update: Update<String> = Update::from_sequence(
+            vec![(1, (3..4).into()), (2, (1..1).into())],
+            vec![(
+                1,
+                vec![UpdateBlock::with_value(
+                    Some(BlockId::new(7, 4)),
+                    None,
+                    "c".to_owned(),
+                )],
+            )],
+            DeleteSet::empty(),
+        );
+
+        let mut doc = Document::with_client_id(2);
+        doc.push("x".to_owned());
+
+        // Client 2 is far enough along, the others aren't
+        assert_eq!(
+            doc.missing_for(&update),
+            Some(
+                [(1, Clock::new(3)), (7, Clock::new(5))]
+                    .into_iter()
+                    .collect()
+            )
+        );
+    }
+
+    #[test]
+    fn applying_update_twice_is_a_no_op() {
+        let mut doc = Document::with_client_id(1);
+        doc.push("a".to_owned());
+        doc.push("b".to_owned());
+        doc.insert(1, "c".to_owned());
+        doc.remove(0);
+
+        let update = Update::from_document(&doc);
+
+        let mut doc2 = Document::with_client_id(2);
+        update.clone().apply(&mut doc2).unwrap();
+
+        let values = doc2.store.iter_values().cloned().collect::<Vec<_>>();
+        let blocks = doc2.store.data.len(1);
+
+        update.apply(&mut doc2).unwrap();
+
+        assert_eq!(
+            doc2.store.iter_values().cloned().collect::<Vec<_>>(),
+            values
+        );
+        assert_eq!(doc2.store.data.len(1), blocks);
+        assert_eq!(values, vec!["c", "b"]);
+    }
+
+    #[test]
+    fn integrates_only_the_unknown_suffix_of_overlapping_update() {
+        let mut doc = Document::with_client_id(1);
+        doc.push("a".to_owned());
+        doc.push("b".to_owned());
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc).apply(&mut doc2).unwrap();
+
+        doc.push("c".to_owned());
+        doc.push("d".to_owned());
+
+        // "a" to "d" are a single run, so the update straddles what doc2 already knows
+        Update::from_document(&doc).apply(&mut doc2).unwrap();
+
+        assert_eq!(
+            doc2.store.iter_values().collect::<Vec<_>>(),
+            vec!["a", "b", "c", "d"]
+        );
+        assert_eq!(doc2.store.next_clock(1), Clock::new(4));
+        assert_eq!(
+            doc2.store
+                .data
+                .iter(1)
+                .map(|block| block.length)
+                .sum::<usize>(),
+            4
+        );
+    }
+
+    #[test]
+    fn cant_apply_update_with_invalid_origin() {
+        let update: Update<String> = Update::from_sequence(
+            vec![(1, (0..1).into()), (2, (0..0).into())],
+            vec![(
+                1,
+                vec![UpdateBlock::with_value(
+                    Some(BlockId::new(2, 1)),
+                    None,
+                    "Test".to_owned(),
+                )],
+            )],
+            DeleteSet::empty(),
+        );
+
+        let mut doc = Document::with_client_id(3);
+
+        assert_eq!(
+            update.apply(&mut doc),
+            Err(ApplyError::InvalidOrigin(BlockId::new(2, 1)))
+        )
+    }
+
+    #[test]
+    fn rejects_origin_missing_from_document_without_changing_it() {
+        let mut doc = Document::with_client_id(3);
+        doc.push("a".to_owned());
+
+        // Client 2 is declared with an empty range, so nothing of theirs can be in the update or
+        // the document
+        let update: Update<String> = Update::from_sequence(
+            vec![(1, (0..2).into()), (2, (0..0).into())],
+            vec![(
+                1,
+                vec![
+                    UpdateBlock::with_value(None, None, "b".to_owned()),
+                    UpdateBlock::with_value(Some(BlockId::new(2, 0)), None, "c".to_owned()),
+                ],
+            )],
+            DeleteSet::empty(),
+        );
+
+        assert_eq!(
+            update.apply(&mut doc),
+            Err(ApplyError::InvalidOrigin(BlockId::new(2, 0)))
+        );
+        assert_eq!(doc.store.iter_values().collect::<Vec<_>>(), vec!["a"]);
+        assert!(!doc.store.data.contains(1));
+    }
+
+    #[test]
+    fn integrates_blocks_after_the_blocks_their_origins_refer_to() {
+        // Client 1's block sits after client 2's, but is listed first
+        let update: Update<String> = Update::from_sequence(
+            vec![(1, (0..1).into()), (2, (0..1).into())],
+            vec![
+                (
+                    1,
+                    vec![UpdateBlock::with_value(
+                        Some(BlockId::new(2, 0)),
+                        None,
+                        "b".to_owned(),
+                    )],
+                ),
+                (2, vec![UpdateBlock::with_value(None, None, "a".to_owned())]),
+            ],
+            DeleteSet::empty(),
+        );
+
+        let mut doc = Document::with_client_id(3);
+        update.apply(&mut doc).unwrap();
+
+        assert_eq!(doc.store.iter_values().collect::<Vec<_>>(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn integrates_blocks_anchored_on_later_clients_like_separate_updates() {
+        let first = |value: &str| UpdateBlock::with_value(None, None, value.to_owned());
+        let between = BlockId::new(3, 0);
+
+        // Client 2's block goes between client 3's, which come after it in the update
+        let client_2 = (
+            2,
+            vec![UpdateBlock::with_value(
+                Some(between),
+                Some(BlockId::new(3, 1)),
+                "b".to_owned(),
+            )],
+        );
+        let client_3 = (
+            3,
+            vec![
+                first("a"),
+                UpdateBlock::with_value(Some(between), None, "c".to_owned()),
+            ],
+        );
+        let update = |blocks: Vec<(ClientId, Vec<UpdateBlock<String>>)>| -> Update<String> {
+            Update::from_sequence(
+                blocks
+                    .iter()
+                    .map(|(client_id, blocks)| (*client_id, (0..blocks.len() as u64).into()))
+                    .collect(),
+                blocks,
+                DeleteSet::empty(),
+            )
+        };
+
+        let mut together = Document::with_client_id(1);
+        update(vec![client_2.clone(), client_3.clone()])
+            .apply(&mut together)
+            .unwrap();
+
+        let mut separately = Document::with_client_id(1);
+        update(vec![client_3]).apply(&mut separately).unwrap();
+        update(vec![client_2]).apply(&mut separately).unwrap();
+
+        assert_eq!(together.to_vec(), vec!["a", "b", "c"]);
+        assert_eq!(together.to_vec(), separately.to_vec());
+        assert_eq!(together.check_integrity(), vec![]);
+    }
+
+    #[test]
+    fn rejects_blocks_whose_origins_wait_on_each_other() {
+        let update: Update<String> = Update::from_sequence(
+            vec![(2, (0..1).into()), (3, (0..1).into())],
+            vec![
+                (
+                    2,
+                    vec![UpdateBlock::with_value(
+                        Some(BlockId::new(3, 0)),
+                        None,
+                        "a".to_owned(),
+                    )],
+                ),
+                (
+                    3,
+                    vec![UpdateBlock::with_value(
+                        Some(BlockId::new(2, 0)),
+                        None,
+                        "b".to_owned(),
+                    )],
+                ),
+            ],
+            DeleteSet::empty(),
+        );
+
+        let mut doc = Document::with_client_id(1);
+        doc.push("x".to_owned());
+        let before = doc.snapshot();
+        let encoded = update.encode().unwrap();
+
+        assert_eq!(
+            update.apply(&mut doc),
+            Err(ApplyError::CyclicOrigins(BlockId::new(3, 0)))
+        );
+        assert_eq!(
+            doc.apply_encoded(&encoded),
+            Err(ApplyEncodedError::Apply(ApplyError::CyclicOrigins(
+                BlockId::new(3, 0)
+            )))
+        );
+        assert_eq!(doc.snapshot(), before);
+    }
+
+    #[test]
+    fn can_merge_two_documents() {
+        let mut doc = Document::with_client_id(1);
+        doc.store.append("test".to_owned());
+
+        let update = Update::from_document(&doc);
+
+        let mut doc2 = Document::with_client_id(2);
+        doc2.store.append("test2".to_owned());
+        update.apply(&mut doc2).unwrap();
+
+        let data: Vec<&String> = doc2.store.iter_values().collect();
+
+        assert_eq!(data, vec!["test", "test2"]);
+    }
+
+    #[test]
+    fn applies_deletes_from_update() {
+        let mut doc = Document::with_client_id(1);
+        doc.push("a".to_owned());
+        doc.push("b".to_owned());
+        doc.push("c".to_owned());
+        doc.remove(1);
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc).apply(&mut doc2).unwrap();
+
+        assert_eq!(doc2.store.iter_values().collect::<Vec<_>>(), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn applies_delete_only_update() {
+        let mut doc = Document::with_client_id(1);
+        doc.push("a".to_owned());
+        doc.push("b".to_owned());
+        doc.push("c".to_owned());
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc).apply(&mut doc2).unwrap();
+
+        doc.remove(1);
+
+        let delete_only: Update<String> =
+            Update::from_sequence(vec![], vec![], DeleteSet::from(&doc));
+        delete_only.apply(&mut doc2).unwrap();
+
+        assert_eq!(doc2.store.iter_values().collect::<Vec<_>>(), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn incremental_update_contains_only_new_blocks() {
+        let mut doc1 = Document::with_client_id(1);
+        doc1.push("a".to_owned());
+
+        let mut doc2 = Document::with_client_id(2);
+        doc2.push("b".to_owned());
+
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+        Update::from_document_since(&doc2, &doc1.clients)
+            .apply(&mut doc1)
+            .unwrap();
+
+        doc1.push("c".to_owned());
+
+        let update = Update::from_document_since(&doc1, &doc2.clients);
+
+        assert_eq!(update.blocks.len(), 1);
+        assert_eq!(update.blocks[0].1.len(), 1);
+        assert!(update.dependency.contains(&(1, ClockRange::from(1..2))));
+        assert!(update.dependency.contains(&(2, ClockRange::from(1..1))));
+
+        update.apply(&mut doc2).unwrap();
+
+        assert_eq!(
+            doc1.store.iter_values().collect::<Vec<_>>(),
+            doc2.store.iter_values().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn incremental_update_is_empty_when_up_to_date() {
+        let mut doc = Document::with_client_id(1);
+        doc.push("a".to_owned());
+
+        let update = Update::from_document_since(&doc, &doc.clients);
+
+        assert!(update.blocks.is_empty());
+        assert_eq!(update.dependency, vec![(1, ClockRange::from(1..1))]);
+    }
+
+    #[test]
+    fn deleted_blocks_are_sent_as_tombstones() {
+        let mut doc = Document::with_client_id(1);
+        doc.push("deleted".to_owned());
+        doc.push("live".to_owned());
+        doc.remove(0);
+
+        let update = Update::from_document(&doc);
+
+        assert_eq!(update.blocks[0].1[0].value, Content::Deleted(1));
+        assert_eq!(
+            update.blocks[0].1[1].value,
+            Content::Value(vec!["live".to_owned()])
+        );
+
+        let mut doc2 = Document::with_client_id(2);
+        update.apply(&mut doc2).unwrap();
+
+        let tombstone = &doc2.store[BlockId::new(1, 0)];
+        assert!(tombstone.deleted);
+        assert_eq!(tombstone.length, 1);
+        assert!(tombstone.value.is_empty());
+
+        assert_eq!(doc2.store.iter_values().collect::<Vec<_>>(), vec!["live"]);
+    }
+
+    #[test]
+    fn merged_blocks_round_trip() {
+        let mut doc = Document::with_client_id(1);
+        for value in ["a", "b", "c", "d", "e"] {
+            doc.push(value.to_owned());
+        }
+        doc.remove(1);
+        doc.insert(3, "x".to_owned());
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc).apply(&mut doc2).unwrap();
+
+        assert_eq!(
+            doc2.store.iter_values().collect::<Vec<_>>(),
+            vec!["a", "c", "d", "x", "e"]
+        );
+        assert_eq!(doc2.state_vector(), doc.state_vector());
+    }
+
+    #[test]
+    fn compacts_sequential_blocks() {
+        let blocks = (0..5u64)
+            .map(|clock| {
+                let origin_left = clock.checked_sub(1).map(|clock| BlockId::new(1, clock));
+
+                Block::with_value_and_right(clock, origin_left, None, clock.to_string())
+            })
+            .collect();
+
+        let update = Update::from_blocks(1, blocks, vec![(1, 0..5)]).compact();
+
+        assert_eq!(update.blocks[0].1.len(), 1);
+        assert_eq!(update.blocks[0].1[0].length(), 5);
+
+        let mut doc = Document::with_client_id(2);
+        update.apply(&mut doc).unwrap();
+
+        assert_eq!(
+            doc.store.iter_values().collect::<Vec<_>>(),
+            vec!["0", "1", "2", "3", "4"]
+        );
+    }
+
+    #[test]
+    fn compacts_adjacent_tombstones() {
+        let mut doc = Document::with_client_id(1);
+        for value in ["a", "b", "c", "d", "e"] {
+            doc.push(value.to_owned());
+        }
+        doc.remove(1);
+        doc.remove(1);
+
+        let update = Update::from_document(&doc);
+
+        assert_eq!(
+            update.blocks[0]
+                .1
+                .iter()
+                .map(|block| block.value.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                Content::Value(vec!["a".to_owned()]),
+                Content::Deleted(2),
+                Content::Value(vec!["d".to_owned(), "e".to_owned()])
+            ]
+        );
+
+        let mut doc2 = Document::with_client_id(2);
+        update.apply(&mut doc2).unwrap();
+
+        assert_eq!(
+            doc2.store.iter_values().collect::<Vec<_>>(),
+            vec!["a", "d", "e"]
+        );
+    }
+
+    #[test]
+    fn does_not_compact_blocks_inserted_in_between() {
+        let mut doc = Document::with_client_id(1);
+        doc.push("a".to_owned());
+        doc.push("c".to_owned());
+        doc.insert(1, "b".to_owned());
+
+        let update = Update::from_document(&doc);
+
+        // `a` and `c` were split apart locally but are still one run on the wire
+        assert_eq!(
+            update.blocks[0]
+                .1
+                .iter()
+                .map(|block| block.value.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                Content::Value(vec!["a".to_owned(), "c".to_owned()]),
+                Content::Value(vec!["b".to_owned()])
+            ]
+        );
+
+        let mut doc2 = Document::with_client_id(2);
+        update.apply(&mut doc2).unwrap();
+
+        assert_eq!(
+            doc2.store.iter_values().collect::<Vec<_>>(),
+            vec!["a", "b", "c"]
+        );
+    }
+
+    #[test]
+    fn can_validate_empty_doc() {
+        let valid_update: Update<String> =
+            Update::from_sequence(vec![], vec![], DeleteSet::empty());
+
+        assert_eq!(valid_update.validate(), Ok(()));
+    }
+
+    #[test]
+    fn throws_error_if_client_doesnt_exist_range() {
+        let valid_update: Update<String> =
+            Update::from_sequence(vec![], vec![(1, vec![])], DeleteSet::empty());
+
+        assert_eq!(
+            valid_update.validate(),
+            Err(ValidationError::ClientDoesNotExist(1))
+        );
+    }
+
+    #[test]
+    fn origin_from_undeclared_client_is_a_dependency() {
+        let valid_update: Update<String> = Update::from_sequence(
+            vec![(1, (0..1).into())],
+            vec![(
+                1,
+                vec![UpdateBlock::with_value(
+                    Some(BlockId::new(2, 0)),
+                    None,
+                    "Test".to_owned(),
+                )],
+            )],
+            DeleteSet::empty(),
+        );
+
+        // The origin has to be in the document already, which makes it a dependency
+        assert_eq!(valid_update.validate(), Ok(()));
+        assert_eq!(
+            valid_update.required_state(),
+            [(2, Clock::new(1))].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn throws_error_if_update_outside_range_left() {
+        let valid_update: Update<String> = Update::from_sequence(
+            vec![(1, (0..1).into()), (2, (0..0).into())],
+            vec![(
+                1,
+                vec![UpdateBlock::with_value(
+                    Some(BlockId::new(2, 1)),
+                    None,
+                    "Test".to_owned(),
+                )],
+            )],
+            DeleteSet::empty(),
+        );
+
+        assert_eq!(
+            valid_update.validate(),
+            Err(ValidationError::UpdateOutsideRange(BlockId::new(2, 1)))
+        );
+    }
+
+    #[test]
+    fn throws_error_if_origin_is_at_the_end_of_its_range() {
+        let valid_update: Update<String> = Update::from_sequence(
+            vec![(1, (0..1).into()), (2, (0..1).into())],
+            vec![(
+                1,
+                vec![UpdateBlock::with_value(
+                    Some(BlockId::new(2, 1)),
+                    None,
+                    "Test".to_owned(),
+                )],
+            )],
+            DeleteSet::empty(),
+        );
+
+        // Ranges are exclusive, so client 2's elements end at clock 0
+        assert_eq!(
+            valid_update.validate(),
+            Err(ValidationError::UpdateOutsideRange(BlockId::new(2, 1)))
+        );
+    }
+
+    #[test]
+    fn throws_error_if_update_outside_range_right() {
+        let valid_update: Update<String> = Update::from_sequence(
+            vec![(1, (0..1).into()), (2, (0..0).into())],
+            vec![(
+                1,
+                vec![UpdateBlock::with_value(
+                    None,
+                    Some(BlockId::new(2, 1)),
+                    "Test".to_owned(),
+                )],
+            )],
+            DeleteSet::empty(),
+        );
+
+        assert_eq!(
+            valid_update.validate(),
+            Err(ValidationError::UpdateOutsideRange(BlockId::new(2, 1)))
+        );
+    }
+
+    #[test]
+    fn incremental_updates_only_send_new_deletions() {
+        let mut doc1 = Document::with_client_id(1);
+        for value in ["a", "b", "c", "d", "e"] {
+            doc1.push(value.to_owned());
+        }
+        doc1.remove(0);
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+
+        doc1.remove(2);
+
+        let update = Update::from_document_since_deletes(
+            &doc1,
+            doc2.state_vector().as_ref(),
+            &DeleteSet::from(&doc2),
+        );
+        assert_eq!(
+            update.deletes.iter().collect::<Vec<_>>(),
+            vec![&BlockRange::new(1, 3..4)]
+        );
+
+        update.apply(&mut doc2).unwrap();
+        assert_eq!(doc2.to_vec(), vec!["b", "c", "e"]);
+    }
+
+    #[test]
+    fn rejects_absurd_deleted_lengths_before_touching_the_document() {
+        let forged: Update<String> = Update::from_sequence(
+            vec![(1, (0..u64::MAX).into())],
+            vec![(
+                1,
+                vec![UpdateBlock {
+                    origin_left: None,
+                    origin_right: None,
+                    value: Content::Deleted(u64::MAX),
+                    priority: 0,
+                }],
+            )],
+            DeleteSet::empty(),
+        );
+
+        let mut document: Document<String> = Document::with_client_id(2);
+        let decoded = document.decode_update(&forged.encode().unwrap()).unwrap();
+
+        assert_eq!(
+            decoded.apply(&mut document),
+            Err(ApplyError::LimitExceeded(Limit::DeletedLength))
+        );
+        assert!(document.state_vector().as_ref().is_empty());
+        assert_eq!(document.blocks().count(), 0);
+    }
+
+    #[test]
+    fn rejects_updates_beyond_the_documents_limits() {
+        let mut source = Document::with_client_id(1);
+        for value in ["a", "b", "c"] {
+            source.push(value.to_owned());
+        }
+        source.remove(1);
+        let update = Update::from_document(&source);
+
+        let mut small =
+            Document::with_client_id(2).with_limits(Limits::default().with_max_elements(2));
+        assert_eq!(
+            small.apply_or_queue(update.clone()),
+            Err(ApplyError::LimitExceeded(Limit::Elements))
+        );
+
+        let mut few_blocks = Document::with_client_id(2)
+            .with_limits(Limits::default().with_max_blocks_per_client(2));
+        assert_eq!(
+            update.clone().apply(&mut few_blocks),
+            Err(ApplyError::LimitExceeded(Limit::BlocksPerClient))
+        );
+
+        let mut roomy = Document::with_client_id(2).with_limits(
+            Limits::default()
+                .with_max_elements(3)
+                .with_max_blocks_per_client(3),
+        );
+        update.apply(&mut roomy).unwrap();
+        assert_eq!(roomy.to_vec(), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn rejects_oversized_payloads_before_decoding() {
+        let mut source = Document::with_client_id(1);
+        source.push("a".repeat(100));
+        let encoded = Update::from_document(&source).encode().unwrap();
+
+        let document: Document<String> =
+            Document::with_client_id(2).with_limits(Limits::default().with_max_update_bytes(64));
+
+        assert_eq!(
+            document.decode_update(&encoded),
+            Err(DecodeError::LimitExceeded(Limit::UpdateBytes))
+        );
+    }
+
+    #[test]
+    fn forged_collection_lengths_dont_reserve_memory() {
+        // A version, then a dependency list claiming 2^40 entries
+        let mut forged = vec![FORMAT_VERSION as u8, 253];
+        forged.extend((1u64 << 40).to_le_bytes());
+
+        assert_eq!(
+            Update::<String>::decode(&forged),
+            Err(DecodeError::Malformed(
+                bincode::error::DecodeError::LimitExceeded
+            ))
+        );
+    }
+
+    #[test]
+    fn rejects_self_referential_origins() {
+        let update: Update<String> = Update::from_sequence(
+            vec![(1, (0..1).into())],
+            vec![(
+                1,
+                vec![UpdateBlock::with_value(
+                    Some(BlockId::new(1, 0)),
+                    None,
+                    "Test".to_owned(),
+                )],
+            )],
+            DeleteSet::empty(),
+        );
+
+        assert_eq!(
+            update.validate(),
+            Err(ValidationError::FutureOrigin(BlockId::new(1, 0)))
+        );
+
+        let mut document = Document::with_client_id(2);
+        assert_eq!(
+            update.apply(&mut document),
+            Err(ApplyError::FutureOrigin(BlockId::new(1, 0)))
+        );
+        assert!(document.is_empty());
+    }
+
+    #[test]
+    fn applies_the_clients_whose_dependencies_are_met() {
+        let mut doc1 = Document::with_client_id(1);
+        let first = doc1.transact(|transaction| {
+            transaction.push("a".to_owned());
+        });
+        doc1.push("b".to_owned());
+
+        let mut doc2 = Document::with_client_id(2);
+        doc2.push("x".to_owned());
+
+        let mut source = Document::with_client_id(3);
+        Update::from_document(&doc1).apply(&mut source).unwrap();
+        Update::from_document(&doc2).apply(&mut source).unwrap();
+        source.remove(0);
+        source.remove(1);
+
+        // Built as if the receiver already had "a", which it doesn't
+        let mut receiver = Document::with_client_id(4);
+        let since: ClockVector = [(1, Clock::new(1))].into_iter().collect();
+        let update = Update::from_document_since(&source, &since);
+
+        assert!(matches!(
+            update.clone().apply(&mut receiver),
+            Err(ApplyError::MissingDependency { client_id: 1, .. })
+        ));
+
+        let outcome = update.apply_partial(&mut receiver).unwrap();
+
+        // "x" and its deletion are in, while "b" and the deletion of "a" wait
+        assert_eq!(outcome.applied_clients, vec![2]);
+        assert!(receiver.is_empty());
+        assert_eq!(receiver.state_vector().clock(2), Clock::new(1));
+        assert_eq!(receiver.state_vector().clock(1), Clock::new(0));
+
+        let remainder = outcome.remainder.unwrap();
+        assert!(matches!(
+            remainder.clone().apply_partial(&mut receiver),
+            Ok(ApplyOutcome {
+                remainder: Some(_),
+                ..
+            })
+        ));
+
+        first.apply(&mut receiver).unwrap();
+        assert_eq!(receiver.iter().collect::<Vec<_>>(), vec!["a"]);
+
+        let outcome = remainder.apply_partial(&mut receiver).unwrap();
+        assert_eq!(outcome.applied_clients, vec![1]);
+        assert_eq!(outcome.remainder, None);
+        assert!(receiver.content_eq(&source));
+        assert_eq!(receiver.iter().collect::<Vec<_>>(), vec!["b"]);
+    }
+
+    #[test]
+    fn holds_back_clients_inserted_next_to_held_back_clients() {
+        let mut doc1 = Document::with_client_id(1);
+        let first = doc1.transact(|transaction| {
+            transaction.push("a".to_owned());
+        });
+        doc1.push("b".to_owned());
+
+        // Client 2 inserts after "b", which the receiver can't have yet
+        let mut doc2 = Document::with_client_id(2);
+        Update::from_document(&doc1).apply(&mut doc2).unwrap();
+        doc2.push("c".to_owned());
+
+        let mut receiver = Document::with_client_id(3);
+        let since: ClockVector = [(1, Clock::new(1))].into_iter().collect();
+        let outcome = Update::from_document_since(&doc2, &since)
+            .apply_partial(&mut receiver)
+            .unwrap();
+
+        assert!(outcome.applied_clients.is_empty());
+        assert!(receiver.is_empty());
+
+        first.apply(&mut receiver).unwrap();
+        let outcome = outcome
+            .remainder
+            .unwrap()
+            .apply_partial(&mut receiver)
+            .unwrap();
+
+        assert_eq!(outcome.applied_clients, vec![1, 2]);
+        assert!(receiver.content_eq(&doc2));
+    }
+
+    #[test]
+    fn rejects_marks_on_unknown_elements() {
+        let mut source = Document::with_client_id(1);
+        source.push("a".to_owned());
+        source.add_mark(0..1, "bold", vec![1]);
+
+        // Only the marks, claiming no dependencies
+        let update: Update<String> = Update {
+            marks: source.marks.marks(),
+            ..Update::from_sequence(vec![], vec![], DeleteSet::empty())
+        };
+
+        let mut document = Document::with_client_id(2);
+        assert_eq!(
+            update.clone().apply(&mut document),
+            Err(ApplyError::MissingOrigin(BlockId::new(1, 0)))
+        );
+
+        Update::from_document(&source).apply(&mut document).unwrap();
+        update.apply(&mut document).unwrap();
+        assert_eq!(document.marks_at(0).count(), 1);
+    }
+
+    #[test]
+    fn marks_anchored_outside_the_main_sequence_are_never_shown() {
+        let mut source = Document::with_client_id(1);
+        source.push("a".to_owned());
+        source.get_or_create_root("notes").push("b".to_owned());
+        source.add_mark(0..1, "bold", vec![1]);
+
+        let mut update = Update::from_document(&source);
+        update.marks[0].start = BlockId::new(1, 1);
+        update.marks[0].end = BlockId::new(1, 1);
+
+        // Whether or not the update is accepted, looking up marks mustn't fail on it
+        let mut document = Document::with_client_id(2);
+        let _ = update.apply(&mut document);
+
+        assert_eq!(document.marks_at(0).count(), 0);
+    }
+
+    #[test]
+    fn rejects_marks_and_replaced_values_on_elements_of_named_roots() {
+        let mut source = Document::with_client_id(1);
+        source.push("a".to_owned());
+        source.get_or_create_root("notes").push("b".to_owned());
+        source.add_mark(0..1, "bold", vec![1]);
+        source.set(0, "c".to_owned());
+
+        let in_root = BlockId::new(1, 1);
+        let mut marked = Update::from_document(&source);
+        marked.marks[0].end = in_root;
+        let mut replaced = Update::from_document(&source);
+        replaced.registers[0].element = in_root;
+
+        for update in [marked, replaced] {
+            let mut document = Document::with_client_id(2);
+            assert_eq!(
+                update.clone().apply(&mut document),
+                Err(ApplyError::MissingOrigin(in_root))
+            );
+
+            // Nor once the root's element is already in the document
+            Update::from_document(&source).apply(&mut document).unwrap();
+            assert_eq!(
+                document.apply_encoded(&update.encode().unwrap()),
+                Err(ApplyEncodedError::Apply(ApplyError::MissingOrigin(in_root)))
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_writes_at_the_largest_clock() {
+        let mut source = Document::with_client_id(1);
+        source.push("a".to_owned());
+        source.set(0, "b".to_owned());
+        source.map_set("key", "value".to_owned());
+
+        let mut document = Document::with_client_id(2);
+        Update::from_document(&source).apply(&mut document).unwrap();
+
+        let mut register = Update::from_document(&source);
+        register.registers[0].clock = Clock::MAX;
+        let mut entry = Update::from_document(&source);
+        entry.map[0].clock = Clock::MAX;
+
+        for update in [register, entry] {
+            assert_eq!(
+                document.apply_encoded(&update.encode().unwrap()),
+                Err(ApplyEncodedError::Apply(ApplyError::WriteClockOverflow(1)))
+            );
+            assert_eq!(
+                update.apply(&mut document),
+                Err(ApplyError::WriteClockOverflow(1))
+            );
+        }
+
+        // Local writes still tick past everything the document accepted
+        document.set(0, "c".to_owned());
+        document.map_set("key", "other".to_owned());
+        Update::from_document(&document).apply(&mut source).unwrap();
+
+        assert_eq!(source.to_vec(), vec!["c"]);
+        assert_eq!(source.map_get("key"), Some(&"other".to_owned()));
+    }
+
+    #[test]
+    fn rejects_origins_later_in_the_same_update() {
+        let update: Update<String> = Update::from_sequence(
+            vec![(1, (0..2).into())],
+            vec![(
+                1,
+                vec![
+                    UpdateBlock::with_value(None, Some(BlockId::new(1, 1)), "a".to_owned()),
+                    UpdateBlock::with_value(None, None, "b".to_owned()),
+                ],
+            )],
+            DeleteSet::empty(),
+        );
+
+        assert_eq!(
+            update.validate(),
+            Err(ValidationError::FutureOrigin(BlockId::new(1, 1)))
+        );
+    }
+
+    #[test]
+    fn corrupt_origins_never_hang() {
+        for seed in 0..20 {
+            let (sender, receiver) = mpsc::channel();
+
+            thread::spawn(move || {
+                let mut rng = StdRng::seed_from_u64(seed);
+                let mut source = Document::with_client_id(9);
+                for update in random_updates(seed, 20) {
+                    update.apply(&mut source).unwrap();
+                }
+
+                let mut update = Update::from_document(&source);
+                let clients: Vec<(ClientId, Clock)> = update
+                    .dependency
+                    .iter()
+                    .map(|(client_id, range)| (*client_id, range.end))
+                    .collect();
+                let client = rng.gen_range(0, update.blocks.len());
+                let (_, blocks) = &mut update.blocks[client];
+                let index = rng.gen_range(0, blocks.len());
+                let block = &mut blocks[index];
+                let (client_id, end) = clients[rng.gen_range(0, clients.len())];
+                let origin = Some(BlockId::new(client_id, rng.gen_range(0, end.get() + 1)));
+
+                if rng.gen() {
+                    block.origin_left = origin;
+                } else {
+                    block.origin_right = origin;
+                }
+
+                let mut document = Document::with_client_id(10);
+                let result = update.apply(&mut document);
+                sender
+                    .send(result.map(|()| document.iter().count() == document.len()))
+                    .unwrap();
+            });
+
+            match receiver.recv_timeout(Duration::from_secs(10)) {
+                Ok(Ok(consistent)) => assert!(consistent, "seed {}", seed),
+                Ok(Err(_)) => {}
+                Err(error) => panic!("seed {} didn't finish: {}", seed, error),
+            }
+        }
+    }
+
+    #[test]
+    fn validate_ok_if_valid_update() {
+        let valid_update: Update<String> = Update::from_sequence(
+            vec![(1, (0..1).into()), (2, (0..1).into())],
+            vec![(
+                1,
+                vec![UpdateBlock::with_value(
+                    Some(BlockId::new(2, 0)),
+                    None,
+                    "Test".to_owned(),
+                )],
+            )],
+            DeleteSet::empty(),
+        );
+
+        assert_eq!(valid_update.validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_apply_serialized_update() {
+        let mut document = Document::with_client_id(1);
+        document.store.append("Test".to_owned());
+        document.store.append("Test 2".to_owned());
+        document.store.append("Test 3".to_owned());
+        document.store.delete_range(0, 2);
+
+        let update = Update::from_document(&document);
+
+        let encoded_update = update.encode().unwrap();
+        let decoded_update = Update::<String>::decode(&encoded_update).unwrap();
+
+        assert_eq!(update, decoded_update);
+        // 31 bytes before blocks were written against a client table
+        assert!(encoded_update.len() <= 28, "{} bytes", encoded_update.len());
+        assert!(encode_version_7(&update).len() >= 31);
+
+        let mut bad_version = encoded_update.clone();
+        bad_version[0] = FORMAT_VERSION as u8 + 1;
+
+        assert_eq!(
+            Update::<String>::decode(&bad_version),
+            Err(DecodeError::UnsupportedVersion {
+                found: FORMAT_VERSION + 1,
+                supported: FORMAT_VERSION
+            })
+        );
+
+        // Versions are varints, so can grow past a single byte
+        let mut wide_version = vec![251, 0xe8, 0x03];
+        wide_version.extend(&encoded_update[1..]);
+
+        assert_eq!(
+            Update::<String>::decode(&wide_version),
+            Err(DecodeError::UnsupportedVersion {
+                found: 1000,
+                supported: FORMAT_VERSION
+            })
+        );
+        assert!(matches!(
+            Update::<String>::decode(&encoded_update[..encoded_update.len() - 3]),
+            Err(DecodeError::Malformed(_))
+        ));
+        assert_eq!(
+            Update::<String>::decode(&[]),
+            Err(DecodeError::MissingVersion)
+        );
+
+        let mut trailing = encoded_update;
+        trailing.push(0);
+
+        assert_eq!(
+            Update::<String>::decode(&trailing),
+            Err(DecodeError::TrailingBytes(1))
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn validate_apply_json_update() {
+        let mut document = Document::with_client_id(1);
+        document.store.append("Test".to_owned());
+        document.store.append("Test 2".to_owned());
+        document.store.append("Test 3".to_owned());
+        document.store.delete_range(0, 2);
+
+        let update = Update::from_document(&document);
+
+        let encoded_update = serde_json::to_string(&update).unwrap();
+        let decoded_update: Update<String> = serde_json::from_str(&encoded_update).unwrap();
+
+        assert_eq!(update, decoded_update);
+
+        let mut other = Document::with_client_id(2);
+        decoded_update.apply(&mut other).unwrap();
+
+        assert_eq!(
+            other.store.iter_values().collect::<Vec<_>>(),
+            vec!["Test 3"]
+        );
+    }
+
+    #[test]
+    fn encoded_update_byte_layout_is_stable() {
+        // A client id wide enough to force a full little-endian u64 in the varint encoding
+        let mut document = Document::with_client_id(0x0102_0304_0506_0708);
+        document.store.append("a".to_owned());
+        document.store.append("b".to_owned());
+        document.store.delete_range(0, 1);
+
+        let configuration = config::standard().with_little_endian();
+        let encoded_update =
+            encode_to_vec(Update::from_document(&document), configuration).unwrap();
+
+        assert_eq!(encoded_update, ENCODED_UPDATE_FIXTURE);
+
+        let (decoded_update, _): (Update<String>, usize) =
+            decode_from_slice(ENCODED_UPDATE_FIXTURE, configuration).unwrap();
+
+        assert_eq!(decoded_update, Update::from_document(&document));
+
+        // A client with nothing deleted adds its blocks but no entry to the deletes
+        let mut other = Document::with_client_id(2);
+        other.push("c".to_owned());
+        Update::from_document(&other).apply(&mut document).unwrap();
+
+        let update = Update::from_document(&document);
+        let deletes_length = encode_to_vec(update.deletes(), configuration)
+            .unwrap()
+            .len();
+
+        assert_eq!(deletes_length, 13);
+    }
+
+    #[test]
+    fn encodes_the_same_document_to_the_same_bytes() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut docs: Vec<Document<String>> = (1..=6).map(Document::with_client_id).collect();
+
+        for step in 0..60 {
+            let doc = &mut docs[step % 6];
+            let index = rng.gen_range(0, doc.len() + 1);
+            doc.insert(index, step.to_string());
+
+            if step % 4 == 0 {
+                doc.remove(rng.gen_range(0, doc.len()));
+            }
+
+            let (from, to) = (rng.gen_range(0, 6), rng.gen_range(0, 6));
+            if from != to {
+                Update::from_document_since(&docs[from], docs[to].state_vector().as_ref())
+                    .apply(&mut docs[to])
+                    .unwrap();
+            }
+        }
+
+        let document = &docs[0];
+        let update = Update::from_document(document).encode().unwrap();
+        let full_update = document.encode_full_update().unwrap();
+
+        // Restoring rebuilds the document's maps, with new hashers and so new iteration orders
+        for _ in 0..5 {
+            let restored = Document::restore(document.snapshot());
+
+            assert_eq!(Update::from_document(&restored).encode().unwrap(), update);
+            assert_eq!(restored.encode_full_update().unwrap(), full_update);
+        }
+    }
+
+    /// Makes `steps` random edits across three documents, sending each edit's update to the other
+    /// documents straight away, and returns every update in the order it was made.
+    fn random_updates(seed: u64, steps: usize) -> Vec<Update<usize>> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut docs: Vec<Document<usize>> = (1..=3).map(Document::with_client_id).collect();
+        let mut updates = vec![];
+
+        for step in 0..steps {
+            let doc = rng.gen_range(0, docs.len());
+            let len = docs[doc].len();
+
+            let update = docs[doc].transact(|transaction| match rng.gen_range(0, 4) {
+                0 if len > 0 => {
+                    let index = rng.gen_range(0, len);
+                    transaction.remove_range(index, rng.gen_range(1, (len - index).min(3) + 1));
+                }
+                _ => {
+                    let index = rng.gen_range(0, len + 1);
+                    for offset in 0..rng.gen_range(1, 4) {
+                        transaction.insert(index + offset, step);
+                    }
+                }
+            });
+
+            for (other, document) in docs.iter_mut().enumerate() {
+                if other != doc {
+                    update.clone().apply(document).unwrap();
+                }
+            }
+
+            updates.push(update);
+        }
+
+        updates
+    }
+
+    #[test]
+    fn merged_update_is_equivalent_to_applying_each_in_order() {
+        for seed in 0..10 {
+            let updates = random_updates(seed, 30);
+
+            let mut sequential = Document::with_client_id(9);
+            for update in updates.clone() {
+                update.apply(&mut sequential).unwrap();
+            }
+
+            let merged = updates
+                .into_iter()
+                .reduce(|merged, update| merged.merge(update).unwrap())
+                .unwrap();
+
+            let mut coalesced = Document::with_client_id(9);
+            merged.apply(&mut coalesced).unwrap();
+
+            assert_eq!(coalesced.to_vec(), sequential.to_vec(), "seed {}", seed);
+            assert_eq!(coalesced.state_vector(), sequential.state_vector());
+        }
+    }
+
+    #[test]
+    fn merges_updates_which_overlap() {
+        let mut doc = Document::with_client_id(1);
+        doc.push("a".to_owned());
+        doc.push("b".to_owned());
+        let first = Update::from_document(&doc);
+
+        doc.insert(1, "c".to_owned());
+        doc.remove(0);
+        let second = Update::from_document(&doc);
+
+        let mut other = Document::with_client_id(2);
+        first.merge(second).unwrap().apply(&mut other).unwrap();
+
+        assert_eq!(other.to_vec(), doc.to_vec());
+    }
+
+    #[test]
+    fn merging_updates_with_a_gap_fails() {
+        let mut doc = Document::with_client_id(1);
+        let first = doc.transact(|transaction| {
+            transaction.push("a".to_owned());
+        });
+        doc.push("b".to_owned());
+        let third = doc.transact(|transaction| {
+            transaction.push("c".to_owned());
+        });
+
+        assert_eq!(
+            first.merge(third),
+            Err(MergeError::NonContiguousRanges {
+                client_id: 1,
+                first: ClockRange::from(0..1),
+                second: ClockRange::from(2..3),
+            })
+        );
+    }
+
+    const ENCODED_UPDATE_FIXTURE: &[u8] = &[
+        1, 253, 8, 7, 6, 5, 4, 3, 2, 1, 0, 2, 1, 253, 8, 7, 6, 5, 4, 3, 2, 1, 1, 0, 0, 2, 4, 1, 9,
+        1, 1, 98, 1, 253, 8, 7, 6, 5, 4, 3, 2, 1, 1, 0, 1, 0,
+    ];
+
+    /// The body of [`ENCODED_UPDATE_FIXTURE`]'s update as format version 7 wrote it, with each
+    /// block's origins and content tagged and client ids written out in full.
+    const VERSION_7_FIXTURE: &[u8] = &[
+        1, 253, 8, 7, 6, 5, 4, 3, 2, 1, 0, 2, 1, 253, 8, 7, 6, 5, 4, 3, 2, 1, 2, 0, 0, 1, 1, 1,
+        253, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 0, 1, 1, 98, 1, 253, 8, 7, 6, 5, 4, 3, 2, 1, 1, 0, 1, 0,
+    ];
+
+    /// Encodes `update` as format version 7 did, before blocks were written against a client
+    /// table.
+    fn encode_version_7(update: &Update<String>) -> Vec<u8> {
+        struct UpdateV7<'a>(&'a Update<String>);
+
+        impl Encode for UpdateV7<'_> {
+            fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+                let update = self.0;
+
+                update.dependency.encode(encoder)?;
+                update.blocks.encode(encoder)?;
+                update.deletes.encode(encoder)?;
+
+                encode_extensions(
+                    encoder,
+                    ExtensionParts {
+                        map: &update.map,
+                        marks: &update.marks,
+                        priorities: &update.priorities(),
+                        moves: &update.moves,
+                        deletions: &update.deletions,
+                        roots: &update.roots,
+                        registers: &update.registers,
+                        checkpoint: &update.checkpoint,
+                        censored: &update.censored,
+                    },
+                )
+            }
+        }
+
+        let mut bytes = encode_to_vec(7u32, config::standard()).unwrap();
+        bytes.extend(encode_to_vec(UpdateV7(update), config::standard()).unwrap());
+
+        bytes
+    }
+
+    #[test]
+    fn decodes_updates_written_by_version_7() {
+        let mut document = Document::with_client_id(0x0102_0304_0506_0708);
+        document.store.append("a".to_owned());
+        document.store.append("b".to_owned());
+        document.store.delete_range(0, 1);
+
+        let mut encoded_update = vec![7];
+        encoded_update.extend(VERSION_7_FIXTURE);
+        assert_eq!(
+            encoded_update,
+            encode_version_7(&Update::from_document(&document))
+        );
+
+        assert_eq!(
+            Update::<String>::decode(&encoded_update),
+            Ok(Update::from_document(&document))
+        );
+
+        let mut other = Document::with_client_id(2);
+        other.apply_encoded(&encoded_update).unwrap();
+        assert!(other.content_eq(&document));
+
+        // Every part of an update survives the older layout
+        let mut source = Document::with_client_id(3).with_priority(4);
+        source.extend(["x", "y", "z"].map(String::from));
+        source.remove(1);
+        source.map_set("title", "notes".to_owned());
+        source.add_mark(0..2, "bold", vec![1]);
+        Update::from_document(&source).apply(&mut other).unwrap();
+        other.insert(1, "w".to_owned());
+
+        let update = Update::from_document(&other);
+        assert_eq!(
+            Update::<String>::decode(&encode_version_7(&update)),
+            Ok(update)
+        );
+    }
+
+    #[test]
+    fn writes_runs_of_edits_more_compactly_than_version_7() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut docs: Vec<Document<String>> = (0..2).map(|_| Document::new()).collect();
+
+        // Two clients with full-width ids typing words, mostly near where they last typed
+        for step in 0..400 {
+            let doc = &mut docs[step % 2];
+            let index = (doc.len() / 2 + rng.gen_range(0, 3)).min(doc.len());
+            doc.insert(index, ((b'a' + (step % 26) as u8) as char).to_string());
+
+            if step % 40 == 39 {
+                let (from, to) = (step % 2, (step + 1) % 2);
+                let update =
+                    Update::from_document_since(&docs[from], docs[to].state_vector().as_ref());
+                update.apply(&mut docs[to]).unwrap();
+            }
+        }
+
+        let update = Update::from_document(&docs[0]);
+        let compact = update.encode().unwrap().len();
+        let legacy = encode_version_7(&update).len();
+
+        assert!(compact * 10 < legacy * 7, "{} vs {} bytes", compact, legacy);
+    }
+
+    /// A random update, which may be malformed, to check encoding round trips whatever it holds.
+    fn random_update(rng: &mut StdRng) -> Update<String> {
+        let clients: Vec<ClientId> = (0..rng.gen_range(1, 5))
+            .map(|_| match rng.gen_range(0, 3) {
+                0 => rng.gen_range(0, 4),
+                1 => rng.gen(),
+                _ => u64::MAX - rng.gen_range(0, 4),
+            })
+            .collect();
+        let random_clock = |rng: &mut StdRng| match rng.gen_range(0, 3) {
+            0 => rng.gen_range(0, 10),
+            1 => rng.gen(),
+            _ => u64::MAX - rng.gen_range(0, 10),
+        };
+
+        let mut dependency = vec![];
+        let mut blocks = vec![];
+
+        for &client_id in &clients {
+            let start = random_clock(rng);
+            let mut client_blocks = vec![];
+
+            for _ in 0..rng.gen_range(0, 5) {
+                let origin = |rng: &mut StdRng| {
+                    rng.gen_bool(0.7).then(|| {
+                        // Mostly clients in the update, sometimes one which isn't
+                        let client_id = match rng.gen_bool(0.8) {
+                            true => *clients.choose(rng).unwrap(),
+                            false => rng.gen(),
+                        };
+
+                        BlockId::new(client_id, random_clock(rng))
+                    })
+                };
+
+                client_blocks.push(UpdateBlock {
+                    origin_left: origin(rng),
+                    origin_right: origin(rng),
+                    value: match rng.gen_bool(0.3) {
+                        true => Content::Deleted(random_clock(rng)),
+                        false => Content::Value(
+                            (0..rng.gen_range(1, 4))
+                                .map(|_| rng.gen_range(0, 1000).to_string())
+                                .collect(),
+                        ),
+                    },
+                    priority: 0,
+                });
+            }
+
+            dependency.push((client_id, ClockRange::from(start..random_clock(rng))));
+            blocks.push((client_id, client_blocks));
+        }
+
+        let mut deletes = DeleteSet::empty();
+        for _ in 0..rng.gen_range(0, 4) {
+            let start = rng.gen_range(0, 100);
+            deletes.insert(
+                *clients.choose(rng).unwrap(),
+                start..start + rng.gen_range(1, 5),
+            );
+        }
+
+        Update::from_sequence(dependency, blocks, deletes)
+    }
+
+    #[test]
+    fn random_updates_round_trip_through_the_encoding() {
+        let mut rng = StdRng::seed_from_u64(11);
+
+        for _ in 0..500 {
+            let update = random_update(&mut rng);
+            let encoded_update = update.encode().unwrap();
+
+            assert_eq!(encoded_update.len(), update.encoded_size_hint());
+            assert_eq!(Update::<String>::decode(&encoded_update), Ok(update));
+        }
+
+        // And updates of real documents, which compact their blocks into runs
+        let mut docs: Vec<Document<String>> = (1..=3).map(Document::with_client_id).collect();
+
+        for step in 0..300 {
+            let doc = &mut docs[step % 3];
+            let index = rng.gen_range(0, doc.len() + 1);
+            doc.insert(index, step.to_string());
+
+            if step % 3 == 0 {
+                doc.remove(rng.gen_range(0, doc.len()));
+            }
+
+            let (from, to) = (rng.gen_range(0, 3), rng.gen_range(0, 3));
+            let since = docs[to].state_vector();
+            let update = Update::from_document_since(&docs[from], since.as_ref());
+            let decoded = Update::<String>::decode(&update.encode().unwrap()).unwrap();
+
+            assert_eq!(decoded, update);
+            decoded.apply(&mut docs[to]).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_block_lists() {
         let mut document = Document::with_client_id(1);
-        document.store.append("Test".to_owned());
-        document.store.append("Test 2".to_owned());
-        document.store.append("Test 3".to_owned());
-        document.store.delete_range(0, 2);
+        document.push("a".to_owned());
+        document.push("b".to_owned());
+
+        // Version, dependency, table, one client, then its index, start and number of blocks
+        let encoded_update = Update::from_document(&document).encode().unwrap();
+        assert_eq!(
+            encoded_update[..11],
+            [FORMAT_VERSION as u8, 1, 1, 0, 2, 1, 1, 1, 0, 0, 1]
+        );
+
+        let corrupt = |at: usize, byte: u8| {
+            let mut bytes = encoded_update.clone();
+            bytes[at] = byte;
+
+            Update::<String>::decode(&bytes)
+        };
+
+        // A client outside the table, blocks starting somewhere else, and an unknown flag
+        for (at, byte) in [(8, 1), (9, 1), (11, 0x10)] {
+            assert!(matches!(corrupt(at, byte), Err(DecodeError::Malformed(_))));
+        }
+
+        let mut other = Document::<String>::with_client_id(2);
+        let mut bytes = encoded_update.clone();
+        bytes[8] = 1;
+        assert!(matches!(
+            other.apply_encoded(&bytes),
+            Err(ApplyEncodedError::Decode(DecodeError::Malformed(_)))
+        ));
+    }
+
+    #[test]
+    fn decodes_updates_written_before_extensions() {
+        let mut document = Document::with_client_id(1);
+        document.store.append("a".to_owned());
+        document.store.append("b".to_owned());
+        document.store.delete_range(0, 1);
 
         let update = Update::from_document(&document);
 
-        let configuration = config::standard();
-        let encoded_update = encode_to_vec(update.clone(), configuration).unwrap();
-        let (decoded_update, size): (Update<String>, usize) =
-            decode_from_slice(&encoded_update, configuration).unwrap();
+        // Version 3 wrote the map and marks as two lists in place of the extensions, version 2
+        // left out the marks, and version 1 the map too
+        let mut encoded_update = encode_version_7(&update);
+        assert_eq!(encoded_update.pop(), Some(0));
+        encoded_update.extend([0, 0]);
 
-        assert_eq!(update, decoded_update);
-        assert_eq!(encoded_update.len(), 37);
+        for version in (1..=3).rev() {
+            encoded_update[0] = version;
+
+            assert_eq!(
+                Update::<String>::decode(&encoded_update),
+                Ok(update.clone())
+            );
+
+            encoded_update.pop();
+        }
+    }
+
+    /// A full update encoded with format version 3, of a document where client 2 inserted "x"
+    /// into client 1's "abc" and deleted "b", set "title" in the map and made "ax" bold.
+    const VERSION_3_FIXTURE: &[u8] = &[
+        3, 2, 2, 0, 1, 1, 0, 3, 2, 2, 1, 1, 1, 0, 1, 1, 1, 0, 1, 1, 120, 1, 3, 0, 0, 0, 1, 1, 97,
+        1, 1, 0, 0, 1, 1, 1, 1, 1, 0, 0, 1, 1, 99, 2, 2, 0, 1, 1, 1, 1, 1, 5, 116, 105, 116, 108,
+        101, 1, 5, 110, 111, 116, 101, 115, 1, 2, 1, 1, 0, 2, 0, 4, 98, 111, 108, 100, 1, 1, 1, 2,
+    ];
+
+    #[test]
+    fn applies_a_stored_version_3_update() {
+        let update = Update::<String>::decode(VERSION_3_FIXTURE).unwrap();
+
+        let mut document = Document::with_client_id(3);
+        update.clone().apply(&mut document).unwrap();
+
+        assert_eq!(document.iter().collect::<Vec<_>>(), vec!["a", "x", "c"]);
+        assert_eq!(document.map_get("title").map(String::as_str), Some("notes"));
+        assert_eq!(
+            document.marks_at(1).collect::<Vec<_>>(),
+            vec![("bold", &[1][..])]
+        );
+        assert_eq!(document.marks_at(2).count(), 0);
+
+        // Re-encoding in the current format loses nothing
+        let reencoded = Update::<String>::decode(&update.encode().unwrap()).unwrap();
+        assert_eq!(reencoded, update);
+    }
+
+    #[test]
+    fn skips_unknown_extensions() {
+        let mut document = Document::with_client_id(1);
+        document.push("a".to_owned());
+        document.map_set("key", "value".to_owned());
+
+        let update = Update::from_document(&document);
+        let encoded_update = update.encode().unwrap();
+
+        // Only the map extension, which a newer version follows with an extension of id 200
+        let section = encoding::encode_section(&update.map).unwrap();
+        let (body, extensions) = encoded_update.split_at(encoded_update.len() - section.len() - 3);
+        assert_eq!(extensions[..3], [1, 0, section.len() as u8]);
+
+        let mut extended = body.to_vec();
+        extended.push(2);
+        extended.extend(&extensions[1..]);
+        extended.extend([200, 3, 1, 2, 3]);
+
+        assert_eq!(Update::<String>::decode(&extended), Ok(update));
+    }
+
+    #[test]
+    fn hydrate_keeps_deleted_lengths_within_a_single_block() {
+        let block: UpdateBlock<String> = UpdateBlock {
+            origin_left: None,
+            origin_right: None,
+            value: Content::Deleted(u64::from(u32::MAX)),
+            priority: 0,
+        };
+
+        assert_eq!(
+            block.hydrate(Clock::ZERO).map(|b| b.length),
+            Ok(u32::MAX as usize)
+        );
+    }
+
+    #[test]
+    fn hydrates_deleted_content_as_tombstones() {
+        let block: UpdateBlock<String> = UpdateBlock {
+            origin_left: None,
+            origin_right: None,
+            value: Content::Deleted(3),
+            priority: 0,
+        };
+        let block = block.hydrate(Clock::ZERO).unwrap();
+
+        assert!(block.deleted);
+        assert_eq!((block.length, block.live_length()), (3, 0));
+    }
+
+    #[test]
+    fn inserts_beside_received_tombstones_converge() {
+        let mut doc1 = Document::with_client_id(1);
+        doc1.extend(["a", "b", "c", "d", "e"].map(String::from));
+        doc1.remove_range(1, 3);
+
+        let mut doc2 = Document::with_client_id(2);
+        Update::decode(&doc1.encode_full_update().unwrap())
+            .unwrap()
+            .apply(&mut doc2)
+            .unwrap();
+
+        assert_eq!(doc2.len(), 2);
+        assert_eq!(doc2.stats().deleted_elements, 3);
+
+        // Either side of the deleted run, which the new elements' origins point into
+        doc2.insert(1, "x".to_owned());
+        doc2.insert(0, "w".to_owned());
+        doc2.push("y".to_owned());
+
+        Update::from_document_since(&doc2, doc1.state_vector().as_ref())
+            .apply(&mut doc1)
+            .unwrap();
+
+        assert!(doc1.content_eq(&doc2));
+        assert_eq!(doc1.state_fingerprint(), doc2.state_fingerprint());
+        assert_eq!(doc1.iter().map(String::as_str).collect::<String>(), "waxey");
+    }
+
+    #[test]
+    fn encoded_size_hint_matches_the_encoding() {
+        let mut doc = Document::with_client_id(1).with_priority(2);
+        doc.extend(["a", "b", "c"].map(String::from));
+        doc.remove(1);
+        doc.map_set("title", "x".to_owned());
+        doc.add_mark(0..2, "bold", vec![1]);
+
+        let update = Update::from_document(&doc);
+
+        assert_eq!(update.encoded_size_hint(), update.encode().unwrap().len());
+    }
+
+    #[test]
+    fn splits_blocks_which_dont_fit_in_a_chunk() {
+        let mut doc = Document::with_client_id(1);
+        doc.extend((0..1000).map(|value| format!("{:0>20}", value)));
+
+        let chunks = Update::from_document(&doc).split(1024);
+        assert!(chunks.len() > 20);
+
+        let mut replica = Document::with_client_id(2);
+        for chunk in chunks {
+            assert!(chunk.encode().unwrap().len() <= 1024);
+            chunk.apply(&mut replica).unwrap();
+        }
+
+        assert!(replica.content_eq(&doc));
+    }
+
+    #[test]
+    fn split_chunks_keep_blocks_in_their_roots() {
+        let mut doc = Document::with_client_id(1);
+        for value in 0..300 {
+            let value = format!("{:0>20}", value);
+            if value.ends_with('0') {
+                doc.get_or_create_root("tens").push(value);
+            } else {
+                doc.push(value);
+            }
+        }
+
+        let chunks = Update::from_document(&doc).split(512);
+        assert!(chunks.len() > 10);
+
+        let mut replica = Document::with_client_id(2);
+        for chunk in chunks {
+            assert!(chunk.encode().unwrap().len() <= 512);
+            chunk.apply(&mut replica).unwrap();
+        }
+
+        assert!(replica.content_eq(&doc));
+        assert_eq!(replica.len(), 270);
+        assert_eq!(replica.get_or_create_root("tens").len(), 30);
+    }
+
+    #[test]
+    fn split_chunks_apply_in_any_order_through_the_queue() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut docs: Vec<Document<String>> = (1..=3).map(Document::with_client_id).collect();
+
+        // Each client catches up with the last one to edit before making its own edits, so the
+        // clients' blocks refer to each other without any concurrent inserts
+        let steps = 2700;
+
+        for step in 0..steps {
+            let editor = step % 3;
+            let previous = (step + 2) % 3;
+            let update =
+                Update::from_document_since(&docs[previous], docs[editor].state_vector().as_ref());
+            update.apply(&mut docs[editor]).unwrap();
+
+            let doc = &mut docs[editor];
+            let index = rng.gen_range(0, doc.len() + 1);
+            doc.insert(index, format!("{:0>500}", step));
+
+            if step % 5 == 0 {
+                let index = rng.gen_range(0, doc.len());
+                doc.remove(index);
+            }
+        }
+
+        let source = &mut docs[(steps - 1) % 3];
+        source.map_set("title", "chunked".to_owned());
+        source.add_mark(10..20, "bold", vec![1]);
+        let source = &docs[(steps - 1) % 3];
+
+        let update = Update::from_document(source);
+        assert!(update.encoded_size_hint() > 1 << 20);
+
+        let mut chunks = update.split(64 * 1024);
+        assert!(chunks.len() > 16);
+
+        for chunk in &chunks {
+            assert!(chunk.encoded_size_hint() <= 64 * 1024);
+        }
+
+        chunks.shuffle(&mut rng);
+
+        let mut replica = Document::with_client_id(4);
+        for chunk in chunks {
+            let chunk = Update::decode(&chunk.encode().unwrap()).unwrap();
+            replica.apply_or_queue(chunk).unwrap();
+        }
+
+        assert_eq!(replica.pending_updates(), 0);
+        assert!(replica.content_eq(source));
+        assert_eq!(replica.state_fingerprint(), source.state_fingerprint());
+        assert_eq!(replica.map_get("title"), source.map_get("title"));
+        assert_eq!(replica.marks_at(15).count(), 1);
+    }
+
+    #[test]
+    fn replicas_converge_through_local_updates_alone() {
+        for seed in 0..40 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut docs: Vec<Document<String>> = (1..=3).map(Document::with_client_id).collect();
+            let mut inboxes: Vec<Vec<Update<String>>> = vec![vec![]; 3];
+
+            for step in 0..60 {
+                let author = rng.gen_range(0, 3);
+                let doc = &mut docs[author];
+                let before = doc.state_vector();
+
+                match rng.gen_range(0, 10) {
+                    0..=3 => {
+                        let index = rng.gen_range(0, doc.len() + 1);
+                        doc.insert(index, step.to_string());
+                    }
+                    4 if !doc.is_empty() => doc.remove(rng.gen_range(0, doc.len())),
+                    5 | 6 if doc.len() > 2 => {
+                        let start = rng.gen_range(0, doc.len() - 1);
+                        doc.move_range(start..start + 2, rng.gen_range(0, doc.len() + 1));
+                    }
+                    7 if !doc.is_empty() => {
+                        doc.set(rng.gen_range(0, doc.len()), format!("set{}", step));
+                    }
+                    8 if !doc.is_empty() => {
+                        let start = rng.gen_range(0, doc.len());
+                        doc.add_mark(start..doc.len(), "bold", vec![step as u8]);
+                    }
+                    _ => {
+                        doc.map_set(format!("key{}", step % 4), step.to_string());
+                    }
+                }
+
+                let update = doc.take_local_update(before.as_ref());
+                for (index, inbox) in inboxes.iter_mut().enumerate() {
+                    if index != author {
+                        inbox.push(update.clone());
+                    }
+                }
+
+                // A replica takes in some of what it has been sent, in any order
+                let reader = rng.gen_range(0, 3);
+                let inbox = &mut inboxes[reader];
+                inbox.shuffle(&mut rng);
+                let count = rng.gen_range(0, inbox.len() + 1);
+                for update in inbox.drain(..count) {
+                    docs[reader].apply_or_queue(update).unwrap();
+                }
+            }
+
+            for (doc, inbox) in docs.iter_mut().zip(&mut inboxes) {
+                inbox.shuffle(&mut rng);
+                for update in inbox.drain(..) {
+                    doc.apply_or_queue(update).unwrap();
+                }
+            }
+
+            let marks = |doc: &Document<String>| -> Vec<Vec<(String, Vec<u8>)>> {
+                (0..doc.len())
+                    .map(|index| {
+                        doc.marks_at(index)
+                            .map(|(key, value)| (key.to_owned(), value.to_vec()))
+                            .collect()
+                    })
+                    .collect()
+            };
+
+            for doc in &docs[1..] {
+                assert_eq!(doc.to_vec(), docs[0].to_vec(), "seed {}", seed);
+                assert_eq!(marks(doc), marks(&docs[0]), "seed {}", seed);
+                for key in 0..4 {
+                    let key = format!("key{}", key);
+                    assert_eq!(doc.map_get(&key), docs[0].map_get(&key), "seed {}", seed);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encoded_updates_apply_like_decoded_ones() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut docs: Vec<Document<String>> = (1..=3)
+            .map(|id| Document::with_client_id(id).with_priority(id as u8))
+            .collect();
+        let mut decoded: Document<String> = Document::with_client_id(9);
+        let mut encoded = Document::with_client_id(9);
+
+        for step in 0..120 {
+            let doc = &mut docs[step % 3];
+
+            match rng.gen_range(0, 10) {
+                0..=5 => {
+                    let index = rng.gen_range(0, doc.len() + 1);
+                    doc.insert(index, step.to_string());
+                }
+                6 | 7 if !doc.is_empty() => {
+                    let index = rng.gen_range(0, doc.len());
+                    doc.remove(index);
+                }
+                8 if doc.len() > 2 => {
+                    let start = rng.gen_range(0, doc.len() - 1);
+                    doc.move_range(start..start + 2, rng.gen_range(0, doc.len() + 1));
+                    doc.add_mark(0..doc.len().min(3), "bold", vec![step as u8]);
+                }
+                _ => {
+                    doc.map_set(format!("key{}", step % 4), step.to_string());
+                }
+            }
+
+            // Replicas hear from each other now and then, so updates have blocks from several
+            // clients inserted next to each other
+            if step % 7 == 0 {
+                let (from, to) = (rng.gen_range(0, 3), rng.gen_range(0, 3));
+                if from != to {
+                    let update =
+                        Update::from_document_since(&docs[from], docs[to].state_vector().as_ref());
+                    update.apply(&mut docs[to]).unwrap();
+                }
+            }
+
+            if step % 40 == 39 {
+                let source = &docs[rng.gen_range(0, 3)];
+                let update = Update::from_document_since(source, decoded.state_vector().as_ref());
+                let bytes = update.encode().unwrap();
+
+                decoded
+                    .decode_update(&bytes)
+                    .unwrap()
+                    .apply(&mut decoded)
+                    .unwrap();
+                encoded.apply_encoded(&bytes).unwrap();
+
+                assert_eq!(
+                    encoded.iter_with_ids().collect::<Vec<_>>(),
+                    decoded.iter_with_ids().collect::<Vec<_>>()
+                );
+                assert_eq!(encoded.state_vector(), decoded.state_vector());
+                assert_eq!(encoded.state_fingerprint(), decoded.state_fingerprint());
+                assert_eq!(encoded.stats(), decoded.stats());
+            }
+        }
+
+        for index in 0..decoded.len() {
+            assert!(encoded.marks_at(index).eq(decoded.marks_at(index)));
+        }
+
+        for key in 0..4 {
+            let key = format!("key{}", key);
+            assert_eq!(encoded.map_get(&key), decoded.map_get(&key));
+        }
+    }
+
+    #[test]
+    fn rejects_encoded_updates_without_changing_the_document() {
+        let mut doc = Document::with_client_id(3);
+        doc.push("a".to_owned());
+
+        // Client 2 is declared with an empty range, so nothing of theirs can be in the update or
+        // the document
+        let missing_origin: Update<String> = Update::from_sequence(
+            vec![(1, (0..2).into()), (2, (0..0).into())],
+            vec![(
+                1,
+                vec![
+                    UpdateBlock::with_value(None, None, "b".to_owned()),
+                    UpdateBlock::with_value(Some(BlockId::new(2, 0)), None, "c".to_owned()),
+                ],
+            )],
+            DeleteSet::empty(),
+        );
+
+        assert_eq!(
+            doc.apply_encoded(&missing_origin.encode().unwrap()),
+            Err(ApplyEncodedError::Apply(ApplyError::InvalidOrigin(
+                BlockId::new(2, 0)
+            )))
+        );
+
+        let mut source = Document::with_client_id(1);
+        source.push("b".to_owned());
+        source.map_set("key", "value".to_owned());
+        let bytes = Update::from_document(&source).encode().unwrap();
+
+        assert!(matches!(
+            doc.apply_encoded(&bytes[..bytes.len() - 1]),
+            Err(ApplyEncodedError::Decode(DecodeError::Malformed(_)))
+        ));
+
+        assert_eq!(doc.to_vec(), vec!["a"]);
+        assert!(!doc.store.data.contains(1));
+        assert_eq!(doc.map_len(), 0);
+    }
+
+    // Byte-level mutations of a valid update, which must be rejected or applied but never panic
+    #[cfg(not(target_arch = "wasm32"))]
+    mod mutations {
+        use crate::clock::ClockRange;
+        use crate::delete_set::DeleteSet;
+        use crate::document::BlockId;
+        use crate::update::Content;
+        use crate::{Document, Update};
+        use proptest::collection::vec;
+        use proptest::prelude::*;
+
+        #[derive(Clone, Debug)]
+        enum Mutation {
+            Flip { position: usize, bit: u8 },
+            Set { position: usize, byte: u8 },
+            Insert { position: usize, byte: u8 },
+            Remove { position: usize },
+            Truncate { position: usize },
+        }
+
+        fn mutation() -> impl Strategy<Value = Mutation> {
+            prop_oneof![
+                3 => (any::<usize>(), 0..8u8)
+                    .prop_map(|(position, bit)| Mutation::Flip { position, bit }),
+                3 => (any::<usize>(), any::<u8>())
+                    .prop_map(|(position, byte)| Mutation::Set { position, byte }),
+                1 => (any::<usize>(), any::<u8>())
+                    .prop_map(|(position, byte)| Mutation::Insert { position, byte }),
+                1 => any::<usize>().prop_map(|position| Mutation::Remove { position }),
+                1 => any::<usize>().prop_map(|position| Mutation::Truncate { position }),
+            ]
+        }
+
+        fn mutate(bytes: &mut Vec<u8>, mutation: &Mutation) {
+            let len = bytes.len();
+
+            match *mutation {
+                _ if len == 0 => {}
+                Mutation::Flip { position, bit } => bytes[position % len] ^= 1 << bit,
+                Mutation::Set { position, byte } => bytes[position % len] = byte,
+                Mutation::Insert { position, byte } => bytes.insert(position % (len + 1), byte),
+                Mutation::Remove { position } => {
+                    bytes.remove(position % len);
+                }
+                Mutation::Truncate { position } => bytes.truncate(position % len),
+            }
+        }
+
+        /// A change to one of the ids, clocks or lengths of a decoded update, to reach the checks
+        /// behind decoding, which catches most byte mutations.
+        #[derive(Clone, Debug)]
+        enum Perturbation {
+            OriginLeft {
+                index: usize,
+                id: Option<BlockId>,
+            },
+            OriginRight {
+                index: usize,
+                id: Option<BlockId>,
+            },
+            DeletedLength {
+                index: usize,
+                length: u64,
+            },
+            Dependency {
+                index: usize,
+                start: u64,
+                end: u64,
+            },
+            Delete {
+                id: BlockId,
+                length: u64,
+            },
+            Mark {
+                index: usize,
+                start: BlockId,
+                end: BlockId,
+            },
+            Move {
+                index: usize,
+                start: BlockId,
+                marker: BlockId,
+            },
+            Deletion {
+                index: usize,
+                start: BlockId,
+                length: u64,
+            },
+            Root {
+                id: BlockId,
+                length: u64,
+            },
+        }
+
+        fn clock() -> impl Strategy<Value = u64> {
+            prop_oneof![4 => 0..16u64, 1 => u64::MAX - 4..=u64::MAX]
+        }
+
+        fn id() -> impl Strategy<Value = BlockId> {
+            (0..4u64, clock()).prop_map(|(client_id, clock)| BlockId::new(client_id, clock))
+        }
+
+        fn perturbation() -> impl Strategy<Value = Perturbation> {
+            let index = 0..8usize;
+
+            prop_oneof![
+                (index.clone(), proptest::option::of(id()))
+                    .prop_map(|(index, id)| Perturbation::OriginLeft { index, id }),
+                (index.clone(), proptest::option::of(id()))
+                    .prop_map(|(index, id)| Perturbation::OriginRight { index, id }),
+                (index.clone(), clock())
+                    .prop_map(|(index, length)| Perturbation::DeletedLength { index, length }),
+                (index.clone(), clock(), clock()).prop_map(|(index, start, end)| {
+                    Perturbation::Dependency { index, start, end }
+                }),
+                (id(), clock()).prop_map(|(id, length)| Perturbation::Delete { id, length }),
+                (index.clone(), id(), id()).prop_map(|(index, start, end)| Perturbation::Mark {
+                    index,
+                    start,
+                    end
+                }),
+                (index.clone(), id(), id()).prop_map(|(index, start, marker)| {
+                    Perturbation::Move {
+                        index,
+                        start,
+                        marker,
+                    }
+                }),
+                (index, id(), clock()).prop_map(|(index, start, length)| {
+                    Perturbation::Deletion {
+                        index,
+                        start,
+                        length,
+                    }
+                }),
+                (id(), clock()).prop_map(|(id, length)| Perturbation::Root { id, length }),
+            ]
+        }
+
+        fn perturb(update: &mut Update<String>, perturbation: &Perturbation) {
+            let mut blocks = update.blocks.iter_mut().flat_map(|(_, blocks)| blocks);
+
+            match *perturbation {
+                Perturbation::OriginLeft { index, id } => {
+                    if let Some(block) = blocks.nth(index) {
+                        block.origin_left = id;
+                    }
+                }
+                Perturbation::OriginRight { index, id } => {
+                    if let Some(block) = blocks.nth(index) {
+                        block.origin_right = id;
+                    }
+                }
+                Perturbation::DeletedLength { index, length } => {
+                    if let Some(block) = blocks.nth(index) {
+                        block.value = Content::Deleted(length);
+                    }
+                }
+                Perturbation::Dependency { index, start, end } => {
+                    if let Some((_, range)) = update.dependency.get_mut(index) {
+                        *range = ClockRange::from(start..end);
+                    }
+                }
+                Perturbation::Delete { id, length } => update
+                    .deletes
+                    .insert(id.client_id, id.clock..id.clock.saturating_advance(length)),
+                Perturbation::Mark { index, start, end } => {
+                    if let Some(mark) = update.marks.get_mut(index) {
+                        mark.start = start;
+                        mark.end = end;
+                    }
+                }
+                Perturbation::Move {
+                    index,
+                    start,
+                    marker,
+                } => {
+                    if let Some(moved) = update.moves.get_mut(index) {
+                        moved.start = start;
+                        moved.marker = marker;
+                    }
+                }
+                Perturbation::Deletion {
+                    index,
+                    start,
+                    length,
+                } => {
+                    if let Some(record) = update.deletions.get_mut(index) {
+                        record.start = start;
+                        record.length = length;
+                    }
+                }
+                Perturbation::Root { id, length } => {
+                    let mut clocks = DeleteSet::empty();
+                    clocks.insert(id.client_id, id.clock..id.clock.saturating_advance(length));
+                    update.roots.push(("notes".to_owned(), clocks));
+                }
+            }
+        }
+
+        /// A document the update below builds on, and the update, which uses every extension.
+        fn base_and_update() -> (Document<String>, Vec<u8>) {
+            let mut source = Document::with_client_id(1);
+            source.extend(["a", "b", "c", "d"].map(String::from));
+            let mut base = Document::with_client_id(2);
+            Update::from_document(&source).apply(&mut base).unwrap();
+            base.push("e".to_owned());
+            Update::from_document_since(&base, source.state_vector().as_ref())
+                .apply(&mut source)
+                .unwrap();
+
+            let before = source.state_vector();
+            source.insert(1, "x".to_owned());
+            source.insert_values(4, ["y", "z"].map(String::from));
+            source.remove_range(2, 2);
+            source.remove(4);
+            source.add_mark(0..3, "bold", vec![1]);
+            source.map_set("title", "t".to_owned());
+            source.move_range(0..1, 3);
+            source.get_or_create_root("notes").push("n".to_owned());
+            let update = source.take_local_update(before.as_ref());
+
+            // Blocks of a second client base is missing, which link to the first client's
+            let mut other = Document::with_client_id(3);
+            Update::from_document(&source).apply(&mut other).unwrap();
+            other.insert_values(2, ["p", "q"].map(String::from));
+            other.remove(0);
+            let other_update = Update::from_document_since(&other, source.state_vector().as_ref());
+
+            (base, update.merge(other_update).unwrap().encode().unwrap())
+        }
+
+        proptest! {
+            #[test]
+            fn applying_mutated_updates_never_panics(mutations in vec(mutation(), 1..8)) {
+                let (mut base, mut bytes) = base_and_update();
+                for mutation in &mutations {
+                    mutate(&mut bytes, mutation);
+                }
+
+                if let Ok(update) = base.decode_update(&bytes) {
+                    let _ = base.apply_or_queue(update);
+                }
+
+                let (mut base, _) = base_and_update();
+                let _ = base.apply_encoded(&bytes);
+            }
+
+            #[test]
+            fn applying_perturbed_updates_never_panics(
+                perturbations in vec(perturbation(), 1..4)
+            ) {
+                let (mut base, bytes) = base_and_update();
+                let mut update = Update::decode(&bytes).unwrap();
+                for perturbation in &perturbations {
+                    perturb(&mut update, perturbation);
+                }
+
+                if let Ok(bytes) = update.encode() {
+                    let (mut encoded_base, _) = base_and_update();
+                    let _ = encoded_base.apply_encoded(&bytes);
+                }
+                let _ = base.apply_or_queue(update);
+            }
+        }
+    }
+
+    // Run with `cross test --target armv7-unknown-linux-gnueabihf` to exercise the 32-bit paths
+    #[cfg(target_pointer_width = "32")]
+    mod pointer_width_32 {
+        use crate::clock::Clock;
+        use crate::update::{ApplyError, Content, UpdateBlock};
+
+        #[test]
+        fn rejects_deleted_lengths_wider_than_usize() {
+            let block: UpdateBlock<String> = UpdateBlock {
+                origin_left: None,
+                origin_right: None,
+                value: Content::Deleted(u64::from(u32::MAX) + 1),
+                priority: 0,
+            };
+
+            assert_eq!(
+                block.hydrate(Clock::ZERO),
+                Err(ApplyError::InvalidLength(u64::from(u32::MAX) + 1))
+            );
+        }
     }
 }
@@ -0,0 +1,387 @@
+//! Viewing a document as it was at an earlier state vector, for [`Document::checkout`].
+//!
+//! State vectors only count inserted elements, so to tell which deletions a version had, every
+//! deletion records who made it and how far their clock had got at the time.
+
+use crate::block::Item;
+use crate::clock::{Clock, ClockRange};
+use crate::delete_set::DeleteSet;
+use crate::document::{BlockId, ClientId, ClockVector};
+use crate::storage::BlockStorage;
+use crate::Document;
+use bincode::{Decode, Encode};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// A deletion of `length` elements of `start`'s client from `start` onwards, made by client
+/// `by.client_id` when the next element it would insert had clock `by.clock`.
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct DeleteRecord {
+    pub(crate) start: BlockId,
+    pub(crate) length: u64,
+    pub(crate) by: BlockId,
+}
+
+impl DeleteRecord {
+    fn clocks(&self) -> ClockRange {
+        ClockRange::new(
+            self.start.clock,
+            self.start.clock.saturating_advance(self.length),
+        )
+    }
+
+    /// Whether a replica at `state` has everything the deleter had, and so counts as having seen
+    /// the deletion.
+    fn is_seen_by(&self, state: &ClockVector) -> bool {
+        state.get(&self.by.client_id).copied().unwrap_or_default() >= self.by.clock
+    }
+
+    fn key(&self) -> (ClientId, Clock, u64, ClientId, Clock) {
+        (
+            self.start.client_id,
+            self.start.clock,
+            self.length,
+            self.by.client_id,
+            self.by.clock,
+        )
+    }
+}
+
+/// Every deletion a document knows the maker of.
+#[derive(Debug, Default)]
+pub(crate) struct DeleteLog {
+    deletions: HashSet<DeleteRecord>,
+}
+
+impl DeleteLog {
+    pub(crate) fn new() -> DeleteLog {
+        DeleteLog::default()
+    }
+
+    /// Records that everything in `deleted` was deleted by the replica whose next element is
+    /// `by`.
+    pub(crate) fn record(&mut self, deleted: &DeleteSet, by: BlockId) {
+        for range in deleted.iter() {
+            self.merge(DeleteRecord {
+                start: range.start(),
+                length: range.len(),
+                by,
+            });
+        }
+    }
+
+    pub(crate) fn merge(&mut self, deletion: DeleteRecord) {
+        self.deletions.insert(deletion);
+    }
+
+    /// The deletions of any of the elements in `deletes`, ordered so they always encode the same
+    /// way.
+    pub(crate) fn covering(&self, deletes: &DeleteSet) -> Vec<DeleteRecord> {
+        let mut covering: Vec<DeleteRecord> = self
+            .deletions
+            .iter()
+            .filter(|deletion| deletes.overlaps(deletion.start.client_id, deletion.clocks()))
+            .copied()
+            .collect();
+        covering.sort_by_key(DeleteRecord::key);
+
+        covering
+    }
+
+    /// Every deletion, ordered so they always encode the same way.
+    pub(crate) fn deletions(&self) -> Vec<DeleteRecord> {
+        let mut deletions: Vec<DeleteRecord> = self.deletions.iter().copied().collect();
+        deletions.sort_by_key(DeleteRecord::key);
+
+        deletions
+    }
+
+    pub(crate) fn from_deletions(deletions: Vec<DeleteRecord>) -> DeleteLog {
+        DeleteLog {
+            deletions: deletions.into_iter().collect(),
+        }
+    }
+
+    /// The elements a replica at `state` had seen deleted, and every element with a known
+    /// deleter.
+    fn seen_by(&self, state: &ClockVector) -> (DeleteSet, DeleteSet) {
+        let mut seen = DeleteSet::empty();
+        let mut known = DeleteSet::empty();
+
+        for deletion in &self.deletions {
+            let client_id = deletion.start.client_id;
+
+            if deletion.is_seen_by(state) {
+                seen.insert(client_id, deletion.clocks());
+            }
+
+            known.insert(client_id, deletion.clocks());
+        }
+
+        (seen, known)
+    }
+}
+
+/// The values of deleted elements, kept by documents made with
+/// [`Document::with_retained_deletions`] so earlier versions can be shown.
+#[derive(Debug, Default)]
+pub(crate) struct DeletedValues<T> {
+    /// Runs of values by client, then by the clock of the first.
+    values: HashMap<ClientId, BTreeMap<Clock, Vec<T>>>,
+}
+
+impl<T: Item> DeletedValues<T> {
+    pub(crate) fn new() -> DeletedValues<T> {
+        DeletedValues {
+            values: HashMap::new(),
+        }
+    }
+
+    /// Keeps `values`, the values of the elements from `start` onwards.
+    pub(crate) fn insert(&mut self, start: BlockId, values: Vec<T>) {
+        if !values.is_empty() {
+            self.values
+                .entry(start.client_id)
+                .or_default()
+                .insert(start.clock, values);
+        }
+    }
+
+    /// Forgets the values of the elements `client_id` created within `clocks`.
+    pub(crate) fn remove(&mut self, client_id: ClientId, clocks: ClockRange) {
+        let Some(runs) = self.values.get_mut(&client_id) else {
+            return;
+        };
+
+        // Runs don't overlap, so those ending after `clocks` starts are the last to start before
+        // it ends
+        let overlapping: Vec<Clock> = runs
+            .range(..clocks.end)
+            .rev()
+            .take_while(|(start, values)| start.advance(values.len() as u64) > clocks.start)
+            .map(|(start, _)| *start)
+            .collect();
+
+        for start in overlapping {
+            let Some(mut values) = runs.remove(&start) else {
+                continue;
+            };
+
+            if start.advance(values.len() as u64) > clocks.end {
+                let after = values.split_off(clocks.end.offset_from(start) as usize);
+                runs.insert(clocks.end, after);
+            }
+
+            if start < clocks.start {
+                values.truncate(clocks.start.offset_from(start) as usize);
+                runs.insert(start, values);
+            }
+        }
+    }
+
+    pub(crate) fn get(&self, id: BlockId) -> Option<&T> {
+        let (start, values) = self
+            .values
+            .get(&id.client_id)?
+            .range(..=id.clock)
+            .next_back()?;
+
+        values.get(usize::try_from(id.clock.offset_from(*start)).ok()?)
+    }
+
+    /// Every run of values with the id of its first element, ordered by id.
+    pub(crate) fn to_parts(&self) -> Vec<(BlockId, Vec<T>)> {
+        let mut parts: Vec<(BlockId, Vec<T>)> = self
+            .values
+            .iter()
+            .flat_map(|(client_id, runs)| {
+                runs.iter()
+                    .map(|(clock, values)| (BlockId::new(*client_id, *clock), values.clone()))
+            })
+            .collect();
+        parts.sort_by_key(|(id, _)| (id.client_id, id.clock));
+
+        parts
+    }
+
+    pub(crate) fn from_parts(parts: Vec<(BlockId, Vec<T>)>) -> DeletedValues<T> {
+        let mut values = DeletedValues::new();
+
+        for (start, run) in parts {
+            values.insert(start, run);
+        }
+
+        values
+    }
+}
+
+/// The live elements of a document as they were at an earlier state vector. See
+/// [`Document::checkout`].
+#[derive(Debug)]
+pub struct VersionView<'a, T: Item> {
+    values: Vec<&'a T>,
+}
+
+impl<'a, T: Item> VersionView<'a, T> {
+    pub(crate) fn new<S: BlockStorage<T>>(
+        document: &'a Document<T, S>,
+        at: &ClockVector,
+    ) -> VersionView<'a, T> {
+        let store = &document.store;
+        let (seen, known) = document.deletions.seen_by(at);
+        let mut values = vec![];
+
+        for block in store.iter_blocks() {
+            let BlockId { client_id, clock } = block.id();
+            let end = at.get(&client_id).copied().unwrap_or_default();
+
+            for (offset, clock) in ClockRange::starting_at(clock, block.len() as u64)
+                .iter()
+                .take_while(|clock| *clock < end)
+                .enumerate()
+            {
+                if !block.is_deleted() {
+                    values.push(&block.values()[offset]);
+                    continue;
+                }
+
+                let id = BlockId::new(client_id, clock);
+
+                // Deletions nobody recorded the maker of, e.g. from older peers, always count
+                if seen.contains(id) || !known.contains(id) {
+                    continue;
+                }
+
+                values.extend(store.deleted_values.as_ref().and_then(|kept| kept.get(id)));
+            }
+        }
+
+        VersionView { values }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn iter_values(&self) -> impl Iterator<Item = &'a T> + '_ {
+        self.values.iter().copied()
+    }
+
+    pub fn to_vec(&self) -> Vec<T> {
+        self.values.iter().map(|value| (*value).clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Document, StateVector, Update};
+
+    /// A document edited in three phases, with its state vector and content after each.
+    fn document_with_versions() -> (Document<String>, Vec<(StateVector, Vec<String>)>) {
+        let mut document = Document::with_client_id(1).with_retained_deletions();
+        let mut versions = vec![];
+
+        document.push("a".to_owned());
+        document.push("b".to_owned());
+        document.push("c".to_owned());
+        versions.push((document.state_vector(), document.to_vec()));
+
+        document.insert(1, "d".to_owned());
+        document.remove(0);
+        versions.push((document.state_vector(), document.to_vec()));
+
+        document.push("e".to_owned());
+        document.remove_range(1, 2);
+        versions.push((document.state_vector(), document.to_vec()));
+
+        (document, versions)
+    }
+
+    #[test]
+    fn shows_the_document_as_it_was_at_each_version() {
+        let (document, versions) = document_with_versions();
+
+        for (state, content) in &versions {
+            let view = document.checkout(state.as_ref());
+
+            assert_eq!(&view.to_vec(), content);
+            assert_eq!(view.len(), content.len());
+            assert!(view.iter_values().eq(content.iter()));
+        }
+
+        assert_eq!(
+            document.checkout(&Default::default()).to_vec(),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn replicas_which_received_the_edits_show_the_same_versions() {
+        let (mut document, versions) = document_with_versions();
+        let mut replica = Document::with_client_id(2).with_retained_deletions();
+
+        // Replayed phase by phase, so the replica sees each element before it's deleted
+        let mut replayed = Document::with_client_id(1).with_retained_deletions();
+        replayed.push("a".to_owned());
+        replayed.push("b".to_owned());
+        replayed.push("c".to_owned());
+        Update::from_document_since(&replayed, replica.state_vector().as_ref())
+            .apply(&mut replica)
+            .unwrap();
+
+        replayed.insert(1, "d".to_owned());
+        replayed.remove(0);
+        Update::from_document_since(&replayed, replica.state_vector().as_ref())
+            .apply(&mut replica)
+            .unwrap();
+
+        replayed.push("e".to_owned());
+        replayed.remove_range(1, 2);
+        Update::from_document_since(&replayed, replica.state_vector().as_ref())
+            .apply(&mut replica)
+            .unwrap();
+
+        for (state, content) in &versions {
+            assert_eq!(&replica.checkout(state.as_ref()).to_vec(), content);
+        }
+
+        // Deletions made elsewhere count once everything their maker had is in
+        replica.push("x".to_owned());
+        replica.remove(0);
+        Update::from_document(&replica)
+            .apply(&mut document)
+            .unwrap();
+
+        let (last, _) = &versions[2];
+        assert_eq!(document.checkout(last.as_ref()).to_vec(), vec!["d", "e"]);
+        assert_eq!(
+            document.checkout(document.state_vector().as_ref()).to_vec(),
+            vec!["e", "x"]
+        );
+    }
+
+    #[test]
+    fn leaves_out_deleted_elements_whose_values_were_not_kept() {
+        let mut document = Document::with_client_id(1);
+        document.push("a".to_owned());
+        document.push("b".to_owned());
+        let before = document.state_vector();
+        document.remove(0);
+
+        assert_eq!(document.checkout(before.as_ref()).to_vec(), vec!["b"]);
+
+        // Tombstones arrive without their values
+        let (document, versions) = document_with_versions();
+        let mut replica = Document::with_client_id(2).with_retained_deletions();
+        Update::from_document(&document)
+            .apply(&mut replica)
+            .unwrap();
+
+        let (second, _) = &versions[1];
+        assert_eq!(replica.checkout(second.as_ref()).to_vec(), vec!["d"]);
+    }
+}
@@ -0,0 +1,188 @@
+//! JavaScript bindings for [`TextDocument`], for replicas running in the browser. Enabled by the
+//! `wasm` feature, for use with `wasm-bindgen` or `wasm-pack`.
+//!
+//! Errors are thrown as JS `Error`s. Those from applying an update start with the name of the
+//! [`ApplyError`](crate::ApplyError) variant, e.g. `MissingDependency: ...`.
+
+use crate::encoding;
+use crate::{StateVector, TextDocument, TextUpdateError, Update};
+use js_sys::{Function, Uint8Array};
+use wasm_bindgen::prelude::*;
+
+/// A collaboratively edited string. Indices count `char`s, as on [`TextDocument`].
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct YDoc {
+    text: TextDocument,
+    on_update: Option<Function>,
+}
+
+#[wasm_bindgen]
+impl YDoc {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> YDoc {
+        YDoc::default()
+    }
+
+    /// Inserts `text` so its first `char` ends up at `index`.
+    #[wasm_bindgen(js_name = insertStr)]
+    pub fn insert_str(&mut self, index: usize, text: &str) -> Result<(), JsValue> {
+        if index > self.text.len_chars() {
+            return Err(out_of_range(index, self.text.len_chars()));
+        }
+
+        let update = self.text.insert_str(index, text);
+
+        self.updated(update)
+    }
+
+    /// Deletes `len` `char`s from `index` onwards.
+    pub fn delete(&mut self, index: usize, len: usize) -> Result<(), JsValue> {
+        let end = index.saturating_add(len);
+
+        if end > self.text.len_chars() {
+            return Err(out_of_range(end, self.text.len_chars()));
+        }
+
+        let update = self.text.delete(index..end);
+
+        self.updated(update)
+    }
+
+    #[wasm_bindgen(js_name = toString)]
+    pub fn to_js_string(&self) -> String {
+        self.text.to_string()
+    }
+
+    /// The document's state vector, for a peer to pass to `encodeUpdateSince`.
+    #[wasm_bindgen(js_name = encodeStateVector)]
+    pub fn encode_state_vector(&self) -> Result<Vec<u8>, JsValue> {
+        encoding::encode(&self.text.state_vector()).map_err(error)
+    }
+
+    /// Everything a peer with the encoded state vector `sv` is missing. An empty state vector
+    /// encodes the whole document.
+    #[wasm_bindgen(js_name = encodeUpdateSince)]
+    pub fn encode_update_since(&self, sv: &[u8]) -> Result<Vec<u8>, JsValue> {
+        let since = if sv.is_empty() {
+            StateVector::new()
+        } else {
+            encoding::decode(sv).map_err(error)?
+        };
+
+        self.text.encode_update(&since).map_err(error)
+    }
+
+    #[wasm_bindgen(js_name = applyUpdate)]
+    pub fn apply_update(&mut self, update: &[u8]) -> Result<(), JsValue> {
+        self.text.apply_update(update).map_err(|e| match e {
+            TextUpdateError::Decode(e) => error(e),
+            TextUpdateError::Apply(e) => {
+                let debug = format!("{:?}", e);
+                let variant: String = debug.chars().take_while(|c| c.is_alphanumeric()).collect();
+
+                JsError::new(&format!("{}: {}", variant, e)).into()
+            }
+        })
+    }
+
+    /// Calls `callback` with the encoded update after each local edit, e.g. to send it over a
+    /// WebSocket. Replaces any earlier callback.
+    #[wasm_bindgen(js_name = onUpdate)]
+    pub fn on_update(&mut self, callback: Function) {
+        self.on_update = Some(callback);
+    }
+
+    /// Passes a local edit's update to the `onUpdate` callback, if there is one.
+    fn updated(&self, update: Update<char>) -> Result<(), JsValue> {
+        let Some(callback) = &self.on_update else {
+            return Ok(());
+        };
+
+        let bytes = update.encode().map_err(error)?;
+        callback.call1(&JsValue::NULL, &Uint8Array::from(bytes.as_slice()))?;
+
+        Ok(())
+    }
+}
+
+fn error(error: impl std::error::Error) -> JsValue {
+    JsError::new(&error.to_string()).into()
+}
+
+fn out_of_range(index: usize, len: usize) -> JsValue {
+    JsError::new(&format!(
+        "index {} out of range for text of length {}",
+        index, len
+    ))
+    .into()
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use crate::wasm::YDoc;
+    use js_sys::{Array, Function, Uint8Array};
+    use wasm_bindgen::JsCast;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    fn sync(from: &YDoc, to: &mut YDoc) {
+        let sv = to.encode_state_vector().unwrap();
+        let update = from.encode_update_since(&sv).unwrap();
+
+        to.apply_update(&update).unwrap();
+    }
+
+    #[wasm_bindgen_test]
+    fn syncs_two_docs() {
+        let mut first = YDoc::new();
+        let mut second = YDoc::new();
+
+        first.insert_str(0, "hello world").unwrap();
+        sync(&first, &mut second);
+        assert_eq!(second.to_js_string(), "hello world");
+
+        second.delete(5, 6).unwrap();
+        second.insert_str(5, ", there").unwrap();
+        sync(&second, &mut first);
+        assert_eq!(first.to_js_string(), "hello, there");
+    }
+
+    #[wasm_bindgen_test]
+    fn sends_each_local_edit_to_the_callback() {
+        let mut first = YDoc::new();
+        let mut second = YDoc::new();
+        let sent = Array::new();
+        let callback = Function::new_with_args("update", "this.push(update)").bind0(&sent);
+
+        first.on_update(callback);
+        first.insert_str(0, "abc").unwrap();
+        first.delete(0, 1).unwrap();
+
+        assert_eq!(sent.length(), 2);
+
+        for update in sent.iter() {
+            second
+                .apply_update(&Uint8Array::new(&update).to_vec())
+                .unwrap();
+        }
+
+        assert_eq!(second.to_js_string(), "bc");
+    }
+
+    #[wasm_bindgen_test]
+    fn names_the_apply_error() {
+        let mut first = YDoc::new();
+        let mut second = YDoc::new();
+        first.insert_str(0, "a").unwrap();
+        let before = first.encode_state_vector().unwrap();
+        first.insert_str(1, "b").unwrap();
+
+        let error = second
+            .apply_update(&first.encode_update_since(&before).unwrap())
+            .unwrap_err();
+        let message = String::from(error.dyn_into::<js_sys::Error>().unwrap().message());
+
+        assert!(message.starts_with("MissingDependency: "));
+        assert!(first.insert_str(5, "c").is_err());
+    }
+}